@@ -0,0 +1,65 @@
+//! Kernel command lines: `key=value` options and bare flags separated by spaces,
+//! e.g. `log_level=debug console=serial nosmp`. A value in double quotes may
+//! contain spaces, `name="a b"`.
+
+/// One option of a command line, `value` is `None` for a bare flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Param<'a> {
+    pub key: &'a str,
+    pub value: Option<&'a str>,
+}
+
+/// The options of `cmdline` in order.
+pub fn params(cmdline: &str) -> impl Iterator<Item = Param<'_>> {
+    let mut rest = cmdline;
+    core::iter::from_fn(move || {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+
+        let mut quoted = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, ch)| {
+                if ch == '"' {
+                    quoted = !quoted;
+                }
+                ch.is_whitespace() && !quoted
+            })
+            .map_or(rest.len(), |(end, _)| end);
+        let (param, remaining) = rest.split_at(end);
+        rest = remaining;
+
+        Some(match param.split_once('=') {
+            Some((key, value)) => Param {
+                key,
+                value: Some(unquote(value)),
+            },
+            None => Param {
+                key: param,
+                value: None,
+            },
+        })
+    })
+}
+
+/// The value of the last `key=value` in `cmdline`, later options win like on Linux.
+pub fn value<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    params(cmdline)
+        .filter(|param| param.key == key)
+        .filter_map(|param| param.value)
+        .last()
+}
+
+/// Whether `cmdline` has the bare flag `key`.
+pub fn flag(cmdline: &str, key: &str) -> bool {
+    params(cmdline).any(|param| param.key == key && param.value.is_none())
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
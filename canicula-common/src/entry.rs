@@ -1,3 +1,486 @@
+use core::fmt;
+
 pub trait KernelEntry {
     fn entry() -> !;
 }
+
+/// `CANICULA` in little endian, first in every [`BootInfo`].
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"CANICULA");
+/// Bump whenever the layout of [`BootInfo`] or anything it holds changes.
+pub const BOOT_INFO_VERSION: u32 = 3;
+
+/// Handed from the loader to the kernel entry point.
+///
+/// The structure has no implicit padding, [`BootInfo::checksum`] covers every byte.
+/// Everything but [`BootInfo::arch_info`] means the same on every architecture.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub version: u32,
+    /// CRC-32 of the structure with this field zero, set by [`BootInfo::seal`].
+    pub checksum: u32,
+    /// Which field of [`BootInfo::arch_info`] the loader filled in.
+    pub arch: Arch,
+    /// Zero, fills what would be padding.
+    pub reserved: u32,
+    /// The processor the kernel is entered on: the local APIC ID on x86_64,
+    /// the hart ID on riscv64, the affinity bits of MPIDR_EL1 on aarch64.
+    pub boot_cpu: u64,
+    pub framebuffer: FrameBufferInfo,
+    /// A PSF font loaded from the boot volume, empty if none was configured.
+    pub font: MemoryRegion,
+    /// Virtual address where all of physical memory is mapped, `0` if it is identity mapped.
+    pub physical_memory_offset: u64,
+    /// Bytes of physical memory mapped there, at least up to the end of the memory map.
+    pub physical_memory_size: u64,
+    pub memory_attributes: MemoryAttributesTable,
+    /// Physical memory as the firmware left it at exit from boot services.
+    pub memory_map: MemoryMap,
+    pub firmware: FirmwareTables,
+    /// How far above its link address the loader placed the kernel.
+    pub kernel_slide: u64,
+    /// Virtual address of the lowest kernel segment, slide included.
+    pub kernel_base: u64,
+    pub kernel_relocation: KernelRelocation,
+    /// An initial RAM disk loaded from the boot volume, empty if none was configured.
+    pub initrd: MemoryRegion,
+    /// The UTF-8 kernel command line from the loader configuration, may be empty.
+    pub cmdline: MemoryRegion,
+    /// Extra files loaded from the boot volume, e.g. driver blobs.
+    pub modules: BootModules,
+    pub arch_info: ArchInfo,
+}
+
+/// A startup IPI only carries the page number of a real mode address.
+pub const SMP_TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    Riscv64,
+    Aarch64,
+}
+
+impl Arch {
+    /// The architecture this is built for.
+    pub const fn current() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        return Arch::X86_64;
+        #[cfg(target_arch = "riscv64")]
+        return Arch::Riscv64;
+        #[cfg(target_arch = "aarch64")]
+        return Arch::Aarch64;
+    }
+}
+
+/// Handoff details only one architecture has, [`BootInfo::arch`] tells which.
+///
+/// Build it from one of the variants with `into()`, the unused bytes stay zero
+/// for the checksum.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union ArchInfo {
+    pub x86_64: X86Info,
+    pub riscv64: RiscvInfo,
+    pub aarch64: Aarch64Info,
+    raw: [u64; 4],
+}
+
+const _: () = assert!(core::mem::size_of::<ArchInfo>() == 32);
+
+impl fmt::Debug for ArchInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ArchInfo")
+            .field(unsafe { &self.raw })
+            .finish()
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct X86Info {
+    /// Physical address of a free page below [`SMP_TRAMPOLINE_LIMIT`] for the code
+    /// application processors start in, `0` if the loader found none.
+    pub smp_trampoline: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RiscvInfo {
+    /// `satp.MODE` the kernel is entered with, e.g. 8 for Sv39 or 9 for Sv48,
+    /// which decides where [`BootInfo::physical_memory_offset`] can be.
+    pub satp_mode: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Aarch64Info {
+    /// Exception level the kernel is entered at, 1 or 2.
+    pub current_el: u64,
+}
+
+impl From<X86Info> for ArchInfo {
+    fn from(info: X86Info) -> Self {
+        let mut arch_info = ArchInfo { raw: [0; 4] };
+        arch_info.x86_64 = info;
+        arch_info
+    }
+}
+
+impl From<RiscvInfo> for ArchInfo {
+    fn from(info: RiscvInfo) -> Self {
+        let mut arch_info = ArchInfo { raw: [0; 4] };
+        arch_info.riscv64 = info;
+        arch_info
+    }
+}
+
+impl From<Aarch64Info> for ArchInfo {
+    fn from(info: Aarch64Info) -> Self {
+        let mut arch_info = ArchInfo { raw: [0; 4] };
+        arch_info.aarch64 = info;
+        arch_info
+    }
+}
+
+impl BootInfo {
+    /// Checksum the finished structure, the last thing the loader does to it.
+    pub fn seal(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+
+    /// Check that the loader that wrote this was built against the same `BootInfo`.
+    pub fn check(&self) -> Result<(), &'static str> {
+        if self.magic != BOOT_INFO_MAGIC {
+            return Err("boot info has no magic, not started by canicula-efi?");
+        }
+        if self.version != BOOT_INFO_VERSION {
+            return Err("boot info version differs, loader and kernel do not match");
+        }
+        if self.checksum != self.compute_checksum() {
+            return Err("boot info checksum does not match");
+        }
+        if self.arch != Arch::current() {
+            return Err("boot info is for another architecture");
+        }
+        Ok(())
+    }
+
+    pub fn x86_64(&self) -> Option<&X86Info> {
+        (self.arch == Arch::X86_64).then_some(unsafe { &self.arch_info.x86_64 })
+    }
+
+    pub fn riscv64(&self) -> Option<&RiscvInfo> {
+        (self.arch == Arch::Riscv64).then_some(unsafe { &self.arch_info.riscv64 })
+    }
+
+    pub fn aarch64(&self) -> Option<&Aarch64Info> {
+        (self.arch == Arch::Aarch64).then_some(unsafe { &self.arch_info.aarch64 })
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        let mut copy = *self;
+        copy.checksum = 0;
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &copy as *const BootInfo as *const u8,
+                core::mem::size_of::<BootInfo>(),
+            )
+        };
+        crc32(bytes)
+    }
+
+    /// The kernel command line, empty if it is not valid UTF-8.
+    ///
+    /// # Safety
+    ///
+    /// `cmdline` must still be mapped where the loader left it.
+    pub unsafe fn cmdline(&self) -> &str {
+        if self.cmdline.is_empty() {
+            return "";
+        }
+        let bytes = core::slice::from_raw_parts(
+            self.cmdline.address as *const u8,
+            self.cmdline.size as usize,
+        );
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+}
+
+/// An array of [`BootModule`]s in loader memory.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BootModules {
+    pub address: u64,
+    pub count: u64,
+}
+
+impl BootModules {
+    /// # Safety
+    ///
+    /// `address` must still point at `count` modules.
+    pub unsafe fn iter(&self) -> impl Iterator<Item = BootModule> + '_ {
+        (0..self.count).map(move |index| {
+            let module = self.address + index * core::mem::size_of::<BootModule>() as u64;
+            unsafe { (module as *const BootModule).read_unaligned() }
+        })
+    }
+}
+
+/// A file the loader read for the kernel, both regions are physical memory.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootModule {
+    pub content: MemoryRegion,
+    /// The UTF-8 path from the loader configuration.
+    pub path: MemoryRegion,
+}
+
+impl BootModule {
+    /// The path the module was loaded from, empty if it is not valid UTF-8.
+    ///
+    /// # Safety
+    ///
+    /// `path` must still be mapped where the loader left it.
+    pub unsafe fn path(&self) -> &str {
+        if self.path.is_empty() {
+            return "";
+        }
+        let bytes =
+            core::slice::from_raw_parts(self.path.address as *const u8, self.path.size as usize);
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FrameBufferInfo {
+    pub address: u64,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per scan line, may be larger than `width`.
+    pub stride: u32,
+    pub pixel_format: PixelFormat,
+    /// Where each channel sits in a 32-bit pixel, filled in for every format but `Unknown`.
+    pub masks: PixelMasks,
+    /// Zero, fills what would be padding, see [`BootInfo`].
+    pub reserved: u32,
+    /// RAM of `size` bytes laid out like the framebuffer to draw into before
+    /// copying to it, `0` if the loader could not spare it.
+    pub back_buffer: u64,
+}
+
+const _: () = assert!(core::mem::size_of::<FrameBufferInfo>() == 56);
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+    /// Channels are only described by the masks.
+    Bitmask,
+    /// No linear framebuffer is available.
+    Unknown,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PixelMasks {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+}
+
+impl PixelMasks {
+    /// Red in the lowest byte.
+    pub const RGB: PixelMasks = PixelMasks {
+        red: 0x0000ff,
+        green: 0x00ff00,
+        blue: 0xff0000,
+    };
+    /// Blue in the lowest byte.
+    pub const BGR: PixelMasks = PixelMasks {
+        red: 0xff0000,
+        green: 0x00ff00,
+        blue: 0x0000ff,
+    };
+
+    /// Pack an 0xRRGGBB color into a pixel.
+    pub fn encode(&self, color: u32) -> u32 {
+        channel(color >> 16 & 0xff, self.red)
+            | channel(color >> 8 & 0xff, self.green)
+            | channel(color & 0xff, self.blue)
+    }
+}
+
+/// Scale an 8-bit channel value to the width of `mask` and move it into place.
+fn channel(value: u32, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let bits = (mask >> shift).count_ones();
+    let value = if bits >= 8 {
+        value << (bits - 8)
+    } else {
+        value >> (8 - bits)
+    };
+    (value << shift) & mask
+}
+
+/// Physical addresses of tables the firmware publishes, `0` for the ones it does not.
+///
+/// The loader never calls `SetVirtualAddressMap`, so the runtime services keep
+/// their physical addresses and stay callable through the identity mapping.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FirmwareTables {
+    /// ACPI RSDP, the 2.0 one if the firmware has both.
+    pub rsdp: u64,
+    /// 32-bit SMBIOS entry point, starts with `_SM_`.
+    pub smbios: u64,
+    /// 64-bit SMBIOS 3 entry point, starts with `_SM3_`.
+    pub smbios3: u64,
+    pub efi_system_table: u64,
+    pub efi_runtime_services: u64,
+    /// Flattened device tree, what riscv64 and aarch64 machines usually describe themselves with.
+    pub device_tree: u64,
+}
+
+/// How the loader fixed up the kernel image before jumping to it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KernelRelocation {
+    pub status: RelocationStatus,
+    /// `R_X86_64_RELATIVE` entries the loader applied.
+    pub count: u32,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationStatus {
+    /// An `ET_EXEC` kernel, loaded at its link address untouched.
+    Fixed,
+    /// An `ET_DYN` kernel whose relocations were applied for `kernel_base`.
+    Relocated,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub address: u64,
+    pub size: u64,
+}
+
+impl MemoryRegion {
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+}
+
+/// The physical memory map, sorted by address, merged and free of overlaps.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryMap {
+    pub address: u64,
+    pub count: u64,
+}
+
+impl MemoryMap {
+    /// # Safety
+    ///
+    /// `address` must still point at `count` entries.
+    pub unsafe fn entries(&self) -> &[MemoryMapEntry] {
+        if self.count == 0 {
+            return &[];
+        }
+        core::slice::from_raw_parts(self.address as *const MemoryMapEntry, self.count as usize)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryMapEntry {
+    pub region: MemoryRegion,
+    pub kind: MemoryKind,
+}
+
+impl MemoryMapEntry {
+    pub fn end(&self) -> u64 {
+        self.region.address + self.region.size
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Free for the kernel to allocate.
+    Usable,
+    /// Holds the kernel, its page tables or what the loader handed over.
+    Bootloader,
+    /// Not RAM or never to be touched, e.g. the framebuffer.
+    Reserved,
+    /// ACPI tables, usable once the kernel has read them.
+    AcpiReclaimable,
+    AcpiNvs,
+    /// Used by EFI runtime services, must stay mapped for them.
+    RuntimeServices,
+}
+
+pub const EFI_MEMORY_XP: u64 = 0x4000;
+pub const EFI_MEMORY_RO: u64 = 0x20000;
+pub const EFI_MEMORY_RUNTIME: u64 = 1 << 63;
+
+pub const EFI_RUNTIME_SERVICES_CODE: u32 = 5;
+pub const EFI_RUNTIME_SERVICES_DATA: u32 = 6;
+
+/// `EFI_MEMORY_DESCRIPTOR` as laid out by the firmware.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct EfiMemoryDescriptor {
+    pub kind: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub page_count: u64,
+    pub attribute: u64,
+}
+
+/// The entries of the firmware's `EFI_MEMORY_ATTRIBUTES_TABLE`, copied out by the loader.
+///
+/// Each entry describes part of a runtime services region and whether it may be
+/// written (`EFI_MEMORY_RO`) or executed (`EFI_MEMORY_XP`).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryAttributesTable {
+    pub address: u64,
+    pub entry_count: u32,
+    /// Stride between entries, may be larger than `EfiMemoryDescriptor`.
+    pub descriptor_size: u32,
+}
+
+impl MemoryAttributesTable {
+    /// # Safety
+    ///
+    /// `address` must still point at `entry_count` readable descriptors.
+    pub unsafe fn entries(&self) -> impl Iterator<Item = EfiMemoryDescriptor> + '_ {
+        (0..self.entry_count as u64).map(move |index| {
+            let entry = self.address + index * self.descriptor_size as u64;
+            unsafe { (entry as *const EfiMemoryDescriptor).read_unaligned() }
+        })
+    }
+}
+
+/// CRC-32 as in zlib, bit by bit since it only runs over a [`BootInfo`] once.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
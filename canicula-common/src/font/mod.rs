@@ -0,0 +1,266 @@
+use crate::unicode::REPLACEMENT_CHARACTER;
+
+mod builtin;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: usize = 4;
+const PSF1_MODE512: u8 = 0x01;
+const PSF1_MODEHASTAB: u8 = 0x02;
+const PSF1_MODESEQ: u8 = 0x04;
+const PSF1_SEPARATOR: u16 = 0xffff;
+const PSF1_STARTSEQ: u16 = 0xfffe;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xff;
+const PSF2_STARTSEQ: u8 = 0xfe;
+
+/// Framebuffers at least this tall get glyphs drawn at twice their size.
+const HIDPI_HEIGHT: usize = 1440;
+/// Smallest console that scaling is still allowed to leave.
+const MIN_COLUMNS: usize = 80;
+const MIN_ROWS: usize = 25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    BadMagic,
+    Truncated,
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum UnicodeTable<'a> {
+    /// Glyph `n` is the codepoint `first + n`.
+    Range(u32),
+    Psf1(&'a [u8]),
+    Psf2(&'a [u8]),
+}
+
+/// A fixed size bitmap font, either the built-in one or a PSF1/PSF2 file.
+#[derive(Debug, Clone, Copy)]
+pub struct Font<'a> {
+    glyphs: &'a [u8],
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+    unicode: UnicodeTable<'a>,
+    replacement: usize,
+}
+
+/// One glyph bitmap, rows are padded to whole bytes with the leftmost pixel in the high bit.
+#[derive(Debug, Clone, Copy)]
+pub struct Glyph<'a> {
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+}
+
+impl Glyph<'_> {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        let row = self.width.div_ceil(8);
+        self.data[y * row + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+impl<'a> Font<'a> {
+    /// Parse a PSF1 or PSF2 font, the data is borrowed, not copied.
+    pub fn parse(data: &'a [u8]) -> Result<Self, FontError> {
+        if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(data)
+        } else if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(data)
+        } else {
+            Err(FontError::BadMagic)
+        }
+    }
+
+    fn parse_psf1(data: &'a [u8]) -> Result<Self, FontError> {
+        if data.len() < PSF1_HEADER_SIZE {
+            return Err(FontError::Truncated);
+        }
+        let mode = data[2];
+        let height = data[3] as usize;
+        let glyph_count = if mode & PSF1_MODE512 != 0 { 512 } else { 256 };
+
+        let end = PSF1_HEADER_SIZE + glyph_count * height;
+        let glyphs = data
+            .get(PSF1_HEADER_SIZE..end)
+            .ok_or(FontError::Truncated)?;
+        let unicode = if mode & (PSF1_MODEHASTAB | PSF1_MODESEQ) != 0 {
+            UnicodeTable::Psf1(&data[end..])
+        } else {
+            UnicodeTable::Range(0)
+        };
+
+        Ok(Self::new(glyphs, glyph_count, height, 8, height, unicode))
+    }
+
+    fn parse_psf2(data: &'a [u8]) -> Result<Self, FontError> {
+        let field = |index: usize| {
+            data.get(4 + index * 4..8 + index * 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+                .ok_or(FontError::Truncated)
+        };
+        let version = field(0)?;
+        let header_size = field(1)?;
+        let flags = field(2)? as u32;
+        let glyph_count = field(3)?;
+        let bytes_per_glyph = field(4)?;
+        let height = field(5)?;
+        let width = field(6)?;
+
+        if version != 0 || header_size < PSF2_HEADER_SIZE {
+            return Err(FontError::Unsupported);
+        }
+        if width == 0 || height == 0 || bytes_per_glyph < width.div_ceil(8) * height {
+            return Err(FontError::Unsupported);
+        }
+
+        let end = glyph_count
+            .checked_mul(bytes_per_glyph)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(FontError::Truncated)?;
+        let glyphs = data.get(header_size..end).ok_or(FontError::Truncated)?;
+        let unicode = if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            UnicodeTable::Psf2(&data[end..])
+        } else {
+            UnicodeTable::Range(0)
+        };
+
+        Ok(Self::new(
+            glyphs,
+            glyph_count,
+            bytes_per_glyph,
+            width,
+            height,
+            unicode,
+        ))
+    }
+
+    fn new(
+        glyphs: &'a [u8],
+        glyph_count: usize,
+        bytes_per_glyph: usize,
+        width: usize,
+        height: usize,
+        unicode: UnicodeTable<'a>,
+    ) -> Self {
+        let mut font = Font {
+            glyphs,
+            glyph_count,
+            bytes_per_glyph,
+            width,
+            height,
+            unicode,
+            replacement: 0,
+        };
+        font.replacement = font
+            .index(REPLACEMENT_CHARACTER)
+            .or_else(|| font.index('?'))
+            .unwrap_or(0);
+        font
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn glyph_count(&self) -> usize {
+        self.glyph_count
+    }
+
+    /// The glyph index for `ch`, `None` if the font does not cover it.
+    pub fn index(&self, ch: char) -> Option<usize> {
+        let index = match self.unicode {
+            UnicodeTable::Range(first) => (ch as u32).checked_sub(first)? as usize,
+            UnicodeTable::Psf1(table) => {
+                let mut index = 0;
+                let mut in_sequence = false;
+                table
+                    .chunks_exact(2)
+                    .map(|entry| u16::from_le_bytes([entry[0], entry[1]]))
+                    .find_map(|entry| {
+                        match entry {
+                            PSF1_SEPARATOR => {
+                                index += 1;
+                                in_sequence = false;
+                            }
+                            PSF1_STARTSEQ => in_sequence = true,
+                            _ if !in_sequence && entry as u32 == ch as u32 => return Some(index),
+                            _ => {}
+                        }
+                        None
+                    })?
+            }
+            UnicodeTable::Psf2(table) => {
+                table
+                    .split(|byte| *byte == PSF2_SEPARATOR)
+                    .position(|entry| {
+                        // anything after a start marker is a sequence, not a single codepoint
+                        let single = entry.split(|byte| *byte == PSF2_STARTSEQ).next().unwrap();
+                        core::str::from_utf8(single).is_ok_and(|chars| chars.contains(ch))
+                    })?
+            }
+        };
+        (index < self.glyph_count).then_some(index)
+    }
+
+    /// The glyph for `ch`, or the font's replacement glyph if it has none.
+    pub fn glyph(&self, ch: char) -> Glyph<'a> {
+        self.glyph_at(self.index(ch).unwrap_or(self.replacement))
+    }
+
+    fn glyph_at(&self, index: usize) -> Glyph<'a> {
+        let start = index * self.bytes_per_glyph;
+        Glyph {
+            data: &self.glyphs[start..start + self.bytes_per_glyph],
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Integer scale for this font on a `width` x `height` framebuffer.
+    ///
+    /// HiDPI framebuffers get 2x, as long as an 80x25 console still fits.
+    pub fn scale_for(&self, width: usize, height: usize) -> usize {
+        let fits = |scale: usize| {
+            width / (self.width * scale) >= MIN_COLUMNS
+                && height / (self.height * scale) >= MIN_ROWS
+        };
+        if height >= HIDPI_HEIGHT && fits(2) {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+impl Font<'static> {
+    /// The 8x8 ASCII font compiled into the kernel, used when no PSF font is loaded.
+    pub fn builtin() -> Self {
+        let glyph_count = builtin::GLYPHS.len() / 8;
+        Font {
+            glyphs: &builtin::GLYPHS,
+            glyph_count,
+            bytes_per_glyph: 8,
+            width: 8,
+            height: 8,
+            unicode: UnicodeTable::Range(0x20),
+            replacement: glyph_count - 1,
+        }
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OperateError {
     InvalidFileDescriptor,
     Fault,
@@ -7,4 +7,13 @@ pub enum OperateError {
     DeviceNoFreeSpace,
     NotFoundDev,
     TimeOut,
+    InvalidFileSystem,
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    ReadOnly,
+    /// Still in use, e.g. a mount with open files.
+    Busy,
+    /// A fixed size table, e.g. of open files, is full.
+    TableFull,
 }
@@ -0,0 +1,92 @@
+//! Where the loader puts things in the kernel half of the address space.
+//!
+//! The loader maps by these addresses and the kernel relies on them, the kernel
+//! linker script has to place `KERNEL_BEGIN` at [`KERNEL_BASE`]. The loader checks
+//! the kernel image with [`check_kernel`] and the kernel checks what it was handed
+//! with [`check_handoff`], so a mismatch stops the boot instead of faulting later.
+
+use crate::entry::{BootInfo, SMP_TRAMPOLINE_LIMIT};
+
+/// Bytes covered by one entry of the level 4 page table.
+pub const PML4_ENTRY_SIZE: u64 = 1 << 39;
+/// Lowest address of the upper half, the kernel owns everything above.
+pub const KERNEL_SPACE_START: u64 = 0xFFFF_8000_0000_0000;
+
+/// All of physical memory is mapped starting here.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = KERNEL_SPACE_START;
+/// The low 4GiB are always mapped, MMIO below 4GiB is not in every memory map.
+pub const PHYSICAL_MEMORY_SIZE_MIN: u64 = 0x1_0000_0000;
+/// Everything up to the kernel image, far beyond what any CPU addresses today.
+pub const PHYSICAL_MEMORY_SIZE_MAX: u64 = KERNEL_BASE - PHYSICAL_MEMORY_OFFSET;
+
+/// Link address of the kernel, KASLR moves it up by less than [`KERNEL_SLIDE_WINDOW`].
+pub const KERNEL_BASE: u64 = 0xFFFF_F800_0000_0000;
+pub const KERNEL_SLIDE_WINDOW: u64 = 0x4000_0000;
+/// The kernel image, slid or not, has to stay within one level 4 entry.
+pub const KERNEL_SIZE_MAX: u64 = PML4_ENTRY_SIZE - KERNEL_SLIDE_WINDOW;
+
+/// Where the kernel maps MMIO and other memory it sets up at runtime.
+pub const KERNEL_VMAP_START: u64 = 0xFFFF_FE80_0000_0000;
+pub const KERNEL_VMAP_SIZE: u64 = PML4_ENTRY_SIZE;
+
+/// Lowest address of the boot stack.
+pub const KERNEL_STACK_ADDRESS: u64 = 0xFFFF_FF01_0000_0000;
+pub const KERNEL_STACK_SIZE: u64 = 512 * 0x1000;
+/// Where the stack pointer starts, the stack grows down from here.
+pub const KERNEL_STACK_TOP: u64 = KERNEL_STACK_ADDRESS + KERNEL_STACK_SIZE;
+
+/// Index of the level 4 entry translating `address`.
+pub const fn pml4_index(address: u64) -> usize {
+    ((address >> 39) & 0x1ff) as usize
+}
+
+pub const PHYSICAL_MEMORY_PML4_INDEX: usize = pml4_index(PHYSICAL_MEMORY_OFFSET);
+pub const KERNEL_PML4_INDEX: usize = pml4_index(KERNEL_BASE);
+pub const KERNEL_STACK_PML4_INDEX: usize = pml4_index(KERNEL_STACK_ADDRESS);
+pub const KERNEL_VMAP_PML4_INDEX: usize = pml4_index(KERNEL_VMAP_START);
+
+// the regions must not share level 4 entries, each one gets its own tables
+const _: () = {
+    assert!(PHYSICAL_MEMORY_OFFSET & (PML4_ENTRY_SIZE - 1) == 0);
+    assert!(KERNEL_BASE & (PML4_ENTRY_SIZE - 1) == 0);
+    assert!(PHYSICAL_MEMORY_SIZE_MIN <= PHYSICAL_MEMORY_SIZE_MAX);
+    assert!(KERNEL_PML4_INDEX < KERNEL_VMAP_PML4_INDEX);
+    assert!(KERNEL_VMAP_PML4_INDEX < KERNEL_STACK_PML4_INDEX);
+    assert!(pml4_index(KERNEL_VMAP_START + KERNEL_VMAP_SIZE - 1) == KERNEL_VMAP_PML4_INDEX);
+    assert!(pml4_index(KERNEL_STACK_TOP - 1) == KERNEL_STACK_PML4_INDEX);
+};
+
+/// Check a kernel linked at `link_base..link_end` against the layout, before
+/// the loader maps it.
+pub fn check_kernel(link_base: u64, link_end: u64) -> Result<(), &'static str> {
+    if link_base != KERNEL_BASE {
+        return Err("kernel is not linked at KERNEL_BASE");
+    }
+    if link_end < link_base || link_end - link_base > KERNEL_SIZE_MAX {
+        return Err("kernel image is larger than KERNEL_SIZE_MAX");
+    }
+    Ok(())
+}
+
+/// Check that the loader built the address space this kernel was compiled for.
+pub fn check_handoff(boot_info: &BootInfo) -> Result<(), &'static str> {
+    if boot_info.physical_memory_offset != PHYSICAL_MEMORY_OFFSET {
+        return Err("physical memory is not mapped at PHYSICAL_MEMORY_OFFSET");
+    }
+    if !(PHYSICAL_MEMORY_SIZE_MIN..=PHYSICAL_MEMORY_SIZE_MAX)
+        .contains(&boot_info.physical_memory_size)
+    {
+        return Err("mapped physical memory is out of bounds");
+    }
+    if boot_info.kernel_slide >= KERNEL_SLIDE_WINDOW {
+        return Err("kernel slid past KERNEL_SLIDE_WINDOW");
+    }
+    if boot_info.kernel_base.wrapping_sub(boot_info.kernel_slide) != KERNEL_BASE {
+        return Err("kernel is not linked at KERNEL_BASE, check KERNEL_BEGIN in linker.ld");
+    }
+    let x86 = boot_info.x86_64().ok_or("boot info is not for x86_64")?;
+    if x86.smp_trampoline & 0xfff != 0 || x86.smp_trampoline >= SMP_TRAMPOLINE_LIMIT {
+        return Err("SMP trampoline is not a page below 1MiB");
+    }
+    Ok(())
+}
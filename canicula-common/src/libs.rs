@@ -1,6 +1,14 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 pub mod bootloader;
+pub mod cmdline;
 pub mod entry;
+pub mod font;
 pub mod fs;
+pub mod layout;
+pub mod qemu;
+#[cfg(test)]
+mod tests;
+pub mod time;
+pub mod unicode;
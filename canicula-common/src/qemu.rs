@@ -0,0 +1,34 @@
+/// How a test run inside QEMU ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestResult {
+    Success,
+    Failed,
+}
+
+impl TestResult {
+    /// The exit status QEMU reports to the host, the same on every architecture.
+    ///
+    /// x86's `isa-debug-exit` can only produce odd statuses `(code << 1) | 1`, so
+    /// `0` and `1` are not available and both results use codes it can express.
+    pub const fn host_status(self) -> u32 {
+        match self {
+            TestResult::Success => 33,
+            TestResult::Failed => 35,
+        }
+    }
+}
+
+/// An exit device of QEMU, e.g. `isa-debug-exit` on x86 or `sifive_test` on RISC-V.
+pub trait TestExit {
+    /// Stop QEMU so it exits with `result.host_status()`, halting if there is no
+    /// exit device.
+    fn exit(&self, result: TestResult) -> !;
+
+    fn exit_success(&self) -> ! {
+        self.exit(TestResult::Success)
+    }
+
+    fn exit_failure(&self) -> ! {
+        self.exit(TestResult::Failed)
+    }
+}
@@ -0,0 +1,97 @@
+mod test {
+    use crate::time::{parse_utc_offset, DateTime};
+
+    fn date_time(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            utc_offset: 0,
+        }
+    }
+
+    #[test]
+    fn unix_timestamp() {
+        for (timestamp, expected) in [
+            (0, date_time(1970, 1, 1, 0, 0, 0)),
+            (-1, date_time(1969, 12, 31, 23, 59, 59)),
+            (951_782_400, date_time(2000, 2, 29, 0, 0, 0)),
+            (1_700_000_000, date_time(2023, 11, 14, 22, 13, 20)),
+            (1_709_251_199, date_time(2024, 2, 29, 23, 59, 59)),
+            (4_102_444_800, date_time(2100, 1, 1, 0, 0, 0)),
+        ] {
+            let date_time = DateTime::from_unix_timestamp(timestamp);
+            assert_eq!(date_time, expected, "{}", timestamp);
+            assert_eq!(date_time.to_unix_timestamp(), timestamp);
+        }
+    }
+
+    #[test]
+    fn utc_offset() {
+        let utc = DateTime::from_unix_timestamp(1_700_000_000);
+
+        let east = utc.with_utc_offset(8 * 60);
+        assert_eq!(
+            east,
+            DateTime {
+                utc_offset: 8 * 60,
+                ..date_time(2023, 11, 15, 6, 13, 20)
+            }
+        );
+        assert_eq!(east.to_unix_timestamp(), 1_700_000_000);
+
+        let west = utc.with_utc_offset(-(5 * 60 + 30));
+        assert_eq!(west.day, 14);
+        assert_eq!((west.hour, west.minute), (16, 43));
+        assert_eq!(west.to_unix_timestamp(), 1_700_000_000);
+        assert_eq!(west.with_utc_offset(0), utc);
+    }
+
+    #[test]
+    fn display() {
+        let utc = DateTime::from_unix_timestamp(1_700_000_000);
+        assert_eq!(utc.to_string(), "2023-11-14 22:13:20 +00:00");
+        assert_eq!(
+            utc.with_utc_offset(8 * 60).to_string(),
+            "2023-11-15 06:13:20 +08:00"
+        );
+        assert_eq!(
+            utc.with_utc_offset(-(5 * 60 + 30)).to_string(),
+            "2023-11-14 16:43:20 -05:30"
+        );
+    }
+
+    #[test]
+    fn parse_offset() {
+        for (offset, expected) in [
+            ("Z", Some(0)),
+            ("UTC", Some(0)),
+            ("+08:00", Some(480)),
+            (" +09:30 ", Some(570)),
+            ("-0530", Some(-330)),
+            ("+8", Some(480)),
+            ("-12", Some(-720)),
+            ("+14:00", Some(840)),
+            ("-14:00", Some(-840)),
+            ("08:00", None),
+            ("+15:00", None),
+            ("-15:00", None),
+            ("+14:30", None),
+            ("+999", None),
+            ("-99999", None),
+            ("+01:60", None),
+            ("+-05:00", None),
+            ("-05:-30", None),
+            ("--0530", None),
+            ("-+05", None),
+            ("+ab:00", None),
+            ("+", None),
+            ("", None),
+        ] {
+            assert_eq!(parse_utc_offset(offset), expected, "{:?}", offset);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use core::fmt;
+
+const SECONDS_PER_MINUTE: i64 = 60;
+const SECONDS_PER_HOUR: i64 = 60 * SECONDS_PER_MINUTE;
+const SECONDS_PER_DAY: i64 = 24 * SECONDS_PER_HOUR;
+
+/// Wall-clock calendar time, always in the Gregorian calendar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// Offset from UTC in minutes, `0` for UTC itself.
+    pub utc_offset: i16,
+}
+
+impl DateTime {
+    /// Build a `DateTime` from seconds since the Unix epoch (UTC).
+    pub fn from_unix_timestamp(timestamp: i64) -> Self {
+        let days = timestamp.div_euclid(SECONDS_PER_DAY);
+        let seconds = timestamp.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (seconds / SECONDS_PER_HOUR) as u8,
+            minute: (seconds % SECONDS_PER_HOUR / SECONDS_PER_MINUTE) as u8,
+            second: (seconds % SECONDS_PER_MINUTE) as u8,
+            utc_offset: 0,
+        }
+    }
+
+    /// Seconds since the Unix epoch, taking `utc_offset` into account.
+    pub fn to_unix_timestamp(&self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+
+        days * SECONDS_PER_DAY
+            + self.hour as i64 * SECONDS_PER_HOUR
+            + self.minute as i64 * SECONDS_PER_MINUTE
+            + self.second as i64
+            - self.utc_offset as i64 * SECONDS_PER_MINUTE
+    }
+
+    /// The same instant expressed in another timezone.
+    pub fn with_utc_offset(&self, utc_offset: i16) -> Self {
+        let local = self.to_unix_timestamp() + utc_offset as i64 * SECONDS_PER_MINUTE;
+
+        DateTime {
+            utc_offset,
+            ..DateTime::from_unix_timestamp(local)
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )?;
+
+        let sign = if self.utc_offset < 0 { '-' } else { '+' };
+        let offset = self.utc_offset.unsigned_abs();
+        write!(f, " {}{:02}:{:02}", sign, offset / 60, offset % 60)
+    }
+}
+
+/// Parse a timezone such as `+08:00`, `-0530` or `Z` into minutes east of UTC.
+pub fn parse_utc_offset(offset: &str) -> Option<i16> {
+    let offset = offset.trim();
+    if offset == "Z" || offset == "UTC" {
+        return Some(0);
+    }
+
+    let (sign, rest) = match offset.as_bytes().first()? {
+        b'+' => (1, &offset[1..]),
+        b'-' => (-1, &offset[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if rest.len() == 4 => rest.split_at(2),
+        None => (rest, "0"),
+    };
+
+    // `parse` would take a second sign
+    if !hours.bytes().chain(minutes.bytes()).all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i16 = hours.parse().ok()?;
+    let minutes: i16 = minutes.parse().ok()?;
+    // no zone is further than 14 hours from UTC
+    if hours > 14 || minutes > 59 || hours * 60 + minutes > 14 * 60 {
+        return None;
+    }
+
+    Some(sign * (hours * 60 + minutes))
+}
+
+// Algorithms from http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u8;
+    let month = (if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    }) as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
@@ -0,0 +1,121 @@
+pub const REPLACEMENT_CHARACTER: char = '\u{fffd}';
+
+/// Codepoints drawn across two console cells (East Asian Wide and Fullwidth).
+const WIDE: [(u32, u32); 14] = [
+    (0x1100, 0x115f),
+    (0x2e80, 0x303e),
+    (0x3041, 0x33ff),
+    (0x3400, 0x4dbf),
+    (0x4e00, 0x9fff),
+    (0xa000, 0xa4cf),
+    (0xac00, 0xd7a3),
+    (0xf900, 0xfaff),
+    (0xfe30, 0xfe4f),
+    (0xff00, 0xff60),
+    (0xffe0, 0xffe6),
+    (0x1f300, 0x1f64f),
+    (0x1f900, 0x1f9ff),
+    (0x20000, 0x3fffd),
+];
+
+/// Codepoints that take no cell of their own.
+const ZERO_WIDTH: [(u32, u32); 5] = [
+    (0x0300, 0x036f),
+    (0x200b, 0x200f),
+    (0x20d0, 0x20ff),
+    (0xfe00, 0xfe0f),
+    (0xfeff, 0xfeff),
+];
+
+/// Number of console cells `ch` occupies: 0, 1 or 2.
+pub fn char_width(ch: char) -> usize {
+    let ch = ch as u32;
+    let within = |ranges: &[(u32, u32)]| {
+        ranges
+            .iter()
+            .any(|(first, last)| (*first..=*last).contains(&ch))
+    };
+
+    if ch < 0x300 {
+        1
+    } else if within(&ZERO_WIDTH) {
+        0
+    } else if within(&WIDE) {
+        2
+    } else {
+        1
+    }
+}
+
+/// Incremental UTF-8 decoder for byte streams that may split a character across writes.
+///
+/// Malformed input decodes to [`REPLACEMENT_CHARACTER`], one per broken sequence.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Utf8Decoder {
+    code_point: u32,
+    /// Continuation bytes still expected.
+    remaining: u8,
+    /// Length of the sequence being decoded, to reject overlong encodings.
+    length: u8,
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Self {
+        Utf8Decoder {
+            code_point: 0,
+            remaining: 0,
+            length: 0,
+        }
+    }
+
+    pub fn decode(&mut self, bytes: &[u8], mut emit: impl FnMut(char)) {
+        for byte in bytes {
+            self.push(*byte, &mut emit);
+        }
+    }
+
+    pub fn push(&mut self, byte: u8, emit: &mut impl FnMut(char)) {
+        if self.remaining > 0 {
+            if byte & 0xc0 == 0x80 {
+                self.code_point = self.code_point << 6 | (byte & 0x3f) as u32;
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    emit(self.finish());
+                }
+                return;
+            }
+            // the sequence was cut short, the current byte starts afresh
+            self.remaining = 0;
+            emit(REPLACEMENT_CHARACTER);
+        }
+
+        let (code_point, remaining) = match byte {
+            0x00..=0x7f => {
+                emit(byte as char);
+                return;
+            }
+            0xc2..=0xdf => (byte & 0x1f, 1),
+            0xe0..=0xef => (byte & 0x0f, 2),
+            0xf0..=0xf4 => (byte & 0x07, 3),
+            _ => {
+                emit(REPLACEMENT_CHARACTER);
+                return;
+            }
+        };
+        self.code_point = code_point as u32;
+        self.remaining = remaining;
+        self.length = remaining + 1;
+    }
+
+    fn finish(&self) -> char {
+        let minimum = match self.length {
+            2 => 0x80,
+            3 => 0x800,
+            _ => 0x10000,
+        };
+        if self.code_point < minimum {
+            return REPLACEMENT_CHARACTER;
+        }
+        char::from_u32(self.code_point).unwrap_or(REPLACEMENT_CHARACTER)
+    }
+}
@@ -0,0 +1,316 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use log::warn;
+
+/// Read from the root of the boot volume, missing keys keep their built in default.
+pub static CONFIG_PATH: &str = "\\loader.conf";
+
+static DEFAULT_KERNEL_PATH: &str = "\\canicula-kernel";
+/// Build with e.g. `font=\\fonts\\ter-v32n.psf` to use a PSF font on the framebuffer console.
+static DEFAULT_FONT_PATH: Option<&str> = option_env!("font");
+/// Seconds the boot menu waits before starting the default entry.
+static DEFAULT_TIMEOUT: u32 = 5;
+static DEFAULT_BAUD: u32 = 115_200;
+
+/// What the loader does with the kernel file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    /// Map the kernel ELF and jump to it with a `BootInfo`.
+    Canicula,
+    /// Start the kernel file as an EFI application, e.g. a Linux `vmlinuz` with
+    /// the EFI stub, passing the command line as its load options.
+    Efi,
+    /// Start any other EFI application, e.g. another operating system's boot
+    /// manager, with the command line as its load options and no initrd.
+    Chainload,
+}
+
+/// Where the registers of a 16550 compatible UART are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialAddress {
+    /// Legacy I/O ports, `com1` to `com4`.
+    Io(u16),
+    /// Memory mapped registers one byte apart, `mmio:0xADDRESS`.
+    Mmio(u64),
+}
+
+/// The UART the loader logs to once boot services and the firmware console are gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub address: SerialAddress,
+    pub baud: u32,
+}
+
+/// One thing the loader can boot.
+#[derive(Debug, Clone)]
+pub struct BootEntry {
+    pub title: String,
+    pub mode: BootMode,
+    pub kernel: String,
+    /// Loaded in order and concatenated, e.g. CPU microcode in front of an initramfs.
+    pub initrd: Vec<String>,
+    /// Extra files handed to a Canicula kernel as they are, e.g. driver blobs.
+    pub modules: Vec<String>,
+    pub font: Option<String>,
+    /// Graphics mode as `(width, height)`, the largest one available if unset.
+    pub resolution: Option<(usize, usize)>,
+    pub cmdline: String,
+    /// Detached signature of the kernel, `<kernel>.sig` if unset.
+    pub signature: Option<String>,
+    /// Load a relocatable kernel at a random offset from its link address.
+    pub kaslr: bool,
+    /// Measure the files and command line into the TPM, see [`crate::measure`].
+    pub measure: bool,
+}
+
+impl Default for BootEntry {
+    fn default() -> Self {
+        BootEntry {
+            title: "Canicula".to_string(),
+            mode: BootMode::Canicula,
+            kernel: DEFAULT_KERNEL_PATH.to_string(),
+            initrd: Vec::new(),
+            modules: Vec::new(),
+            font: DEFAULT_FONT_PATH.map(ToString::to_string),
+            resolution: None,
+            cmdline: String::new(),
+            signature: None,
+            kaslr: false,
+            measure: false,
+        }
+    }
+}
+
+/// Settings from `\loader.conf`.
+///
+/// The file holds one `key=value` pair per line, `#` starts a comment line.
+/// Each `[title]` line starts a boot entry, entry keys in front of the first one
+/// are shared by all entries, or make up the only entry if there are no titles.
+/// `initrd` and `module` may be repeated to add more files, an empty value drops
+/// the ones set so far. Files are read from the boot volume, or from the first
+/// ext4 partition with an `ext4:` prefix, e.g. `ext4:/boot/canicula-kernel`.
+/// `serial` and `baud` set up a serial console for the loader itself:
+///
+/// ```text
+/// timeout=3
+/// default=Linux
+/// serial=com1
+/// baud=115200
+///
+/// [Canicula]
+/// kernel=\canicula-kernel
+/// resolution=1280x800
+/// module=\drivers\virtio-blk.bin
+///
+/// [Linux]
+/// mode=efi
+/// kernel=\vmlinuz
+/// initrd=\intel-ucode.img
+/// initrd=\initrd.img
+/// cmdline=console=ttyS0 root=/dev/vda1
+///
+/// [Windows]
+/// mode=chainload
+/// kernel=\EFI\Microsoft\Boot\bootmgfw.efi
+/// ```
+#[derive(Debug, Clone)]
+pub struct BootConfig {
+    /// Seconds to wait for a choice, `0` boots the default entry straight away.
+    pub timeout: u32,
+    /// Index into `entries`.
+    pub default: usize,
+    /// Never empty.
+    pub entries: Vec<BootEntry>,
+    /// No serial output if unset.
+    pub serial: Option<SerialConfig>,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        BootConfig {
+            timeout: DEFAULT_TIMEOUT,
+            default: 0,
+            entries: alloc::vec![BootEntry::default()],
+            serial: None,
+        }
+    }
+}
+
+impl BootConfig {
+    /// Apply the settings in `content` over the defaults, bad lines are skipped with a warning.
+    pub fn parse(content: &str) -> Self {
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        let mut timeout = DEFAULT_TIMEOUT;
+        let mut default = None;
+        let mut serial = None;
+        let mut baud = DEFAULT_BAUD;
+        let mut shared = BootEntry::default();
+        let mut entries: Vec<BootEntry> = Vec::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(title) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                entries.push(BootEntry {
+                    title: title.trim().to_string(),
+                    ..shared.clone()
+                });
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                warn!("{}:{}: expected key=value", CONFIG_PATH, number + 1);
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+
+            match key {
+                "timeout" => match value.parse() {
+                    Ok(seconds) => timeout = seconds,
+                    Err(_) => warn!("{}:{}: invalid timeout {}", CONFIG_PATH, number + 1, value),
+                },
+                "default" => default = Some(value.to_string()),
+                "serial" => match serial_address(value) {
+                    Ok(address) => serial = address,
+                    Err(message) => {
+                        warn!("{}:{}: {} {}", CONFIG_PATH, number + 1, message, value)
+                    }
+                },
+                "baud" => match value.parse() {
+                    Ok(rate @ 1..) => baud = rate,
+                    _ => warn!(
+                        "{}:{}: invalid baud rate {}",
+                        CONFIG_PATH,
+                        number + 1,
+                        value
+                    ),
+                },
+                _ => {
+                    let entry = entries.last_mut().unwrap_or(&mut shared);
+                    if let Err(message) = entry.set(key, value) {
+                        warn!(
+                            "{}:{}: {} {}={}",
+                            CONFIG_PATH,
+                            number + 1,
+                            message,
+                            key,
+                            value
+                        );
+                    }
+                }
+            }
+        }
+
+        if entries.is_empty() {
+            entries.push(shared);
+        }
+        let default = match default {
+            None => 0,
+            Some(default) => find_entry(&entries, &default).unwrap_or_else(|| {
+                warn!(
+                    "{}: no entry {}, booting the first one",
+                    CONFIG_PATH, default
+                );
+                0
+            }),
+        };
+        BootConfig {
+            timeout,
+            default,
+            entries,
+            serial: serial.map(|address| SerialConfig { address, baud }),
+        }
+    }
+}
+
+impl BootEntry {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        match key {
+            "mode" => match value {
+                "canicula" => self.mode = BootMode::Canicula,
+                "efi" => self.mode = BootMode::Efi,
+                "chainload" => self.mode = BootMode::Chainload,
+                _ => return Err("unknown mode"),
+            },
+            "kernel" => match value {
+                "" => return Err("empty kernel path"),
+                _ => self.kernel = value.to_string(),
+            },
+            "initrd" => match value {
+                "" => self.initrd.clear(),
+                _ => self.initrd.push(value.to_string()),
+            },
+            "module" => match value {
+                "" => self.modules.clear(),
+                _ => self.modules.push(value.to_string()),
+            },
+            "font" => self.font = optional(value),
+            "resolution" => match value {
+                "" => self.resolution = None,
+                _ => {
+                    let (width, height) =
+                        value.split_once('x').ok_or("expected WIDTHxHEIGHT for")?;
+                    match (width.trim().parse(), height.trim().parse()) {
+                        (Ok(width), Ok(height)) => self.resolution = Some((width, height)),
+                        _ => return Err("expected WIDTHxHEIGHT for"),
+                    }
+                }
+            },
+            "cmdline" => self.cmdline = value.to_string(),
+            "signature" => self.signature = optional(value),
+            "kaslr" => match value {
+                "on" | "true" => self.kaslr = true,
+                "off" | "false" => self.kaslr = false,
+                _ => return Err("expected on or off for"),
+            },
+            "measure" => match value {
+                "on" | "true" => self.measure = true,
+                "off" | "false" => self.measure = false,
+                _ => return Err("expected on or off for"),
+            },
+            _ => return Err("unknown key"),
+        }
+        Ok(())
+    }
+}
+
+/// An entry by title, or by its number counting from 1 as shown in the menu.
+fn find_entry(entries: &[BootEntry], name: &str) -> Option<usize> {
+    if let Some(index) = entries.iter().position(|entry| entry.title == name) {
+        return Some(index);
+    }
+    match name.parse::<usize>() {
+        Ok(number @ 1..) if number <= entries.len() => Some(number - 1),
+        _ => None,
+    }
+}
+
+/// `com1` to `com4` or `mmio:0xADDRESS`, `off` or an empty value for none.
+fn serial_address(value: &str) -> Result<Option<SerialAddress>, &'static str> {
+    let address = match value {
+        "" | "off" => return Ok(None),
+        "com1" => SerialAddress::Io(0x3f8),
+        "com2" => SerialAddress::Io(0x2f8),
+        "com3" => SerialAddress::Io(0x3e8),
+        "com4" => SerialAddress::Io(0x2e8),
+        _ => {
+            let address = value
+                .strip_prefix("mmio:")
+                .and_then(|address| address.strip_prefix("0x"))
+                .and_then(|address| u64::from_str_radix(address, 16).ok())
+                .ok_or("expected com1 to com4 or mmio:0xADDRESS, not")?;
+            SerialAddress::Mmio(address)
+        }
+    };
+    Ok(Some(address))
+}
+
+/// An empty value switches a setting off.
+fn optional(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
@@ -3,15 +3,53 @@
 
 extern crate alloc;
 
-use log::{debug, info};
-use uefi::boot::{AllocateType, MemoryType};
-use uefi::proto::console::gop::GraphicsOutput;
+mod boot_config;
+mod ext4_volume;
+mod initrd;
+mod kaslr;
+mod measure;
+mod memory_map;
+mod menu;
+mod relocation;
+mod serial;
+mod signature;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use boot_config::{BootConfig, BootEntry, BootMode, SerialConfig, CONFIG_PATH};
+use canicula_common::entry::{
+    Arch, BootInfo, BootModule, BootModules, FirmwareTables, FrameBufferInfo, KernelRelocation,
+    MemoryAttributesTable, MemoryKind, MemoryMap, MemoryMapEntry, MemoryRegion, PixelFormat,
+    PixelMasks, RelocationStatus, X86Info, BOOT_INFO_MAGIC, BOOT_INFO_VERSION,
+    SMP_TRAMPOLINE_LIMIT,
+};
+use canicula_common::layout::{
+    self, KERNEL_STACK_ADDRESS, KERNEL_STACK_SIZE, KERNEL_STACK_TOP, PHYSICAL_MEMORY_OFFSET,
+    PHYSICAL_MEMORY_SIZE_MAX, PHYSICAL_MEMORY_SIZE_MIN,
+};
+use core::fmt::Write;
+use core::ptr::NonNull;
+use ext4_volume::{Ext4Volume, EXT4_PREFIX};
+use initrd::InitrdServer;
+use log::{debug, error, info, warn};
+use measure::Tpm;
+use serial::SerialPort;
+use uefi::boot::{
+    AllocateType, MemoryType, OpenProtocolAttributes, OpenProtocolParams, SearchType,
+};
+use uefi::mem::memory_map::{MemoryDescriptor, MemoryMap as _};
+use uefi::proto::console::gop::{self, GraphicsOutput};
+use uefi::proto::device_path::build::{self, DevicePathBuilder};
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::loaded_image::LoadedImage;
 use uefi::proto::media::file::File;
-use uefi::proto::media::file::{FileAttribute, FileInfo, FileMode, FileType};
+use uefi::proto::media::file::{
+    Directory, FileAttribute, FileInfo, FileMode, FileType, RegularFile,
+};
 use uefi::proto::media::fs::SimpleFileSystem;
-use uefi::{prelude::*, CStr16};
+use uefi::{guid, prelude::*, CStr16, CString16, Guid, Handle, Identify};
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Efer, EferFlags};
-use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
 use x86_64::structures::paging::{
     FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
     Size2MiB, Size4KiB,
@@ -19,12 +57,18 @@ use x86_64::structures::paging::{
 use x86_64::{align_up, PhysAddr, VirtAddr};
 use xmas_elf::{program, ElfFile};
 
-static KERNEL_PATH: &str = "\\canicula-kernel";
-static KERNEL_STACK_ADDRESS: u64 = 0xFFFF_FF01_0000_0000;
-static KERNEL_STACK_SIZE: u64 = 512;
-static PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFF_8000_0000_0000;
 static FILE_BUFFER_SIZE: usize = 0x400;
+static MEMORY_ATTRIBUTES_TABLE_GUID: Guid = guid!("dcfa911d-26eb-469f-a220-38b7dc461220");
+static ACPI_TABLE_GUID: Guid = guid!("eb9d2d30-2d88-11d3-9a16-0090273fc14d");
+static ACPI2_TABLE_GUID: Guid = guid!("8868e871-e4f1-11d3-bc22-0080c73c8881");
+static SMBIOS_TABLE_GUID: Guid = guid!("eb9d2d31-2d88-11d3-9a16-0090273fc14d");
+static SMBIOS3_TABLE_GUID: Guid = guid!("f2fd1544-9794-4a2c-992e-e5bbcf20e394");
+static DEVICE_TREE_GUID: Guid = guid!("b1b621d5-f19c-41a5-830b-d9152c69aae0");
 static PAGE_SIZE: usize = 0x1000;
+/// Entries the memory map may grow by between sizing it and exiting boot services.
+static MEMORY_MAP_SLACK: usize = 32;
+/// Every file of a concatenated initrd starts at a multiple of this.
+static INITRD_ALIGN: u64 = 4;
 
 struct UEFIFrameAllocator();
 
@@ -41,19 +85,15 @@ unsafe impl FrameAllocator<Size4KiB> for UEFIFrameAllocator {
     }
 }
 
-struct GraphicInfo {
-    frame_buffer_addr: u64,
-    frame_buffer_size: u64,
-}
-
 #[entry]
 fn main() -> Status {
     uefi::helpers::init().unwrap();
     info!("bootloader is running");
 
     // load simple file system protocol
-    let simple_file_system_handle = uefi::boot::get_handle_for_protocol::<SimpleFileSystem>()
-        .expect("Cannot get protocol handle");
+    let Some(simple_file_system_handle) = boot_volume() else {
+        return Status::NOT_FOUND;
+    };
 
     let mut simple_file_system_protocol =
         uefi::boot::open_protocol_exclusive::<SimpleFileSystem>(simple_file_system_handle)
@@ -64,55 +104,256 @@ fn main() -> Status {
         .open_volume()
         .expect("Cannot open volume");
 
-    // open kernel file in the root using simple file system
-    let mut kernel_path_buffer = [0u16; FILE_BUFFER_SIZE];
-    let kernel_path = CStr16::from_str_with_buf(KERNEL_PATH, &mut kernel_path_buffer)
-        .expect("Invalid kernel path!");
-    let kernel_file_handle = root
-        .open(kernel_path, FileMode::Read, FileAttribute::empty())
-        .expect("Cannot open kernel file");
-    let mut kernel_file = match kernel_file_handle.into_type().unwrap() {
-        FileType::Regular(f) => f,
-        _ => panic!("This file does not exist!"),
-    };
-    info!("Kernel file opened successfully!");
-
-    // load kernel file info and size
-    let mut kernel_file_info_buffer = [0u8; FILE_BUFFER_SIZE];
-    let kernel_file_info: &mut FileInfo = kernel_file
-        .get_info(&mut kernel_file_info_buffer)
-        .expect("Cannot get file info");
-    info!("Kernel file info: {:?}", kernel_file_info);
-    let kernel_file_size =
-        usize::try_from(kernel_file_info.file_size()).expect("Invalid file size!");
-    info!("Kernel file size: {:?}", kernel_file_size);
-
-    // load kernel file into memory
-    let mut kernel_file_address = uefi::boot::allocate_pages(
-        AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        kernel_file_size / PAGE_SIZE + 1,
+    let config = load_config(&mut root);
+    info!("boot config: {:?}", config);
+    let entry = menu::choose(&config);
+    info!("booting {}", entry.title);
+    match entry.mode {
+        BootMode::Canicula => boot_canicula(root, entry, config.serial),
+        BootMode::Efi | BootMode::Chainload => {
+            start_efi_image(root, simple_file_system_handle, entry)
+        }
+    }
+}
+
+/// Load `\loader.conf`, or fall back to the built in defaults without one.
+fn load_config(root: &mut Directory) -> BootConfig {
+    let Some(file) = open_file(root, CONFIG_PATH) else {
+        info!("no {}, using the default configuration", CONFIG_PATH);
+        return BootConfig::default();
+    };
+    let Some(region) = read_file(file, CONFIG_PATH) else {
+        return BootConfig::default();
+    };
+
+    let content =
+        unsafe { core::slice::from_raw_parts(region.address as *const u8, region.size as usize) };
+    match core::str::from_utf8(content) {
+        Ok(content) => BootConfig::parse(content),
+        Err(_) => {
+            warn!(
+                "{} is not valid UTF-8, using the default configuration",
+                CONFIG_PATH
+            );
+            BootConfig::default()
+        }
+    }
+}
+
+/// The volume the loader itself was read from, where its configuration and kernels live.
+///
+/// With several file systems present any other one could hold a different
+/// `\loader.conf`, so only a single file system is used as a fallback when the
+/// loader's own device does not carry one.
+fn boot_volume() -> Option<Handle> {
+    let handles = match boot::locate_handle_buffer(SearchType::ByProtocol(&SimpleFileSystem::GUID))
+    {
+        Ok(handles) => handles,
+        Err(_) => {
+            error!("no file system to load the kernel from");
+            return None;
+        }
+    };
+    let device = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())
+        .ok()
+        .and_then(|image| image.device());
+
+    match device {
+        Some(device) if handles.contains(&device) => Some(device),
+        _ if handles.len() == 1 => {
+            warn!("the loader's device has no file system, using the only one found");
+            Some(handles[0])
+        }
+        _ => {
+            error!(
+                "cannot tell which of {} file systems the loader was started from",
+                handles.len()
+            );
+            None
+        }
+    }
+}
+
+/// Start the kernel file as an EFI application, returning if it exits.
+///
+/// `volume` is the device the file was read from, the image finds its own
+/// files relative to it, e.g. the Windows boot manager its BCD store.
+fn start_efi_image(mut root: Directory, volume: Handle, entry: &BootEntry) -> Status {
+    let image = load_file(&mut root, &entry.kernel).expect("Cannot load kernel file");
+    let buffer =
+        unsafe { core::slice::from_raw_parts(image.address as *const u8, image.size as usize) };
+    if let Err(message) = check_signature(&mut root, entry, buffer) {
+        error!("refusing to start {}: {}", entry.kernel, message);
+        return Status::SECURITY_VIOLATION;
+    }
+    // the firmware cannot resolve files on an ext4 partition, they go without a path
+    let mut device_path = Vec::new();
+    let file_path = if entry.kernel.starts_with(EXT4_PREFIX) {
+        None
+    } else {
+        let file_path = file_device_path(volume, &entry.kernel, &mut device_path);
+        if file_path.is_none() {
+            warn!("cannot build a device path for {}", entry.kernel);
+        }
+        file_path
+    };
+    let handle = boot::load_image(
+        boot::image_handle(),
+        boot::LoadImageSource::FromBuffer { buffer, file_path },
     )
-    .expect("Cannot allocate memory in the RAM!");
+    .expect("Not a valid EFI image");
 
-    let kernel_file_address = unsafe { kernel_file_address.as_mut() as *mut u8 };
+    let cmdline = &entry.cmdline;
+    let options = CString16::try_from(cmdline.as_str()).expect("Invalid command line");
+    {
+        let mut loaded_image = boot::open_protocol_exclusive::<LoadedImage>(handle)
+            .expect("Cannot open the loaded image protocol");
+        unsafe {
+            loaded_image.set_load_options(options.as_ptr() as *const u8, options.num_bytes() as u32)
+        };
+    }
 
-    let kernel_file_in_memory = unsafe {
-        core::ptr::write_bytes(kernel_file_address, 0, kernel_file_size);
-        core::slice::from_raw_parts_mut(kernel_file_address, kernel_file_size)
+    // the Linux EFI stub asks for its initrd through LoadFile2, the server has
+    // to stay installed until the image returns
+    let initrd = match entry.mode {
+        BootMode::Efi => load_initrd(&mut root, &entry.initrd),
+        _ => MemoryRegion::default(),
+    };
+    if entry.measure {
+        if let Some(mut tpm) = Tpm::open() {
+            tpm.measure_region(&entry.initrd.join(" "), initrd);
+            tpm.measure_cmdline(cmdline);
+        }
+    }
+    let _initrd_server = if initrd.is_empty() {
+        None
+    } else {
+        match InitrdServer::install(initrd) {
+            Ok(server) => Some(server),
+            Err(error) => {
+                warn!("cannot serve the initrd: {:?}", error.status());
+                None
+            }
+        }
     };
-    let kernel_file_size = kernel_file
-        .read(kernel_file_in_memory)
-        .expect("Cannot read file into the memory!");
-    info!("Kernel file loaded into memory successfully!");
 
-    let kernel_content = &mut kernel_file_in_memory[..kernel_file_size];
+    info!("starting {} with \"{}\"", entry.kernel, cmdline);
+    match boot::start_image(handle) {
+        Ok(()) => Status::SUCCESS,
+        Err(error) => error.status(),
+    }
+}
+
+/// The device path of `volume` followed by a file path node for `path`, built in `storage`.
+fn file_device_path<'a>(
+    volume: Handle,
+    path: &str,
+    storage: &'a mut Vec<u8>,
+) -> Option<&'a DevicePath> {
+    let volume_path = unsafe {
+        boot::open_protocol::<DevicePath>(
+            OpenProtocolParams {
+                handle: volume,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .ok()?;
+    let path_name = CString16::try_from(path).ok()?;
+
+    let mut builder = DevicePathBuilder::with_vec(storage);
+    for node in volume_path.node_iter() {
+        builder = builder.push(&node).ok()?;
+    }
+    builder
+        .push(&build::media::FilePath {
+            path_name: &path_name,
+        })
+        .ok()?
+        .finalize()
+        .ok()
+}
+
+/// Map the canicula kernel ELF and jump to it, never returns.
+fn boot_canicula(mut root: Directory, entry: &BootEntry, serial: Option<SerialConfig>) -> Status {
+    // load kernel file into memory, from the boot volume or an ext4 partition
+    let kernel_file = load_file(&mut root, &entry.kernel).expect("Cannot load kernel file");
+    let kernel_file_size = kernel_file.size as usize;
+    let kernel_file_pages = kernel_file_size / PAGE_SIZE + 1;
+    let kernel_content = unsafe {
+        core::slice::from_raw_parts_mut(kernel_file.address as *mut u8, kernel_file_size)
+    };
+    if let Err(message) = check_signature(&mut root, entry, kernel_content) {
+        error!("refusing to boot {}: {}", entry.kernel, message);
+        return Status::SECURITY_VIOLATION;
+    }
+    // the file as read, before relocation patches it
+    let mut tpm = if entry.measure { Tpm::open() } else { None };
+    if let Some(tpm) = tpm.as_mut() {
+        tpm.measure_file(&entry.kernel, kernel_content);
+    }
     let kernel_address = kernel_content.as_ptr() as *const u8 as usize;
     info!("Kernel file address: 0x{:x}", kernel_address);
+    let kernel_image = MemoryRegion {
+        address: kernel_address as u64,
+        size: (kernel_file_pages * PAGE_SIZE) as u64,
+    };
+
+    // pick a base and patch the file before anything of it is mapped
+    let (kernel_slide, kernel_relocation) = {
+        let kernel_elf = ElfFile::new(kernel_content).expect("Not a valid ELF file.");
+        let link_base = relocation::link_base(&kernel_elf);
+        let link_end = relocation::link_end(&kernel_elf);
+        if let Err(message) = relocation::validate(&kernel_elf)
+            .and_then(|()| layout::check_kernel(link_base, link_end))
+        {
+            error!("refusing to boot {}: {}", entry.kernel, message);
+            return Status::LOAD_ERROR;
+        }
+        let relocatable = relocation::is_relocatable(&kernel_elf);
+        let slide = match (entry.kaslr, relocatable) {
+            (true, true) => kaslr::random_slide(),
+            (true, false) => {
+                warn!(
+                    "{} is not relocatable, loading it at its link address",
+                    entry.kernel
+                );
+                0
+            }
+            (false, _) => 0,
+        };
+        let relocations = if relocatable {
+            match relocation::relocations(&kernel_elf, slide) {
+                Ok(relocations) => relocations,
+                Err(message) => {
+                    error!("cannot relocate {}: {}", entry.kernel, message);
+                    return Status::LOAD_ERROR;
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        let kernel_relocation = KernelRelocation {
+            status: if relocatable {
+                RelocationStatus::Relocated
+            } else {
+                RelocationStatus::Fixed
+            },
+            count: relocations.len() as u32,
+        };
+        for (offset, value) in relocations {
+            kernel_content[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        (slide, kernel_relocation)
+    };
+    info!("kernel slide: {:#x}, {:?}", kernel_slide, kernel_relocation);
 
     // parsing kernel elf
     let kernel_elf = ElfFile::new(kernel_content).expect("Not a valid ELF file.");
-    let kernel_entry_point = kernel_elf.header.pt2.entry_point() as usize;
+    let kernel_entry_point = (kernel_elf.header.pt2.entry_point() + kernel_slide) as usize;
+    let kernel_base = relocation::link_base(&kernel_elf) + kernel_slide;
 
     info!("elf file: {:?}", kernel_entry_point);
 
@@ -144,27 +385,37 @@ fn main() -> Status {
             map_segment(
                 &segment,
                 kernel_start,
+                kernel_slide,
                 &mut page_table,
                 &mut UEFIFrameAllocator(),
             )
             .expect("failed to map segment");
         }
+        if let Some(relro) = relocation::relro(&kernel_elf) {
+            protect_relro(
+                relro.start + kernel_slide,
+                relro.end + kernel_slide,
+                &mut page_table,
+            )
+            .expect("failed to protect relro");
+        }
     }
 
     {
         map_stack(
             KERNEL_STACK_ADDRESS,
-            KERNEL_STACK_SIZE,
+            KERNEL_STACK_SIZE / PAGE_SIZE as u64,
             &mut page_table,
             &mut UEFIFrameAllocator(),
         )
         .expect("failed to map stack");
     }
 
+    let physical_memory_size = physical_memory_size();
     {
         map_physical_memory(
             PHYSICAL_MEMORY_OFFSET,
-            0x1_0000_0000,
+            physical_memory_size,
             &mut page_table,
             &mut UEFIFrameAllocator(),
         );
@@ -182,23 +433,629 @@ fn main() -> Status {
     let mut gop = uefi::boot::open_protocol_exclusive::<GraphicsOutput>(gop_handler)
         .expect("failed to open GraphicsOutput");
 
-    let graphic_info = GraphicInfo {
-        frame_buffer_addr: gop.frame_buffer().as_mut_ptr() as u64,
-        frame_buffer_size: gop.frame_buffer().size() as u64,
+    set_graphics_mode(&mut gop, entry.resolution);
+    let mode_info = gop.current_mode_info();
+    let (width, height) = mode_info.resolution();
+    let (pixel_format, masks) = match mode_info.pixel_format() {
+        gop::PixelFormat::Rgb => (PixelFormat::Rgb, PixelMasks::RGB),
+        gop::PixelFormat::Bgr => (PixelFormat::Bgr, PixelMasks::BGR),
+        gop::PixelFormat::Bitmask => match mode_info.pixel_bitmask() {
+            Some(bitmask) => (
+                PixelFormat::Bitmask,
+                PixelMasks {
+                    red: bitmask.red,
+                    green: bitmask.green,
+                    blue: bitmask.blue,
+                },
+            ),
+            None => (PixelFormat::Unknown, PixelMasks::default()),
+        },
+        _ => (PixelFormat::Unknown, PixelMasks::default()),
+    };
+    let framebuffer_size = gop.frame_buffer().size() as u64;
+    let framebuffer = FrameBufferInfo {
+        address: gop.frame_buffer().as_mut_ptr() as u64,
+        size: framebuffer_size,
+        width: width as u32,
+        height: height as u32,
+        stride: mode_info.stride() as u32,
+        pixel_format,
+        masks,
+        reserved: 0,
+        back_buffer: allocate_back_buffer(framebuffer_size),
+    };
+    info!("framebuffer: {:?}", framebuffer);
+
+    let mut boot_info = BootInfo {
+        magic: BOOT_INFO_MAGIC,
+        version: BOOT_INFO_VERSION,
+        checksum: 0,
+        arch: Arch::X86_64,
+        reserved: 0,
+        boot_cpu: boot_cpu(),
+        framebuffer,
+        font: load_optional_file(&mut root, entry.font.as_deref()),
+        physical_memory_offset: PHYSICAL_MEMORY_OFFSET,
+        physical_memory_size,
+        memory_attributes: load_memory_attributes(),
+        memory_map: MemoryMap::default(),
+        firmware: find_firmware_tables(),
+        kernel_slide,
+        kernel_base,
+        kernel_relocation,
+        initrd: load_initrd(&mut root, &entry.initrd),
+        cmdline: copy_to_loader_memory(entry.cmdline.as_bytes()),
+        modules: load_modules(&mut root, &entry.modules),
+        arch_info: X86Info {
+            smp_trampoline: allocate_smp_trampoline(),
+        }
+        .into(),
     };
+    if let Some(mut tpm) = tpm {
+        tpm.measure_region(&entry.initrd.join(" "), boot_info.initrd);
+        for module in unsafe { boot_info.modules.iter() } {
+            tpm.measure_region(unsafe { module.path() }, module.content);
+        }
+        tpm.measure_cmdline(&entry.cmdline);
+    }
+
+    // the map is built again after exit, where nothing can be allocated and only
+    // the serial console is left to log to
+    let reserved = reserved_ranges(kernel_image, &boot_info);
+    let max_address = max_physical_address();
+    let map = allocate_memory_map(reserved.len());
+    let firmware_map =
+        uefi::boot::memory_map(MemoryType::LOADER_DATA).expect("failed to get memory map");
+    let (count, report) = memory_map::build(
+        firmware_map.entries().map(memory_map_entry),
+        &reserved,
+        max_address,
+        map,
+    );
+    drop(firmware_map);
+    info!("memory map: {} entries, {:?}", count, report);
+    if report.overlaps > 0 || report.above_limit > 0 {
+        warn!(
+            "firmware memory map has {} overlapping regions and {} above {:#x}",
+            report.overlaps, report.above_limit, max_address
+        );
+    }
 
     // exit boot services
     info!("exit boot services");
-    let _memory_map;
+    let firmware_map;
     unsafe {
-        _memory_map = uefi::boot::exit_boot_services(MemoryType::BOOT_SERVICES_DATA);
+        firmware_map = uefi::boot::exit_boot_services(MemoryType::BOOT_SERVICES_DATA);
+    }
+    let (count, report) = memory_map::build(
+        firmware_map.entries().map(memory_map_entry),
+        &reserved,
+        max_address,
+        map,
+    );
+    boot_info.memory_map = MemoryMap {
+        address: map.as_ptr() as u64,
+        count: count as u64,
+    };
+    boot_info.seal();
+    if let Some(serial) = serial {
+        let mut serial = SerialPort::init(serial);
+        let _ = writeln!(serial, "memory map: {} entries, {:?}", count, report);
+        let _ = writeln!(serial, "jumping to the kernel at {:#x}", kernel_entry_point);
     }
 
     unsafe {
-        core::arch::asm!("mov rsp, {stack}", stack = in(reg) KERNEL_STACK_ADDRESS);
-        core::arch::asm!("mov rbp, rsp");
-        core::arch::asm!("mov rdi, {graphic_info}", graphic_info = in(reg) &graphic_info);
-        core::arch::asm!("jmp {kernel}", kernel = in(reg) kernel_entry_point, options(noreturn));
+        core::arch::asm!(
+            "mov rsp, {stack}",
+            "mov rbp, rsp",
+            "jmp {kernel}",
+            stack = in(reg) KERNEL_STACK_TOP,
+            kernel = in(reg) kernel_entry_point,
+            in("rdi") &boot_info as *const BootInfo,
+            options(noreturn)
+        );
+    }
+}
+
+/// Ranges the kernel must not allocate from although the firmware may call them free.
+///
+/// Loader allocations are `LOADER_DATA` and marked by their type already, but the
+/// firmware's page tables and the loader stack holding `boot_info` are not.
+fn reserved_ranges(kernel_image: MemoryRegion, boot_info: &BootInfo) -> Vec<MemoryMapEntry> {
+    let page_tables = page_table_frames();
+    let mut reserved: Vec<MemoryMapEntry> =
+        memory_map::frame_ranges(&page_tables, PAGE_SIZE as u64, MemoryKind::Bootloader).collect();
+    reserved.push(page_range(
+        kernel_image.address,
+        kernel_image.size,
+        MemoryKind::Bootloader,
+    ));
+    reserved.push(page_range(
+        boot_info as *const BootInfo as u64,
+        core::mem::size_of::<BootInfo>() as u64,
+        MemoryKind::Bootloader,
+    ));
+    reserved.push(page_range(
+        boot_info.framebuffer.address,
+        boot_info.framebuffer.size,
+        MemoryKind::Reserved,
+    ));
+    reserved
+}
+
+/// The pages covering `size` bytes at `address`.
+fn page_range(address: u64, size: u64, kind: MemoryKind) -> MemoryMapEntry {
+    let start = address & !(PAGE_SIZE as u64 - 1);
+    let end = align_up(address + size, PAGE_SIZE as u64);
+    MemoryMapEntry {
+        region: MemoryRegion {
+            address: start,
+            size: end - start,
+        },
+        kind,
+    }
+}
+
+/// Frames of every page table reachable from CR3, sorted.
+fn page_table_frames() -> Vec<u64> {
+    fn walk(table: u64, level: u8, frames: &mut Vec<u64>) {
+        frames.push(table);
+        if level == 1 {
+            return;
+        }
+        // the firmware identity maps all memory
+        let table = unsafe { &*(table as *const PageTable) };
+        for entry in table.iter() {
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                walk(entry.addr().as_u64(), level - 1, frames);
+            }
+        }
+    }
+
+    let mut frames = Vec::new();
+    walk(Cr3::read().0.start_address().as_u64(), 4, &mut frames);
+    frames.sort_unstable();
+    frames.dedup();
+    frames
+}
+
+/// Room for the final memory map, allocated while boot services still run.
+///
+/// Exiting boot services can only add a few entries to the current map, every
+/// reserved range splits at most one entry in two.
+fn allocate_memory_map(reserved: usize) -> &'static mut [MemoryMapEntry] {
+    let entries = uefi::boot::memory_map(MemoryType::LOADER_DATA)
+        .expect("failed to get memory map")
+        .len();
+    let capacity = entries + MEMORY_MAP_SLACK + 2 * reserved;
+    let size = capacity * core::mem::size_of::<MemoryMapEntry>();
+    let address = uefi::boot::allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        size.div_ceil(PAGE_SIZE),
+    )
+    .expect("failed to allocate the memory map");
+    unsafe { core::slice::from_raw_parts_mut(address.as_ptr() as *mut MemoryMapEntry, capacity) }
+}
+
+fn memory_map_entry(descriptor: &MemoryDescriptor) -> MemoryMapEntry {
+    let kind = match descriptor.ty {
+        MemoryType::CONVENTIONAL
+        | MemoryType::BOOT_SERVICES_CODE
+        | MemoryType::BOOT_SERVICES_DATA => MemoryKind::Usable,
+        MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => MemoryKind::Bootloader,
+        MemoryType::ACPI_RECLAIM => MemoryKind::AcpiReclaimable,
+        MemoryType::ACPI_NON_VOLATILE => MemoryKind::AcpiNvs,
+        MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA => {
+            MemoryKind::RuntimeServices
+        }
+        _ => MemoryKind::Reserved,
+    };
+    MemoryMapEntry {
+        region: MemoryRegion {
+            address: descriptor.phys_start,
+            size: descriptor.page_count * PAGE_SIZE as u64,
+        },
+        kind,
+    }
+}
+
+/// Bytes of physical memory to map at [`PHYSICAL_MEMORY_OFFSET`], everything the
+/// firmware's memory map covers in whole 2MiB pages.
+fn physical_memory_size() -> u64 {
+    let highest = uefi::boot::memory_map(MemoryType::LOADER_DATA)
+        .map(|map| {
+            map.entries()
+                .map(|descriptor| memory_map_entry(descriptor).end())
+                .max()
+                .unwrap_or(0)
+        })
+        .unwrap_or_else(|_| {
+            warn!("no memory map, mapping the low 4GiB only");
+            0
+        });
+    let limit = max_physical_address().min(PHYSICAL_MEMORY_SIZE_MAX);
+    let size = align_up(highest, Size2MiB::SIZE)
+        .max(PHYSICAL_MEMORY_SIZE_MIN)
+        .min(limit);
+    info!("physical memory: {:#x} bytes mapped", size);
+    size
+}
+
+/// One past the highest physical address the CPU can reach.
+fn max_physical_address() -> u64 {
+    use core::arch::x86_64::__cpuid;
+
+    let bits = unsafe {
+        if __cpuid(0x8000_0000).eax >= 0x8000_0008 {
+            __cpuid(0x8000_0008).eax & 0xff
+        } else {
+            36
+        }
+    };
+    1 << bits
+}
+
+/// Check `kernel` against its detached signature if the loader was built with a public key.
+fn check_signature(
+    root: &mut Directory,
+    entry: &BootEntry,
+    kernel: &[u8],
+) -> Result<(), &'static str> {
+    let Some(key) = signature::public_key()? else {
+        warn!("no public key built in, {} is not verified", entry.kernel);
+        return Ok(());
+    };
+
+    let path = entry
+        .signature
+        .clone()
+        .unwrap_or_else(|| alloc::format!("{}.sig", entry.kernel));
+    let region = load_file(root, &path).ok_or("cannot read the signature file")?;
+    let signature =
+        unsafe { core::slice::from_raw_parts(region.address as *const u8, region.size as usize) };
+
+    signature::verify(&key, kernel, signature)?;
+    info!("{} verified against {}", entry.kernel, path);
+    Ok(())
+}
+
+/// Copy the entries of the EFI memory attributes table into loader memory.
+///
+/// Firmware may allocate the table from boot services memory, which is fair game
+/// once boot services have exited.
+fn load_memory_attributes() -> MemoryAttributesTable {
+    let table = uefi::system::with_config_table(|entries| {
+        entries
+            .iter()
+            .find(|entry| entry.guid == MEMORY_ATTRIBUTES_TABLE_GUID)
+            .map(|entry| entry.address as *const u32)
+    });
+    let Some(table) = table else {
+        warn!("no EFI memory attributes table, runtime regions stay RWX");
+        return MemoryAttributesTable::default();
+    };
+
+    // version, number of entries, descriptor size, reserved
+    let (version, entry_count, descriptor_size) = unsafe { (*table, *table.add(1), *table.add(2)) };
+    if version < 1 || entry_count == 0 {
+        return MemoryAttributesTable::default();
+    }
+
+    let size = entry_count as usize * descriptor_size as usize;
+    let Ok(mut address) = uefi::boot::allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        size / PAGE_SIZE + 1,
+    ) else {
+        warn!("cannot allocate memory for the memory attributes table");
+        return MemoryAttributesTable::default();
+    };
+    let address = unsafe { address.as_mut() as *mut u8 };
+    unsafe { core::ptr::copy_nonoverlapping(table.add(4) as *const u8, address, size) };
+
+    info!("memory attributes table: {} entries", entry_count);
+    MemoryAttributesTable {
+        address: address as u64,
+        entry_count,
+        descriptor_size,
+    }
+}
+
+/// Switch to the configured resolution, or to the largest mode with a linear framebuffer.
+fn set_graphics_mode(gop: &mut GraphicsOutput, resolution: Option<(usize, usize)>) {
+    let modes: Vec<gop::Mode> = gop
+        .modes()
+        .filter(|mode| mode.info().pixel_format() != gop::PixelFormat::BltOnly)
+        .collect();
+    let configured = resolution.and_then(|resolution| {
+        let mode = modes
+            .iter()
+            .find(|mode| mode.info().resolution() == resolution);
+        if mode.is_none() {
+            warn!(
+                "no {}x{} graphics mode, picking the largest one",
+                resolution.0, resolution.1
+            );
+        }
+        mode
+    });
+    let Some(mode) = configured.or_else(|| {
+        modes.iter().max_by_key(|mode| {
+            let (width, height) = mode.info().resolution();
+            width * height
+        })
+    }) else {
+        warn!("no graphics mode with a linear framebuffer");
+        return;
+    };
+
+    let (current, (width, height)) = (gop.current_mode_info(), mode.info().resolution());
+    if current.resolution() == (width, height)
+        && current.pixel_format() == mode.info().pixel_format()
+    {
+        return;
+    }
+    match gop.set_mode(mode) {
+        Ok(()) => info!("graphics mode {}x{}", width, height),
+        Err(error) => warn!(
+            "cannot switch to {}x{}: {:?}",
+            width,
+            height,
+            error.status()
+        ),
+    }
+}
+
+/// Pointers the kernel needs to find ACPI, SMBIOS and the EFI runtime services.
+fn find_firmware_tables() -> FirmwareTables {
+    let table = |guid: Guid| {
+        uefi::system::with_config_table(|entries| {
+            entries
+                .iter()
+                .find(|entry| entry.guid == guid)
+                .map_or(0, |entry| entry.address as u64)
+        })
+    };
+    let (efi_system_table, efi_runtime_services) = match uefi::table::system_table_raw() {
+        Some(system_table) => (system_table.as_ptr() as u64, unsafe {
+            system_table.as_ref().runtime_services as u64
+        }),
+        None => (0, 0),
+    };
+
+    let acpi2 = table(ACPI2_TABLE_GUID);
+    let tables = FirmwareTables {
+        rsdp: if acpi2 != 0 {
+            acpi2
+        } else {
+            table(ACPI_TABLE_GUID)
+        },
+        smbios: table(SMBIOS_TABLE_GUID),
+        smbios3: table(SMBIOS3_TABLE_GUID),
+        efi_system_table,
+        efi_runtime_services,
+        device_tree: table(DEVICE_TREE_GUID),
+    };
+    if tables.rsdp == 0 {
+        warn!("no ACPI RSDP in the EFI configuration table");
+    }
+    info!("firmware tables: {:x?}", tables);
+    tables
+}
+
+/// Read the file at `path` into loader memory, empty if no path is configured or it cannot be read.
+///
+/// The kernel checks the format of what it is given, e.g. that a font is PSF.
+fn load_optional_file(root: &mut Directory, path: Option<&str>) -> MemoryRegion {
+    let Some(path) = path else {
+        return MemoryRegion::default();
+    };
+    load_file(root, path).unwrap_or_else(|| {
+        warn!("cannot load file {}", path);
+        MemoryRegion::default()
+    })
+}
+
+/// Read the initrd files of an entry back to back, each starting 4-byte aligned
+/// so concatenated cpio archives stay readable. Files that cannot be read are skipped.
+fn load_initrd(root: &mut Directory, paths: &[String]) -> MemoryRegion {
+    let files: Vec<MemoryRegion> = paths
+        .iter()
+        .map(|path| load_optional_file(root, Some(path.as_str())))
+        .filter(|file| !file.is_empty())
+        .collect();
+    if files.len() < 2 {
+        return files.first().copied().unwrap_or_default();
+    }
+
+    let size = files
+        .iter()
+        .map(|file| align_up(file.size, INITRD_ALIGN))
+        .sum::<u64>() as usize;
+    let Ok(mut address) = uefi::boot::allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        size / PAGE_SIZE + 1,
+    ) else {
+        warn!("cannot allocate memory for the initrd");
+        return MemoryRegion::default();
+    };
+    let initrd = unsafe { core::slice::from_raw_parts_mut(address.as_mut() as *mut u8, size) };
+    initrd.fill(0);
+
+    let count = files.len();
+    let mut offset = 0;
+    for file in files {
+        let content =
+            unsafe { core::slice::from_raw_parts(file.address as *const u8, file.size as usize) };
+        initrd[offset..offset + content.len()].copy_from_slice(content);
+        offset += align_up(file.size, INITRD_ALIGN) as usize;
+        unsafe {
+            let pages = file.size as usize / PAGE_SIZE + 1;
+            let _ = uefi::boot::free_pages(NonNull::new_unchecked(file.address as *mut u8), pages);
+        }
+    }
+
+    info!("initrd: {} files, {} bytes", count, size);
+    MemoryRegion {
+        address: initrd.as_ptr() as u64,
+        size: size as u64,
+    }
+}
+
+/// Initial local APIC ID of the processor the loader runs on.
+fn boot_cpu() -> u64 {
+    let ebx = unsafe { core::arch::x86_64::__cpuid(1).ebx };
+    (ebx >> 24) as u64
+}
+
+/// RAM for the kernel console to draw into, `0` if there is not enough of it.
+fn allocate_back_buffer(size: u64) -> u64 {
+    if size == 0 {
+        return 0;
+    }
+    match uefi::boot::allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        size.div_ceil(PAGE_SIZE as u64) as usize,
+    ) {
+        Ok(pages) => pages.as_ptr() as u64,
+        Err(error) => {
+            warn!(
+                "no memory for a framebuffer back buffer: {:?}",
+                error.status()
+            );
+            0
+        }
+    }
+}
+
+/// A page for application processors to start in, `0` if there is none below 1MiB.
+///
+/// Being `LOADER_DATA` the page is handed over as bootloader memory, so the
+/// kernel does not allocate it before it has started the other processors.
+fn allocate_smp_trampoline() -> u64 {
+    match uefi::boot::allocate_pages(
+        AllocateType::MaxAddress(SMP_TRAMPOLINE_LIMIT - 1),
+        MemoryType::LOADER_DATA,
+        1,
+    ) {
+        Ok(page) => {
+            info!("SMP trampoline at {:#x}", page.as_ptr() as u64);
+            page.as_ptr() as u64
+        }
+        Err(error) => {
+            warn!(
+                "no page below 1MiB for the SMP trampoline: {:?}",
+                error.status()
+            );
+            0
+        }
+    }
+}
+
+/// Read each module and its path into loader memory, skipping files that cannot be read.
+fn load_modules(root: &mut Directory, paths: &[String]) -> BootModules {
+    let modules: Vec<BootModule> = paths
+        .iter()
+        .map(|path| BootModule {
+            content: load_optional_file(root, Some(path.as_str())),
+            path: copy_to_loader_memory(path.as_bytes()),
+        })
+        .filter(|module| !module.content.is_empty())
+        .collect();
+
+    let table = unsafe {
+        core::slice::from_raw_parts(
+            modules.as_ptr() as *const u8,
+            core::mem::size_of_val(modules.as_slice()),
+        )
+    };
+    BootModules {
+        address: copy_to_loader_memory(table).address,
+        count: modules.len() as u64,
+    }
+}
+
+/// Read a file into loader pages, `ext4:` paths come from the first ext4 partition
+/// and all others from the boot volume.
+fn load_file(root: &mut Directory, path: &str) -> Option<MemoryRegion> {
+    match path.strip_prefix(EXT4_PREFIX) {
+        Some(path) => Ext4Volume::find()?.read_file(path),
+        None => open_file(root, path).and_then(|file| read_file(file, path)),
+    }
+}
+
+fn open_file(root: &mut Directory, path: &str) -> Option<RegularFile> {
+    let mut path_buffer = [0u16; FILE_BUFFER_SIZE];
+    let Ok(file_path) = CStr16::from_str_with_buf(path, &mut path_buffer) else {
+        warn!("invalid path: {}", path);
+        return None;
+    };
+    match root
+        .open(file_path, FileMode::Read, FileAttribute::empty())
+        .ok()
+        .and_then(|handle| handle.into_type().ok())
+    {
+        Some(FileType::Regular(f)) => Some(f),
+        _ => None,
+    }
+}
+
+/// Read all of `file` into freshly allocated loader pages.
+fn read_file(mut file: RegularFile, path: &str) -> Option<MemoryRegion> {
+    let mut file_info_buffer = [0u8; FILE_BUFFER_SIZE];
+    let file_size = match file.get_info::<FileInfo>(&mut file_info_buffer) {
+        Ok(info) => info.file_size() as usize,
+        Err(_) => {
+            warn!("cannot get file info of {}", path);
+            return None;
+        }
+    };
+
+    let Ok(mut address) = uefi::boot::allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        file_size / PAGE_SIZE + 1,
+    ) else {
+        warn!("cannot allocate memory for {}", path);
+        return None;
+    };
+    let in_memory =
+        unsafe { core::slice::from_raw_parts_mut(address.as_mut() as *mut u8, file_size) };
+    let Ok(size) = file.read(in_memory) else {
+        warn!("cannot read file {}", path);
+        return None;
+    };
+
+    info!("{} loaded, {} bytes", path, size);
+    Some(MemoryRegion {
+        address: in_memory.as_ptr() as u64,
+        size: size as u64,
+    })
+}
+
+/// Copy `bytes` into loader pages, which stay mapped for the kernel.
+fn copy_to_loader_memory(bytes: &[u8]) -> MemoryRegion {
+    if bytes.is_empty() {
+        return MemoryRegion::default();
+    }
+    let Ok(mut address) = uefi::boot::allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        bytes.len() / PAGE_SIZE + 1,
+    ) else {
+        warn!("cannot allocate loader memory");
+        return MemoryRegion::default();
+    };
+    let address = unsafe { address.as_mut() as *mut u8 };
+    unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), address, bytes.len()) };
+    MemoryRegion {
+        address: address as u64,
+        size: bytes.len() as u64,
     }
 }
 
@@ -213,7 +1070,7 @@ pub fn map_stack(
     let stack_start = Page::containing_address(VirtAddr::new(addr));
     let stack_end = stack_start + pages;
 
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
 
     for page in Page::range(stack_start, stack_end) {
         let frame = frame_allocator
@@ -229,9 +1086,11 @@ pub fn map_stack(
     Ok(())
 }
 
+/// Map `segment` of the kernel file at `kernel_start`, `slide` bytes above its link address.
 fn map_segment(
     segment: &program::ProgramHeader,
     kernel_start: PhysAddr,
+    slide: u64,
     page_table: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
@@ -243,7 +1102,7 @@ fn map_segment(
     let file_size = segment.file_size();
     let file_offset = segment.offset() & !0xfff;
     let phys_start_addr = kernel_start + file_offset;
-    let virt_start_addr = VirtAddr::new(segment.virtual_addr());
+    let virt_start_addr = VirtAddr::new(segment.virtual_addr() + slide);
 
     let start_page: Page = Page::containing_address(virt_start_addr);
     let start_frame = PhysFrame::containing_address(phys_start_addr);
@@ -335,18 +1194,36 @@ fn map_segment(
     Ok(())
 }
 
+/// Drop write access to `start..end` of the kernel, relocations have been applied.
+fn protect_relro(
+    start: u64,
+    end: u64,
+    page_table: &mut impl Mapper<Size4KiB>,
+) -> Result<(), FlagUpdateError> {
+    debug!("relro at {:#x}..{:#x}", start, end);
+    let start_page: Page = Page::containing_address(VirtAddr::new(start));
+    let end_page: Page = Page::containing_address(VirtAddr::new(end));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    for page in Page::range(start_page, end_page) {
+        unsafe { page_table.update_flags(page, flags)?.flush() };
+    }
+    Ok(())
+}
+
+/// Map physical memory below `size` at `offset` with 2MiB pages, the page
+/// directories and tables above them are allocated as they are needed.
 pub fn map_physical_memory(
     offset: u64,
-    max_addr: u64,
+    size: u64,
     page_table: &mut impl Mapper<Size2MiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) {
     info!("mapping physical memory");
     let start_frame = PhysFrame::containing_address(PhysAddr::new(0));
-    let end_frame = PhysFrame::containing_address(PhysAddr::new(max_addr));
-    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+    let end_frame = PhysFrame::containing_address(PhysAddr::new(align_up(size, Size2MiB::SIZE)));
+    for frame in PhysFrame::<Size2MiB>::range(start_frame, end_frame) {
         let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64() + offset));
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
         unsafe {
             page_table
                 .map_to(page, frame, flags, frame_allocator)
@@ -0,0 +1,150 @@
+use alloc::vec;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use canicula_common::entry::MemoryRegion;
+use canicula_common::fs::OperateError;
+use canicula_ext4::Ext4Reader;
+use log::{info, warn};
+use uefi::boot::{
+    self, AllocateType, MemoryType, OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol,
+    SearchType,
+};
+use uefi::proto::media::block::BlockIO;
+use uefi::Identify;
+
+use crate::PAGE_SIZE;
+
+/// Paths starting with this are read from an ext4 partition, e.g. `ext4:/boot/kernel`.
+pub static EXT4_PREFIX: &str = "ext4:";
+/// Largest ext4 block size, the reader needs one block of scratch space.
+static MAX_BLOCK_SIZE: usize = 0x10000;
+
+/// The partition [`read_bytes`] reads from, the ext4 reader only takes a plain fn.
+static DEVICE: AtomicPtr<BlockIO> = AtomicPtr::new(ptr::null_mut());
+
+/// An ext4 partition opened through `EFI_BLOCK_IO_PROTOCOL`, only ever read.
+pub struct Ext4Volume {
+    block_io: ScopedProtocol<BlockIO>,
+}
+
+impl Ext4Volume {
+    /// The first partition holding an ext4 file system.
+    pub fn find() -> Option<Self> {
+        let handles = boot::locate_handle_buffer(SearchType::ByProtocol(&BlockIO::GUID)).ok()?;
+        let mut scratch = vec![0u8; MAX_BLOCK_SIZE];
+        for &handle in handles.iter() {
+            // shared access, the FAT driver keeps the partition the loader came from
+            let Ok(block_io) = (unsafe {
+                boot::open_protocol::<BlockIO>(
+                    OpenProtocolParams {
+                        handle,
+                        agent: boot::image_handle(),
+                        controller: None,
+                    },
+                    OpenProtocolAttributes::GetProtocol,
+                )
+            }) else {
+                continue;
+            };
+            let media = block_io.media();
+            if !media.is_logical_partition() || !media.is_media_present() {
+                continue;
+            }
+            let block_size = media.block_size();
+
+            let volume = Ext4Volume::attach(block_io);
+            if Ext4Reader::mount(read_bytes, &mut scratch).is_ok() {
+                info!("ext4 partition found, {} byte sectors", block_size);
+                return Some(volume);
+            }
+        }
+        warn!("no ext4 partition found");
+        None
+    }
+
+    fn attach(block_io: ScopedProtocol<BlockIO>) -> Self {
+        let device = &*block_io as *const BlockIO as *mut BlockIO;
+        DEVICE.store(device, Ordering::SeqCst);
+        Ext4Volume { block_io }
+    }
+
+    /// Read the file at the absolute `path` into freshly allocated loader pages.
+    pub fn read_file(&mut self, path: &str) -> Option<MemoryRegion> {
+        let mut scratch = vec![0u8; MAX_BLOCK_SIZE];
+        let mut reader = Ext4Reader::mount(read_bytes, &mut scratch).ok()?;
+        let inode = match reader.open(path) {
+            Ok(Some(inode)) if inode.is_file() => inode,
+            Ok(_) => {
+                warn!("no file {} on the ext4 partition", path);
+                return None;
+            }
+            Err(error) => {
+                warn!("cannot look up {}: {:?}", path, error);
+                return None;
+            }
+        };
+
+        let size = inode.size() as usize;
+        let Ok(address) = boot::allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::LOADER_DATA,
+            size / PAGE_SIZE + 1,
+        ) else {
+            warn!("cannot allocate memory for {}", path);
+            return None;
+        };
+        let in_memory = unsafe { core::slice::from_raw_parts_mut(address.as_ptr(), size) };
+        match reader.read_file(&inode, 0, in_memory) {
+            Ok(read) if read == size => {}
+            _ => {
+                warn!("cannot read file {}", path);
+                unsafe {
+                    let _ = boot::free_pages(address, size / PAGE_SIZE + 1);
+                }
+                return None;
+            }
+        }
+
+        info!("{} loaded from ext4, {} bytes", path, size);
+        Some(MemoryRegion {
+            address: in_memory.as_ptr() as u64,
+            size: size as u64,
+        })
+    }
+}
+
+impl Drop for Ext4Volume {
+    fn drop(&mut self) {
+        let device = &*self.block_io as *const BlockIO as *mut BlockIO;
+        let _ =
+            DEVICE.compare_exchange(device, ptr::null_mut(), Ordering::SeqCst, Ordering::SeqCst);
+    }
+}
+
+/// Read from the attached partition through a page aligned bounce buffer, which
+/// covers whole device blocks and any `IoAlign` up to a page.
+fn read_bytes(offset: usize, buffer: &mut [u8]) -> Result<usize, OperateError> {
+    let device = NonNull::new(DEVICE.load(Ordering::SeqCst)).ok_or(OperateError::Fault)?;
+    let block_io = unsafe { device.as_ref() };
+    let media = block_io.media();
+    let block_size = media.block_size() as usize;
+
+    let first = offset / block_size;
+    let end = (offset + buffer.len()).div_ceil(block_size);
+    let size = (end - first) * block_size;
+    let pages = size.div_ceil(PAGE_SIZE);
+    let bounce = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+        .map_err(|_| OperateError::Fault)?;
+
+    let blocks = unsafe { core::slice::from_raw_parts_mut(bounce.as_ptr(), size) };
+    let read = block_io.read_blocks(media.media_id(), first as u64, blocks);
+    if read.is_ok() {
+        let within = offset - first * block_size;
+        buffer.copy_from_slice(&blocks[within..within + buffer.len()]);
+    }
+    unsafe {
+        let _ = boot::free_pages(bounce, pages);
+    }
+    read.map(|()| buffer.len()).map_err(|_| OperateError::IO)
+}
@@ -0,0 +1,133 @@
+use alloc::boxed::Box;
+use core::ffi::c_void;
+
+use canicula_common::entry::MemoryRegion;
+use uefi::{guid, Guid, Handle, Status};
+
+static DEVICE_PATH_PROTOCOL_GUID: Guid = guid!("09576e91-6d3f-11d2-8e39-00a0c969723b");
+static LOAD_FILE2_PROTOCOL_GUID: Guid = guid!("4006c0c1-fcb3-403e-996d-4a6c8724e06d");
+
+/// A vendor media device path with `LINUX_EFI_INITRD_MEDIA_GUID`, the Linux EFI
+/// stub looks for a LoadFile2 protocol on it to fetch its initrd.
+#[repr(C, packed)]
+struct InitrdDevicePath {
+    kind: u8,
+    sub_kind: u8,
+    length: u16,
+    vendor: Guid,
+    end_kind: u8,
+    end_sub_kind: u8,
+    end_length: u16,
+}
+
+static INITRD_DEVICE_PATH: InitrdDevicePath = InitrdDevicePath {
+    // media device path, vendor defined
+    kind: 0x04,
+    sub_kind: 0x03,
+    length: 20,
+    vendor: guid!("5568e427-68fc-4f3d-ac74-ca555231cc68"),
+    // end of the entire device path
+    end_kind: 0x7f,
+    end_sub_kind: 0xff,
+    end_length: 4,
+};
+
+/// `EFI_LOAD_FILE2_PROTOCOL` followed by the buffer it hands out.
+#[repr(C)]
+struct InitrdLoadFile {
+    load_file: unsafe extern "efiapi" fn(
+        this: *mut InitrdLoadFile,
+        file_path: *const c_void,
+        boot_policy: u8,
+        buffer_size: *mut usize,
+        buffer: *mut c_void,
+    ) -> Status,
+    initrd: MemoryRegion,
+}
+
+unsafe extern "efiapi" fn load_file(
+    this: *mut InitrdLoadFile,
+    _file_path: *const c_void,
+    boot_policy: u8,
+    buffer_size: *mut usize,
+    buffer: *mut c_void,
+) -> Status {
+    if boot_policy != 0 {
+        return Status::UNSUPPORTED;
+    }
+    if this.is_null() || buffer_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let initrd = (*this).initrd;
+    let size = initrd.size as usize;
+    if buffer.is_null() || *buffer_size < size {
+        *buffer_size = size;
+        return Status::BUFFER_TOO_SMALL;
+    }
+    core::ptr::copy_nonoverlapping(initrd.address as *const u8, buffer as *mut u8, size);
+    *buffer_size = size;
+    Status::SUCCESS
+}
+
+/// Serves an initrd to the Linux EFI stub until dropped.
+pub struct InitrdServer {
+    handle: Handle,
+    protocol: *mut InitrdLoadFile,
+}
+
+impl InitrdServer {
+    /// Install the initrd device path and its LoadFile2 protocol on a new handle.
+    pub fn install(initrd: MemoryRegion) -> uefi::Result<Self> {
+        let protocol = Box::into_raw(Box::new(InitrdLoadFile { load_file, initrd }));
+        let device_path = &INITRD_DEVICE_PATH as *const InitrdDevicePath as *const c_void;
+
+        let installed = unsafe {
+            uefi::boot::install_protocol_interface(None, &DEVICE_PATH_PROTOCOL_GUID, device_path)
+                .and_then(|handle| {
+                    uefi::boot::install_protocol_interface(
+                        Some(handle),
+                        &LOAD_FILE2_PROTOCOL_GUID,
+                        protocol as *const c_void,
+                    )
+                    .inspect_err(|_| {
+                        let _ = uefi::boot::uninstall_protocol_interface(
+                            handle,
+                            &DEVICE_PATH_PROTOCOL_GUID,
+                            device_path,
+                        );
+                    })
+                })
+        };
+        match installed {
+            Ok(handle) => Ok(InitrdServer { handle, protocol }),
+            Err(error) => {
+                drop(unsafe { Box::from_raw(protocol) });
+                Err(error)
+            }
+        }
+    }
+}
+
+impl Drop for InitrdServer {
+    fn drop(&mut self) {
+        let device_path = &INITRD_DEVICE_PATH as *const InitrdDevicePath as *const c_void;
+        let uninstalled = unsafe {
+            uefi::boot::uninstall_protocol_interface(
+                self.handle,
+                &LOAD_FILE2_PROTOCOL_GUID,
+                self.protocol as *const c_void,
+            )
+            .and_then(|()| {
+                uefi::boot::uninstall_protocol_interface(
+                    self.handle,
+                    &DEVICE_PATH_PROTOCOL_GUID,
+                    device_path,
+                )
+            })
+        };
+        // a protocol still installed keeps pointing at the box, leak it then
+        if uninstalled.is_ok() {
+            drop(unsafe { Box::from_raw(self.protocol) });
+        }
+    }
+}
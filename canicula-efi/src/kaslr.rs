@@ -0,0 +1,27 @@
+use canicula_common::layout::KERNEL_SLIDE_WINDOW;
+use log::warn;
+use uefi::proto::rng::Rng;
+use x86_64::instructions::random::RdRand;
+
+/// Slides are a multiple of this, so the kernel keeps its 2MiB alignment.
+pub static SLIDE_ALIGN: u64 = 0x20_0000;
+
+/// A random slide, `0` if neither the UEFI RNG protocol nor RDRAND is available.
+pub fn random_slide() -> u64 {
+    let Some(random) = random_u64() else {
+        warn!("no random number source, the kernel is not moved");
+        return 0;
+    };
+    random % (KERNEL_SLIDE_WINDOW / SLIDE_ALIGN) * SLIDE_ALIGN
+}
+
+fn random_u64() -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    let from_firmware = uefi::boot::get_handle_for_protocol::<Rng>()
+        .and_then(uefi::boot::open_protocol_exclusive::<Rng>)
+        .and_then(|mut rng| rng.get_rng(None, &mut bytes));
+    if from_firmware.is_ok() {
+        return Some(u64::from_le_bytes(bytes));
+    }
+    RdRand::new().and_then(|rdrand| rdrand.get_u64())
+}
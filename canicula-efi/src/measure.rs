@@ -0,0 +1,80 @@
+//! Measured boot through `EFI_TCG2_PROTOCOL`.
+//!
+//! With `measure=on` an entry's kernel, initrd, modules and command line are
+//! hashed into TPM PCRs before the loader hands over, each one recorded in the
+//! TCG event log with `EV_IPL`. The PCRs are the ones GRUB uses, so existing
+//! attestation policies carry over. PE images started with `mode=efi` or
+//! `mode=chainload` are measured by the firmware's `LoadImage` itself.
+
+use canicula_common::entry::MemoryRegion;
+use log::{info, warn};
+use uefi::boot::{self, ScopedProtocol};
+use uefi::proto::tcg::v2::{HashLogExtendEventFlags, PcrEventInputs, Tcg};
+use uefi::proto::tcg::{EventType, PcrIndex};
+
+static CMDLINE_PCR: PcrIndex = PcrIndex(8);
+static FILE_PCR: PcrIndex = PcrIndex(9);
+
+/// The TPM behind the firmware's TCG2 protocol.
+pub struct Tpm {
+    tcg: ScopedProtocol<Tcg>,
+}
+
+impl Tpm {
+    /// The TPM if the firmware has TCG2 and reports one present.
+    pub fn open() -> Option<Self> {
+        let Some(mut tcg) = boot::get_handle_for_protocol::<Tcg>()
+            .and_then(boot::open_protocol_exclusive::<Tcg>)
+            .ok()
+        else {
+            warn!("no TCG2 protocol, boot is not measured");
+            return None;
+        };
+        match tcg.get_capability() {
+            Ok(capability) if capability.tpm_present() => Some(Tpm { tcg }),
+            _ => {
+                warn!("no TPM present, boot is not measured");
+                None
+            }
+        }
+    }
+
+    /// Extend `pcr` with the hash of `data`, logging `description` as the event.
+    fn measure(&mut self, pcr: PcrIndex, data: &[u8], description: &str) {
+        let event = match PcrEventInputs::new_in_box(pcr, EventType::IPL, description.as_bytes()) {
+            Ok(event) => event,
+            Err(error) => {
+                warn!("cannot describe {}: {:?}", description, error.status());
+                return;
+            }
+        };
+        match self
+            .tcg
+            .hash_log_extend_event(HashLogExtendEventFlags::empty(), data, &event)
+        {
+            Ok(()) => info!("{} measured into PCR {}", description, pcr.0),
+            Err(error) => warn!("cannot measure {}: {:?}", description, error.status()),
+        }
+    }
+
+    /// Measure a file into PCR 9, its path is the event.
+    pub fn measure_file(&mut self, path: &str, content: &[u8]) {
+        self.measure(FILE_PCR, content, path);
+    }
+
+    /// Measure a file the loader read into `region`, see [`Tpm::measure_file`].
+    pub fn measure_region(&mut self, path: &str, region: MemoryRegion) {
+        if region.is_empty() {
+            return;
+        }
+        let content = unsafe {
+            core::slice::from_raw_parts(region.address as *const u8, region.size as usize)
+        };
+        self.measure_file(path, content);
+    }
+
+    /// Measure the kernel command line into PCR 8, it is the event as well.
+    pub fn measure_cmdline(&mut self, cmdline: &str) {
+        self.measure(CMDLINE_PCR, cmdline.as_bytes(), cmdline);
+    }
+}
@@ -0,0 +1,183 @@
+use canicula_common::entry::{MemoryKind, MemoryMapEntry, MemoryRegion};
+
+/// What [`build`] had to fix up in the firmware's memory map.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Report {
+    /// Regions overlapping an earlier one, the overlap is only usable if both are.
+    pub overlaps: usize,
+    /// Gaps between regions, usually MMIO.
+    pub holes: usize,
+    /// Regions cut off above the highest physical address of the CPU.
+    pub above_limit: usize,
+    /// Reserved ranges that did not fit into the map, the map is unsafe to use then.
+    pub dropped: usize,
+}
+
+/// Turn the firmware regions into a sorted map without overlaps and paint the
+/// `reserved` ranges over it, returning the number of entries written to `map`.
+///
+/// Runs after boot services have exited, so it works in place and never allocates.
+pub fn build(
+    regions: impl Iterator<Item = MemoryMapEntry>,
+    reserved: &[MemoryMapEntry],
+    max_address: u64,
+    map: &mut [MemoryMapEntry],
+) -> (usize, Report) {
+    let mut report = Report::default();
+    let mut count = 0;
+    for mut entry in regions {
+        if entry.end() > max_address {
+            report.above_limit += 1;
+            if entry.region.address >= max_address {
+                continue;
+            }
+            entry.region.size = max_address - entry.region.address;
+        }
+        if entry.region.is_empty() {
+            continue;
+        }
+        if count == map.len() {
+            report.dropped += 1;
+            continue;
+        }
+        map[count] = entry;
+        count += 1;
+    }
+    map[..count].sort_unstable_by_key(|entry| entry.region.address);
+
+    count = remove_overlaps(&mut map[..count], &mut report);
+    for range in reserved.iter().filter(|range| !range.region.is_empty()) {
+        match paint(map, count, *range) {
+            Some(painted) => count = painted,
+            None => report.dropped += 1,
+        }
+    }
+    count = merge(&mut map[..count]);
+
+    report.holes = map[..count]
+        .windows(2)
+        .filter(|pair| pair[0].end() < pair[1].region.address)
+        .count();
+    (count, report)
+}
+
+/// Trim overlapping regions of a sorted map, the overlap goes to the region
+/// that is not usable. Returns the new length.
+fn remove_overlaps(map: &mut [MemoryMapEntry], report: &mut Report) -> usize {
+    let mut count = 0;
+    for index in 0..map.len() {
+        let mut entry = map[index];
+        if count > 0 && map[count - 1].end() > entry.region.address {
+            report.overlaps += 1;
+            let previous = &mut map[count - 1];
+            if previous.kind == MemoryKind::Usable && entry.kind != MemoryKind::Usable {
+                // the usable tail past `entry` is lost, which is safe
+                previous.region.size = entry.region.address - previous.region.address;
+            } else {
+                let end = entry.end().max(previous.end());
+                entry.region.address = previous.end();
+                entry.region.size = end - entry.region.address;
+            }
+            if previous.region.is_empty() {
+                count -= 1;
+            }
+        }
+        if !entry.region.is_empty() {
+            map[count] = entry;
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Replace whatever `range` covers in the first `count` entries by `range` itself.
+/// Returns the new length, `None` if `map` has no room left.
+fn paint(map: &mut [MemoryMapEntry], mut count: usize, range: MemoryMapEntry) -> Option<usize> {
+    let (start, end) = (range.region.address, range.end());
+    let mut index = 0;
+    while index < count {
+        let entry = map[index];
+        if entry.end() <= start || entry.region.address >= end {
+            index += 1;
+            continue;
+        }
+        let piece = |from: u64, to: u64| MemoryMapEntry {
+            region: MemoryRegion {
+                address: from,
+                size: to - from,
+            },
+            kind: entry.kind,
+        };
+        let left = (entry.region.address < start).then(|| piece(entry.region.address, start));
+        let right = (entry.end() > end).then(|| piece(end, entry.end()));
+        match (left, right) {
+            (None, None) => {
+                map.copy_within(index + 1..count, index);
+                count -= 1;
+            }
+            (Some(piece), None) | (None, Some(piece)) => {
+                map[index] = piece;
+                index += 1;
+            }
+            (Some(left), Some(right)) => {
+                if count == map.len() {
+                    return None;
+                }
+                map.copy_within(index + 1..count, index + 2);
+                map[index] = left;
+                map[index + 1] = right;
+                count += 1;
+                index += 2;
+            }
+        }
+    }
+
+    if count == map.len() {
+        return None;
+    }
+    let at = map[..count].partition_point(|entry| entry.region.address < start);
+    map.copy_within(at..count, at + 1);
+    map[at] = range;
+    Some(count + 1)
+}
+
+/// Merge neighbours of the same kind, returns the new length.
+fn merge(map: &mut [MemoryMapEntry]) -> usize {
+    let mut count = 0;
+    for index in 0..map.len() {
+        let entry = map[index];
+        if count > 0 {
+            let previous = &mut map[count - 1];
+            if previous.kind == entry.kind && previous.end() == entry.region.address {
+                previous.region.size += entry.region.size;
+                continue;
+            }
+        }
+        map[count] = entry;
+        count += 1;
+    }
+    count
+}
+
+/// Merge sorted frames of `frame_size` bytes into ranges of the given kind.
+pub fn frame_ranges(
+    frames: &[u64],
+    frame_size: u64,
+    kind: MemoryKind,
+) -> impl Iterator<Item = MemoryMapEntry> + '_ {
+    let mut frames = frames.iter().peekable();
+    core::iter::from_fn(move || {
+        let start = *frames.next()?;
+        let mut end = start + frame_size;
+        while frames.next_if(|&&frame| frame == end).is_some() {
+            end += frame_size;
+        }
+        Some(MemoryMapEntry {
+            region: MemoryRegion {
+                address: start,
+                size: end - start,
+            },
+            kind,
+        })
+    })
+}
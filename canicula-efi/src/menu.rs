@@ -0,0 +1,86 @@
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::{boot, print, println};
+
+use crate::boot_config::{BootConfig, BootEntry};
+
+/// Microseconds between keyboard polls.
+static POLL_INTERVAL: usize = 100_000;
+static POLLS_PER_SECOND: u32 = 10;
+
+/// Let the user pick an entry on the text console.
+///
+/// Without a key press the default entry is booted once the timeout expires,
+/// any key stops the countdown. With a single entry or no timeout there is no menu.
+pub fn choose(config: &BootConfig) -> &BootEntry {
+    let entries = &config.entries;
+    if entries.len() == 1 || config.timeout == 0 {
+        return &entries[config.default];
+    }
+
+    let mut selected = config.default;
+    let mut polls_left = Some(config.timeout * POLLS_PER_SECOND);
+    draw(config, selected, polls_left);
+    loop {
+        match read_key() {
+            None => {
+                match polls_left {
+                    Some(0) => return &entries[config.default],
+                    Some(polls) => {
+                        polls_left = Some(polls - 1);
+                        if polls % POLLS_PER_SECOND == 0 {
+                            draw(config, selected, polls_left);
+                        }
+                    }
+                    None => {}
+                }
+                boot::stall(POLL_INTERVAL);
+                continue;
+            }
+            Some(Key::Special(ScanCode::UP)) => {
+                selected = selected.checked_sub(1).unwrap_or(entries.len() - 1);
+            }
+            Some(Key::Special(ScanCode::DOWN)) => {
+                selected = (selected + 1) % entries.len();
+            }
+            Some(Key::Printable(key)) => match char::from(key) {
+                '\r' | '\n' => return &entries[selected],
+                digit @ '1'..='9' => {
+                    let index = digit as usize - '1' as usize;
+                    if index < entries.len() {
+                        return &entries[index];
+                    }
+                }
+                _ => {}
+            },
+            Some(_) => {}
+        }
+        polls_left = None;
+        draw(config, selected, polls_left);
+    }
+}
+
+fn read_key() -> Option<Key> {
+    uefi::system::with_stdin(|input| input.read_key().ok().flatten())
+}
+
+fn draw(config: &BootConfig, selected: usize, polls_left: Option<u32>) {
+    uefi::system::with_stdout(|output| {
+        let _ = output.clear();
+    });
+
+    println!("Canicula boot menu");
+    println!();
+    for (index, entry) in config.entries.iter().enumerate() {
+        let marker = if index == selected { '>' } else { ' ' };
+        println!(" {} {}. {}", marker, index + 1, entry.title);
+    }
+    println!();
+    match polls_left {
+        Some(polls) => print!(
+            "Booting {} in {} s, press any key to stop",
+            config.entries[config.default].title,
+            polls.div_ceil(POLLS_PER_SECOND)
+        ),
+        None => print!("Up and down to choose, Enter or the entry number to boot"),
+    }
+}
@@ -0,0 +1,163 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use xmas_elf::dynamic::Tag;
+use xmas_elf::header;
+use xmas_elf::program::{SegmentData, Type};
+use xmas_elf::ElfFile;
+
+const PAGE_SIZE: u64 = 0x1000;
+const R_X86_64_RELATIVE: u32 = 8;
+/// Size of an `Elf64_Rela` entry.
+const RELA_ENTRY_SIZE: usize = 24;
+
+/// Whether the kernel can run at another address than it was linked at.
+pub fn is_relocatable(elf: &ElfFile) -> bool {
+    matches!(elf.header.pt2.type_().as_type(), header::Type::SharedObject)
+}
+
+/// Reject files the loader cannot start, before any of them is mapped.
+pub fn validate(elf: &ElfFile) -> Result<(), &'static str> {
+    if elf.header.pt2.machine().as_machine() != header::Machine::X86_64 {
+        return Err("not an x86_64 kernel");
+    }
+    match elf.header.pt2.type_().as_type() {
+        header::Type::Executable | header::Type::SharedObject => {}
+        _ => return Err("neither an executable nor a position independent kernel"),
+    }
+    if elf
+        .program_iter()
+        .any(|segment| segment.get_type() == Ok(Type::Interp))
+    {
+        return Err("kernel asks for a dynamic linker");
+    }
+    if elf
+        .program_iter()
+        .filter(|segment| segment.get_type() == Ok(Type::Load))
+        .any(|segment| segment.flags().is_write() && segment.flags().is_execute())
+    {
+        return Err("segment is both writable and executable");
+    }
+
+    let entry = elf.header.pt2.entry_point();
+    let executable = elf
+        .program_iter()
+        .filter(|segment| segment.get_type() == Ok(Type::Load))
+        .any(|segment| {
+            segment.flags().is_execute()
+                && entry >= segment.virtual_addr()
+                && entry < segment.virtual_addr() + segment.mem_size()
+        });
+    if !executable {
+        return Err("entry point outside an executable segment");
+    }
+    Ok(())
+}
+
+/// Lowest address a loadable segment of the kernel is linked at.
+pub fn link_base(elf: &ElfFile) -> u64 {
+    elf.program_iter()
+        .filter(|segment| segment.get_type() == Ok(Type::Load))
+        .map(|segment| segment.virtual_addr())
+        .min()
+        .unwrap_or(0)
+}
+
+/// End of the highest loadable segment as linked, `.bss` included.
+pub fn link_end(elf: &ElfFile) -> u64 {
+    elf.program_iter()
+        .filter(|segment| segment.get_type() == Ok(Type::Load))
+        .map(|segment| segment.virtual_addr() + segment.mem_size())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Whole pages of `PT_GNU_RELRO` at their link address, only written by
+/// relocations so they can be mapped read-only once those are applied.
+pub fn relro(elf: &ElfFile) -> Option<Range<u64>> {
+    let segment = elf
+        .program_iter()
+        .find(|segment| segment.get_type() == Ok(Type::GnuRelro))?;
+    let start = segment.virtual_addr().next_multiple_of(PAGE_SIZE);
+    let end = (segment.virtual_addr() + segment.mem_size()) & !(PAGE_SIZE - 1);
+    (start < end).then_some(start..end)
+}
+
+/// File offsets and the values to store there for the kernel to run `slide`
+/// bytes above its link address.
+///
+/// Only `R_X86_64_RELATIVE` entries of the `DT_RELA` table are supported, each
+/// one has to patch eight bytes of a writable segment.
+pub fn relocations(elf: &ElfFile, slide: u64) -> Result<Vec<(usize, u64)>, &'static str> {
+    let Some(table) = rela_table(elf)? else {
+        return Ok(Vec::new());
+    };
+
+    table
+        .chunks_exact(RELA_ENTRY_SIZE)
+        .map(|entry| {
+            let field = |index: usize| {
+                let bytes = &entry[index * 8..index * 8 + 8];
+                u64::from_le_bytes(bytes.try_into().unwrap())
+            };
+            let (offset, info, addend) = (field(0), field(1), field(2));
+            if info as u32 != R_X86_64_RELATIVE {
+                return Err("unsupported relocation type");
+            }
+            let target =
+                file_offset(elf, offset, true).ok_or("relocation outside a writable segment")?;
+            Ok((target, addend.wrapping_add(slide)))
+        })
+        .collect()
+}
+
+/// The raw `DT_RELA` table named by the dynamic segment, `None` without one.
+fn rela_table<'a>(elf: &ElfFile<'a>) -> Result<Option<&'a [u8]>, &'static str> {
+    let Some(dynamic) = elf
+        .program_iter()
+        .find(|segment| segment.get_type() == Ok(Type::Dynamic))
+    else {
+        return Ok(None);
+    };
+    let Ok(SegmentData::Dynamic64(entries)) = dynamic.get_data(elf) else {
+        return Err("malformed dynamic segment");
+    };
+
+    let (mut address, mut size) = (None, 0);
+    for entry in entries {
+        match entry.get_tag()? {
+            Tag::Null => break,
+            Tag::Rela => address = Some(entry.get_ptr()?),
+            Tag::RelaSize => size = entry.get_val()?,
+            Tag::RelaEnt if entry.get_val()? != RELA_ENTRY_SIZE as u64 => {
+                return Err("unexpected relocation entry size");
+            }
+            Tag::Needed => return Err("kernel depends on a shared library"),
+            Tag::Rel | Tag::Relr | Tag::JmpRel | Tag::TextRel => {
+                return Err("unsupported relocation table");
+            }
+            _ => {}
+        }
+    }
+
+    let Some(address) = address else {
+        return Ok(None);
+    };
+    let start = file_offset(elf, address, false).ok_or("relocations outside the kernel file")?;
+    elf.input
+        .get(start..start + size as usize)
+        .map(Some)
+        .ok_or("relocations outside the kernel file")
+}
+
+/// Where the 8 bytes at `address` are in the file, if a loadable segment holds them.
+fn file_offset(elf: &ElfFile, address: u64, writable: bool) -> Option<usize> {
+    elf.program_iter()
+        .filter(|segment| segment.get_type() == Ok(Type::Load))
+        .filter(|segment| !writable || segment.flags().is_write())
+        .find(|segment| {
+            address >= segment.virtual_addr()
+                && address + 8 <= segment.virtual_addr() + segment.file_size()
+        })
+        .map(|segment| (segment.offset() + address - segment.virtual_addr()) as usize)
+}
@@ -0,0 +1,82 @@
+use core::fmt;
+
+use x86_64::instructions::port::Port;
+
+use crate::boot_config::{SerialAddress, SerialConfig};
+
+/// Input clock of a 16550 divided by 16, the baud rate at divisor 1.
+static UART_CLOCK: u32 = 115_200;
+
+static DATA: u16 = 0;
+static INTERRUPT_ENABLE: u16 = 1;
+static FIFO_CONTROL: u16 = 2;
+static LINE_CONTROL: u16 = 3;
+static MODEM_CONTROL: u16 = 4;
+static LINE_STATUS: u16 = 5;
+
+static LINE_CONTROL_DLAB: u8 = 1 << 7;
+/// 8 data bits, no parity, one stop bit.
+static LINE_CONTROL_8N1: u8 = 0x03;
+static LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// A 16550 compatible UART, for output once the firmware console is gone.
+pub struct SerialPort {
+    address: SerialAddress,
+}
+
+impl SerialPort {
+    /// Program the baud rate and line control of the configured UART.
+    pub fn init(config: SerialConfig) -> Self {
+        let mut port = SerialPort {
+            address: config.address,
+        };
+        let divisor = (UART_CLOCK / config.baud).clamp(1, u16::MAX as u32) as u16;
+        port.write(INTERRUPT_ENABLE, 0x00);
+        port.write(LINE_CONTROL, LINE_CONTROL_DLAB);
+        port.write(DATA, divisor as u8);
+        port.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        port.write(LINE_CONTROL, LINE_CONTROL_8N1);
+        // enable and clear FIFOs with a 14 byte threshold
+        port.write(FIFO_CONTROL, 0xc7);
+        // data terminal ready, request to send
+        port.write(MODEM_CONTROL, 0x03);
+        port
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        while self.read(LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, byte);
+    }
+
+    fn read(&mut self, register: u16) -> u8 {
+        match self.address {
+            SerialAddress::Io(base) => unsafe { Port::new(base + register).read() },
+            SerialAddress::Mmio(base) => unsafe {
+                core::ptr::read_volatile((base + register as u64) as *const u8)
+            },
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match self.address {
+            SerialAddress::Io(base) => unsafe { Port::new(base + register).write(value) },
+            SerialAddress::Mmio(base) => unsafe {
+                core::ptr::write_volatile((base + register as u64) as *mut u8, value)
+            },
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
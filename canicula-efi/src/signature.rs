@@ -0,0 +1,40 @@
+//! Detached ed25519 signatures over the kernel file.
+//!
+//! Build the loader with `kernel_public_key` set to the hex encoded public key
+//! to refuse kernels without a valid signature next to them, e.g.:
+//!
+//! ```text
+//! openssl genpkey -algorithm ed25519 -out kernel.key
+//! openssl pkey -in kernel.key -pubout -outform DER | tail -c 32 | xxd -p -c 64
+//! openssl pkeyutl -sign -inkey kernel.key -rawin -in esp/canicula-kernel -out esp/canicula-kernel.sig
+//! ```
+
+use ed25519_compact::{PublicKey, Signature};
+
+static KERNEL_PUBLIC_KEY: Option<&str> = option_env!("kernel_public_key");
+
+/// The built in public key, `None` if kernels are not verified.
+pub fn public_key() -> Result<Option<PublicKey>, &'static str> {
+    let Some(hex) = KERNEL_PUBLIC_KEY else {
+        return Ok(None);
+    };
+
+    let invalid = "the built in public key is not 32 bytes of hex";
+    let mut key = [0u8; PublicKey::BYTES];
+    if hex.len() != 2 * key.len() {
+        return Err(invalid);
+    }
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let high = (digits[0] as char).to_digit(16).ok_or(invalid)?;
+        let low = (digits[1] as char).to_digit(16).ok_or(invalid)?;
+        *byte = (high << 4 | low) as u8;
+    }
+    Ok(Some(PublicKey::new(key)))
+}
+
+/// Check the raw 64 byte `signature` of `kernel`.
+pub fn verify(key: &PublicKey, kernel: &[u8], signature: &[u8]) -> Result<(), &'static str> {
+    let signature = Signature::from_slice(signature).map_err(|_| "malformed signature file")?;
+    key.verify(kernel, &signature)
+        .map_err(|_| "the signature does not match the kernel")
+}
@@ -0,0 +1,43 @@
+//! Metadata checksum throughput of each backend, `cargo bench -p canicula-ext4`.
+//!
+//! Sizes follow what a metadata-heavy workload checksums: 4KiB bitmaps, 256 byte
+//! inodes and 64 byte group descriptors.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use canicula_ext4::ChecksumBackend;
+
+const BYTES_PER_RUN: usize = 256 << 20;
+
+fn main() {
+    let data = (0..4096u32)
+        .map(|i| (i * 31 + i / 7) as u8)
+        .collect::<Vec<u8>>();
+
+    for (name, size) in [("bitmap", 4096), ("inode", 256), ("descriptor", 64)] {
+        let mut portable = None;
+        for backend in [ChecksumBackend::Portable, ChecksumBackend::Sse42] {
+            if !backend.is_supported() {
+                println!("{:<10} {:?}: not supported", name, backend);
+                continue;
+            }
+
+            let start = Instant::now();
+            let mut crc = !0;
+            for _ in 0..BYTES_PER_RUN / size {
+                crc = backend.crc32c(crc, black_box(&data[..size]));
+            }
+            black_box(crc);
+            let seconds = start.elapsed().as_secs_f64();
+
+            let throughput = BYTES_PER_RUN as f64 / seconds / (1 << 20) as f64;
+            let speedup = portable.map(|base| throughput / base).unwrap_or(1.0);
+            portable.get_or_insert(throughput);
+            println!(
+                "{:<10} {:?}: {:>8.0} MiB/s, {:.1}x",
+                name, backend, throughput, speedup
+            );
+        }
+    }
+}
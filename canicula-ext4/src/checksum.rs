@@ -0,0 +1,141 @@
+const CRC32C_POLY: u32 = 0x82f6_3b78;
+const CRC16_POLY: u16 = 0xa001;
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+const CRC16_TABLE: [u16; 256] = crc16_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC16_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// How metadata checksums are computed, see [`crate::Ext4FS::with_checksum_backend`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumBackend {
+    /// Table driven, works everywhere.
+    Portable,
+    /// The SSE4.2 `crc32` instruction, eight bytes at a time.
+    Sse42,
+}
+
+impl ChecksumBackend {
+    /// The fastest backend this CPU supports.
+    pub fn detect() -> Self {
+        if ChecksumBackend::Sse42.is_supported() {
+            ChecksumBackend::Sse42
+        } else {
+            ChecksumBackend::Portable
+        }
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            ChecksumBackend::Portable => true,
+            ChecksumBackend::Sse42 => has_sse42(),
+        }
+    }
+
+    /// Raw CRC32C register update without pre/post inversion, like the kernel's `ext4_chksum`.
+    pub fn crc32c(self, seed: u32, data: &[u8]) -> u32 {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            ChecksumBackend::Sse42 if has_sse42() => unsafe { crc32c_sse42(seed, data) },
+            _ => crc32c(seed, data),
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn has_sse42() -> bool {
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    const UNKNOWN: u8 = 0;
+    const ABSENT: u8 = 1;
+    const PRESENT: u8 = 2;
+    // CPUID is slow enough to dominate small checksums, ask once
+    static SSE42: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+    match SSE42.load(Ordering::Relaxed) {
+        UNKNOWN => {
+            // CPUID leaf 1, ECX bit 20
+            #[allow(unused_unsafe)]
+            let leaf = unsafe { core::arch::x86_64::__cpuid(1) };
+            let present = leaf.ecx & (1 << 20) != 0;
+            SSE42.store(if present { PRESENT } else { ABSENT }, Ordering::Relaxed);
+            present
+        }
+        state => state == PRESENT,
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn has_sse42() -> bool {
+    false
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(seed: u32, data: &[u8]) -> u32 {
+    use core::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    let mut chunks = data.chunks_exact(8);
+    let mut crc = seed as u64;
+    for chunk in &mut chunks {
+        let word = u64::from_le_bytes(chunk.try_into().unwrap());
+        crc = _mm_crc32_u64(crc, word);
+    }
+    let mut crc = crc as u32;
+    for byte in chunks.remainder() {
+        crc = _mm_crc32_u8(crc, *byte);
+    }
+    crc
+}
+
+/// Raw CRC32C register update without pre/post inversion, like the kernel's `ext4_chksum`.
+pub fn crc32c(seed: u32, data: &[u8]) -> u32 {
+    data.iter().fold(seed, |crc, byte| {
+        CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// CRC16 (ANSI, reflected) used by the older `gdt_csum` feature.
+pub fn crc16(seed: u16, data: &[u8]) -> u16 {
+    data.iter().fold(seed, |crc, byte| {
+        CRC16_TABLE[((crc ^ *byte as u16) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
@@ -1,75 +1,898 @@
 #![cfg_attr(not(test), no_std)]
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
+#[cfg(feature = "alloc")]
+use alloc::vec;
+#[cfg(feature = "alloc")]
 use alloc::vec::Vec;
 use canicula_common::fs::OperateError;
-use core::mem::MaybeUninit;
-use types::super_block::SuperBlock;
+#[cfg(feature = "alloc")]
+use core::cell::Cell;
+#[cfg(feature = "alloc")]
+use stats::Metadata;
+#[cfg(feature = "alloc")]
+use types::data_block_bitmap::Bitmap;
+#[cfg(feature = "alloc")]
+use types::extent::MAX_INIT_LEN;
+#[cfg(feature = "alloc")]
+use types::group_descriptors::{self, BG_BLOCK_UNINIT, BG_INODE_UNINIT};
+#[cfg(feature = "alloc")]
+use types::super_block::*;
 
+#[cfg(feature = "alloc")]
+pub use checksum::ChecksumBackend;
+pub use reader::Ext4Reader;
+#[cfg(feature = "alloc")]
+pub use scrub::ScrubReport;
+#[cfg(feature = "alloc")]
+pub use stats::FsStats;
+pub use types::extent::Extent;
+pub use types::group_descriptors::GroupDescriptor;
+pub use types::inode_table::Inode;
+pub use types::super_block::SuperBlockSnapshot;
+
+#[cfg(feature = "alloc")]
+mod checksum;
+pub mod reader;
+#[cfg(feature = "alloc")]
+pub mod scrub;
+#[cfg(feature = "alloc")]
+pub mod stats;
+#[cfg(all(test, feature = "alloc"))]
 mod tests;
 mod types;
+#[cfg(feature = "alloc")]
+mod write;
 
 const GROUP_ZERO_PADDING: usize = 1024;
 
+/// Bitmaps of a flex group sit back to back, so prefetch reads through the slots of
+/// uninitialised neighbours (up to this many blocks) rather than issuing another request.
+#[cfg(feature = "alloc")]
+const PREFETCH_MAX_GAP: u64 = 32;
+
+/// Read into the buffer starting at a byte offset, returning the bytes read.
+pub type ReadBytes = fn(usize, &mut [u8]) -> Result<usize, OperateError>;
+/// Write the buffer starting at a byte offset, returning the bytes written.
+pub type WriteBytes = fn(usize, &[u8]) -> Result<usize, OperateError>;
+
+/// Allocator state of one block group, bitmaps are only read on first use.
+#[cfg(feature = "alloc")]
+struct Group {
+    descriptor: GroupDescriptor,
+    block_bitmap: Option<Bitmap>,
+    inode_bitmap: Option<Bitmap>,
+    descriptor_dirty: bool,
+    block_bitmap_dirty: bool,
+    inode_bitmap_dirty: bool,
+}
+
+#[cfg(feature = "alloc")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BitmapKind {
+    Block,
+    Inode,
+}
+
+#[cfg(feature = "alloc")]
+impl BitmapKind {
+    fn metadata(self) -> Metadata {
+        match self {
+            BitmapKind::Block => Metadata::BlockBitmap,
+            BitmapKind::Inode => Metadata::InodeBitmap,
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
 #[allow(unused)]
 pub struct Ext4FS<const SIZE: usize> {
     read_byte: fn(usize) -> Result<u8, OperateError>,
     write_byte: fn(u8, usize) -> Result<usize, OperateError>,
-    super_block: Option<SuperBlock>,
+    read_bytes: Option<ReadBytes>,
+    write_bytes: Option<WriteBytes>,
+    raw_super_block: Vec<u8>,
+    super_block: Option<SuperBlockSnapshot>,
+    super_block_dirty: bool,
+    groups: Vec<Group>,
+    clock: Option<fn() -> i64>,
+    checksum: ChecksumBackend,
+    stats: Cell<FsStats>,
 }
 
+#[cfg(feature = "alloc")]
 #[allow(unused)]
 impl<const SIZE: usize> Ext4FS<SIZE> {
     pub fn new(
         read_byte: fn(usize) -> Result<u8, OperateError>,
         write_byte: fn(u8, usize) -> Result<usize, OperateError>,
     ) -> Self {
-        let mut super_block = MaybeUninit::<SuperBlock>::uninit();
-        let void_super_block_fields = unsafe {
-            core::slice::from_raw_parts(
-                &super_block as *const _ as *const u8,
-                core::mem::size_of::<SuperBlock>(),
-            )
+        // read data from physical device.
+        let raw_super_block = (0..SUPER_BLOCK_SIZE)
+            .map(|i| (read_byte)(GROUP_ZERO_PADDING + i).unwrap_or(0))
+            .collect::<Vec<u8>>();
+        let super_block = SuperBlockSnapshot::from_bytes(&raw_super_block);
+        let mut stats = FsStats::default();
+        stats.record_read(Metadata::SuperBlock, SUPER_BLOCK_SIZE);
+
+        Ext4FS {
+            read_byte,
+            write_byte,
+            read_bytes: None,
+            write_bytes: None,
+            raw_super_block,
+            super_block: Some(super_block),
+            super_block_dirty: false,
+            groups: Vec::new(),
+            clock: None,
+            checksum: ChecksumBackend::detect(),
+            stats: Cell::new(stats),
+        }
+    }
+
+    /// Use `clock` (seconds since the Unix epoch) for timestamps, the superblock's
+    /// write time is set from it whenever [`Ext4FS::flush`] writes the superblock.
+    pub fn with_clock(mut self, clock: fn() -> i64) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// The on-disk timestamp for "now", or `0` if no clock was provided.
+    pub fn now(&self) -> u32 {
+        self.clock.map(|clock| clock() as u32).unwrap_or(0)
+    }
+
+    /// Compute metadata checksums with `backend` instead of the detected one, e.g. when
+    /// the kernel's own CPU feature detection knows better. Unsupported backends fall
+    /// back to the portable one.
+    pub fn with_checksum_backend(mut self, backend: ChecksumBackend) -> Self {
+        self.checksum = if backend.is_supported() {
+            backend
+        } else {
+            ChecksumBackend::Portable
         };
+        self
+    }
 
-        let mut data_index = 0;
-        for (i, field) in void_super_block_fields
-            .chunks(core::mem::size_of::<u8>())
-            .enumerate()
-        {
-            // get the current field length.
-            let size = field.len();
-            let length = size / 8;
-            let mut contents: Vec<u8> = Vec::new();
-
-            // read data from physical device.
-            let mut count = 0;
-            while count < length {
-                count = count + 1;
-                let byte = (read_byte)(length + GROUP_ZERO_PADDING);
-
-                match byte {
-                    Ok(byte) => contents.push(byte),
-                    Err(_) => contents.push(0),
+    pub fn checksum_backend(&self) -> ChecksumBackend {
+        self.checksum
+    }
+
+    /// Transfer whole buffers per device call instead of one byte at a time.
+    pub fn with_block_io(mut self, read_bytes: ReadBytes, write_bytes: WriteBytes) -> Self {
+        self.read_bytes = Some(read_bytes);
+        self.write_bytes = Some(write_bytes);
+        self
+    }
+
+    pub fn super_block(&self) -> Option<&SuperBlockSnapshot> {
+        self.super_block.as_ref()
+    }
+
+    fn sb(&self) -> &SuperBlockSnapshot {
+        self.super_block.as_ref().unwrap()
+    }
+
+    pub fn stats(&self) -> FsStats {
+        self.stats.get()
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.set(FsStats::default());
+    }
+
+    fn record(&self, update: impl FnOnce(&mut FsStats)) {
+        let mut stats = self.stats.get();
+        update(&mut stats);
+        self.stats.set(stats);
+    }
+
+    fn read(&self, kind: Metadata, offset: usize, buffer: &mut [u8]) -> Result<(), OperateError> {
+        self.record(|stats| stats.record_read(kind, buffer.len()));
+        match self.read_bytes {
+            Some(read_bytes) => {
+                if read_bytes(offset, buffer)? != buffer.len() {
+                    return Err(OperateError::IO);
+                }
+            }
+            None => {
+                for (i, byte) in buffer.iter_mut().enumerate() {
+                    *byte = (self.read_byte)(offset + i)?;
                 }
             }
+        }
+        Ok(())
+    }
 
-            let ptr = super_block.as_mut_ptr();
-            // initialize the value of the structure
-            // because it is little-endian data, it is read backwards
-            for content in contents.iter().rev() {
-                unsafe {
-                    core::ptr::write((ptr as *const u8).offset(data_index) as *mut u8, *content)
-                };
-                data_index = data_index + 1;
+    fn write(&self, kind: Metadata, offset: usize, buffer: &[u8]) -> Result<(), OperateError> {
+        self.record(|stats| stats.record_write(kind, buffer.len()));
+        match self.write_bytes {
+            Some(write_bytes) => {
+                if write_bytes(offset, buffer)? != buffer.len() {
+                    return Err(OperateError::IO);
+                }
+            }
+            None => {
+                for (i, byte) in buffer.iter().enumerate() {
+                    (self.write_byte)(*byte, offset + i)?;
+                }
             }
         }
+        Ok(())
+    }
 
-        Ext4FS {
-            read_byte,
-            write_byte,
-            super_block: Some(unsafe { super_block.assume_init() }),
+    fn block_size(&self) -> usize {
+        self.sb().block_size()
+    }
+
+    fn read_blocks(
+        &self,
+        kind: Metadata,
+        block: u64,
+        count: usize,
+    ) -> Result<Vec<u8>, OperateError> {
+        let block_size = self.block_size();
+        let mut buffer = vec![0u8; block_size * count];
+        self.read(kind, block as usize * block_size, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Validate the superblock and load the group descriptor table.
+    ///
+    /// Bitmaps are not read here, they are loaded per group on first allocation
+    /// or in batches by [`Ext4FS::prefetch`].
+    pub fn mount(&mut self) -> Result<(), OperateError> {
+        let sb = self.sb();
+        if !sb.is_valid() {
+            return Err(OperateError::InvalidFileSystem);
+        }
+
+        let group_count = sb.group_count() as usize;
+        let desc_size = sb.desc_size();
+        let descs_per_block = self.block_size() / desc_size;
+        let table_blocks = group_count.div_ceil(descs_per_block);
+
+        // without meta_bg the whole table is contiguous, read it in one go.
+        let mut table = Vec::with_capacity(table_blocks * self.block_size());
+        let contiguous = if sb.has_incompat(FEATURE_INCOMPAT_META_BG) {
+            (sb.s_first_meta_bg as usize).min(table_blocks)
+        } else {
+            table_blocks
+        };
+        table.extend(self.read_blocks(
+            Metadata::GroupDescriptors,
+            self.sb().descriptor_block(0),
+            contiguous,
+        )?);
+        for index in contiguous..table_blocks {
+            table.extend(self.read_blocks(
+                Metadata::GroupDescriptors,
+                self.sb().descriptor_block(index),
+                1,
+            )?);
+        }
+
+        self.groups = table
+            .chunks(desc_size)
+            .take(group_count)
+            .map(|raw| Group {
+                descriptor: GroupDescriptor::from_bytes(raw),
+                block_bitmap: None,
+                inode_bitmap: None,
+                descriptor_dirty: false,
+                block_bitmap_dirty: false,
+                inode_bitmap_dirty: false,
+            })
+            .collect();
+
+        Ok(())
+    }
+
+    pub fn group_count(&self) -> u32 {
+        self.groups.len() as u32
+    }
+
+    /// Number of blocks in `group`, the last group may be short.
+    fn blocks_in_group(&self, group: u32) -> usize {
+        let sb = self.sb();
+        let remaining = sb.blocks_count() - self.sb().group_first_block(group);
+        remaining.min(sb.s_blocks_per_group as u64) as usize
+    }
+
+    /// Blocks at the start of `group` used by the superblock and descriptor table copies.
+    fn group_overhead_blocks(&self, group: u32) -> usize {
+        let sb = self.sb();
+        let has_super = self.sb().has_super(group) as usize;
+        let table_blocks = (sb.group_count() as u64).div_ceil(self.sb().descriptors_per_block());
+
+        if !sb.has_incompat(FEATURE_INCOMPAT_META_BG) {
+            if has_super == 0 {
+                return 0;
+            }
+            return 1 + table_blocks as usize + sb.s_reserved_gdt_blocks as usize;
+        }
+
+        let meta_group = group as u64 / self.sb().descriptors_per_block();
+        let index = group as u64 % self.sb().descriptors_per_block();
+        let mut blocks = has_super;
+        if meta_group < sb.s_first_meta_bg as u64 {
+            if has_super == 1 {
+                blocks += sb.s_first_meta_bg as usize + sb.s_reserved_gdt_blocks as usize;
+            }
+        } else if index == 0 || index == 1 || index == self.sb().descriptors_per_block() - 1 {
+            blocks += 1;
+        }
+        blocks
+    }
+
+    fn crc32c(&self, seed: u32, data: &[u8]) -> u32 {
+        self.checksum.crc32c(seed, data)
+    }
+
+    fn checksum_seed(&self) -> u32 {
+        let sb = self.sb();
+        if sb.has_incompat(FEATURE_INCOMPAT_CSUM_SEED) {
+            sb.s_checksum_seed
+        } else {
+            self.crc32c(!0, &sb.s_uuid)
+        }
+    }
+
+    fn has_metadata_csum(&self) -> bool {
+        self.sb().has_ro_compat(FEATURE_RO_COMPAT_METADATA_CSUM)
+    }
+
+    fn has_group_csum(&self) -> bool {
+        self.has_metadata_csum() || self.sb().has_ro_compat(FEATURE_RO_COMPAT_GDT_CSUM)
+    }
+
+    /// Read the block and inode bitmaps of every group ahead of time.
+    ///
+    /// Bitmaps of the groups in one flex group are usually laid out back to back,
+    /// so each run of adjacent bitmap blocks is fetched with a single device read.
+    pub fn prefetch(&mut self) -> Result<(), OperateError> {
+        let groups_per_flex = self.sb().groups_per_flex();
+        let group_count = self.group_count();
+
+        let mut flex_start = 0;
+        while flex_start < group_count {
+            let flex_end = (flex_start + groups_per_flex).min(group_count);
+            self.prefetch_bitmaps(flex_start..flex_end, BitmapKind::Block)?;
+            self.prefetch_bitmaps(flex_start..flex_end, BitmapKind::Inode)?;
+            flex_start = flex_end;
+        }
+        Ok(())
+    }
+
+    fn prefetch_bitmaps(
+        &mut self,
+        groups: core::ops::Range<u32>,
+        kind: BitmapKind,
+    ) -> Result<(), OperateError> {
+        let mut pending: Vec<(u64, u32)> = Vec::new();
+        for group in groups {
+            let state = &self.groups[group as usize];
+            let (loaded, uninit, block) = match kind {
+                BitmapKind::Block => (
+                    state.block_bitmap.is_some(),
+                    state.descriptor.has_flag(BG_BLOCK_UNINIT),
+                    state.descriptor.block_bitmap(),
+                ),
+                BitmapKind::Inode => (
+                    state.inode_bitmap.is_some(),
+                    state.descriptor.has_flag(BG_INODE_UNINIT),
+                    state.descriptor.inode_bitmap(),
+                ),
+            };
+            if loaded {
+                continue;
+            }
+            if uninit && self.has_group_csum() {
+                self.load_bitmap(group, kind)?;
+                continue;
+            }
+            pending.push((block, group));
+        }
+        pending.sort_unstable();
+
+        let block_size = self.block_size();
+        let mut start = 0;
+        while start < pending.len() {
+            let mut end = start + 1;
+            while end < pending.len() && pending[end].0 - pending[end - 1].0 <= PREFETCH_MAX_GAP {
+                end += 1;
+            }
+
+            let first = pending[start].0;
+            let count = (pending[end - 1].0 - first + 1) as usize;
+            let buffer = self.read_blocks(kind.metadata(), first, count)?;
+            for (block, group) in &pending[start..end] {
+                let offset = (block - first) as usize * block_size;
+                let chunk = &buffer[offset..offset + block_size];
+                let bitmap = Some(Bitmap::from_bytes(chunk.to_vec()));
+                let state = &mut self.groups[*group as usize];
+                match kind {
+                    BitmapKind::Block => state.block_bitmap = bitmap,
+                    BitmapKind::Inode => state.inode_bitmap = bitmap,
+                }
+            }
+            start = end;
+        }
+        Ok(())
+    }
+
+    fn load_bitmap(&mut self, group: u32, kind: BitmapKind) -> Result<(), OperateError> {
+        let state = &self.groups[group as usize];
+        let loaded = match kind {
+            BitmapKind::Block => state.block_bitmap.is_some(),
+            BitmapKind::Inode => state.inode_bitmap.is_some(),
+        };
+        if loaded {
+            self.record(|stats| stats.bitmap_hits += 1);
+            return Ok(());
+        }
+        self.record(|stats| stats.bitmap_misses += 1);
+
+        let descriptor = &state.descriptor;
+        let bitmap = match kind {
+            BitmapKind::Block if descriptor.has_flag(BG_BLOCK_UNINIT) && self.has_group_csum() => {
+                self.init_block_bitmap(group)
+            }
+            BitmapKind::Inode if descriptor.has_flag(BG_INODE_UNINIT) && self.has_group_csum() => {
+                self.init_inode_bitmap()
+            }
+            BitmapKind::Block => Bitmap::from_bytes(self.read_blocks(
+                Metadata::BlockBitmap,
+                descriptor.block_bitmap(),
+                1,
+            )?),
+            BitmapKind::Inode => Bitmap::from_bytes(self.read_blocks(
+                Metadata::InodeBitmap,
+                descriptor.inode_bitmap(),
+                1,
+            )?),
+        };
+
+        let state = &mut self.groups[group as usize];
+        match kind {
+            BitmapKind::Block => state.block_bitmap = Some(bitmap),
+            BitmapKind::Inode => state.inode_bitmap = Some(bitmap),
+        }
+        Ok(())
+    }
+
+    /// Build the block bitmap of a `BLOCK_UNINIT` group, which is not stored on disk.
+    fn init_block_bitmap(&self, group: u32) -> Bitmap {
+        let block_size = self.block_size();
+        let mut bitmap = Bitmap::new(block_size);
+
+        bitmap.set_range(0, self.group_overhead_blocks(group));
+
+        let first = self.sb().group_first_block(group);
+        let blocks = self.blocks_in_group(group) as u64;
+        let descriptor = &self.groups[group as usize].descriptor;
+        let inode_table_blocks =
+            (self.sb().s_inodes_per_group as usize * self.sb().inode_size()).div_ceil(block_size);
+        for (start, count) in [
+            (descriptor.block_bitmap(), 1),
+            (descriptor.inode_bitmap(), 1),
+            (descriptor.inode_table(), inode_table_blocks),
+        ] {
+            for block in start..start + count as u64 {
+                if block >= first && block < first + blocks {
+                    bitmap.set((block - first) as usize);
+                }
+            }
+        }
+
+        // everything past the end of the group is permanently in use
+        bitmap.set_range(blocks as usize, block_size * 8);
+        bitmap
+    }
+
+    fn init_inode_bitmap(&self) -> Bitmap {
+        let block_size = self.block_size();
+        let mut bitmap = Bitmap::new(block_size);
+        bitmap.set_range(self.sb().s_inodes_per_group as usize, block_size * 8);
+        bitmap
+    }
+
+    pub fn is_block_bitmap_loaded(&self, group: u32) -> bool {
+        self.groups[group as usize].block_bitmap.is_some()
+    }
+
+    pub fn is_inode_bitmap_loaded(&self, group: u32) -> bool {
+        self.groups[group as usize].inode_bitmap.is_some()
+    }
+
+    /// Whether `block` is marked in use in its group's bitmap.
+    pub fn is_block_used(&mut self, block: u64) -> Result<bool, OperateError> {
+        let (group, index) = self.block_group(block)?;
+        self.load_bitmap(group, BitmapKind::Block)?;
+        Ok(self.groups[group as usize]
+            .block_bitmap
+            .as_ref()
+            .unwrap()
+            .get(index))
+    }
+
+    fn block_group(&self, block: u64) -> Result<(u32, usize), OperateError> {
+        let sb = self.sb();
+        if block < sb.s_first_data_block as u64 || block >= sb.blocks_count() {
+            return Err(OperateError::Fault);
+        }
+        let relative = block - sb.s_first_data_block as u64;
+        let group = relative / sb.s_blocks_per_group as u64;
+        let index = relative % sb.s_blocks_per_group as u64;
+        Ok((group as u32, index as usize))
+    }
+
+    /// Allocate a free block, searching from `goal_group` onwards.
+    ///
+    /// Groups whose descriptor reports no free blocks are skipped without
+    /// reading their bitmap.
+    pub fn allocate_block(&mut self, goal_group: u32) -> Result<u64, OperateError> {
+        self.allocate_blocks(goal_group, 1).map(|(block, _)| block)
+    }
+
+    /// Allocate up to `count` contiguous blocks, searching from `goal_group` onwards.
+    ///
+    /// Returns the first block and how many were taken, fewer than `count` when the
+    /// first free run found is shorter. The superblock and descriptor table copies at
+    /// the start of a group, reserved GDT blocks included, are never handed out even
+    /// if the bitmap marks them free, `resize_inode` relies on them staying put.
+    ///
+    /// A run longer than the free count in the group's descriptor means the two
+    /// disagree, that is [`OperateError::InvalidFileSystem`] and nothing is taken.
+    pub fn allocate_blocks(
+        &mut self,
+        goal_group: u32,
+        count: usize,
+    ) -> Result<(u64, usize), OperateError> {
+        let group_count = self.group_count();
+        for i in 0..group_count {
+            let group = (goal_group + i) % group_count;
+            if self.groups[group as usize].descriptor.free_blocks_count() == 0 {
+                continue;
+            }
+
+            self.load_bitmap(group, BitmapKind::Block)?;
+            let limit = self.blocks_in_group(group);
+            let first = self.group_overhead_blocks(group);
+            let state = &mut self.groups[group as usize];
+            let bitmap = state.block_bitmap.as_mut().unwrap();
+            let Some((index, len)) = bitmap.find_zero_run(first, limit, count.max(1)) else {
+                self.record(|stats| stats.allocation_retries += 1);
+                continue;
+            };
+
+            let free = state.descriptor.free_blocks_count();
+            let Some(free) = free.checked_sub(len as u32) else {
+                return Err(OperateError::InvalidFileSystem);
+            };
+            bitmap.set_range(index, index + len);
+            state.descriptor.set_free_blocks_count(free);
+            state.descriptor.clear_flag(BG_BLOCK_UNINIT);
+            state.descriptor_dirty = true;
+            state.block_bitmap_dirty = true;
+            self.adjust_free_blocks(-(len as i64));
+            self.record(|stats| stats.blocks_allocated += len as u64);
+
+            return Ok((self.sb().group_first_block(group) + index as u64, len));
+        }
+        Err(OperateError::DeviceNoFreeSpace)
+    }
+
+    /// Write `buffers` back to back into newly allocated blocks, zero-padding the last one.
+    ///
+    /// Blocks are taken in contiguous runs and each run goes to the device in a single
+    /// request. Bitmaps and free counts only change in memory, so one
+    /// [`Ext4FS::flush`] afterwards writes the metadata of the whole batch. The returned
+    /// extents number logical blocks from `0` and still have to be linked into an inode,
+    /// [`Ext4FS::write_at`] writes into a file instead.
+    pub fn write_blocks(
+        &mut self,
+        goal_group: u32,
+        buffers: &[&[u8]],
+    ) -> Result<Vec<Extent>, OperateError> {
+        let block_size = self.block_size();
+        let total = buffers.iter().map(|buffer| buffer.len()).sum::<usize>();
+        let mut remaining = total.div_ceil(block_size);
+        let mut sources = buffers.iter().copied();
+        let mut pending: &[u8] = &[];
+        let mut extents: Vec<Extent> = Vec::new();
+        let mut goal_group = goal_group;
+
+        while remaining > 0 {
+            let result = self
+                .allocate_blocks(goal_group, remaining.min(MAX_INIT_LEN as usize))
+                .and_then(|(start, len)| {
+                    extents.push(Extent {
+                        block: extents
+                            .last()
+                            .map_or(0, |last| last.block + last.len as u32),
+                        len: len as u16,
+                        start,
+                        initialized: true,
+                    });
+                    let mut run = vec![0u8; len * block_size];
+                    gather(&mut run, &mut pending, &mut sources);
+                    self.write(Metadata::Data, start as usize * block_size, &run)
+                });
+            if let Err(error) = result {
+                self.release(&extents);
+                return Err(error);
+            }
+
+            let extent = extents.last().unwrap();
+            remaining -= extent.len as usize;
+            let next = extent.start + extent.len as u64;
+            goal_group = self
+                .block_group(next)
+                .map_or(goal_group, |(group, _)| group);
+        }
+        Ok(extents)
+    }
+
+    /// Give back every block of `extents` after a failed write. The first error stays
+    /// the one reported, blocks that cannot be freed are counted in
+    /// [`FsStats::blocks_leaked`] and left to e2fsck.
+    fn release(&mut self, extents: &[Extent]) {
+        for extent in extents {
+            for block in extent.start..extent.start + extent.len as u64 {
+                if self.free_block(block).is_err() {
+                    self.record(|stats| stats.blocks_leaked += 1);
+                }
+            }
+        }
+    }
+
+    pub fn free_block(&mut self, block: u64) -> Result<(), OperateError> {
+        let (group, index) = self.block_group(block)?;
+        self.load_bitmap(group, BitmapKind::Block)?;
+
+        let state = &mut self.groups[group as usize];
+        let bitmap = state.block_bitmap.as_mut().unwrap();
+        if !bitmap.get(index) {
+            return Err(OperateError::Fault);
+        }
+
+        bitmap.clear(index);
+        let free = state.descriptor.free_blocks_count();
+        state.descriptor.set_free_blocks_count(free + 1);
+        state.descriptor_dirty = true;
+        state.block_bitmap_dirty = true;
+        self.adjust_free_blocks(1);
+        Ok(())
+    }
+
+    /// Allocate an inode number, searching from `goal_group` onwards.
+    pub fn allocate_inode(
+        &mut self,
+        goal_group: u32,
+        directory: bool,
+    ) -> Result<u32, OperateError> {
+        let group_count = self.group_count();
+        let inodes_per_group = self.sb().s_inodes_per_group;
+        let first_inode = self.sb().s_first_ino;
+        let has_group_csum = self.has_group_csum();
+
+        for i in 0..group_count {
+            let group = (goal_group + i) % group_count;
+            if self.groups[group as usize].descriptor.free_inodes_count() == 0 {
+                continue;
+            }
+
+            self.load_bitmap(group, BitmapKind::Inode)?;
+            let state = &mut self.groups[group as usize];
+            let bitmap = state.inode_bitmap.as_mut().unwrap();
+            // inodes below s_first_ino are reserved
+            let start = if group == 0 {
+                first_inode as usize - 1
+            } else {
+                0
+            };
+            let Some(index) = bitmap.find_first_zero(start, inodes_per_group as usize) else {
+                self.record(|stats| stats.allocation_retries += 1);
+                continue;
+            };
+
+            bitmap.set(index);
+            let descriptor = &mut state.descriptor;
+            descriptor.set_free_inodes_count(descriptor.free_inodes_count() - 1);
+            if directory {
+                descriptor.set_used_dirs_count(descriptor.used_dirs_count() + 1);
+            }
+            if has_group_csum {
+                let unused = inodes_per_group - index as u32 - 1;
+                if descriptor.has_flag(BG_INODE_UNINIT) || unused < descriptor.itable_unused() {
+                    descriptor.set_itable_unused(unused);
+                }
+            }
+            descriptor.clear_flag(BG_INODE_UNINIT);
+            state.descriptor_dirty = true;
+            state.inode_bitmap_dirty = true;
+            self.adjust_free_inodes(-1);
+            self.record(|stats| stats.inodes_allocated += 1);
+
+            return Ok(group * inodes_per_group + index as u32 + 1);
+        }
+        Err(OperateError::DeviceNoFreeSpace)
+    }
+
+    pub fn free_inode(&mut self, inode: u32, directory: bool) -> Result<(), OperateError> {
+        let inodes_per_group = self.sb().s_inodes_per_group;
+        if inode == 0 || inode > self.sb().s_inodes_count {
+            return Err(OperateError::Fault);
+        }
+        let group = (inode - 1) / inodes_per_group;
+        let index = ((inode - 1) % inodes_per_group) as usize;
+        self.load_bitmap(group, BitmapKind::Inode)?;
+
+        let state = &mut self.groups[group as usize];
+        let bitmap = state.inode_bitmap.as_mut().unwrap();
+        if !bitmap.get(index) {
+            return Err(OperateError::Fault);
+        }
+
+        bitmap.clear(index);
+        let descriptor = &mut state.descriptor;
+        descriptor.set_free_inodes_count(descriptor.free_inodes_count() + 1);
+        if directory {
+            descriptor.set_used_dirs_count(descriptor.used_dirs_count().saturating_sub(1));
+        }
+        state.descriptor_dirty = true;
+        state.inode_bitmap_dirty = true;
+        self.adjust_free_inodes(1);
+        Ok(())
+    }
+
+    fn set_super_block_field(&mut self, field: SuperBlock, value: u64) {
+        let slice = field.slice();
+        let bytes = value.to_le_bytes();
+        self.raw_super_block[slice.offset..slice.offset + slice.size]
+            .copy_from_slice(&bytes[..slice.size]);
+        self.super_block = Some(SuperBlockSnapshot::from_bytes(&self.raw_super_block));
+        self.super_block_dirty = true;
+    }
+
+    fn adjust_free_blocks(&mut self, delta: i64) {
+        let free = (self.sb().free_blocks_count() as i64 + delta) as u64;
+        self.set_super_block_field(SuperBlock::FreeBlocksCountLo, free & 0xffff_ffff);
+        if self.sb().has_incompat(FEATURE_INCOMPAT_64BIT) {
+            self.set_super_block_field(SuperBlock::FreeBlocksCountHi, free >> 32);
+        }
+    }
+
+    fn adjust_free_inodes(&mut self, delta: i64) {
+        let free = (self.sb().s_free_inodes_count as i64 + delta) as u64;
+        self.set_super_block_field(SuperBlock::FreeInodesCount, free);
+    }
+
+    fn bitmap_checksum(&self, bitmap: &Bitmap, bits: u32) -> u32 {
+        self.crc32c(
+            self.checksum_seed(),
+            &bitmap.as_bytes()[..bits as usize / 8],
+        )
+    }
+
+    fn descriptor_checksum(&self, group: u32, descriptor: &GroupDescriptor) -> u16 {
+        let raw = descriptor.as_bytes();
+        let group = group.to_le_bytes();
+        let checksum_end = group_descriptors::CHECKSUM + 2;
+
+        if self.has_metadata_csum() {
+            let crc = self.crc32c(self.checksum_seed(), &group);
+            let crc = self.crc32c(crc, &raw[..group_descriptors::CHECKSUM]);
+            let crc = self.crc32c(crc, &[0, 0]);
+            let crc = self.crc32c(crc, &raw[checksum_end..]);
+            crc as u16
+        } else {
+            let crc = checksum::crc16(!0, &self.sb().s_uuid);
+            let crc = checksum::crc16(crc, &group);
+            let crc = checksum::crc16(crc, &raw[..group_descriptors::CHECKSUM]);
+            checksum::crc16(crc, &raw[checksum_end..])
+        }
+    }
+
+    /// Write back dirty bitmaps, group descriptors and the superblock.
+    pub fn flush(&mut self) -> Result<(), OperateError> {
+        let block_size = self.block_size();
+        let desc_size = self.sb().desc_size();
+        let descriptors_per_block = self.sb().descriptors_per_block() as usize;
+        let blocks_per_group = self.sb().s_clusters_per_group;
+        let inodes_per_group = self.sb().s_inodes_per_group;
+
+        for group in 0..self.group_count() {
+            let state = &self.groups[group as usize];
+            let mut descriptor = state.descriptor.clone();
+
+            if state.block_bitmap_dirty {
+                let bitmap = state.block_bitmap.as_ref().unwrap();
+                if self.has_metadata_csum() {
+                    descriptor
+                        .set_block_bitmap_checksum(self.bitmap_checksum(bitmap, blocks_per_group));
+                }
+                self.write(
+                    Metadata::BlockBitmap,
+                    descriptor.block_bitmap() as usize * block_size,
+                    bitmap.as_bytes(),
+                )?;
+            }
+            if state.inode_bitmap_dirty {
+                let bitmap = state.inode_bitmap.as_ref().unwrap();
+                if self.has_metadata_csum() {
+                    descriptor
+                        .set_inode_bitmap_checksum(self.bitmap_checksum(bitmap, inodes_per_group));
+                }
+                self.write(
+                    Metadata::InodeBitmap,
+                    descriptor.inode_bitmap() as usize * block_size,
+                    bitmap.as_bytes(),
+                )?;
+            }
+
+            if state.descriptor_dirty || state.block_bitmap_dirty || state.inode_bitmap_dirty {
+                if self.has_group_csum() {
+                    descriptor.set_checksum(self.descriptor_checksum(group, &descriptor));
+                }
+                let table_block = self
+                    .sb()
+                    .descriptor_block(group as usize / descriptors_per_block);
+                let offset = table_block as usize * block_size
+                    + (group as usize % descriptors_per_block) * desc_size;
+                self.write(Metadata::GroupDescriptors, offset, descriptor.as_bytes())?;
+
+                let state = &mut self.groups[group as usize];
+                state.descriptor = descriptor;
+                state.descriptor_dirty = false;
+                state.block_bitmap_dirty = false;
+                state.inode_bitmap_dirty = false;
+            }
+        }
+
+        if self.super_block_dirty {
+            // without a clock the write time stays as it was, rather than going back to 1970
+            if self.clock.is_some() {
+                self.set_super_block_field(SuperBlock::Wtime, self.now() as u64);
+            }
+            if self.has_metadata_csum() {
+                let offset = SuperBlock::Checksum.offset();
+                let crc = self.crc32c(!0, &self.raw_super_block[..offset]);
+                self.raw_super_block[offset..offset + 4].copy_from_slice(&crc.to_le_bytes());
+            }
+            self.write(
+                Metadata::SuperBlock,
+                GROUP_ZERO_PADDING,
+                &self.raw_super_block,
+            )?;
+            self.super_block = Some(SuperBlockSnapshot::from_bytes(&self.raw_super_block));
+            self.super_block_dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Fill `run` from `pending` and then the following `sources`, leaving the rest zeroed.
+#[cfg(feature = "alloc")]
+fn gather<'a>(
+    run: &mut [u8],
+    pending: &mut &'a [u8],
+    sources: &mut impl Iterator<Item = &'a [u8]>,
+) {
+    let mut filled = 0;
+    while filled < run.len() {
+        if pending.is_empty() {
+            match sources.next() {
+                Some(source) => *pending = source,
+                None => return,
+            }
         }
+        let count = pending.len().min(run.len() - filled);
+        run[filled..filled + count].copy_from_slice(&pending[..count]);
+        *pending = &pending[count..];
+        filled += count;
     }
 }
@@ -0,0 +1,341 @@
+use canicula_common::fs::OperateError;
+
+use crate::types::directory::{DirectoryEntry, ENTRY_HEADER};
+use crate::types::extent::{Extent, ExtentHeader, ExtentIndex, ENTRY_SIZE};
+use crate::types::group_descriptors::{GroupDescriptor, MAX_DESC_SIZE};
+use crate::types::inode_table::{
+    Inode, BLOCK_SIZE, EXT4_EXTENTS_FL, EXT4_INLINE_DATA_FL, INODE_CORE_SIZE,
+};
+use crate::types::super_block::*;
+use crate::{ReadBytes, GROUP_ZERO_PADDING};
+
+pub const ROOT_INODE: u32 = 2;
+
+/// Trees deeper than this are treated as corrupt, the kernel allows at most 5 levels.
+pub(crate) const MAX_EXTENT_DEPTH: u16 = 5;
+pub(crate) const DIRECT_BLOCKS: usize = 12;
+
+fn read_exact(read_bytes: ReadBytes, offset: usize, buffer: &mut [u8]) -> Result<(), OperateError> {
+    if read_bytes(offset, buffer)? != buffer.len() {
+        return Err(OperateError::IO);
+    }
+    Ok(())
+}
+
+/// Read-only access to an ext4 volume that never allocates.
+///
+/// Small structures are read onto the stack, anything block sized goes through a
+/// scratch buffer owned by the caller, which must hold at least one filesystem block.
+/// Checksums are not verified and the journal is not replayed.
+pub struct Ext4Reader<'a> {
+    read_bytes: ReadBytes,
+    super_block: SuperBlockSnapshot,
+    buffer: &'a mut [u8],
+}
+
+impl<'a> Ext4Reader<'a> {
+    pub fn mount(read_bytes: ReadBytes, buffer: &'a mut [u8]) -> Result<Self, OperateError> {
+        if buffer.len() < SUPER_BLOCK_SIZE {
+            return Err(OperateError::Fault);
+        }
+        read_exact(
+            read_bytes,
+            GROUP_ZERO_PADDING,
+            &mut buffer[..SUPER_BLOCK_SIZE],
+        )?;
+        let super_block = SuperBlockSnapshot::from_bytes(&buffer[..SUPER_BLOCK_SIZE]);
+        if !super_block.is_valid() {
+            return Err(OperateError::InvalidFileSystem);
+        }
+        if buffer.len() < super_block.block_size() {
+            return Err(OperateError::Fault);
+        }
+
+        Ok(Ext4Reader {
+            read_bytes,
+            super_block,
+            buffer,
+        })
+    }
+
+    pub fn super_block(&self) -> &SuperBlockSnapshot {
+        &self.super_block
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.super_block.block_size()
+    }
+
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<(), OperateError> {
+        read_exact(self.read_bytes, offset, buffer)
+    }
+
+    /// Read `block` into the scratch buffer.
+    fn read_block(&mut self, block: u64) -> Result<(), OperateError> {
+        if block >= self.super_block.blocks_count() {
+            return Err(OperateError::InvalidFileSystem);
+        }
+        let block_size = self.block_size();
+        read_exact(
+            self.read_bytes,
+            block as usize * block_size,
+            &mut self.buffer[..block_size],
+        )
+    }
+
+    fn read_u32(&self, offset: usize) -> Result<u32, OperateError> {
+        let mut bytes = [0u8; 4];
+        self.read(offset, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn group_descriptor(&self, group: u32) -> Result<GroupDescriptor, OperateError> {
+        let sb = &self.super_block;
+        if group >= sb.group_count() {
+            return Err(OperateError::Fault);
+        }
+
+        let desc_size = sb.desc_size();
+        let descriptors_per_block = sb.descriptors_per_block();
+        let table_block = sb.descriptor_block((group as u64 / descriptors_per_block) as usize);
+        let offset = table_block as usize * self.block_size()
+            + (group as u64 % descriptors_per_block) as usize * desc_size;
+
+        let mut raw = [0u8; MAX_DESC_SIZE];
+        self.read(offset, &mut raw[..desc_size])?;
+        Ok(GroupDescriptor::from_bytes(&raw[..desc_size]))
+    }
+
+    pub fn inode(&self, number: u32) -> Result<Inode, OperateError> {
+        let sb = &self.super_block;
+        if number == 0 || number > sb.s_inodes_count {
+            return Err(OperateError::Fault);
+        }
+
+        let group = (number - 1) / sb.s_inodes_per_group;
+        let index = (number - 1) % sb.s_inodes_per_group;
+        let table = self.group_descriptor(group)?.inode_table();
+        let offset = table as usize * self.block_size() + index as usize * sb.inode_size();
+
+        let mut raw = [0u8; INODE_CORE_SIZE];
+        self.read(offset, &mut raw)?;
+        Ok(Inode::from_bytes(number, &raw))
+    }
+
+    /// Physical block behind logical block `logical` of `inode`, `None` for a hole.
+    pub fn map_block(&mut self, inode: &Inode, logical: u32) -> Result<Option<u64>, OperateError> {
+        Ok(self.map(inode, logical)?.map(|(block, _)| block))
+    }
+
+    /// Like [`Ext4Reader::map_block`], also returning how many blocks from there on
+    /// are physically contiguous.
+    fn map(&mut self, inode: &Inode, logical: u32) -> Result<Option<(u64, u64)>, OperateError> {
+        if inode.has_flag(EXT4_EXTENTS_FL) {
+            self.map_extent(inode, logical)
+        } else {
+            Ok(self.map_indirect(inode, logical)?.map(|block| (block, 1)))
+        }
+    }
+
+    fn map_extent(
+        &mut self,
+        inode: &Inode,
+        logical: u32,
+    ) -> Result<Option<(u64, u64)>, OperateError> {
+        let block_size = self.block_size();
+        let mut in_inode = true;
+        let mut parent_depth = None;
+
+        loop {
+            let node = if in_inode {
+                inode.block()
+            } else {
+                &self.buffer[..block_size]
+            };
+            let header = ExtentHeader::from_bytes(node);
+            if !header.is_valid(node.len())
+                || header.depth > MAX_EXTENT_DEPTH
+                || parent_depth.is_some_and(|depth| header.depth + 1 != depth)
+            {
+                return Err(OperateError::InvalidFileSystem);
+            }
+
+            let entries = (1..=header.entries as usize)
+                .map(|index| &node[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE]);
+            if header.depth == 0 {
+                let extent = entries
+                    .map(Extent::from_bytes)
+                    .find(|extent| extent.contains(logical));
+                // uninitialised extents read as zeros, just like holes
+                return Ok(extent.filter(|extent| extent.initialized).map(|extent| {
+                    let skip = (logical - extent.block) as u64;
+                    (extent.start + skip, extent.len as u64 - skip)
+                }));
+            }
+
+            // the child covering `logical` is the last one starting at or before it
+            let Some(index) = entries
+                .map(ExtentIndex::from_bytes)
+                .take_while(|index| index.block <= logical)
+                .last()
+            else {
+                return Ok(None);
+            };
+            parent_depth = Some(header.depth);
+            in_inode = false;
+            self.read_block(index.leaf)?;
+        }
+    }
+
+    fn map_indirect(&self, inode: &Inode, logical: u32) -> Result<Option<u64>, OperateError> {
+        let block_size = self.block_size();
+        let pointers_per_block = (block_size / 4) as u64;
+        let mut logical = logical as u64;
+
+        if logical < DIRECT_BLOCKS as u64 {
+            let block = inode.block_pointer(logical as usize);
+            return Ok((block != 0).then_some(block as u64));
+        }
+        logical -= DIRECT_BLOCKS as u64;
+
+        // single, double and triple indirect
+        let mut span = 1;
+        for level in 0..3 {
+            span *= pointers_per_block;
+            if logical >= span {
+                logical -= span;
+                continue;
+            }
+
+            let mut block = inode.block_pointer(DIRECT_BLOCKS + level);
+            let mut covered = span;
+            for _ in 0..=level {
+                if block == 0 {
+                    return Ok(None);
+                }
+                covered /= pointers_per_block;
+                let entry = logical / covered;
+                logical %= covered;
+                block = self.read_u32(block as usize * block_size + entry as usize * 4)?;
+            }
+            return Ok((block != 0).then_some(block as u64));
+        }
+        Ok(None)
+    }
+
+    /// Read the contents of `inode` from byte `offset` straight into `buffer`.
+    ///
+    /// Returns the bytes read, short only at the end of the file.
+    pub fn read_file(
+        &mut self,
+        inode: &Inode,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, OperateError> {
+        let size = inode.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = (size - offset).min(buffer.len() as u64) as usize;
+
+        if inode.has_flag(EXT4_INLINE_DATA_FL) {
+            // data past i_block lives in an extended attribute, which is not read here
+            if size > BLOCK_SIZE as u64 {
+                return Err(OperateError::InvalidFileSystem);
+            }
+            let offset = offset as usize;
+            buffer[..len].copy_from_slice(&inode.block()[offset..offset + len]);
+            return Ok(len);
+        }
+
+        let block_size = self.block_size() as u64;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = position % block_size;
+            let logical = u32::try_from(position / block_size)
+                .map_err(|_| OperateError::InvalidFileSystem)?;
+
+            match self.map(inode, logical)? {
+                Some((block, contiguous)) => {
+                    let available = (contiguous * block_size - within).min((len - done) as u64);
+                    let target = &mut buffer[done..done + available as usize];
+                    self.read((block * block_size + within) as usize, target)?;
+                    done += available as usize;
+                }
+                None => {
+                    let available = (block_size - within).min((len - done) as u64) as usize;
+                    buffer[done..done + available].fill(0);
+                    done += available;
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    /// Call `visit` with the inode number and name of each entry of `directory`,
+    /// `.` and `..` included, until it returns `false`.
+    ///
+    /// Hashed directories are walked linearly, their index blocks look like empty entries.
+    pub fn read_dir(
+        &mut self,
+        directory: &Inode,
+        mut visit: impl FnMut(u32, &[u8]) -> bool,
+    ) -> Result<(), OperateError> {
+        if !directory.is_dir() {
+            return Err(OperateError::Fault);
+        }
+        if directory.has_flag(EXT4_INLINE_DATA_FL) {
+            return Err(OperateError::InvalidFileSystem);
+        }
+
+        let block_size = self.block_size();
+        let has_file_type = self.super_block.has_incompat(FEATURE_INCOMPAT_FILETYPE);
+        let blocks = directory.size().div_ceil(block_size as u64);
+        for logical in 0..blocks as u32 {
+            let Some(block) = self.map_block(directory, logical)? else {
+                continue;
+            };
+            self.read_block(block)?;
+
+            let mut offset = 0;
+            while offset + ENTRY_HEADER <= block_size {
+                let raw = &self.buffer[offset..block_size];
+                let entry = DirectoryEntry::from_bytes(raw, has_file_type)
+                    .ok_or(OperateError::InvalidFileSystem)?;
+                if entry.inode != 0 && !visit(entry.inode, entry.name(raw)) {
+                    return Ok(());
+                }
+                offset += entry.record_len;
+            }
+        }
+        Ok(())
+    }
+
+    /// Inode number of the entry called `name` in `directory`.
+    pub fn lookup(&mut self, directory: &Inode, name: &str) -> Result<Option<u32>, OperateError> {
+        let mut found = None;
+        self.read_dir(directory, |inode, entry| {
+            if entry == name.as_bytes() {
+                found = Some(inode);
+            }
+            found.is_none()
+        })?;
+        Ok(found)
+    }
+
+    /// Resolve an absolute `/` separated path, symbolic links are not followed.
+    pub fn open(&mut self, path: &str) -> Result<Option<Inode>, OperateError> {
+        let mut inode = self.inode(ROOT_INODE)?;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if !inode.is_dir() {
+                return Ok(None);
+            }
+            let Some(number) = self.lookup(&inode, name)? else {
+                return Ok(None);
+            };
+            inode = self.inode(number)?;
+        }
+        Ok(Some(inode))
+    }
+}
@@ -0,0 +1,547 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use canicula_common::fs::OperateError;
+
+use crate::reader::{DIRECT_BLOCKS, MAX_EXTENT_DEPTH};
+use crate::stats::Metadata;
+use crate::types::data_block_bitmap::Bitmap;
+use crate::types::directory::{self, DirectoryEntry, ENTRY_HEADER, TAIL_SIZE};
+use crate::types::extent::{Extent, ExtentHeader, ExtentIndex, ENTRY_SIZE};
+use crate::types::group_descriptors::{BG_BLOCK_UNINIT, BG_INODE_UNINIT, MAX_DESC_SIZE};
+use crate::types::inode_table::*;
+use crate::types::super_block::*;
+use crate::{Ext4FS, GROUP_ZERO_PADDING};
+
+/// One problem found by [`Ext4FS::scrub`].
+///
+/// Tree nodes are given by block number, `0` stands for the root in `i_block`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Issue {
+    SuperBlockChecksum,
+    DescriptorChecksum {
+        group: u32,
+    },
+    BlockBitmapChecksum {
+        group: u32,
+    },
+    InodeBitmapChecksum {
+        group: u32,
+    },
+    InodeChecksum {
+        inode: u32,
+    },
+    /// Bad magic, more entries than fit or a depth not matching the parent.
+    ExtentHeader {
+        inode: u32,
+        node: u64,
+    },
+    ExtentChecksum {
+        inode: u32,
+        node: u64,
+    },
+    /// Entries out of order, overlapping or outside the range of the parent index.
+    ExtentOrder {
+        inode: u32,
+        node: u64,
+    },
+    /// A data or tree block outside the filesystem.
+    BlockRange {
+        inode: u32,
+        block: u64,
+    },
+    /// An entry that does not fit its block or names an inode that cannot exist,
+    /// `block` is the logical block of the directory.
+    DirectoryEntry {
+        inode: u32,
+        block: u32,
+        offset: usize,
+    },
+    DirectoryChecksum {
+        inode: u32,
+        block: u32,
+    },
+    /// The first block does not start with `.` and `..`.
+    DirectoryDots {
+        inode: u32,
+    },
+}
+
+/// What a scrub looked at and everything it found wrong.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ScrubReport {
+    pub groups: u32,
+    /// Inodes marked in use.
+    pub inodes: u64,
+    pub directories: u64,
+    pub directory_entries: u64,
+    /// Extent tree and indirect blocks read.
+    pub map_blocks: u64,
+    pub issues: Vec<Issue>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+fn u16_at(raw: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([raw[offset], raw[offset + 1]])
+}
+
+fn u32_at(raw: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        raw[offset],
+        raw[offset + 1],
+        raw[offset + 2],
+        raw[offset + 3],
+    ])
+}
+
+/// Whether `i_extra_isize` of the on-disk inode `raw` covers the high half of its checksum.
+pub(crate) fn has_checksum_hi(raw: &[u8]) -> bool {
+    raw.len() > INODE_CORE_SIZE
+        && INODE_CORE_SIZE + u16_at(raw, EXTRA_ISIZE) as usize >= CHECKSUM_HI + 2
+}
+
+impl<const SIZE: usize> Ext4FS<SIZE> {
+    /// Verify checksums, block maps and directories of the whole filesystem.
+    ///
+    /// Nothing is written and no state is cached, so it is safe to run on a live
+    /// mount now and then. Only what is on disk is checked, pending changes have to
+    /// be flushed first or this fails with [`OperateError::Fault`]. I/O errors end
+    /// the scrub, corruption only ends up in the report.
+    pub fn scrub(&self) -> Result<ScrubReport, OperateError> {
+        let mut report = ScrubReport::default();
+        self.scrub_super_block(&mut report)?;
+        for group in 0..self.group_count() {
+            self.scrub_group(group, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    /// The superblock part of [`Ext4FS::scrub`].
+    pub fn scrub_super_block(&self, report: &mut ScrubReport) -> Result<(), OperateError> {
+        if self.super_block_dirty {
+            return Err(OperateError::Fault);
+        }
+        if self.has_metadata_csum() {
+            let offset = SuperBlock::Checksum.offset();
+            let mut raw = vec![0u8; SUPER_BLOCK_SIZE];
+            self.read(Metadata::SuperBlock, GROUP_ZERO_PADDING, &mut raw)?;
+            if self.crc32c(!0, &raw[..offset]) != u32_at(&raw, offset) {
+                report.issues.push(Issue::SuperBlockChecksum);
+            }
+        }
+        Ok(())
+    }
+
+    /// Scrub a single group: its descriptor, bitmaps and the inodes in use.
+    ///
+    /// [`Ext4FS::scrub`] one group at a time, for callers spreading the work out.
+    pub fn scrub_group(&self, group: u32, report: &mut ScrubReport) -> Result<(), OperateError> {
+        let state = &self.groups[group as usize];
+        if state.descriptor_dirty || state.block_bitmap_dirty || state.inode_bitmap_dirty {
+            return Err(OperateError::Fault);
+        }
+        let descriptor = &state.descriptor;
+        report.groups += 1;
+
+        if self.has_group_csum()
+            && self.descriptor_checksum(group, descriptor) != descriptor.checksum()
+        {
+            report.issues.push(Issue::DescriptorChecksum { group });
+        }
+        // uninitialised bitmaps are not on disk
+        if self.has_metadata_csum() && !descriptor.has_flag(BG_BLOCK_UNINIT) {
+            let bitmap = Bitmap::from_bytes(self.read_blocks(
+                Metadata::BlockBitmap,
+                descriptor.block_bitmap(),
+                1,
+            )?);
+            let bits = self.sb().s_clusters_per_group;
+            if !self.bitmap_checksum_matches(&bitmap, bits, descriptor.block_bitmap_checksum()) {
+                report.issues.push(Issue::BlockBitmapChecksum { group });
+            }
+        }
+        if descriptor.has_flag(BG_INODE_UNINIT) && self.has_group_csum() {
+            return Ok(());
+        }
+
+        let inodes_per_group = self.sb().s_inodes_per_group;
+        let bitmap = Bitmap::from_bytes(self.read_blocks(
+            Metadata::InodeBitmap,
+            descriptor.inode_bitmap(),
+            1,
+        )?);
+        if self.has_metadata_csum()
+            && !self.bitmap_checksum_matches(
+                &bitmap,
+                inodes_per_group,
+                descriptor.inode_bitmap_checksum(),
+            )
+        {
+            report.issues.push(Issue::InodeBitmapChecksum { group });
+        }
+
+        // the tail of the table past `itable_unused` may never have been written
+        let used = if self.has_group_csum() {
+            inodes_per_group.saturating_sub(descriptor.itable_unused())
+        } else {
+            inodes_per_group
+        } as usize;
+        let inode_size = self.sb().inode_size();
+        let inodes_per_block = self.block_size() / inode_size;
+        for table_block in 0..used.div_ceil(inodes_per_block) {
+            let first = table_block * inodes_per_block;
+            let indexes = first..(first + inodes_per_block).min(used);
+            if !indexes.clone().any(|index| bitmap.get(index)) {
+                continue;
+            }
+            let raw = self.read_blocks(
+                Metadata::InodeTable,
+                descriptor.inode_table() + table_block as u64,
+                1,
+            )?;
+            for index in indexes.filter(|&index| bitmap.get(index)) {
+                let offset = (index - first) * inode_size;
+                let number = group * inodes_per_group + index as u32 + 1;
+                self.scrub_inode(number, &raw[offset..offset + inode_size], report)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn bitmap_checksum_matches(&self, bitmap: &Bitmap, bits: u32, stored: u32) -> bool {
+        let checksum = self.bitmap_checksum(bitmap, bits);
+        // 32 byte descriptors only keep the low half
+        if self.sb().desc_size() >= MAX_DESC_SIZE {
+            checksum == stored
+        } else {
+            checksum as u16 == stored as u16
+        }
+    }
+
+    /// Seed of the checksums of an inode and of the blocks it owns.
+    pub(crate) fn inode_checksum_seed(&self, number: u32, raw: &[u8]) -> u32 {
+        let crc = self.crc32c(self.checksum_seed(), &number.to_le_bytes());
+        self.crc32c(crc, &raw[GENERATION..GENERATION + 4])
+    }
+
+    /// Checksum of the whole on-disk inode `raw`, only the low 16 bits when
+    /// `i_extra_isize` leaves no room for the rest.
+    pub(crate) fn inode_checksum(&self, seed: u32, raw: &[u8]) -> u32 {
+        let crc = self.crc32c(seed, &raw[..CHECKSUM_LO]);
+        let crc = self.crc32c(crc, &[0, 0]);
+        let mut crc = self.crc32c(crc, &raw[CHECKSUM_LO + 2..INODE_CORE_SIZE]);
+        if raw.len() > INODE_CORE_SIZE {
+            crc = self.crc32c(crc, &raw[INODE_CORE_SIZE..CHECKSUM_HI]);
+            let rest = if has_checksum_hi(raw) {
+                crc = self.crc32c(crc, &[0, 0]);
+                CHECKSUM_HI + 2
+            } else {
+                CHECKSUM_HI
+            };
+            crc = self.crc32c(crc, &raw[rest..]);
+        }
+        if has_checksum_hi(raw) {
+            crc
+        } else {
+            crc & 0xffff
+        }
+    }
+
+    fn inode_checksum_matches(&self, seed: u32, raw: &[u8]) -> bool {
+        let mut stored = u16_at(raw, CHECKSUM_LO) as u32;
+        if has_checksum_hi(raw) {
+            stored |= (u16_at(raw, CHECKSUM_HI) as u32) << 16;
+        }
+        self.inode_checksum(seed, raw) == stored
+    }
+
+    /// Whether `count` blocks from `start` lie within the filesystem.
+    fn in_filesystem(&self, start: u64, count: u64) -> bool {
+        start >= self.sb().s_first_data_block as u64
+            && start
+                .checked_add(count)
+                .is_some_and(|end| end <= self.sb().blocks_count())
+    }
+
+    fn scrub_inode(
+        &self,
+        number: u32,
+        raw: &[u8],
+        report: &mut ScrubReport,
+    ) -> Result<(), OperateError> {
+        report.inodes += 1;
+        let inode = Inode::from_bytes(number, raw);
+        let seed = self.inode_checksum_seed(number, raw);
+        if self.has_metadata_csum() && !self.inode_checksum_matches(seed, raw) {
+            report.issues.push(Issue::InodeChecksum { inode: number });
+        }
+
+        // only directories need their blocks afterwards
+        let mut extents = Vec::new();
+        let is_fast_symlink = inode.is_symlink()
+            && !inode.has_flag(EXT4_EXTENTS_FL)
+            && inode.size() < BLOCK_SIZE as u64;
+        if inode.has_flag(EXT4_INLINE_DATA_FL) {
+            return Ok(());
+        } else if inode.has_flag(EXT4_EXTENTS_FL) {
+            self.scrub_extent_tree(&inode, seed, &mut extents, report)?;
+        } else if (inode.is_file() || inode.is_dir() || inode.is_symlink()) && !is_fast_symlink {
+            self.scrub_block_map(&inode, &mut extents, report)?;
+        }
+
+        if inode.is_dir() {
+            extents.sort_unstable_by_key(|extent| extent.block);
+            self.scrub_directory(&inode, seed, &extents, report)?;
+        }
+        Ok(())
+    }
+
+    fn scrub_extent_tree(
+        &self,
+        inode: &Inode,
+        seed: u32,
+        extents: &mut Vec<Extent>,
+        report: &mut ScrubReport,
+    ) -> Result<(), OperateError> {
+        let number = inode.number();
+        // node, the depth its parent expects and the logical blocks it may cover
+        let mut pending = vec![(0u64, None, 0u64, u32::MAX as u64 + 1)];
+        while let Some((node, parent_depth, first, end)) = pending.pop() {
+            let block;
+            let raw = if node == 0 {
+                inode.block()
+            } else {
+                report.map_blocks += 1;
+                block = self.read_blocks(Metadata::BlockMap, node, 1)?;
+                &block[..]
+            };
+
+            let header = ExtentHeader::from_bytes(raw);
+            if !header.is_valid(raw.len())
+                || header.depth > MAX_EXTENT_DEPTH
+                || parent_depth.is_some_and(|depth: u16| header.depth + 1 != depth)
+            {
+                report.issues.push(Issue::ExtentHeader {
+                    inode: number,
+                    node,
+                });
+                continue;
+            }
+            if node != 0 && self.has_metadata_csum() {
+                let tail = ENTRY_SIZE * (1 + header.max as usize);
+                if tail + 4 > raw.len() || self.crc32c(seed, &raw[..tail]) != u32_at(raw, tail) {
+                    report.issues.push(Issue::ExtentChecksum {
+                        inode: number,
+                        node,
+                    });
+                }
+            }
+
+            let entries = (1..=header.entries as usize)
+                .map(|index| &raw[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE]);
+            let mut next = first;
+            if header.depth == 0 {
+                for extent in entries.map(Extent::from_bytes) {
+                    let extent_end = extent.block as u64 + extent.len as u64;
+                    if extent.len == 0 || (extent.block as u64) < next || extent_end > end {
+                        report.issues.push(Issue::ExtentOrder {
+                            inode: number,
+                            node,
+                        });
+                        break;
+                    }
+                    next = extent_end;
+                    if !self.in_filesystem(extent.start, extent.len as u64) {
+                        report.issues.push(Issue::BlockRange {
+                            inode: number,
+                            block: extent.start,
+                        });
+                    } else if inode.is_dir() {
+                        extents.push(extent);
+                    }
+                }
+                continue;
+            }
+
+            let indexes = entries.map(ExtentIndex::from_bytes).collect::<Vec<_>>();
+            for (position, index) in indexes.iter().enumerate() {
+                if (index.block as u64) < next || index.block as u64 >= end {
+                    report.issues.push(Issue::ExtentOrder {
+                        inode: number,
+                        node,
+                    });
+                    break;
+                }
+                next = index.block as u64 + 1;
+                if !self.in_filesystem(index.leaf, 1) {
+                    report.issues.push(Issue::BlockRange {
+                        inode: number,
+                        block: index.leaf,
+                    });
+                    continue;
+                }
+                let child_end = indexes
+                    .get(position + 1)
+                    .map_or(end, |next| next.block as u64);
+                pending.push((
+                    index.leaf,
+                    Some(header.depth),
+                    index.block as u64,
+                    child_end,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the pointers of a classic block map, 12 direct then single, double and
+    /// triple indirect.
+    fn scrub_block_map(
+        &self,
+        inode: &Inode,
+        extents: &mut Vec<Extent>,
+        report: &mut ScrubReport,
+    ) -> Result<(), OperateError> {
+        let pointers_per_block = (self.block_size() / 4) as u64;
+        let mut first = 0;
+        for index in 0..DIRECT_BLOCKS + 3 {
+            let level = index.saturating_sub(DIRECT_BLOCKS - 1) as u32;
+            let block = inode.block_pointer(index) as u64;
+            if block != 0 {
+                self.scrub_indirect(inode, block, level, first, extents, report)?;
+            }
+            first += pointers_per_block.pow(level);
+        }
+        Ok(())
+    }
+
+    /// `block` maps logical blocks from `first` on through `level` levels of indirection.
+    fn scrub_indirect(
+        &self,
+        inode: &Inode,
+        block: u64,
+        level: u32,
+        first: u64,
+        extents: &mut Vec<Extent>,
+        report: &mut ScrubReport,
+    ) -> Result<(), OperateError> {
+        if !self.in_filesystem(block, 1) {
+            report.issues.push(Issue::BlockRange {
+                inode: inode.number(),
+                block,
+            });
+            return Ok(());
+        }
+        if level == 0 {
+            if inode.is_dir() {
+                if let Ok(logical) = u32::try_from(first) {
+                    extents.push(Extent {
+                        block: logical,
+                        len: 1,
+                        start: block,
+                        initialized: true,
+                    });
+                }
+            }
+            return Ok(());
+        }
+
+        report.map_blocks += 1;
+        let raw = self.read_blocks(Metadata::BlockMap, block, 1)?;
+        let span = ((raw.len() / 4) as u64).pow(level - 1);
+        for (index, pointer) in raw.chunks_exact(4).enumerate() {
+            let pointer = u32_at(pointer, 0) as u64;
+            if pointer != 0 {
+                let first = first + index as u64 * span;
+                self.scrub_indirect(inode, pointer, level - 1, first, extents, report)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Walk the entries of every block of `directory`, mapped by `extents` sorted by
+    /// logical block. Index blocks of hashed directories parse as empty entries, their
+    /// own checksums are not verified.
+    fn scrub_directory(
+        &self,
+        directory: &Inode,
+        seed: u32,
+        extents: &[Extent],
+        report: &mut ScrubReport,
+    ) -> Result<(), OperateError> {
+        let number = directory.number();
+        let block_size = self.block_size();
+        let blocks = directory.size().div_ceil(block_size as u64);
+        let has_file_type = self.sb().has_incompat(FEATURE_INCOMPAT_FILETYPE);
+        report.directories += 1;
+
+        if extents.first().is_none_or(|extent| extent.block != 0) {
+            report.issues.push(Issue::DirectoryDots { inode: number });
+        }
+        let blocks_in_use = extents
+            .iter()
+            .filter(|extent| extent.initialized)
+            .flat_map(|extent| (0..extent.len as u32).map(move |skip| (extent, skip)));
+        for (extent, skip) in blocks_in_use {
+            let logical = extent.block + skip;
+            if logical as u64 >= blocks {
+                break;
+            }
+            let raw = self.read_blocks(Metadata::Data, extent.start + skip as u64, 1)?;
+
+            let has_tail = self.has_metadata_csum() && directory::has_tail(&raw);
+            let limit = if has_tail {
+                block_size - TAIL_SIZE
+            } else {
+                block_size
+            };
+            let mut names = Vec::new();
+            let mut offset = 0;
+            while offset + ENTRY_HEADER <= limit {
+                let entry = DirectoryEntry::from_bytes(&raw[offset..limit], has_file_type)
+                    .filter(|entry| entry.inode <= self.sb().s_inodes_count);
+                let Some(entry) = entry else {
+                    report.issues.push(Issue::DirectoryEntry {
+                        inode: number,
+                        block: logical,
+                        offset,
+                    });
+                    break;
+                };
+                if logical == 0 && names.len() < 2 {
+                    names.push((entry.inode, entry.name(&raw[offset..]).to_vec()));
+                }
+                if entry.inode != 0 {
+                    report.directory_entries += 1;
+                }
+                offset += entry.record_len;
+            }
+
+            let has_dots = names.len() == 2
+                && names[0] == (number, b".".to_vec())
+                && names[1].0 != 0
+                && names[1].1 == b"..";
+            if logical == 0 && !has_dots {
+                report.issues.push(Issue::DirectoryDots { inode: number });
+            }
+
+            if self.has_metadata_csum() {
+                let matches = has_tail
+                    && self.crc32c(seed, &raw[..block_size - TAIL_SIZE])
+                        == directory::tail_checksum(&raw);
+                if !matches && (has_tail || !directory.has_flag(EXT4_INDEX_FL)) {
+                    report.issues.push(Issue::DirectoryChecksum {
+                        inode: number,
+                        block: logical,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
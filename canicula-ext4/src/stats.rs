@@ -0,0 +1,91 @@
+/// On-disk structures the filesystem issues I/O for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metadata {
+    SuperBlock,
+    GroupDescriptors,
+    BlockBitmap,
+    InodeBitmap,
+    /// Read by [`crate::Ext4FS::scrub`] and [`crate::Ext4FS::write_at`], written by the latter.
+    InodeTable,
+    /// Extent tree and indirect blocks, read by [`crate::Ext4FS::scrub`] and
+    /// [`crate::Ext4FS::write_at`].
+    BlockMap,
+    /// File contents, written by [`crate::Ext4FS::write_blocks`] and
+    /// [`crate::Ext4FS::write_at`], and directory blocks.
+    Data,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoCounters {
+    /// Device requests, a batched read of several blocks counts once.
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// Counters since mount or the last [`crate::Ext4FS::reset_stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FsStats {
+    pub super_block: IoCounters,
+    pub group_descriptors: IoCounters,
+    pub block_bitmaps: IoCounters,
+    pub inode_bitmaps: IoCounters,
+    pub inode_tables: IoCounters,
+    pub block_maps: IoCounters,
+    pub data: IoCounters,
+    pub blocks_allocated: u64,
+    pub inodes_allocated: u64,
+    /// Blocks a failed write allocated and could not give back.
+    pub blocks_leaked: u64,
+    /// Groups tried by the allocator that could not satisfy the request.
+    pub allocation_retries: u64,
+    /// Bitmap lookups served from memory.
+    pub bitmap_hits: u64,
+    /// Bitmap lookups that had to read or build the bitmap first.
+    pub bitmap_misses: u64,
+}
+
+impl FsStats {
+    pub fn io(&self, kind: Metadata) -> &IoCounters {
+        match kind {
+            Metadata::SuperBlock => &self.super_block,
+            Metadata::GroupDescriptors => &self.group_descriptors,
+            Metadata::BlockBitmap => &self.block_bitmaps,
+            Metadata::InodeBitmap => &self.inode_bitmaps,
+            Metadata::InodeTable => &self.inode_tables,
+            Metadata::BlockMap => &self.block_maps,
+            Metadata::Data => &self.data,
+        }
+    }
+
+    fn io_mut(&mut self, kind: Metadata) -> &mut IoCounters {
+        match kind {
+            Metadata::SuperBlock => &mut self.super_block,
+            Metadata::GroupDescriptors => &mut self.group_descriptors,
+            Metadata::BlockBitmap => &mut self.block_bitmaps,
+            Metadata::InodeBitmap => &mut self.inode_bitmaps,
+            Metadata::InodeTable => &mut self.inode_tables,
+            Metadata::BlockMap => &mut self.block_maps,
+            Metadata::Data => &mut self.data,
+        }
+    }
+
+    pub(crate) fn record_read(&mut self, kind: Metadata, bytes: usize) {
+        let io = self.io_mut(kind);
+        io.reads += 1;
+        io.bytes_read += bytes as u64;
+    }
+
+    pub(crate) fn record_write(&mut self, kind: Metadata, bytes: usize) {
+        let io = self.io_mut(kind);
+        io.writes += 1;
+        io.bytes_written += bytes as u64;
+    }
+
+    /// Percentage of bitmap lookups served from memory, `None` before the first lookup.
+    pub fn bitmap_hit_rate(&self) -> Option<u64> {
+        let lookups = self.bitmap_hits + self.bitmap_misses;
+        (lookups > 0).then(|| self.bitmap_hits * 100 / lookups)
+    }
+}
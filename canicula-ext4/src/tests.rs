@@ -16,4 +16,1024 @@ mod test {
 
         let _fs: Ext4FS<1024> = Ext4FS::new(read_byte, write_byte);
     }
+
+    #[test]
+    fn clock() {
+        use crate::Ext4FS;
+        use canicula_common::fs::OperateError;
+
+        let read_byte = |_offset: usize| -> Result<u8, OperateError> { Ok(0) };
+        let write_byte = |_byte: u8, _offset: usize| -> Result<usize, OperateError> { Ok(1) };
+
+        let fs: Ext4FS<1024> = Ext4FS::new(read_byte, write_byte);
+        assert_eq!(fs.now(), 0);
+
+        let fs = fs.with_clock(|| 1_700_000_000);
+        assert_eq!(fs.now(), 1_700_000_000);
+    }
+
+    use std::cell::{Cell, RefCell};
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use canicula_common::fs::OperateError;
+
+    thread_local! {
+        static IMAGE: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        static READS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Format a fresh image with mkfs.ext4, `None` when e2fsprogs is not installed.
+    fn mkfs(name: &str, args: &[&str], size: &str) -> Option<PathBuf> {
+        let path =
+            std::env::temp_dir().join(format!("canicula-ext4-{}-{}.img", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let status = Command::new("mkfs.ext4")
+            .args(["-F", "-q"])
+            .args(args)
+            .arg(&path)
+            .arg(size)
+            .status()
+            .ok()?;
+        assert!(status.success(), "mkfs.ext4 failed");
+        Some(path)
+    }
+
+    fn load(path: &PathBuf) {
+        IMAGE.with(|image| *image.borrow_mut() = std::fs::read(path).unwrap());
+        READS.with(|reads| reads.set(0));
+    }
+
+    fn store(path: &PathBuf) {
+        IMAGE.with(|image| std::fs::write(path, &*image.borrow()).unwrap());
+    }
+
+    fn fsck(path: &PathBuf) -> bool {
+        Command::new("e2fsck")
+            .args(["-f", "-n"])
+            .arg(path)
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    }
+
+    fn read_byte(offset: usize) -> Result<u8, OperateError> {
+        IMAGE.with(|image| image.borrow().get(offset).copied().ok_or(OperateError::IO))
+    }
+
+    fn write_byte(byte: u8, offset: usize) -> Result<usize, OperateError> {
+        IMAGE.with(|image| {
+            *image.borrow_mut().get_mut(offset).ok_or(OperateError::IO)? = byte;
+            Ok(1)
+        })
+    }
+
+    fn read_bytes(offset: usize, buffer: &mut [u8]) -> Result<usize, OperateError> {
+        READS.with(|reads| reads.set(reads.get() + 1));
+        IMAGE.with(|image| {
+            let image = image.borrow();
+            let source = image
+                .get(offset..offset + buffer.len())
+                .ok_or(OperateError::IO)?;
+            buffer.copy_from_slice(source);
+            Ok(buffer.len())
+        })
+    }
+
+    fn write_bytes(offset: usize, buffer: &[u8]) -> Result<usize, OperateError> {
+        IMAGE.with(|image| {
+            let mut image = image.borrow_mut();
+            let target = image
+                .get_mut(offset..offset + buffer.len())
+                .ok_or(OperateError::IO)?;
+            target.copy_from_slice(buffer);
+            Ok(buffer.len())
+        })
+    }
+
+    fn open() -> crate::Ext4FS<1024> {
+        let mut fs =
+            crate::Ext4FS::new(read_byte, write_byte).with_block_io(read_bytes, write_bytes);
+        fs.mount().expect("mount failed");
+        fs
+    }
+
+    #[test]
+    fn mount() {
+        let Some(path) = mkfs("mount", &["-b", "1024", "-g", "1024", "-G", "4"], "8M") else {
+            return;
+        };
+        load(&path);
+
+        let fs = open();
+        let sb = fs.super_block().unwrap();
+        assert_eq!(sb.s_magic, 0xef53);
+        assert_eq!(sb.block_size(), 1024);
+        assert_eq!(sb.blocks_count(), 8192);
+        assert_eq!(fs.group_count(), 8);
+        assert!((0..8).all(|group| !fs.is_block_bitmap_loaded(group)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn prefetch() {
+        let Some(path) = mkfs(
+            "prefetch",
+            &["-b", "1024", "-g", "256", "-G", "16", "-N", "512"],
+            "8M",
+        ) else {
+            return;
+        };
+        load(&path);
+
+        let mut lazy = open();
+        let mut prefetched = open();
+        READS.with(|reads| reads.set(0));
+        prefetched.prefetch().unwrap();
+        let prefetch_reads = READS.with(|reads| reads.get());
+
+        // 32 groups in 2 flex groups, block and inode bitmaps are each one run per flex group
+        assert_eq!(prefetched.group_count(), 32);
+        assert!(
+            prefetch_reads <= 4,
+            "prefetch issued {} reads",
+            prefetch_reads
+        );
+        for group in 0..prefetched.group_count() {
+            assert!(prefetched.is_block_bitmap_loaded(group));
+            assert!(prefetched.is_inode_bitmap_loaded(group));
+        }
+
+        for block in [1, 300, 4096, 8191] {
+            assert_eq!(lazy.is_block_used(block), prefetched.is_block_used(block));
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn allocate() {
+        let Some(path) = mkfs(
+            "allocate",
+            &["-b", "4096", "-g", "4096", "-O", "metadata_csum,64bit"],
+            "64M",
+        ) else {
+            return;
+        };
+        load(&path);
+
+        let mut fs = open();
+        let free_blocks = fs.super_block().unwrap().free_blocks_count();
+        let free_inodes = fs.super_block().unwrap().s_free_inodes_count;
+
+        // allocating from a group only loads that group's bitmap
+        let block = fs.allocate_block(2).unwrap();
+        assert_eq!((block - 2 * 4096) / 4096, 0);
+        assert!(fs.is_block_bitmap_loaded(2));
+        assert!(!fs.is_block_bitmap_loaded(3));
+        let inode = fs.allocate_inode(1, false).unwrap();
+        fs.flush().unwrap();
+
+        let mut fs = open();
+        assert!(fs.is_block_used(block).unwrap());
+        assert_eq!(
+            fs.super_block().unwrap().free_blocks_count(),
+            free_blocks - 1
+        );
+        assert_eq!(
+            fs.super_block().unwrap().s_free_inodes_count,
+            free_inodes - 1
+        );
+
+        fs.free_block(block).unwrap();
+        assert_eq!(fs.free_block(block), Err(OperateError::Fault));
+        fs.free_inode(inode, false).unwrap();
+        fs.flush().unwrap();
+        store(&path);
+
+        assert!(fsck(&path), "e2fsck reported errors");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_free_count() {
+        let Some(path) = mkfs("corrupt-free-count", &["-b", "1024", "-g", "1024"], "8M") else {
+            return;
+        };
+        load(&path);
+
+        // group 0's descriptor follows the superblock, claim one free block
+        let free_count = 2 * 1024 + 12;
+        write_bytes(free_count, &1u16.to_le_bytes()).unwrap();
+        let mut fs = open();
+        let free_blocks = fs.super_block().unwrap().free_blocks_count();
+        assert_eq!(
+            fs.allocate_blocks(0, 8),
+            Err(OperateError::InvalidFileSystem)
+        );
+
+        // nothing was taken, one block still fits the count
+        assert_eq!(fs.super_block().unwrap().free_blocks_count(), free_blocks);
+        assert_eq!(fs.allocate_blocks(0, 1).map(|(_, len)| len), Ok(1));
+        // the first data block is 1 with 1 KiB blocks, group 0 looks full now
+        let (block, _) = fs.allocate_blocks(0, 8).unwrap();
+        assert_eq!((block - 1) / 1024, 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_time() {
+        let Some(path) = mkfs("write-time", &["-b", "1024", "-O", "metadata_csum"], "8M") else {
+            return;
+        };
+        load(&path);
+        let mkfs_time = open().super_block().unwrap().s_wtime;
+
+        // no clock, the write time mkfs left is kept
+        let mut fs = open();
+        let block = fs.allocate_block(0).unwrap();
+        fs.flush().unwrap();
+        assert_eq!(open().super_block().unwrap().s_wtime, mkfs_time);
+
+        let mut fs = open().with_clock(|| 1_700_000_000);
+        fs.free_block(block).unwrap();
+        fs.flush().unwrap();
+        assert_eq!(fs.super_block().unwrap().s_wtime, 1_700_000_000);
+        assert_eq!(open().super_block().unwrap().s_wtime, 1_700_000_000);
+        store(&path);
+
+        assert!(fsck(&path), "e2fsck reported errors");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_blocks() {
+        let Some(path) = mkfs(
+            "write-blocks",
+            &["-b", "1024", "-g", "1024", "-O", "metadata_csum,64bit"],
+            "8M",
+        ) else {
+            return;
+        };
+        load(&path);
+
+        let mut fs = open();
+        let free_blocks = fs.super_block().unwrap().free_blocks_count();
+        let data = (0..40 * 1024 + 100u32)
+            .map(|i| (i * 13 + i / 1024) as u8)
+            .collect::<Vec<u8>>();
+        let (head, tail) = data.split_at(5000);
+        let (middle, tail) = tail.split_at(3);
+
+        fs.reset_stats();
+        let extents = fs.write_blocks(3, &[head, middle, &[], tail]).unwrap();
+        let blocks = extents
+            .iter()
+            .map(|extent| extent.len as usize)
+            .sum::<usize>();
+        assert_eq!(blocks, 41);
+        assert_eq!(extents[0].block, 0);
+        assert!(extents
+            .windows(2)
+            .all(|pair| pair[1].block == pair[0].block + pair[0].len as u32));
+        // one device request per contiguous run, no metadata until the flush
+        let stats = fs.stats();
+        assert_eq!(stats.data.writes, extents.len() as u64);
+        assert_eq!(stats.blocks_allocated, 41);
+        assert_eq!(
+            stats.block_bitmaps.writes + stats.group_descriptors.writes,
+            0
+        );
+        fs.flush().unwrap();
+        assert_eq!(fs.stats().block_bitmaps.writes, 1);
+
+        let mut written = Vec::new();
+        for extent in &extents {
+            let mut run = vec![0u8; extent.len as usize * 1024];
+            read_bytes(extent.start as usize * 1024, &mut run).unwrap();
+            written.extend(run);
+        }
+        assert_eq!(&written[..data.len()], &data[..]);
+        assert!(written[data.len()..].iter().all(|&byte| byte == 0));
+
+        let mut fs = open();
+        assert_eq!(
+            fs.super_block().unwrap().free_blocks_count(),
+            free_blocks - 41
+        );
+        for extent in &extents {
+            for block in extent.start..extent.start + extent.len as u64 {
+                fs.free_block(block).unwrap();
+            }
+        }
+        fs.flush().unwrap();
+        store(&path);
+
+        assert!(fsck(&path), "e2fsck reported errors");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn write_at() {
+        use crate::Ext4Reader;
+
+        let root =
+            std::env::temp_dir().join(format!("canicula-ext4-write-at-{}.d", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let mut a = (0..3000u32).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        let mut b = b"hello, world\n".to_vec();
+        std::fs::write(root.join("a"), &a).unwrap();
+        std::fs::write(root.join("b"), &b).unwrap();
+        let Some(path) = mkfs(
+            "write-at",
+            &[
+                "-b",
+                "1024",
+                "-O",
+                "metadata_csum,64bit",
+                "-d",
+                root.to_str().unwrap(),
+            ],
+            "8M",
+        ) else {
+            return;
+        };
+        load(&path);
+
+        let mut scratch = [0u8; 1024];
+        let mut reader = Ext4Reader::mount(read_bytes, &mut scratch).unwrap();
+        let a_inode = reader.open("/a").unwrap().unwrap().number();
+        let b_inode = reader.open("/b").unwrap().unwrap().number();
+        let root_inode = reader.open("/").unwrap().unwrap().number();
+
+        fn apply(file: &mut Vec<u8>, offset: usize, data: &[u8]) {
+            if file.len() < offset + data.len() {
+                file.resize(offset + data.len(), 0);
+            }
+            file[offset..offset + data.len()].copy_from_slice(data);
+        }
+
+        let mut fs = open().with_clock(|| 1_700_000_000);
+        let free_blocks = fs.super_block().unwrap().free_blocks_count();
+        assert_eq!(
+            fs.write_at(root_inode, 0, &[b"x"]),
+            Err(OperateError::IsDirectory)
+        );
+
+        // partial blocks at both ends of the overwrite keep their neighbours
+        let patch = (0..700u32).map(|i| (i * 3 + 1) as u8).collect::<Vec<u8>>();
+        let (head, tail) = patch.split_at(200);
+        assert_eq!(fs.write_at(a_inode, 900, &[head, &[], tail]).unwrap(), 700);
+        apply(&mut a, 900, &patch);
+
+        // appending to both files in turn interleaves their blocks, so `a` needs
+        // more extents than fit in the inode
+        for round in 0..6u8 {
+            let chunk = vec![round + 1; 1500];
+            let end = a.len();
+            fs.write_at(a_inode, end as u64, &[&chunk]).unwrap();
+            apply(&mut a, end, &chunk);
+            let chunk = vec![round + 100; 1024];
+            let end = b.len();
+            fs.write_at(b_inode, end as u64, &[&chunk]).unwrap();
+            apply(&mut b, end, &chunk);
+        }
+        // past the end, leaving a hole
+        let end = 100 * 1024 + 10;
+        fs.write_at(a_inode, end as u64, &[b"after the hole"])
+            .unwrap();
+        apply(&mut a, end, b"after the hole");
+
+        let allocated = free_blocks - fs.super_block().unwrap().free_blocks_count();
+        assert_eq!(fs.stats().blocks_leaked, 0);
+        fs.flush().unwrap();
+        store(&path);
+        assert!(fsck(&path), "e2fsck reported errors");
+
+        load(&path);
+        let mut scratch = [0u8; 1024];
+        let mut reader = Ext4Reader::mount(read_bytes, &mut scratch).unwrap();
+        let inode = reader.open("/a").unwrap().unwrap();
+        assert_eq!(inode.size(), a.len() as u64);
+        // the extent tree moved into a leaf block
+        assert_eq!(u16::from_le_bytes([inode.block()[6], inode.block()[7]]), 1);
+        assert!(allocated > 0);
+
+        for (name, expected) in [("/a", &a), ("/b", &b)] {
+            let Ok(output) = Command::new("debugfs")
+                .args(["-R", &format!("cat {}", name)])
+                .arg(&path)
+                .output()
+            else {
+                return;
+            };
+            assert!(output.status.success(), "debugfs failed");
+            assert!(output.stdout == *expected, "{} differs", name);
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Reserved GDT blocks behind each descriptor table copy, as block ranges.
+    fn reserved_gdt_ranges(fs: &crate::Ext4FS<1024>) -> Vec<core::ops::Range<u64>> {
+        let sb = fs.super_block().unwrap();
+        let table_blocks = (sb.group_count() as u64).div_ceil(sb.descriptors_per_block());
+        (0..fs.group_count())
+            .filter(|&group| sb.has_super(group))
+            .map(|group| {
+                let start = sb.group_first_block(group) + 1 + table_blocks;
+                start..start + sb.s_reserved_gdt_blocks as u64
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reserved_gdt_blocks() {
+        let Some(path) = mkfs(
+            "reserved-gdt",
+            &["-b", "1024", "-g", "1024", "-O", "resize_inode"],
+            "8M",
+        ) else {
+            return;
+        };
+        load(&path);
+
+        let mut fs = open();
+        let reserved = reserved_gdt_ranges(&fs)[0].clone();
+        assert!(!reserved.is_empty());
+
+        // a bitmap that wrongly reports the reserved GDT blocks free
+        fs.load_bitmap(0, crate::BitmapKind::Block).unwrap();
+        let bitmap = fs.groups[0].block_bitmap.as_mut().unwrap();
+        for block in reserved.clone() {
+            bitmap.clear((block - 1) as usize);
+        }
+
+        while fs.groups[0].descriptor.free_blocks_count() > 0 {
+            let (start, len) = fs.allocate_blocks(0, 1024).unwrap();
+            let run = start..start + len as u64;
+            assert!(
+                run.end <= reserved.start || run.start >= reserved.end,
+                "allocated {:?} out of the reserved GDT blocks {:?}",
+                run,
+                reserved
+            );
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn preserve_untouched_bytes() {
+        use crate::types::super_block::SuperBlock;
+
+        let Some(path) = mkfs(
+            "preserve",
+            &[
+                "-b",
+                "1024",
+                "-g",
+                "1024",
+                "-O",
+                "resize_inode,metadata_csum,64bit",
+            ],
+            "8M",
+        ) else {
+            return;
+        };
+        load(&path);
+        let before = IMAGE.with(|image| image.borrow().clone());
+
+        let mut fs = open();
+        let mut extents = Vec::new();
+        for group in 0..fs.group_count() {
+            extents.push(fs.allocate_blocks(group, 300).unwrap());
+        }
+        let inode = fs.allocate_inode(3, false).unwrap();
+        fs.flush().unwrap();
+        fs.free_inode(inode, false).unwrap();
+        for (start, len) in extents {
+            for block in start..start + len as u64 {
+                fs.free_block(block).unwrap();
+            }
+        }
+        fs.flush().unwrap();
+        let after = IMAGE.with(|image| image.borrow().clone());
+
+        // only the free counts and the checksum of the primary superblock may change
+        let changed = [
+            SuperBlock::FreeBlocksCountLo,
+            SuperBlock::FreeBlocksCountHi,
+            SuperBlock::FreeInodesCount,
+            SuperBlock::Checksum,
+        ]
+        .map(|field| field.slice());
+        for offset in 0..crate::types::super_block::SUPER_BLOCK_SIZE {
+            if changed
+                .iter()
+                .any(|slice| (slice.offset..slice.offset + slice.size).contains(&offset))
+            {
+                continue;
+            }
+            assert_eq!(
+                before[1024 + offset],
+                after[1024 + offset],
+                "superblock byte {} changed",
+                offset
+            );
+        }
+
+        // backup superblocks, descriptor tables and reserved GDT blocks are left alone
+        let sb = fs.super_block().unwrap();
+        for group in (1..fs.group_count()).filter(|&group| sb.has_super(group)) {
+            let first = sb.group_first_block(group) as usize * 1024;
+            assert!(before[first..first + 2048] == after[first..first + 2048]);
+        }
+        for range in reserved_gdt_ranges(&fs) {
+            let bytes = range.start as usize * 1024..range.end as usize * 1024;
+            assert!(before[bytes.clone()] == after[bytes]);
+        }
+
+        store(&path);
+        assert!(fsck(&path), "e2fsck reported errors");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn checksum_backends() {
+        use crate::ChecksumBackend;
+
+        // the CRC32C check value, with the usual inversion around the raw update
+        for backend in [ChecksumBackend::Portable, ChecksumBackend::detect()] {
+            assert_eq!(!backend.crc32c(!0, b"123456789"), 0xe306_9283);
+        }
+        let data = (0..4096u32)
+            .map(|i| (i * 31 + i / 7) as u8)
+            .collect::<Vec<u8>>();
+        for len in (0..70).chain([256, 4095, 4096]) {
+            assert_eq!(
+                ChecksumBackend::detect().crc32c(0x1234_5678, &data[..len]),
+                ChecksumBackend::Portable.crc32c(0x1234_5678, &data[..len])
+            );
+        }
+
+        let Some(path) = mkfs(
+            "checksum",
+            &["-b", "4096", "-g", "4096", "-O", "metadata_csum,64bit"],
+            "32M",
+        ) else {
+            return;
+        };
+        load(&path);
+        let mut fs = open().with_checksum_backend(ChecksumBackend::Portable);
+        assert_eq!(fs.checksum_backend(), ChecksumBackend::Portable);
+        let block = fs.allocate_block(1).unwrap();
+        let inode = fs.allocate_inode(1, false).unwrap();
+        fs.flush().unwrap();
+        fs.free_block(block).unwrap();
+        fs.free_inode(inode, false).unwrap();
+        fs.flush().unwrap();
+        store(&path);
+
+        assert!(fsck(&path), "e2fsck reported errors");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stats() {
+        let Some(path) = mkfs("stats", &["-b", "1024", "-g", "1024", "-G", "4"], "8M") else {
+            return;
+        };
+        load(&path);
+
+        let mut fs = open();
+        let stats = fs.stats();
+        assert_eq!(stats.super_block.reads, 1);
+        assert_eq!(stats.group_descriptors.reads, 1);
+        assert_eq!(stats.bitmap_hit_rate(), None);
+
+        fs.reset_stats();
+        let block = fs.allocate_block(0).unwrap();
+        fs.free_block(block).unwrap();
+        fs.flush().unwrap();
+
+        let stats = fs.stats();
+        assert_eq!(stats.blocks_allocated, 1);
+        assert_eq!((stats.bitmap_misses, stats.bitmap_hits), (1, 1));
+        assert_eq!(stats.bitmap_hit_rate(), Some(50));
+        assert_eq!(stats.block_bitmaps.reads, 1);
+        assert_eq!(stats.block_bitmaps.writes, 1);
+        assert_eq!(stats.group_descriptors.writes, 1);
+        assert_eq!(stats.super_block.writes, 1);
+        assert_eq!(stats.inode_bitmaps, Default::default());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// A directory tree with a small file, a nested file and a multi-block file.
+    fn populate(name: &str) -> (PathBuf, Vec<u8>) {
+        let root =
+            std::env::temp_dir().join(format!("canicula-ext4-{}-{}.d", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("boot/efi")).unwrap();
+        std::fs::write(root.join("hello.txt"), b"hello, world\n").unwrap();
+        let kernel = (0..300 * 1024u32)
+            .map(|i| (i * 7 + i / 1024) as u8)
+            .collect::<Vec<u8>>();
+        std::fs::write(root.join("boot/kernel"), &kernel).unwrap();
+        (root, kernel)
+    }
+
+    #[test]
+    fn reader() {
+        use crate::Ext4Reader;
+
+        for (name, features) in [("reader", "extent"), ("reader-indirect", "^extent,^64bit")] {
+            let (root, kernel) = populate(name);
+            let Some(path) = mkfs(
+                name,
+                &["-b", "1024", "-O", features, "-d", root.to_str().unwrap()],
+                "8M",
+            ) else {
+                return;
+            };
+            load(&path);
+
+            let mut scratch = [0u8; 1024];
+            let mut reader = Ext4Reader::mount(read_bytes, &mut scratch).unwrap();
+            assert_eq!(reader.block_size(), 1024);
+
+            let hello = reader.open("/hello.txt").unwrap().unwrap();
+            let mut buffer = [0u8; 64];
+            assert_eq!(reader.read_file(&hello, 0, &mut buffer).unwrap(), 13);
+            assert_eq!(&buffer[..13], b"hello, world\n");
+
+            let file = reader.open("/boot/kernel").unwrap().unwrap();
+            assert!(file.is_file());
+            assert_eq!(file.size(), kernel.len() as u64);
+            let mut contents = vec![0u8; kernel.len() + 100];
+            assert_eq!(
+                reader.read_file(&file, 0, &mut contents).unwrap(),
+                kernel.len()
+            );
+            assert!(contents[..kernel.len()] == kernel[..]);
+
+            // unaligned reads spanning several blocks
+            let mut middle = [0u8; 5000];
+            reader.read_file(&file, 70_001, &mut middle).unwrap();
+            assert!(middle[..] == kernel[70_001..75_001]);
+
+            assert!(reader.open("/boot/efi").unwrap().unwrap().is_dir());
+
+            let boot = reader.open("/boot").unwrap().unwrap();
+            let mut names = Vec::new();
+            reader
+                .read_dir(&boot, |_, name| {
+                    names.push(String::from_utf8(name.to_vec()).unwrap());
+                    true
+                })
+                .unwrap();
+            names.sort();
+            assert_eq!(names, [".", "..", "efi", "kernel"]);
+            let mut visited = 0;
+            reader
+                .read_dir(&boot, |_, _| {
+                    visited += 1;
+                    false
+                })
+                .unwrap();
+            assert_eq!(visited, 1);
+            assert!(reader.open("/boot/missing").unwrap().is_none());
+            assert!(reader.open("/hello.txt/boot").unwrap().is_none());
+
+            std::fs::remove_dir_all(root).unwrap();
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn scrub() {
+        use crate::scrub::Issue;
+        use crate::Ext4Reader;
+
+        for (name, features) in [("scrub", "extent"), ("scrub-indirect", "^extent,^64bit")] {
+            let (root, _) = populate(name);
+            let Some(path) = mkfs(
+                name,
+                &["-b", "1024", "-O", features, "-d", root.to_str().unwrap()],
+                "8M",
+            ) else {
+                return;
+            };
+            load(&path);
+            let pristine = IMAGE.with(|image| image.borrow().clone());
+
+            let fs = open();
+            fs.reset_stats();
+            let report = fs.scrub().unwrap();
+            assert!(report.is_clean(), "{:?}", report.issues);
+            assert_eq!(report.groups, fs.group_count());
+            // root, lost+found, boot and boot/efi
+            assert_eq!(report.directories, 4);
+            assert!(report.inodes >= 13);
+            assert!(report.map_blocks > 0);
+            assert_eq!(fs.stats().inode_tables.writes, 0);
+            assert!(IMAGE.with(|image| *image.borrow() == pristine));
+
+            // flip a byte of /hello.txt's inode and break the first entry of /boot
+            let mut scratch = [0u8; 1024];
+            let mut reader = Ext4Reader::mount(read_bytes, &mut scratch).unwrap();
+            let hello = reader.open("/hello.txt").unwrap().unwrap().number();
+            let boot = reader.open("/boot").unwrap().unwrap();
+            let boot_block = reader.map_block(&boot, 0).unwrap().unwrap();
+            let sb = reader.super_block().clone();
+            let group = (hello - 1) / sb.s_inodes_per_group;
+            let index = (hello - 1) % sb.s_inodes_per_group;
+            let table = reader.group_descriptor(group).unwrap().inode_table();
+            let inode_offset = table as usize * 1024 + index as usize * sb.inode_size();
+            IMAGE.with(|image| {
+                let mut image = image.borrow_mut();
+                // i_mtime
+                image[inode_offset + 16] ^= 0xff;
+                // rec_len of `.`
+                image[boot_block as usize * 1024 + 4] = 3;
+            });
+
+            let fs = open();
+            let issues = fs.scrub().unwrap().issues;
+            assert!(issues.contains(&Issue::InodeChecksum { inode: hello }));
+            assert!(issues.contains(&Issue::DirectoryEntry {
+                inode: boot.number(),
+                block: 0,
+                offset: 0,
+            }));
+
+            std::fs::remove_dir_all(root).unwrap();
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn reader_rejects_small_buffer() {
+        let Some(path) = mkfs("reader-buffer", &["-b", "4096"], "8M") else {
+            return;
+        };
+        load(&path);
+
+        let mut scratch = [0u8; 1024];
+        assert!(matches!(
+            crate::Ext4Reader::mount(read_bytes, &mut scratch),
+            Err(OperateError::Fault)
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// One step of a [`differential`] script.
+    #[derive(Debug, Clone, Copy)]
+    enum Op {
+        /// `allocate_blocks(goal_group, count)`
+        AllocateBlocks(u32, usize),
+        /// `allocate_inode(goal_group, false)`
+        AllocateInode(u32),
+        /// Free the `n`th block the script allocated.
+        FreeBlock(usize),
+        /// Free the `n`th inode the script allocated.
+        FreeInode(usize),
+    }
+
+    /// Run `script` through `Ext4FS` and through debugfs on copies of the same
+    /// image, then compare the primary superblock, the descriptor table, the
+    /// bitmaps, the inode tables and the directories byte for byte.
+    ///
+    /// debugfs `setb`/`seti` only flip bitmap bits, so the counts and flags it
+    /// writes are tracked here the way libext2fs' `ext2fs_block_alloc_stats2` and
+    /// `ext2fs_inode_alloc_stats2` keep them. Checksums, bitmap padding and the
+    /// bitmaps of uninitialized groups are left to libext2fs. `E2FSPROGS_FAKE_TIME`
+    /// keeps `s_wtime` where mkfs put it, and backup superblocks and descriptors are
+    /// not compared, only e2fsprogs rewrites those.
+    fn differential(name: &str, args: &[&str], script: &[Op]) {
+        use crate::types::group_descriptors::{BG_BLOCK_UNINIT, BG_INODE_UNINIT};
+        use crate::types::super_block::{
+            FEATURE_RO_COMPAT_GDT_CSUM, FEATURE_RO_COMPAT_METADATA_CSUM,
+        };
+        use crate::{Ext4Reader, GroupDescriptor};
+
+        let Some(path) = mkfs(name, args, "8M") else {
+            return;
+        };
+        let expected_path = path.with_extension("debugfs.img");
+        std::fs::copy(&path, &expected_path).unwrap();
+        load(&path);
+
+        let mut scratch = [0u8; 4096];
+        let mut reader = Ext4Reader::mount(read_bytes, &mut scratch).unwrap();
+        let sb = reader.super_block().clone();
+        let block_size = reader.block_size();
+        let has_group_csum = sb.has_ro_compat(FEATURE_RO_COMPAT_GDT_CSUM)
+            || sb.has_ro_compat(FEATURE_RO_COMPAT_METADATA_CSUM);
+        let original = (0..sb.group_count())
+            .map(|group| reader.group_descriptor(group).unwrap())
+            .collect::<Vec<_>>();
+
+        let whole_block =
+            |block: u64| block as usize * block_size..(block as usize + 1) * block_size;
+        let table_blocks = (sb.group_count() as usize * sb.desc_size()).div_ceil(block_size);
+        let mut regions = vec![("superblock".to_string(), 1024..2048)];
+        for index in 0..table_blocks {
+            let block = sb.descriptor_block(index);
+            regions.push((format!("descriptor block {}", index), whole_block(block)));
+        }
+        for (group, descriptor) in original.iter().enumerate() {
+            let table = descriptor.inode_table() as usize * block_size;
+            let table_size = sb.s_inodes_per_group as usize * sb.inode_size();
+            regions.push((
+                format!("block bitmap {}", group),
+                whole_block(descriptor.block_bitmap()),
+            ));
+            regions.push((
+                format!("inode bitmap {}", group),
+                whole_block(descriptor.inode_bitmap()),
+            ));
+            regions.push((format!("inode table {}", group), table..table + table_size));
+        }
+        for directory in ["/", "/lost+found"] {
+            let inode = reader.open(directory).unwrap().unwrap();
+            for logical in 0..inode.size().div_ceil(block_size as u64) as u32 {
+                let block = reader.map_block(&inode, logical).unwrap().unwrap();
+                regions.push((
+                    format!("{} block {}", directory, logical),
+                    whole_block(block),
+                ));
+            }
+        }
+
+        let mut descriptors = original.clone();
+        let mut free_blocks = sb.free_blocks_count();
+        let mut free_inodes = sb.s_free_inodes_count;
+        let block_stats = |descriptors: &mut [GroupDescriptor], block: u64, inuse: i64| {
+            let group = (block - sb.s_first_data_block as u64) / sb.s_blocks_per_group as u64;
+            let descriptor = &mut descriptors[group as usize];
+            descriptor
+                .set_free_blocks_count((descriptor.free_blocks_count() as i64 - inuse) as u32);
+            descriptor.clear_flag(BG_BLOCK_UNINIT);
+        };
+        let inode_stats = |descriptors: &mut [GroupDescriptor], inode: u32, inuse: i64| {
+            let inodes_per_group = sb.s_inodes_per_group;
+            let group = (inode - 1) / inodes_per_group;
+            let descriptor = &mut descriptors[group as usize];
+            descriptor
+                .set_free_inodes_count((descriptor.free_inodes_count() as i64 - inuse) as u32);
+            descriptor.clear_flag(BG_INODE_UNINIT);
+            if has_group_csum {
+                let group_start = group * inodes_per_group;
+                let first_unused = inodes_per_group - descriptor.itable_unused() + group_start + 1;
+                if inode >= first_unused {
+                    descriptor.set_itable_unused(group_start + inodes_per_group - inode);
+                }
+            }
+        };
+
+        let mut fs = open();
+        let mut allocated_blocks = Vec::new();
+        let mut allocated_inodes = Vec::new();
+        let mut commands = Vec::new();
+        for op in script {
+            match *op {
+                Op::AllocateBlocks(goal, count) => {
+                    let (start, len) = fs.allocate_blocks(goal, count).unwrap();
+                    commands.push(format!("setb {} {}", start, len));
+                    for block in start..start + len as u64 {
+                        block_stats(&mut descriptors, block, 1);
+                        free_blocks -= 1;
+                        allocated_blocks.push(block);
+                    }
+                }
+                Op::AllocateInode(goal) => {
+                    let inode = fs.allocate_inode(goal, false).unwrap();
+                    commands.push(format!("seti <{}>", inode));
+                    inode_stats(&mut descriptors, inode, 1);
+                    free_inodes -= 1;
+                    allocated_inodes.push(inode);
+                }
+                Op::FreeBlock(n) => {
+                    let block = allocated_blocks[n];
+                    fs.free_block(block).unwrap();
+                    commands.push(format!("freeb {}", block));
+                    block_stats(&mut descriptors, block, -1);
+                    free_blocks += 1;
+                }
+                Op::FreeInode(n) => {
+                    let inode = allocated_inodes[n];
+                    fs.free_inode(inode, false).unwrap();
+                    commands.push(format!("freei <{}>", inode));
+                    inode_stats(&mut descriptors, inode, -1);
+                    free_inodes += 1;
+                }
+            }
+        }
+        fs.flush().unwrap();
+
+        for (group, (before, after)) in original.iter().zip(&descriptors).enumerate() {
+            if before.as_bytes() == after.as_bytes() {
+                continue;
+            }
+            commands.push(format!(
+                "set_bg {} free_blocks_count {}",
+                group,
+                after.free_blocks_count()
+            ));
+            commands.push(format!(
+                "set_bg {} free_inodes_count {}",
+                group,
+                after.free_inodes_count()
+            ));
+            commands.push(format!(
+                "set_bg {} itable_unused {}",
+                group,
+                after.itable_unused()
+            ));
+            commands.push(format!("set_bg {} flags {}", group, after.flags()));
+        }
+        commands.push(format!("set_super_value free_blocks_count {}", free_blocks));
+        commands.push(format!("set_super_value free_inodes_count {}", free_inodes));
+
+        let commands_path = path.with_extension("debugfs");
+        std::fs::write(&commands_path, commands.join("\n") + "\n").unwrap();
+        let Ok(output) = Command::new("debugfs")
+            .args(["-w", "-f"])
+            .arg(&commands_path)
+            .arg(&expected_path)
+            .env("E2FSPROGS_FAKE_TIME", sb.s_wtime.to_string())
+            .output()
+        else {
+            return;
+        };
+        // anything past the version banner is a complaint about a command
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            output.status.success() && stderr.lines().all(|line| line.starts_with("debugfs ")),
+            "debugfs failed: {}",
+            stderr
+        );
+
+        let expected = std::fs::read(&expected_path).unwrap();
+        IMAGE.with(|image| {
+            let actual = image.borrow();
+            for (what, range) in regions {
+                let start = range.start;
+                if let Some(offset) = range.into_iter().find(|&i| actual[i] != expected[i]) {
+                    panic!(
+                        "{}: {} differs from debugfs at byte {} after {:?}",
+                        name,
+                        what,
+                        offset - start,
+                        script
+                    );
+                }
+            }
+        });
+
+        std::fs::remove_file(commands_path).unwrap();
+        std::fs::remove_file(expected_path).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn differential_bitmaps() {
+        let script = [
+            Op::AllocateBlocks(0, 1),
+            // groups 5 and 7 start out BLOCK_UNINIT, 7 is the last one
+            Op::AllocateBlocks(5, 20),
+            Op::AllocateBlocks(7, 3),
+            Op::AllocateInode(0),
+            // group 3 starts out INODE_UNINIT
+            Op::AllocateInode(3),
+            Op::AllocateInode(3),
+            Op::FreeBlock(0),
+            Op::FreeBlock(10),
+            Op::FreeInode(1),
+        ];
+        differential(
+            "differential",
+            &["-b", "1024", "-g", "1024", "-G", "4"],
+            &script,
+        );
+        differential(
+            "differential-gdt-csum",
+            &[
+                "-b",
+                "1024",
+                "-g",
+                "1024",
+                "-O",
+                "^metadata_csum,^64bit,uninit_bg",
+            ],
+            &script,
+        );
+    }
 }
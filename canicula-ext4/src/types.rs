@@ -1,5 +1,8 @@
 pub mod data_block;
+#[cfg(feature = "alloc")]
 pub mod data_block_bitmap;
+pub mod directory;
+pub mod extent;
 pub mod group_descriptors;
 pub mod inode_bitmap;
 pub mod inode_table;
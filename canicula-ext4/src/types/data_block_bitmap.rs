@@ -1 +1,70 @@
+use alloc::vec;
+use alloc::vec::Vec;
 
+/// An on-disk allocation bitmap, bit `n` set means entry `n` of the group is in use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitmap {
+    bytes: Vec<u8>,
+}
+
+impl Bitmap {
+    pub fn new(len: usize) -> Self {
+        Bitmap {
+            bytes: vec![0; len],
+        }
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Bitmap { bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        self.bytes[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        self.bytes[index / 8] |= 1 << (index % 8);
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        self.bytes[index / 8] &= !(1 << (index % 8));
+    }
+
+    /// Mark `start..end` as used.
+    pub fn set_range(&mut self, start: usize, end: usize) {
+        for index in start..end {
+            self.set(index);
+        }
+    }
+
+    /// First clear bit in `start..limit`.
+    pub fn find_first_zero(&self, start: usize, limit: usize) -> Option<usize> {
+        let mut index = start;
+        while index < limit {
+            let byte = self.bytes[index / 8];
+            if byte == 0xff && index & 7 == 0 {
+                index += 8;
+                continue;
+            }
+            if byte & (1 << (index % 8)) == 0 {
+                return Some(index);
+            }
+            index += 1;
+        }
+        None
+    }
+
+    /// First run of clear bits in `start..limit` as `(index, len)`, at most `max` long.
+    pub fn find_zero_run(&self, start: usize, limit: usize, max: usize) -> Option<(usize, usize)> {
+        let first = self.find_first_zero(start, limit)?;
+        let mut end = first + 1;
+        while end < limit && end - first < max && !self.get(end) {
+            end += 1;
+        }
+        Some((first, end - first))
+    }
+}
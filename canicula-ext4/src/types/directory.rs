@@ -0,0 +1,75 @@
+#![allow(dead_code)]
+
+/// Inode, record length, name length and file type.
+pub const ENTRY_HEADER: usize = 8;
+/// `ext4_dir_entry_tail`, a fake entry closing each leaf block with `metadata_csum`.
+pub const TAIL_SIZE: usize = 12;
+const TAIL_FILE_TYPE: u8 = 0xde;
+
+/// `ext4_dir_entry_2`, or `ext4_dir_entry` without the `filetype` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    /// `0` for an unused entry.
+    pub inode: u32,
+    pub record_len: usize,
+    pub name_len: usize,
+}
+
+impl DirectoryEntry {
+    /// Parse the entry at the start of `raw`, which ends where the block does.
+    ///
+    /// `None` when the header is cut off or the record does not fit.
+    pub fn from_bytes(raw: &[u8], has_file_type: bool) -> Option<Self> {
+        if raw.len() < ENTRY_HEADER {
+            return None;
+        }
+        let inode = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
+        let record_len = match u16::from_le_bytes([raw[4], raw[5]]) as usize {
+            // 64KiB blocks cannot encode their own size
+            0 | 0xffff => 0x10000,
+            len => (len & 0xfffc) | (len & 3) << 16,
+        };
+        let name_len = if has_file_type {
+            raw[6] as usize
+        } else {
+            u16::from_le_bytes([raw[6], raw[7]]) as usize
+        };
+        if record_len < ENTRY_HEADER
+            || record_len > raw.len()
+            || ENTRY_HEADER + name_len > record_len
+        {
+            return None;
+        }
+        Some(DirectoryEntry {
+            inode,
+            record_len,
+            name_len,
+        })
+    }
+
+    /// The name of the entry parsed from the start of `raw`.
+    pub fn name<'a>(&self, raw: &'a [u8]) -> &'a [u8] {
+        &raw[ENTRY_HEADER..ENTRY_HEADER + self.name_len]
+    }
+}
+
+/// Whether `block` ends in an `ext4_dir_entry_tail`.
+pub fn has_tail(block: &[u8]) -> bool {
+    let Some(tail) = block
+        .len()
+        .checked_sub(TAIL_SIZE)
+        .map(|start| &block[start..])
+    else {
+        return false;
+    };
+    tail[..4] == [0; 4]
+        && u16::from_le_bytes([tail[4], tail[5]]) as usize == TAIL_SIZE
+        && tail[6] == 0
+        && tail[7] == TAIL_FILE_TYPE
+}
+
+/// The checksum stored in the tail of `block`, see [`has_tail`].
+pub fn tail_checksum(block: &[u8]) -> u32 {
+    let at = block.len() - 4;
+    u32::from_le_bytes([block[at], block[at + 1], block[at + 2], block[at + 3]])
+}
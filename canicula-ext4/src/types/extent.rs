@@ -0,0 +1,128 @@
+#![allow(dead_code)]
+
+pub const EXTENT_MAGIC: u16 = 0xf30a;
+
+/// Size of the header and of each entry of an extent tree node.
+pub const ENTRY_SIZE: usize = 12;
+
+/// Lengths above this mark an uninitialised extent, which reads as zeros.
+pub const MAX_INIT_LEN: u16 = 32768;
+
+fn u16_at(raw: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([raw[offset], raw[offset + 1]])
+}
+
+fn u32_at(raw: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        raw[offset],
+        raw[offset + 1],
+        raw[offset + 2],
+        raw[offset + 3],
+    ])
+}
+
+fn put_u16(raw: &mut [u8], offset: usize, value: u16) {
+    raw[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(raw: &mut [u8], offset: usize, value: u32) {
+    raw[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// `ext4_extent_header`, at the start of `i_block` and of every tree block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtentHeader {
+    pub magic: u16,
+    pub entries: u16,
+    pub max: u16,
+    /// `0` for leaves, which hold [`Extent`]s, otherwise nodes hold [`ExtentIndex`]es.
+    pub depth: u16,
+}
+
+impl ExtentHeader {
+    pub fn from_bytes(raw: &[u8]) -> Self {
+        ExtentHeader {
+            magic: u16_at(raw, 0),
+            entries: u16_at(raw, 2),
+            max: u16_at(raw, 4),
+            depth: u16_at(raw, 6),
+        }
+    }
+
+    /// Store into the first [`ENTRY_SIZE`] bytes of `raw`, `eh_generation` is zeroed.
+    pub fn store(self, raw: &mut [u8]) {
+        put_u16(raw, 0, self.magic);
+        put_u16(raw, 2, self.entries);
+        put_u16(raw, 4, self.max);
+        put_u16(raw, 6, self.depth);
+        put_u32(raw, 8, 0);
+    }
+
+    /// Whether `entries` fit in a node of `len` bytes.
+    pub fn is_valid(&self, len: usize) -> bool {
+        self.magic == EXTENT_MAGIC
+            && self.entries <= self.max
+            && ENTRY_SIZE * (1 + self.entries as usize) <= len
+    }
+}
+
+/// `ext4_extent_idx`, points at the node covering blocks from `block` onwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtentIndex {
+    pub block: u32,
+    pub leaf: u64,
+}
+
+impl ExtentIndex {
+    pub fn from_bytes(raw: &[u8]) -> Self {
+        ExtentIndex {
+            block: u32_at(raw, 0),
+            leaf: (u16_at(raw, 8) as u64) << 32 | u32_at(raw, 4) as u64,
+        }
+    }
+
+    pub fn store(self, raw: &mut [u8]) {
+        put_u32(raw, 0, self.block);
+        put_u32(raw, 4, self.leaf as u32);
+        put_u16(raw, 8, (self.leaf >> 32) as u16);
+        put_u16(raw, 10, 0);
+    }
+}
+
+/// `ext4_extent`, maps `len` logical blocks from `block` to physical blocks from `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub block: u32,
+    pub len: u16,
+    pub start: u64,
+    pub initialized: bool,
+}
+
+impl Extent {
+    pub fn from_bytes(raw: &[u8]) -> Self {
+        let len = u16_at(raw, 4);
+        let initialized = len <= MAX_INIT_LEN;
+        Extent {
+            block: u32_at(raw, 0),
+            len: if initialized { len } else { len - MAX_INIT_LEN },
+            start: (u16_at(raw, 6) as u64) << 32 | u32_at(raw, 8) as u64,
+            initialized,
+        }
+    }
+
+    pub fn store(self, raw: &mut [u8]) {
+        let len = if self.initialized {
+            self.len
+        } else {
+            self.len + MAX_INIT_LEN
+        };
+        put_u32(raw, 0, self.block);
+        put_u16(raw, 4, len);
+        put_u16(raw, 6, (self.start >> 32) as u16);
+        put_u32(raw, 8, self.start as u32);
+    }
+
+    pub fn contains(&self, block: u32) -> bool {
+        block >= self.block && ((block - self.block) as u64) < self.len as u64
+    }
+}
@@ -1 +1,174 @@
+#![allow(dead_code)]
 
+pub const BG_INODE_UNINIT: u16 = 0x0001;
+pub const BG_BLOCK_UNINIT: u16 = 0x0002;
+pub const BG_INODE_ZEROED: u16 = 0x0004;
+
+const BLOCK_BITMAP_LO: usize = 0;
+const INODE_BITMAP_LO: usize = 4;
+const INODE_TABLE_LO: usize = 8;
+const FREE_BLOCKS_COUNT_LO: usize = 12;
+const FREE_INODES_COUNT_LO: usize = 14;
+const USED_DIRS_COUNT_LO: usize = 16;
+const FLAGS: usize = 18;
+const BLOCK_BITMAP_CSUM_LO: usize = 24;
+const INODE_BITMAP_CSUM_LO: usize = 26;
+const ITABLE_UNUSED_LO: usize = 28;
+pub const CHECKSUM: usize = 30;
+const BLOCK_BITMAP_HI: usize = 32;
+const INODE_BITMAP_HI: usize = 36;
+const INODE_TABLE_HI: usize = 40;
+const FREE_BLOCKS_COUNT_HI: usize = 44;
+const FREE_INODES_COUNT_HI: usize = 46;
+const USED_DIRS_COUNT_HI: usize = 48;
+const ITABLE_UNUSED_HI: usize = 50;
+const BLOCK_BITMAP_CSUM_HI: usize = 56;
+const INODE_BITMAP_CSUM_HI: usize = 58;
+
+/// Largest descriptor size supported, `s_desc_size` may not exceed it.
+pub const MAX_DESC_SIZE: usize = 64;
+
+/// One entry of the group descriptor table.
+///
+/// The raw bytes are kept so fields we do not interpret are written back unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupDescriptor {
+    raw: [u8; MAX_DESC_SIZE],
+    size: usize,
+}
+
+impl GroupDescriptor {
+    pub fn from_bytes(raw: &[u8]) -> Self {
+        let size = raw.len().min(MAX_DESC_SIZE);
+        let mut bytes = [0u8; MAX_DESC_SIZE];
+        bytes[..size].copy_from_slice(&raw[..size]);
+        GroupDescriptor { raw: bytes, size }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw[..self.size]
+    }
+
+    fn is_64bit(&self) -> bool {
+        self.size >= MAX_DESC_SIZE
+    }
+
+    fn u16_at(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.raw[offset], self.raw[offset + 1]])
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes([
+            self.raw[offset],
+            self.raw[offset + 1],
+            self.raw[offset + 2],
+            self.raw[offset + 3],
+        ])
+    }
+
+    fn set_u16_at(&mut self, offset: usize, value: u16) {
+        self.raw[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u32_at(&mut self, offset: usize, value: u32) {
+        self.raw[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn split_u64(&self, lo: usize, hi: usize) -> u64 {
+        let high = if self.is_64bit() { self.u32_at(hi) } else { 0 };
+        (high as u64) << 32 | self.u32_at(lo) as u64
+    }
+
+    fn split_u32(&self, lo: usize, hi: usize) -> u32 {
+        let high = if self.is_64bit() { self.u16_at(hi) } else { 0 };
+        (high as u32) << 16 | self.u16_at(lo) as u32
+    }
+
+    fn set_split_u32(&mut self, lo: usize, hi: usize, value: u32) {
+        self.set_u16_at(lo, value as u16);
+        if self.is_64bit() {
+            self.set_u16_at(hi, (value >> 16) as u16);
+        }
+    }
+
+    pub fn block_bitmap(&self) -> u64 {
+        self.split_u64(BLOCK_BITMAP_LO, BLOCK_BITMAP_HI)
+    }
+
+    pub fn inode_bitmap(&self) -> u64 {
+        self.split_u64(INODE_BITMAP_LO, INODE_BITMAP_HI)
+    }
+
+    pub fn inode_table(&self) -> u64 {
+        self.split_u64(INODE_TABLE_LO, INODE_TABLE_HI)
+    }
+
+    pub fn free_blocks_count(&self) -> u32 {
+        self.split_u32(FREE_BLOCKS_COUNT_LO, FREE_BLOCKS_COUNT_HI)
+    }
+
+    pub fn set_free_blocks_count(&mut self, count: u32) {
+        self.set_split_u32(FREE_BLOCKS_COUNT_LO, FREE_BLOCKS_COUNT_HI, count);
+    }
+
+    pub fn free_inodes_count(&self) -> u32 {
+        self.split_u32(FREE_INODES_COUNT_LO, FREE_INODES_COUNT_HI)
+    }
+
+    pub fn set_free_inodes_count(&mut self, count: u32) {
+        self.set_split_u32(FREE_INODES_COUNT_LO, FREE_INODES_COUNT_HI, count);
+    }
+
+    pub fn used_dirs_count(&self) -> u32 {
+        self.split_u32(USED_DIRS_COUNT_LO, USED_DIRS_COUNT_HI)
+    }
+
+    pub fn set_used_dirs_count(&mut self, count: u32) {
+        self.set_split_u32(USED_DIRS_COUNT_LO, USED_DIRS_COUNT_HI, count);
+    }
+
+    pub fn itable_unused(&self) -> u32 {
+        self.split_u32(ITABLE_UNUSED_LO, ITABLE_UNUSED_HI)
+    }
+
+    pub fn set_itable_unused(&mut self, count: u32) {
+        self.set_split_u32(ITABLE_UNUSED_LO, ITABLE_UNUSED_HI, count);
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.u16_at(FLAGS)
+    }
+
+    pub fn has_flag(&self, flag: u16) -> bool {
+        self.flags() & flag != 0
+    }
+
+    pub fn clear_flag(&mut self, flag: u16) {
+        let flags = self.flags() & !flag;
+        self.set_u16_at(FLAGS, flags);
+    }
+
+    pub fn block_bitmap_checksum(&self) -> u32 {
+        self.split_u32(BLOCK_BITMAP_CSUM_LO, BLOCK_BITMAP_CSUM_HI)
+    }
+
+    pub fn set_block_bitmap_checksum(&mut self, checksum: u32) {
+        self.set_split_u32(BLOCK_BITMAP_CSUM_LO, BLOCK_BITMAP_CSUM_HI, checksum);
+    }
+
+    pub fn inode_bitmap_checksum(&self) -> u32 {
+        self.split_u32(INODE_BITMAP_CSUM_LO, INODE_BITMAP_CSUM_HI)
+    }
+
+    pub fn set_inode_bitmap_checksum(&mut self, checksum: u32) {
+        self.set_split_u32(INODE_BITMAP_CSUM_LO, INODE_BITMAP_CSUM_HI, checksum);
+    }
+
+    pub fn checksum(&self) -> u16 {
+        self.u16_at(CHECKSUM)
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        self.set_u16_at(CHECKSUM, checksum);
+    }
+}
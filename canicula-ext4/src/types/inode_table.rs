@@ -1 +1,104 @@
+#![allow(dead_code)]
 
+pub const S_IFMT: u16 = 0xf000;
+pub const S_IFDIR: u16 = 0x4000;
+pub const S_IFREG: u16 = 0x8000;
+pub const S_IFLNK: u16 = 0xa000;
+
+/// Hashed directory, its index blocks carry no directory entry tail.
+pub const EXT4_INDEX_FL: u32 = 0x0000_1000;
+/// Blocks of a huge file are counted in filesystem blocks rather than 512 byte sectors.
+pub const EXT4_HUGE_FILE_FL: u32 = 0x0004_0000;
+pub const EXT4_EXTENTS_FL: u32 = 0x0008_0000;
+pub const EXT4_INLINE_DATA_FL: u32 = 0x1000_0000;
+
+const MODE: usize = 0;
+pub const SIZE_LO: usize = 4;
+pub const CTIME: usize = 12;
+pub const MTIME: usize = 16;
+pub const BLOCKS_LO: usize = 28;
+const FLAGS: usize = 32;
+pub const BLOCK: usize = 40;
+pub const GENERATION: usize = 100;
+pub const SIZE_HIGH: usize = 108;
+/// `l_i_blocks_high` in `osd2`.
+pub const BLOCKS_HIGH: usize = 116;
+pub const CHECKSUM_LO: usize = 124;
+/// `i_extra_isize`, bytes used past [`INODE_CORE_SIZE`].
+pub const EXTRA_ISIZE: usize = 128;
+pub const CHECKSUM_HI: usize = 130;
+
+/// Size of `i_block`, the block map or the root of the extent tree.
+pub const BLOCK_SIZE: usize = 60;
+/// Bytes of an inode covered by [`Inode`], the rest varies with `s_inode_size`.
+pub const INODE_CORE_SIZE: usize = 128;
+
+/// The fixed part of an on-disk inode, enough to locate and size its data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inode {
+    number: u32,
+    raw: [u8; INODE_CORE_SIZE],
+}
+
+impl Inode {
+    pub fn from_bytes(number: u32, raw: &[u8]) -> Self {
+        let mut bytes = [0u8; INODE_CORE_SIZE];
+        bytes.copy_from_slice(&raw[..INODE_CORE_SIZE]);
+        Inode { number, raw: bytes }
+    }
+
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    fn u16_at(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.raw[offset], self.raw[offset + 1]])
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes([
+            self.raw[offset],
+            self.raw[offset + 1],
+            self.raw[offset + 2],
+            self.raw[offset + 3],
+        ])
+    }
+
+    pub fn mode(&self) -> u16 {
+        self.u16_at(MODE)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode() & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode() & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode() & S_IFMT == S_IFLNK
+    }
+
+    pub fn size(&self) -> u64 {
+        (self.u32_at(SIZE_HIGH) as u64) << 32 | self.u32_at(SIZE_LO) as u64
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.u32_at(FLAGS)
+    }
+
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags() & flag != 0
+    }
+
+    /// The raw `i_block` area.
+    pub fn block(&self) -> &[u8] {
+        &self.raw[BLOCK..BLOCK + BLOCK_SIZE]
+    }
+
+    /// Entry `index` of the classic block map: 12 direct, then single, double and triple indirect.
+    pub fn block_pointer(&self, index: usize) -> u32 {
+        self.u32_at(BLOCK + index * 4)
+    }
+}
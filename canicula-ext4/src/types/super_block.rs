@@ -1,4 +1,8 @@
 #![allow(dead_code)]
+
+use super::group_descriptors::MAX_DESC_SIZE;
+use super::inode_table::INODE_CORE_SIZE;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SuperBlock {
     InodesCount,
@@ -178,277 +182,280 @@ impl SuperBlock {
             },
             SuperBlock::RevLevel => SuperBlockSlice {
                 offset: 76,
-                size: 2,
+                size: 4,
             },
             SuperBlock::DefResuid => SuperBlockSlice {
-                offset: 78,
+                offset: 80,
                 size: 2,
             },
             SuperBlock::DefResgid => SuperBlockSlice {
-                offset: 80,
+                offset: 82,
                 size: 2,
             },
             SuperBlock::FirstIno => SuperBlockSlice {
-                offset: 82,
+                offset: 84,
                 size: 4,
             },
             SuperBlock::InodeSize => SuperBlockSlice {
-                offset: 86,
+                offset: 88,
                 size: 2,
             },
             SuperBlock::BlockGroupNr => SuperBlockSlice {
-                offset: 88,
+                offset: 90,
                 size: 2,
             },
             SuperBlock::FeatureCompat => SuperBlockSlice {
-                offset: 90,
+                offset: 92,
                 size: 4,
             },
             SuperBlock::FeatureIncompat => SuperBlockSlice {
-                offset: 94,
+                offset: 96,
                 size: 4,
             },
             SuperBlock::FeatureRoCompat => SuperBlockSlice {
-                offset: 98,
+                offset: 100,
                 size: 4,
             },
             SuperBlock::Uuid => SuperBlockSlice {
-                offset: 102,
+                offset: 104,
                 size: 16,
             },
             SuperBlock::VolumeName => SuperBlockSlice {
-                offset: 118,
+                offset: 120,
                 size: 16,
             },
             SuperBlock::LastMounted => SuperBlockSlice {
-                offset: 134,
-                size: 4,
+                offset: 136,
+                size: 64,
             },
             SuperBlock::AlgorithmUsageBitmap => SuperBlockSlice {
-                offset: 138,
+                offset: 200,
                 size: 4,
             },
             SuperBlock::PreallocBlocks => SuperBlockSlice {
-                offset: 142,
-                size: 4,
+                offset: 204,
+                size: 1,
             },
             SuperBlock::PreallocDirBlocks => SuperBlockSlice {
-                offset: 146,
-                size: 4,
+                offset: 205,
+                size: 1,
             },
             SuperBlock::ReservedGdtBlocks => SuperBlockSlice {
-                offset: 150,
-                size: 16,
+                offset: 206,
+                size: 2,
             },
             SuperBlock::JournalUuid => SuperBlockSlice {
-                offset: 166,
+                offset: 208,
                 size: 16,
             },
             SuperBlock::JournalInum => SuperBlockSlice {
-                offset: 182,
+                offset: 224,
                 size: 4,
             },
             SuperBlock::JournalDev => SuperBlockSlice {
-                offset: 186,
+                offset: 228,
                 size: 4,
             },
             SuperBlock::LastOrphan => SuperBlockSlice {
-                offset: 190,
+                offset: 232,
                 size: 4,
             },
             SuperBlock::HashSeed => SuperBlockSlice {
-                offset: 194,
-                size: 8,
+                offset: 236,
+                size: 16,
             },
             SuperBlock::DefHashVersion => SuperBlockSlice {
-                offset: 202,
-                size: 4,
+                offset: 252,
+                size: 1,
             },
             SuperBlock::JnlBackupType => SuperBlockSlice {
-                offset: 206,
-                size: 4,
+                offset: 253,
+                size: 1,
             },
             SuperBlock::DescSize => SuperBlockSlice {
-                offset: 210,
-                size: 4,
+                offset: 254,
+                size: 2,
             },
             SuperBlock::DefaultMountOpts => SuperBlockSlice {
-                offset: 214,
+                offset: 256,
                 size: 4,
             },
             SuperBlock::FirstMetaBg => SuperBlockSlice {
-                offset: 218,
+                offset: 260,
                 size: 4,
             },
             SuperBlock::MkfsTime => SuperBlockSlice {
-                offset: 222,
+                offset: 264,
                 size: 4,
             },
             SuperBlock::JnlBlocks => SuperBlockSlice {
-                offset: 226,
-                size: 12,
+                offset: 268,
+                size: 68,
+            },
+            SuperBlock::BlocksCountHi => SuperBlockSlice {
+                offset: 336,
+                size: 4,
             },
             SuperBlock::RBlocksCountHi => SuperBlockSlice {
-                offset: 238,
+                offset: 340,
                 size: 4,
             },
             SuperBlock::FreeBlocksCountHi => SuperBlockSlice {
-                offset: 242,
+                offset: 344,
                 size: 4,
             },
             SuperBlock::MinExtraIsize => SuperBlockSlice {
-                offset: 246,
-                size: 4,
+                offset: 348,
+                size: 2,
             },
             SuperBlock::WantExtraIsize => SuperBlockSlice {
-                offset: 250,
-                size: 4,
+                offset: 350,
+                size: 2,
             },
             SuperBlock::Flags => SuperBlockSlice {
-                offset: 254,
+                offset: 352,
                 size: 4,
             },
             SuperBlock::RaidStride => SuperBlockSlice {
-                offset: 258,
-                size: 4,
+                offset: 356,
+                size: 2,
             },
             SuperBlock::MMPInterval => SuperBlockSlice {
-                offset: 262,
-                size: 4,
+                offset: 358,
+                size: 2,
             },
             SuperBlock::MMPBlock => SuperBlockSlice {
-                offset: 266,
-                size: 4,
+                offset: 360,
+                size: 8,
             },
             SuperBlock::RaidStripeWidth => SuperBlockSlice {
-                offset: 270,
+                offset: 368,
                 size: 4,
             },
             SuperBlock::LogGroupsPerFlex => SuperBlockSlice {
-                offset: 274,
-                size: 4,
+                offset: 372,
+                size: 1,
             },
             SuperBlock::ChecksumType => SuperBlockSlice {
-                offset: 278,
-                size: 4,
+                offset: 373,
+                size: 1,
             },
             SuperBlock::ReservedPad => SuperBlockSlice {
-                offset: 282,
-                size: 6,
+                offset: 374,
+                size: 2,
             },
             SuperBlock::KbytesWritten => SuperBlockSlice {
-                offset: 288,
+                offset: 376,
                 size: 8,
             },
             SuperBlock::SnapshotInum => SuperBlockSlice {
-                offset: 296,
+                offset: 384,
                 size: 4,
             },
             SuperBlock::SnapshotId => SuperBlockSlice {
-                offset: 300,
+                offset: 388,
                 size: 4,
             },
             SuperBlock::SnapshotRBlocksCount => SuperBlockSlice {
-                offset: 304,
+                offset: 392,
                 size: 8,
             },
             SuperBlock::SnapshotList => SuperBlockSlice {
-                offset: 312,
-                size: 8,
+                offset: 400,
+                size: 4,
             },
             SuperBlock::ErrorCount => SuperBlockSlice {
-                offset: 320,
+                offset: 404,
                 size: 4,
             },
             SuperBlock::FirstErrorTime => SuperBlockSlice {
-                offset: 324,
+                offset: 408,
                 size: 4,
             },
             SuperBlock::FirstErrorIno => SuperBlockSlice {
-                offset: 328,
+                offset: 412,
                 size: 4,
             },
             SuperBlock::FirstErrorBlock => SuperBlockSlice {
-                offset: 332,
-                size: 4,
+                offset: 416,
+                size: 8,
             },
             SuperBlock::FirstErrorFunc => SuperBlockSlice {
-                offset: 336,
-                size: 4,
+                offset: 424,
+                size: 32,
             },
             SuperBlock::FirstErrorLine => SuperBlockSlice {
-                offset: 340,
+                offset: 456,
                 size: 4,
             },
             SuperBlock::LastErrorTime => SuperBlockSlice {
-                offset: 344,
-                size: 8,
+                offset: 460,
+                size: 4,
             },
             SuperBlock::LastErrorIno => SuperBlockSlice {
-                offset: 352,
+                offset: 464,
                 size: 4,
             },
             SuperBlock::LastErrorLine => SuperBlockSlice {
-                offset: 364,
+                offset: 468,
                 size: 4,
             },
             SuperBlock::LastErrorBlock => SuperBlockSlice {
-                offset: 356,
-                size: 4,
+                offset: 472,
+                size: 8,
             },
             SuperBlock::LastErrorFunc => SuperBlockSlice {
-                offset: 360,
-                size: 4,
+                offset: 480,
+                size: 32,
             },
             SuperBlock::MountOpts => SuperBlockSlice {
-                offset: 368,
+                offset: 512,
                 size: 64,
             },
             SuperBlock::UsrQuotaInum => SuperBlockSlice {
-                offset: 432,
+                offset: 576,
                 size: 4,
             },
             SuperBlock::GrpQuotaInum => SuperBlockSlice {
-                offset: 436,
+                offset: 580,
                 size: 4,
             },
             SuperBlock::OverheadBlocks => SuperBlockSlice {
-                offset: 440,
+                offset: 584,
                 size: 4,
             },
             SuperBlock::BackupBgs => SuperBlockSlice {
-                offset: 444,
-                size: 6,
+                offset: 588,
+                size: 8,
             },
             SuperBlock::EncryptAlgos => SuperBlockSlice {
-                offset: 450,
+                offset: 596,
                 size: 4,
             },
             SuperBlock::EncryptPwSalt => SuperBlockSlice {
-                offset: 454,
+                offset: 600,
                 size: 16,
             },
             SuperBlock::LpfIno => SuperBlockSlice {
-                offset: 470,
+                offset: 616,
                 size: 4,
             },
             SuperBlock::PrjQuotaInum => SuperBlockSlice {
-                offset: 474,
+                offset: 620,
                 size: 4,
             },
             SuperBlock::ChecksumSeed => SuperBlockSlice {
-                offset: 478,
-                size: 8,
+                offset: 624,
+                size: 4,
             },
             SuperBlock::Reserved => SuperBlockSlice {
-                offset: 486,
-                size: 2,
+                offset: 628,
+                size: 392,
             },
             SuperBlock::Checksum => SuperBlockSlice {
-                offset: 488,
+                offset: 1020,
                 size: 4,
             },
-            _ => SuperBlockSlice { offset: 0, size: 0 },
         }
     }
 
@@ -563,3 +570,272 @@ pub struct SuperBlockSnapshot {
     pub s_reserved: [u32; 98],
     pub s_checksum: u32,
 }
+
+pub const EXT4_SUPER_MAGIC: u16 = 0xef53;
+
+pub const FEATURE_COMPAT_HAS_JOURNAL: u32 = 0x0004;
+pub const FEATURE_COMPAT_RESIZE_INODE: u32 = 0x0010;
+pub const FEATURE_COMPAT_DIR_INDEX: u32 = 0x0020;
+pub const FEATURE_COMPAT_SPARSE_SUPER2: u32 = 0x0200;
+
+pub const FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+pub const FEATURE_INCOMPAT_RECOVER: u32 = 0x0004;
+pub const FEATURE_INCOMPAT_META_BG: u32 = 0x0010;
+pub const FEATURE_INCOMPAT_EXTENTS: u32 = 0x0040;
+pub const FEATURE_INCOMPAT_64BIT: u32 = 0x0080;
+pub const FEATURE_INCOMPAT_FLEX_BG: u32 = 0x0200;
+pub const FEATURE_INCOMPAT_CSUM_SEED: u32 = 0x2000;
+
+pub const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+pub const FEATURE_RO_COMPAT_GDT_CSUM: u32 = 0x0010;
+pub const FEATURE_RO_COMPAT_METADATA_CSUM: u32 = 0x0400;
+
+/// Size of the on-disk superblock in bytes.
+pub const SUPER_BLOCK_SIZE: usize = 1024;
+
+fn read_le(raw: &[u8], field: SuperBlock) -> u64 {
+    let slice = field.slice();
+    raw[slice.offset..slice.offset + slice.size]
+        .iter()
+        .rev()
+        .fold(0, |value, byte| (value << 8) | *byte as u64)
+}
+
+fn read_bytes<const N: usize>(raw: &[u8], field: SuperBlock) -> [u8; N] {
+    let offset = field.offset();
+    let mut bytes = [0u8; N];
+    bytes.copy_from_slice(&raw[offset..offset + N]);
+    bytes
+}
+
+fn read_chars<const N: usize>(raw: &[u8], field: SuperBlock) -> [char; N] {
+    read_bytes::<N>(raw, field).map(|byte| byte as char)
+}
+
+fn read_words<const N: usize>(raw: &[u8], field: SuperBlock) -> [u32; N] {
+    let offset = field.offset();
+    core::array::from_fn(|i| {
+        let start = offset + i * 4;
+        u32::from_le_bytes([raw[start], raw[start + 1], raw[start + 2], raw[start + 3]])
+    })
+}
+
+impl SuperBlockSnapshot {
+    /// Parse the 1024 byte on-disk superblock.
+    pub fn from_bytes(raw: &[u8]) -> Self {
+        let u8_field = |field| read_le(raw, field) as u8;
+        let u16_field = |field| read_le(raw, field) as u16;
+        let u32_field = |field| read_le(raw, field) as u32;
+        let u64_field = |field| read_le(raw, field);
+
+        SuperBlockSnapshot {
+            s_inodes_count: u32_field(SuperBlock::InodesCount),
+            s_blocks_count_lo: u32_field(SuperBlock::BlocksCountLo),
+            s_r_blocks_count_lo: u32_field(SuperBlock::RBlocksCountLo),
+            s_free_blocks_count_lo: u32_field(SuperBlock::FreeBlocksCountLo),
+            s_free_inodes_count: u32_field(SuperBlock::FreeInodesCount),
+            s_first_data_block: u32_field(SuperBlock::FirstDataBlock),
+            s_log_block_size: u32_field(SuperBlock::LogBlockSize),
+            s_log_cluster_size: u32_field(SuperBlock::LogClusterSize),
+            s_blocks_per_group: u32_field(SuperBlock::BlocksPerGroup),
+            s_clusters_per_group: u32_field(SuperBlock::ClustersPerGroup),
+            s_inodes_per_group: u32_field(SuperBlock::InodesPerGroup),
+            s_mtime: u32_field(SuperBlock::Mtime),
+            s_wtime: u32_field(SuperBlock::Wtime),
+            s_mnt_count: u16_field(SuperBlock::MntCount),
+            s_max_mnt_count: u16_field(SuperBlock::MaxMntCount),
+            s_magic: u16_field(SuperBlock::Magic),
+            s_state: u16_field(SuperBlock::State),
+            s_errors: u16_field(SuperBlock::Errors),
+            s_minor_rev_level: u16_field(SuperBlock::MinorRevLevel),
+            s_lastcheck: u32_field(SuperBlock::LastCheck),
+            s_checkinterval: u32_field(SuperBlock::CheckInterval),
+            s_creator_os: u32_field(SuperBlock::CreatorOs),
+            s_rev_level: u32_field(SuperBlock::RevLevel),
+            s_def_resuid: u16_field(SuperBlock::DefResuid),
+            s_def_resgid: u16_field(SuperBlock::DefResgid),
+            s_first_ino: u32_field(SuperBlock::FirstIno),
+            s_inode_size: u16_field(SuperBlock::InodeSize),
+            s_block_group_nr: u16_field(SuperBlock::BlockGroupNr),
+            s_feature_compat: u32_field(SuperBlock::FeatureCompat),
+            s_feature_incompat: u32_field(SuperBlock::FeatureIncompat),
+            s_feature_ro_compat: u32_field(SuperBlock::FeatureRoCompat),
+            s_uuid: read_bytes(raw, SuperBlock::Uuid),
+            s_volume_name: read_chars(raw, SuperBlock::VolumeName),
+            s_last_mounted: read_chars(raw, SuperBlock::LastMounted),
+            s_algorithm_usage_bitmap: u32_field(SuperBlock::AlgorithmUsageBitmap),
+            s_prealloc_blocks: u8_field(SuperBlock::PreallocBlocks),
+            s_prealloc_dir_blocks: u8_field(SuperBlock::PreallocDirBlocks),
+            s_reserved_gdt_blocks: u16_field(SuperBlock::ReservedGdtBlocks),
+            s_journal_uuid: read_bytes(raw, SuperBlock::JournalUuid),
+            s_journal_inum: u32_field(SuperBlock::JournalInum),
+            s_journal_dev: u32_field(SuperBlock::JournalDev),
+            s_last_orphan: u32_field(SuperBlock::LastOrphan),
+            s_hash_seed: read_words(raw, SuperBlock::HashSeed),
+            s_def_hash_version: u8_field(SuperBlock::DefHashVersion),
+            s_jnl_backup_type: u8_field(SuperBlock::JnlBackupType),
+            s_desc_size: u16_field(SuperBlock::DescSize),
+            s_default_mount_opts: u32_field(SuperBlock::DefaultMountOpts),
+            s_first_meta_bg: u32_field(SuperBlock::FirstMetaBg),
+            s_mkfs_time: u32_field(SuperBlock::MkfsTime),
+            s_jnl_blocks: read_words(raw, SuperBlock::JnlBlocks),
+            s_blocks_count_hi: u32_field(SuperBlock::BlocksCountHi),
+            s_r_blocks_count_hi: u32_field(SuperBlock::RBlocksCountHi),
+            s_free_blocks_count_hi: u32_field(SuperBlock::FreeBlocksCountHi),
+            s_min_extra_isize: u16_field(SuperBlock::MinExtraIsize),
+            s_want_extra_isize: u16_field(SuperBlock::WantExtraIsize),
+            s_flags: u32_field(SuperBlock::Flags),
+            s_raid_stride: u16_field(SuperBlock::RaidStride),
+            s_mmp_interval: u16_field(SuperBlock::MMPInterval),
+            s_mmp_block: u64_field(SuperBlock::MMPBlock),
+            s_raid_stripe_width: u32_field(SuperBlock::RaidStripeWidth),
+            s_log_groups_per_flex: u8_field(SuperBlock::LogGroupsPerFlex),
+            s_checksum_type: u8_field(SuperBlock::ChecksumType),
+            s_reserved_pad: u16_field(SuperBlock::ReservedPad),
+            s_kbytes_written: u64_field(SuperBlock::KbytesWritten),
+            s_snapshot_inum: u32_field(SuperBlock::SnapshotInum),
+            s_snapshot_id: u32_field(SuperBlock::SnapshotId),
+            s_snapshot_r_blocks_count: u64_field(SuperBlock::SnapshotRBlocksCount),
+            s_snapshot_list: u32_field(SuperBlock::SnapshotList),
+            s_error_count: u32_field(SuperBlock::ErrorCount),
+            s_first_error_time: u32_field(SuperBlock::FirstErrorTime),
+            s_first_error_ino: u32_field(SuperBlock::FirstErrorIno),
+            s_first_error_block: u64_field(SuperBlock::FirstErrorBlock),
+            s_first_error_func: read_bytes(raw, SuperBlock::FirstErrorFunc),
+            s_first_error_line: u32_field(SuperBlock::FirstErrorLine),
+            s_last_error_time: u32_field(SuperBlock::LastErrorTime),
+            s_last_error_ino: u32_field(SuperBlock::LastErrorIno),
+            s_last_error_line: u32_field(SuperBlock::LastErrorLine),
+            s_last_error_block: u64_field(SuperBlock::LastErrorBlock),
+            s_last_error_func: read_bytes(raw, SuperBlock::LastErrorFunc),
+            s_mount_opts: read_bytes(raw, SuperBlock::MountOpts),
+            s_usr_quota_inum: u32_field(SuperBlock::UsrQuotaInum),
+            s_grp_quota_inum: u32_field(SuperBlock::GrpQuotaInum),
+            s_overhead_blocks: u32_field(SuperBlock::OverheadBlocks),
+            s_backup_bgs: read_words(raw, SuperBlock::BackupBgs),
+            s_encrypt_algos: read_bytes(raw, SuperBlock::EncryptAlgos),
+            s_encrypt_pw_salt: read_bytes(raw, SuperBlock::EncryptPwSalt),
+            s_lpf_ino: u32_field(SuperBlock::LpfIno),
+            s_prj_quota_inum: u32_field(SuperBlock::PrjQuotaInum),
+            s_checksum_seed: u32_field(SuperBlock::ChecksumSeed),
+            s_reserved: read_words(raw, SuperBlock::Reserved),
+            s_checksum: u32_field(SuperBlock::Checksum),
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        1024 << self.s_log_block_size
+    }
+
+    pub fn blocks_count(&self) -> u64 {
+        if self.has_incompat(FEATURE_INCOMPAT_64BIT) {
+            (self.s_blocks_count_hi as u64) << 32 | self.s_blocks_count_lo as u64
+        } else {
+            self.s_blocks_count_lo as u64
+        }
+    }
+
+    pub fn free_blocks_count(&self) -> u64 {
+        if self.has_incompat(FEATURE_INCOMPAT_64BIT) {
+            (self.s_free_blocks_count_hi as u64) << 32 | self.s_free_blocks_count_lo as u64
+        } else {
+            self.s_free_blocks_count_lo as u64
+        }
+    }
+
+    pub fn group_count(&self) -> u32 {
+        let data_blocks = self.blocks_count() - self.s_first_data_block as u64;
+        data_blocks.div_ceil(self.s_blocks_per_group as u64) as u32
+    }
+
+    /// Size of one group descriptor, 32 bytes unless the 64bit feature is on.
+    pub fn desc_size(&self) -> usize {
+        if self.has_incompat(FEATURE_INCOMPAT_64BIT) && self.s_desc_size >= 64 {
+            self.s_desc_size as usize
+        } else {
+            32
+        }
+    }
+
+    /// Groups per flex group, `1` when flex_bg is off.
+    pub fn groups_per_flex(&self) -> u32 {
+        if self.has_incompat(FEATURE_INCOMPAT_FLEX_BG) {
+            1 << self.s_log_groups_per_flex
+        } else {
+            1
+        }
+    }
+
+    pub fn descriptors_per_block(&self) -> u64 {
+        (self.block_size() / self.desc_size()) as u64
+    }
+
+    /// Block holding the `index`th block of the group descriptor table.
+    pub fn descriptor_block(&self, index: usize) -> u64 {
+        let first = self.s_first_data_block as u64;
+        if !self.has_incompat(FEATURE_INCOMPAT_META_BG) || (index as u32) < self.s_first_meta_bg {
+            return first + 1 + index as u64;
+        }
+
+        let group = index as u64 * self.descriptors_per_block();
+        self.group_first_block(group as u32) + self.has_super(group as u32) as u64
+    }
+
+    pub fn group_first_block(&self, group: u32) -> u64 {
+        self.s_first_data_block as u64 + group as u64 * self.s_blocks_per_group as u64
+    }
+
+    /// Whether `group` carries a superblock and group descriptor backup.
+    pub fn has_super(&self, group: u32) -> bool {
+        if group == 0 {
+            return true;
+        }
+        if self.has_compat(FEATURE_COMPAT_SPARSE_SUPER2) {
+            return self.s_backup_bgs.contains(&group);
+        }
+        if group <= 1 || !self.has_ro_compat(FEATURE_RO_COMPAT_SPARSE_SUPER) {
+            return true;
+        }
+        if group & 1 == 0 {
+            return false;
+        }
+
+        [3, 5, 7].iter().any(|base| {
+            let mut power = *base;
+            while power < group {
+                power *= base;
+            }
+            power == group
+        })
+    }
+
+    /// Sanity checks done before trusting any geometry derived from the superblock.
+    pub fn is_valid(&self) -> bool {
+        self.s_magic == EXT4_SUPER_MAGIC
+            && self.s_blocks_per_group != 0
+            && self.s_inodes_per_group != 0
+            && self.s_log_block_size <= 6
+            && self.desc_size() <= MAX_DESC_SIZE
+            && self.inode_size() >= INODE_CORE_SIZE
+    }
+
+    pub fn inode_size(&self) -> usize {
+        if self.s_rev_level == 0 {
+            128
+        } else {
+            self.s_inode_size as usize
+        }
+    }
+
+    pub fn has_compat(&self, feature: u32) -> bool {
+        self.s_feature_compat & feature != 0
+    }
+
+    pub fn has_incompat(&self, feature: u32) -> bool {
+        self.s_feature_incompat & feature != 0
+    }
+
+    pub fn has_ro_compat(&self, feature: u32) -> bool {
+        self.s_feature_ro_compat & feature != 0
+    }
+}
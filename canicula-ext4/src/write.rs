@@ -0,0 +1,343 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use canicula_common::fs::OperateError;
+
+use crate::scrub::has_checksum_hi;
+use crate::stats::Metadata;
+use crate::types::extent::{
+    Extent, ExtentHeader, ExtentIndex, ENTRY_SIZE, EXTENT_MAGIC, MAX_INIT_LEN,
+};
+use crate::types::inode_table::*;
+use crate::{gather, Ext4FS};
+
+/// Extents that fit in `i_block` after the header.
+const ROOT_ENTRIES: usize = BLOCK_SIZE / ENTRY_SIZE - 1;
+
+/// `i_blocks` counts in these unless the inode has [`EXT4_HUGE_FILE_FL`].
+const SECTOR_SIZE: u64 = 512;
+
+fn u16_at(raw: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([raw[offset], raw[offset + 1]])
+}
+
+fn u32_at(raw: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        raw[offset],
+        raw[offset + 1],
+        raw[offset + 2],
+        raw[offset + 3],
+    ])
+}
+
+fn put_u16(raw: &mut [u8], offset: usize, value: u16) {
+    raw[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(raw: &mut [u8], offset: usize, value: u32) {
+    raw[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Fill the leaf `node` with `extents`, it has room for `max` of them.
+fn write_leaf(node: &mut [u8], max: usize, extents: &[Extent]) {
+    node[..ENTRY_SIZE * (1 + max)].fill(0);
+    ExtentHeader {
+        magic: EXTENT_MAGIC,
+        entries: extents.len() as u16,
+        max: max as u16,
+        depth: 0,
+    }
+    .store(node);
+    for (index, extent) in extents.iter().enumerate() {
+        extent.store(&mut node[(index + 1) * ENTRY_SIZE..]);
+    }
+}
+
+/// Join neighbours that are contiguous both logically and on disk.
+fn merge(mut extents: Vec<Extent>) -> Vec<Extent> {
+    extents.sort_unstable_by_key(|extent| extent.block);
+    let mut merged: Vec<Extent> = Vec::with_capacity(extents.len());
+    for extent in extents {
+        match merged.last_mut() {
+            Some(last)
+                if last.initialized
+                    && extent.initialized
+                    && last.block as u64 + last.len as u64 == extent.block as u64
+                    && last.start + last.len as u64 == extent.start
+                    && last.len as u32 + extent.len as u32 <= MAX_INIT_LEN as u32 =>
+            {
+                last.len += extent.len;
+            }
+            _ => merged.push(extent),
+        }
+    }
+    merged
+}
+
+impl<const SIZE: usize> Ext4FS<SIZE> {
+    /// Write `buffers` back to back into the regular file `number` from byte `offset`,
+    /// returning the bytes written.
+    ///
+    /// Blocks the file already has are overwritten in place. Holes and the range past
+    /// the end are allocated in contiguous runs and linked into the extent tree, which
+    /// moves from `i_block` into a leaf block once it needs more than four extents.
+    /// The inode and its leaf go to the device before this returns, the bitmaps and
+    /// free counts, like for [`Ext4FS::write_blocks`], on the next [`Ext4FS::flush`].
+    ///
+    /// Only extent mapped files whose tree has at most one leaf are written, anything
+    /// else is [`OperateError::Fault`], and so is writing into an uninitialised extent.
+    /// On an error the blocks taken by this call are given back, blocks the file
+    /// already had may hold part of the new data.
+    pub fn write_at(
+        &mut self,
+        number: u32,
+        offset: u64,
+        buffers: &[&[u8]],
+    ) -> Result<usize, OperateError> {
+        let mut taken = Vec::new();
+        let result = self.write_file(number, offset, buffers, &mut taken);
+        if result.is_err() {
+            self.release(&taken);
+        }
+        result
+    }
+
+    /// [`Ext4FS::write_at`], every block it allocates goes into `taken` for the rollback.
+    fn write_file(
+        &mut self,
+        number: u32,
+        offset: u64,
+        buffers: &[&[u8]],
+        taken: &mut Vec<Extent>,
+    ) -> Result<usize, OperateError> {
+        let (location, mut raw) = self.read_inode(number)?;
+        let inode = Inode::from_bytes(number, &raw);
+        if inode.is_dir() {
+            return Err(OperateError::IsDirectory);
+        }
+        // block maps and inline data are only read so far
+        if !inode.is_file()
+            || !inode.has_flag(EXT4_EXTENTS_FL)
+            || inode.has_flag(EXT4_INLINE_DATA_FL)
+        {
+            return Err(OperateError::Fault);
+        }
+
+        let total = buffers.iter().map(|buffer| buffer.len()).sum::<usize>();
+        if total == 0 {
+            return Ok(0);
+        }
+        let block_size = self.block_size();
+        let end = offset
+            .checked_add(total as u64)
+            .filter(|end| end.div_ceil(block_size as u64) <= u32::MAX as u64)
+            .ok_or(OperateError::Fault)?;
+        let first = (offset / block_size as u64) as u32;
+        let last = ((end - 1) / block_size as u64) as u32;
+
+        let (mut extents, leaf) = self.read_extents(&inode)?;
+        // they would have to be split into written and unwritten parts
+        if extents.iter().any(|extent| {
+            !extent.initialized
+                && extent.block <= last
+                && extent.block as u64 + extent.len as u64 > first as u64
+        }) {
+            return Err(OperateError::Fault);
+        }
+
+        let group = (number - 1) / self.sb().s_inodes_per_group;
+        let mut sources = buffers.iter().copied();
+        let mut pending: &[u8] = &[];
+        let mut block = first;
+        while block <= last {
+            let mapped = extents
+                .iter()
+                .find(|extent| extent.contains(block))
+                .copied();
+            let (start, len) = match mapped {
+                Some(extent) => {
+                    let extent_end = extent.block as u64 + extent.len as u64;
+                    let len = extent_end.min(last as u64 + 1) - block as u64;
+                    (extent.start + (block - extent.block) as u64, len as usize)
+                }
+                None => {
+                    let hole_end = extents
+                        .iter()
+                        .map(|extent| extent.block as u64)
+                        .filter(|&next| next > block as u64)
+                        .fold(last as u64 + 1, u64::min);
+                    // keep the file contiguous behind the extent before the hole
+                    let goal = extents
+                        .iter()
+                        .filter(|extent| extent.block < block)
+                        .max_by_key(|extent| extent.block)
+                        .and_then(|extent| self.block_group(extent.start + extent.len as u64).ok())
+                        .map_or(group, |(group, _)| group);
+                    let count = (hole_end - block as u64).min(MAX_INIT_LEN as u64);
+                    let (start, len) = self.allocate_blocks(goal, count as usize)?;
+                    let extent = Extent {
+                        block,
+                        len: len as u16,
+                        start,
+                        initialized: true,
+                    };
+                    taken.push(extent);
+                    extents.push(extent);
+                    (start, len)
+                }
+            };
+
+            let run_first = block as u64 * block_size as u64;
+            let run_end = run_first + (len * block_size) as u64;
+            let mut run = vec![0u8; len * block_size];
+            if mapped.is_some() {
+                // keep what the write does not cover in the edge blocks
+                if offset > run_first {
+                    self.read(
+                        Metadata::Data,
+                        start as usize * block_size,
+                        &mut run[..block_size],
+                    )?;
+                }
+                if end < run_end && (len > 1 || offset <= run_first) {
+                    let at = (len - 1) * block_size;
+                    let edge = (start as usize + len - 1) * block_size;
+                    self.read(Metadata::Data, edge, &mut run[at..])?;
+                }
+            }
+            let from = (offset.max(run_first) - run_first) as usize;
+            let to = (end.min(run_end) - run_first) as usize;
+            gather(&mut run[from..to], &mut pending, &mut sources);
+            self.write(Metadata::Data, start as usize * block_size, &run)?;
+            block += len as u32;
+        }
+
+        let extents = merge(extents);
+        if leaf.is_none() && extents.len() <= ROOT_ENTRIES {
+            write_leaf(&mut raw[BLOCK..BLOCK + BLOCK_SIZE], ROOT_ENTRIES, &extents);
+        } else {
+            let max = (block_size - ENTRY_SIZE) / ENTRY_SIZE;
+            // splitting into several leaves is not done yet
+            if extents.len() > max {
+                return Err(OperateError::TableFull);
+            }
+            let leaf = match leaf {
+                Some(leaf) => leaf,
+                None => {
+                    let (start, _) = self.allocate_blocks(group, 1)?;
+                    taken.push(Extent {
+                        block: 0,
+                        len: 1,
+                        start,
+                        initialized: true,
+                    });
+                    start
+                }
+            };
+            let mut node = vec![0u8; block_size];
+            write_leaf(&mut node, max, &extents);
+            if self.has_metadata_csum() {
+                let tail = ENTRY_SIZE * (1 + max);
+                let checksum = self.crc32c(self.inode_checksum_seed(number, &raw), &node[..tail]);
+                put_u32(&mut node, tail, checksum);
+            }
+            self.write(Metadata::BlockMap, leaf as usize * block_size, &node)?;
+
+            let root = &mut raw[BLOCK..BLOCK + BLOCK_SIZE];
+            root.fill(0);
+            ExtentHeader {
+                magic: EXTENT_MAGIC,
+                entries: 1,
+                max: ROOT_ENTRIES as u16,
+                depth: 1,
+            }
+            .store(root);
+            ExtentIndex {
+                block: extents[0].block,
+                leaf,
+            }
+            .store(&mut root[ENTRY_SIZE..]);
+        }
+
+        let size = inode.size().max(end);
+        put_u32(&mut raw, SIZE_LO, size as u32);
+        put_u32(&mut raw, SIZE_HIGH, (size >> 32) as u32);
+        let per_block = if inode.has_flag(EXT4_HUGE_FILE_FL) {
+            1
+        } else {
+            block_size as u64 / SECTOR_SIZE
+        };
+        let added = taken.iter().map(|extent| extent.len as u64).sum::<u64>();
+        let blocks = ((u16_at(&raw, BLOCKS_HIGH) as u64) << 32 | u32_at(&raw, BLOCKS_LO) as u64)
+            + added * per_block;
+        put_u32(&mut raw, BLOCKS_LO, blocks as u32);
+        put_u16(&mut raw, BLOCKS_HIGH, (blocks >> 32) as u16);
+        let now = self.now();
+        if now != 0 {
+            put_u32(&mut raw, MTIME, now);
+            put_u32(&mut raw, CTIME, now);
+        }
+        if self.has_metadata_csum() {
+            let checksum = self.inode_checksum(self.inode_checksum_seed(number, &raw), &raw);
+            put_u16(&mut raw, CHECKSUM_LO, checksum as u16);
+            if has_checksum_hi(&raw) {
+                put_u16(&mut raw, CHECKSUM_HI, (checksum >> 16) as u16);
+            }
+        }
+        self.write(Metadata::InodeTable, location, &raw)?;
+        Ok(total)
+    }
+
+    /// Byte offset of inode `number` on the device and its on-disk bytes.
+    fn read_inode(&self, number: u32) -> Result<(usize, Vec<u8>), OperateError> {
+        let sb = self.sb();
+        if number == 0 || number > sb.s_inodes_count {
+            return Err(OperateError::Fault);
+        }
+
+        let group = (number - 1) / sb.s_inodes_per_group;
+        let index = (number - 1) % sb.s_inodes_per_group;
+        let inode_size = sb.inode_size();
+        let table = self.groups[group as usize].descriptor.inode_table();
+        let offset = table as usize * self.block_size() + index as usize * inode_size;
+
+        let mut raw = vec![0u8; inode_size];
+        self.read(Metadata::InodeTable, offset, &mut raw)?;
+        Ok((offset, raw))
+    }
+
+    /// Extents of `inode` in logical order and the leaf block holding them, if the tree
+    /// has one.
+    fn read_extents(&self, inode: &Inode) -> Result<(Vec<Extent>, Option<u64>), OperateError> {
+        let root = inode.block();
+        let header = ExtentHeader::from_bytes(root);
+        if !header.is_valid(root.len()) {
+            return Err(OperateError::InvalidFileSystem);
+        }
+        let (node, leaf) = match (header.depth, header.entries) {
+            (0, _) => (root.to_vec(), None),
+            (1, 1) => {
+                let leaf = ExtentIndex::from_bytes(&root[ENTRY_SIZE..]).leaf;
+                self.block_group(leaf)?;
+                (self.read_blocks(Metadata::BlockMap, leaf, 1)?, Some(leaf))
+            }
+            // deeper trees and several leaves are only read so far
+            _ => return Err(OperateError::Fault),
+        };
+
+        let header = ExtentHeader::from_bytes(&node);
+        if !header.is_valid(node.len()) || header.depth != 0 {
+            return Err(OperateError::InvalidFileSystem);
+        }
+        let extents = (1..=header.entries as usize)
+            .map(|index| Extent::from_bytes(&node[index * ENTRY_SIZE..]))
+            .collect::<Vec<_>>();
+        let ordered = extents
+            .windows(2)
+            .all(|pair| pair[0].block as u64 + pair[0].len as u64 <= pair[1].block as u64);
+        if !ordered || extents.iter().any(|extent| extent.len == 0) {
+            return Err(OperateError::InvalidFileSystem);
+        }
+        Ok((extents, leaf))
+    }
+}
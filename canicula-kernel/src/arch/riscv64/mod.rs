@@ -1,6 +1,6 @@
+use canicula_common::qemu::TestExit;
 use core::arch::global_asm;
 use log::*;
-use qemu::QEMUExit;
 use qemu::QEMU_EXIT_HANDLE;
 
 use crate::println;
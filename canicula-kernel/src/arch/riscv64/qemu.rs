@@ -1,27 +1,10 @@
 //ref:: https://github.com/andre-richter/qemu-exit
 use core::arch::asm;
 
+use canicula_common::qemu::{TestExit, TestResult};
+
 const VIRT_TEST: u64 = 0x100000;
-const EXIT_SUCCESS: u32 = 0x5555; // Equals `exit(0)`. qemu successful exit
 const EXIT_FAILURE_FLAG: u32 = 0x3333;
-const EXIT_FAILURE: u32 = exit_code_encode(1); // Equals `exit(1)`. qemu failed exit
-const EXIT_RESET: u32 = 0x7777; // qemu reset
-
-#[allow(dead_code)]
-pub trait QEMUExit {
-    /// Exit with specified return code.
-    ///
-    /// Note: For `X86`, code is binary-OR'ed with `0x1` inside QEMU.
-    fn exit(&self, code: u32) -> !;
-
-    /// Exit QEMU using `EXIT_SUCCESS`, aka `0`, if possible.
-    ///
-    /// Note: Not possible for `X86`.
-    fn exit_success(&self) -> !;
-
-    /// Exit QEMU using `EXIT_FAILURE`, aka `1`.
-    fn exit_failure(&self) -> !;
-}
 
 /// RISCV64 configuration
 pub struct RISCV64 {
@@ -41,14 +24,11 @@ impl RISCV64 {
     }
 }
 
-impl QEMUExit for RISCV64 {
-    /// Exit qemu with specified exit code.
-    fn exit(&self, code: u32) -> ! {
-        // If code is not a special value, we need to encode it with EXIT_FAILURE_FLAG.
-        let code_new = match code {
-            EXIT_SUCCESS | EXIT_FAILURE | EXIT_RESET => code,
-            _ => exit_code_encode(code),
-        };
+impl TestExit for RISCV64 {
+    /// Exit qemu with the host status of `result`.
+    fn exit(&self, result: TestResult) -> ! {
+        // sifive_test exits with the upper half as the status when the lower half is EXIT_FAILURE_FLAG.
+        let code_new = exit_code_encode(result.host_status());
 
         unsafe {
             asm!(
@@ -65,14 +45,6 @@ impl QEMUExit for RISCV64 {
             }
         }
     }
-
-    fn exit_success(&self) -> ! {
-        self.exit(EXIT_SUCCESS);
-    }
-
-    fn exit_failure(&self) -> ! {
-        self.exit(EXIT_FAILURE);
-    }
 }
 
 pub const QEMU_EXIT_HANDLE: RISCV64 = RISCV64::new(VIRT_TEST);
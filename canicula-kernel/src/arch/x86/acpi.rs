@@ -0,0 +1,150 @@
+//! ACPI tables, found from the RSDP the loader passed on.
+//!
+//! Only the static tables are read, through the physical memory mapping. There
+//! is no AML interpreter, so nothing in the DSDT or SSDTs is understood.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use canicula_common::entry::BootInfo;
+use log::{info, warn};
+
+use super::frames;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// The ACPI 1.0 part of the RSDP, covered by the first checksum.
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_REVISION: usize = 15;
+const RSDP_RSDT: usize = 16;
+const RSDP_LENGTH: usize = 20;
+const RSDP_XSDT: usize = 24;
+
+/// The header every system description table starts with.
+pub const HEADER_SIZE: usize = 36;
+const HEADER_LENGTH: usize = 4;
+
+// where the FADT keeps the DSDT's address
+const FADT_DSDT: usize = 40;
+const FADT_X_DSDT: usize = 140;
+
+/// Physical address of the XSDT, or of the RSDT for ACPI 1.0, `0` without ACPI.
+static ROOT: AtomicU64 = AtomicU64::new(0);
+/// Size of the root table's entries, 8 for the XSDT and 4 for the RSDT.
+static ROOT_ENTRY_SIZE: AtomicU64 = AtomicU64::new(0);
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+pub fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+/// `len` bytes of physical memory at `address`.
+///
+/// The firmware keeps its tables in ACPI reclaimable memory, which the kernel
+/// never hands out, so they stay put for as long as the kernel runs.
+fn physical(address: u64, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(frames::to_virtual(address), len) }
+}
+
+/// Whether the bytes add up to zero, as every ACPI checksum has them.
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// The whole table at `address`, `None` if its checksum is off.
+fn table(address: u64) -> Option<&'static [u8]> {
+    let header = physical(address, HEADER_SIZE);
+    let length = read_u32(header, HEADER_LENGTH) as usize;
+    if length < HEADER_SIZE {
+        return None;
+    }
+    let table = physical(address, length);
+    checksum(table).then_some(table)
+}
+
+/// Find the root table, after [`frames::init`] mapped physical memory.
+pub fn init(boot_info: &BootInfo) {
+    let rsdp = boot_info.firmware.rsdp;
+    if rsdp == 0 {
+        info!("[acpi] no RSDP");
+        return;
+    }
+    let v1 = physical(rsdp, RSDP_V1_SIZE);
+    if &v1[..8] != RSDP_SIGNATURE || !checksum(v1) {
+        warn!("[acpi] bad RSDP at {:#x}", rsdp);
+        return;
+    }
+    let revision = v1[RSDP_REVISION];
+    let (root, entry_size) = if revision >= 2 {
+        let length = read_u32(v1, RSDP_LENGTH) as usize;
+        let rsdp = physical(rsdp, length.max(RSDP_XSDT + 8));
+        if !checksum(&rsdp[..length]) {
+            warn!("[acpi] bad ACPI 2.0 RSDP checksum");
+            return;
+        }
+        (read_u64(rsdp, RSDP_XSDT), 8)
+    } else {
+        (read_u32(v1, RSDP_RSDT) as u64, 4)
+    };
+    if table(root).is_none() {
+        warn!("[acpi] bad root table at {:#x}", root);
+        return;
+    }
+    ROOT.store(root, Ordering::Relaxed);
+    ROOT_ENTRY_SIZE.store(entry_size, Ordering::Relaxed);
+    info!(
+        "[acpi] revision {}, {} at {:#x}",
+        revision,
+        if entry_size == 8 { "XSDT" } else { "RSDT" },
+        root
+    );
+}
+
+/// Every table the root table lists with `signature`, headers included.
+fn tables(signature: &[u8; 4]) -> impl Iterator<Item = &'static [u8]> + '_ {
+    let root = Some(ROOT.load(Ordering::Relaxed))
+        .filter(|root| *root != 0)
+        .and_then(table);
+    let entry_size = ROOT_ENTRY_SIZE.load(Ordering::Relaxed) as usize;
+    root.into_iter()
+        .flat_map(move |root| root[HEADER_SIZE..].chunks_exact(entry_size))
+        .map(move |entry| match entry_size {
+            8 => read_u64(entry, 0),
+            _ => read_u32(entry, 0) as u64,
+        })
+        .filter(move |address| *address != 0 && physical(*address, 4) == signature)
+        .filter_map(table)
+}
+
+/// The first table with `signature`, header included.
+pub fn find(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    tables(signature).next()
+}
+
+/// Call `visit` with every table with `signature`, e.g. each SSDT.
+pub fn find_all(signature: &[u8; 4], visit: impl FnMut(&'static [u8])) {
+    tables(signature).for_each(visit);
+}
+
+/// The DSDT, which the FADT points at rather than the root table.
+pub fn dsdt() -> Option<&'static [u8]> {
+    let fadt = find(b"FACP")?;
+    let address = if fadt.len() >= FADT_X_DSDT + 8 && read_u64(fadt, FADT_X_DSDT) != 0 {
+        read_u64(fadt, FADT_X_DSDT)
+    } else {
+        read_u32(fadt, FADT_DSDT) as u64
+    };
+    (address != 0)
+        .then(|| table(address))
+        .flatten()
+        .filter(|dsdt| &dsdt[..4] == b"DSDT")
+}
@@ -0,0 +1,392 @@
+//! SATA disks behind the first AHCI controller the driver is bound to.
+//!
+//! Each disk gets one frame for its command list, received FIS area and a
+//! single command table, and a bounce buffer that every transfer goes through.
+//! Commands use slot 0 and are polled for, the controller's interrupt stays off.
+
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+
+use canicula_common::fs::OperateError;
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use super::block::{self, BlockDevice, SECTOR_SIZE};
+use super::device::Device;
+use super::driver::{register_driver, Driver};
+use super::frames::{self, Dma, Owner, FRAME_SIZE};
+use super::pci::{Bar, PciDevice, COMMAND_BUS_MASTER, COMMAND_MEMORY_SPACE};
+use super::vm;
+
+const CLASS_STORAGE: u8 = 0x01;
+const SUBCLASS_SATA: u8 = 0x06;
+const PROG_IF_AHCI: u8 = 0x01;
+/// AHCI base address, the controller's registers.
+const ABAR: u8 = 5;
+
+// generic host control
+const CAP2: usize = 0x24;
+const GHC: usize = 0x04;
+const PI: usize = 0x0c;
+const BOHC: usize = 0x28;
+const GHC_AE: u32 = 1 << 31;
+const CAP2_BOH: u32 = 1 << 0;
+const BOHC_BOS: u32 = 1 << 0;
+const BOHC_OOS: u32 = 1 << 1;
+
+const PORTS: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const MAX_PORTS: usize = 32;
+
+// port registers
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+const IS_TFES: u32 = 1 << 30;
+const SSTS_DET_PRESENT: u32 = 3;
+const SSTS_IPM_ACTIVE: u32 = 1;
+const SIG_ATA: u32 = 0x0000_0101;
+
+// where the port's structures sit within its frame
+const COMMAND_LIST: usize = 0x000;
+const RECEIVED_FIS: usize = 0x400;
+const COMMAND_TABLE: usize = 0x800;
+const PRDT: usize = 0x80;
+
+const FIS_REG_H2D: u8 = 0x27;
+/// The FIS carries a command rather than device control.
+const FIS_COMMAND: u8 = 0x80;
+/// Register FIS length in dwords.
+const FIS_LENGTH: u32 = 5;
+const DEVICE_LBA: u8 = 1 << 6;
+
+const ATA_IDENTIFY: u8 = 0xec;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+
+/// Sectors moved per command, the size of each disk's bounce buffer.
+const TRANSFER_SECTORS: usize = 128;
+/// Register polls before a command or state change counts as timed out.
+const SPIN_LIMIT: usize = 10_000_000;
+
+#[derive(Clone, Copy)]
+struct Registers(u64);
+
+impl Registers {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.0 + offset as u64) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.0 + offset as u64) as *mut u32).write_volatile(value) }
+    }
+
+    /// Poll until `done` holds for the register at `offset`.
+    fn wait(&self, offset: usize, done: impl Fn(u32) -> bool) -> Result<(), OperateError> {
+        for _ in 0..SPIN_LIMIT {
+            if done(self.read(offset)) {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(OperateError::TimeOut)
+    }
+}
+
+struct Port {
+    registers: Registers,
+    memory: Dma,
+    buffer: Dma,
+}
+
+// the DMA memory belongs to the port alone
+unsafe impl Send for Port {}
+
+impl Port {
+    fn stop(&self) -> Result<(), OperateError> {
+        let registers = self.registers;
+        registers.write(PX_CMD, registers.read(PX_CMD) & !CMD_ST);
+        registers.wait(PX_CMD, |cmd| cmd & CMD_CR == 0)?;
+        registers.write(PX_CMD, registers.read(PX_CMD) & !CMD_FRE);
+        registers.wait(PX_CMD, |cmd| cmd & CMD_FR == 0)
+    }
+
+    fn start(&self) -> Result<(), OperateError> {
+        self.stop()?;
+        let registers = self.registers;
+        let list = self.memory.physical + COMMAND_LIST as u64;
+        let fis = self.memory.physical + RECEIVED_FIS as u64;
+        registers.write(PX_CLB, list as u32);
+        registers.write(PX_CLBU, (list >> 32) as u32);
+        registers.write(PX_FB, fis as u32);
+        registers.write(PX_FBU, (fis >> 32) as u32);
+        // both are write one to clear
+        registers.write(PX_SERR, u32::MAX);
+        registers.write(PX_IS, u32::MAX);
+        registers.write(PX_IE, 0);
+        registers.write(PX_CMD, registers.read(PX_CMD) | CMD_FRE);
+        registers.write(PX_CMD, registers.read(PX_CMD) | CMD_ST);
+        Ok(())
+    }
+
+    /// Run an ATA command moving `sectors` sectors between the disk and the bounce buffer.
+    fn issue(
+        &mut self,
+        command: u8,
+        lba: u64,
+        sectors: usize,
+        write: bool,
+    ) -> Result<(), OperateError> {
+        let registers = self.registers;
+        registers.wait(PX_TFD, |tfd| tfd & (TFD_BSY | TFD_DRQ) == 0)?;
+
+        let bytes = (sectors * SECTOR_SIZE) as u32;
+        let table = self.memory.physical + COMMAND_TABLE as u64;
+        unsafe {
+            let header = self.memory.virtual_address.add(COMMAND_LIST) as *mut u32;
+            header.write_volatile(FIS_LENGTH | (write as u32) << 6 | 1 << 16);
+            // bytes transferred, the controller counts up from here
+            header.add(1).write_volatile(0);
+            header.add(2).write_volatile(table as u32);
+            header.add(3).write_volatile((table >> 32) as u32);
+
+            let fis = self.memory.virtual_address.add(COMMAND_TABLE);
+            core::ptr::write_bytes(fis, 0, PRDT + 16);
+            let lba = lba.to_le_bytes();
+            let count = (sectors as u16).to_le_bytes();
+            let register = [
+                FIS_REG_H2D,
+                FIS_COMMAND,
+                command,
+                0,
+                lba[0],
+                lba[1],
+                lba[2],
+                DEVICE_LBA,
+                lba[3],
+                lba[4],
+                lba[5],
+                0,
+                count[0],
+                count[1],
+            ];
+            core::ptr::copy_nonoverlapping(register.as_ptr(), fis, register.len());
+
+            let entry = fis.add(PRDT) as *mut u32;
+            entry.write_volatile(self.buffer.physical as u32);
+            entry
+                .add(1)
+                .write_volatile((self.buffer.physical >> 32) as u32);
+            entry.add(3).write_volatile(bytes - 1);
+        }
+        // the command has to be in memory before the controller is told about it
+        fence(Ordering::SeqCst);
+
+        registers.write(PX_IS, u32::MAX);
+        registers.write(PX_CI, 1);
+        for _ in 0..SPIN_LIMIT {
+            if registers.read(PX_IS) & IS_TFES != 0 {
+                return Err(OperateError::IO);
+            }
+            if registers.read(PX_CI) & 1 == 0 {
+                fence(Ordering::SeqCst);
+                if registers.read(PX_TFD) & TFD_ERR != 0 {
+                    return Err(OperateError::IO);
+                }
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(OperateError::TimeOut)
+    }
+
+    fn buffer(&mut self, len: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.buffer.virtual_address, len) }
+    }
+}
+
+struct Disk {
+    name: &'static str,
+    port: Mutex<Port>,
+    sectors: u64,
+}
+
+impl Disk {
+    /// Check that `len` bytes at `sector` are whole sectors on the disk.
+    fn check(&self, sector: u64, len: usize) -> Result<(), OperateError> {
+        let count = (len / SECTOR_SIZE) as u64;
+        if len & (SECTOR_SIZE - 1) != 0
+            || sector
+                .checked_add(count)
+                .is_none_or(|end| end > self.sectors)
+        {
+            return Err(OperateError::Fault);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Disk {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), OperateError> {
+        self.check(sector, buffer.len())?;
+        let mut port = self.port.lock();
+        for (index, chunk) in buffer
+            .chunks_mut(TRANSFER_SECTORS * SECTOR_SIZE)
+            .enumerate()
+        {
+            let lba = sector + (index * TRANSFER_SECTORS) as u64;
+            port.issue(ATA_READ_DMA_EXT, lba, chunk.len() / SECTOR_SIZE, false)?;
+            chunk.copy_from_slice(port.buffer(chunk.len()));
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), OperateError> {
+        self.check(sector, buffer.len())?;
+        let mut port = self.port.lock();
+        for (index, chunk) in buffer.chunks(TRANSFER_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = sector + (index * TRANSFER_SECTORS) as u64;
+            port.buffer(chunk.len()).copy_from_slice(chunk);
+            port.issue(ATA_WRITE_DMA_EXT, lba, chunk.len() / SECTOR_SIZE, true)?;
+        }
+        Ok(())
+    }
+}
+
+const NAMES: [&str; MAX_PORTS] = [
+    "sata0", "sata1", "sata2", "sata3", "sata4", "sata5", "sata6", "sata7", "sata8", "sata9",
+    "sata10", "sata11", "sata12", "sata13", "sata14", "sata15", "sata16", "sata17", "sata18",
+    "sata19", "sata20", "sata21", "sata22", "sata23", "sata24", "sata25", "sata26", "sata27",
+    "sata28", "sata29", "sata30", "sata31",
+];
+
+static DISKS: [Once<Disk>; MAX_PORTS] = [const { Once::new() }; MAX_PORTS];
+static CLAIMED: AtomicBool = AtomicBool::new(false);
+
+/// Bring up the disk on port `index`, `None` if there is no ATA disk.
+fn attach(hba: Registers, index: usize) -> Result<Option<Disk>, OperateError> {
+    let registers = Registers(hba.0 + (PORTS + index * PORT_SIZE) as u64);
+    let status = registers.read(PX_SSTS);
+    if status & 0xf != SSTS_DET_PRESENT || (status >> 8) & 0xf != SSTS_IPM_ACTIVE {
+        return Ok(None);
+    }
+    if registers.read(PX_SIG) != SIG_ATA {
+        return Ok(None);
+    }
+
+    let memory = frames::allocate_dma(FRAME_SIZE as usize, Owner::Drivers)
+        .ok_or(OperateError::DeviceNoFreeSpace)?;
+    let Some(buffer) = frames::allocate_dma(TRANSFER_SECTORS * SECTOR_SIZE, Owner::Drivers) else {
+        frames::free_dma(memory);
+        return Err(OperateError::DeviceNoFreeSpace);
+    };
+    let mut port = Port {
+        registers,
+        memory,
+        buffer,
+    };
+    port.start()?;
+    port.issue(ATA_IDENTIFY, 0, 1, false)?;
+
+    let identify = port.buffer(SECTOR_SIZE);
+    let word =
+        |index: usize| u16::from_le_bytes([identify[index * 2], identify[index * 2 + 1]]) as u64;
+    let lba48 = word(100) | word(101) << 16 | word(102) << 32 | word(103) << 48;
+    let sectors = if lba48 != 0 {
+        lba48
+    } else {
+        word(60) | word(61) << 16
+    };
+    info!(
+        "[ahci] port {}: {} MiB",
+        index,
+        (sectors * SECTOR_SIZE as u64) >> 20
+    );
+    Ok(Some(Disk {
+        name: NAMES[index],
+        port: Mutex::new(port),
+        sectors,
+    }))
+}
+
+fn probe(controller: &PciDevice) -> Result<(), &'static str> {
+    // the disk names and slots are for one controller
+    if CLAIMED.swap(true, Ordering::Relaxed) {
+        return Err("only the first AHCI controller is used");
+    }
+    let Some(Bar::Memory { address, size }) = controller.bar(ABAR) else {
+        return Err("no AHCI register BAR");
+    };
+    let base = vm::map_mmio(address, size).map_err(|_| "cannot map the AHCI registers")?;
+    controller.enable(COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+    let hba = Registers(base);
+
+    // firmware may still own the controller
+    if hba.read(CAP2) & CAP2_BOH != 0 {
+        hba.write(BOHC, hba.read(BOHC) | BOHC_OOS);
+        if hba.wait(BOHC, |bohc| bohc & BOHC_BOS == 0).is_err() {
+            warn!("[ahci] firmware did not hand the controller over");
+        }
+    }
+    hba.write(GHC, hba.read(GHC) | GHC_AE);
+
+    let implemented = hba.read(PI);
+    for index in (0..MAX_PORTS).filter(|index| implemented & (1 << index) != 0) {
+        match attach(hba, index) {
+            Ok(Some(disk)) => {
+                let disk = DISKS[index].call_once(|| disk);
+                if block::register(disk).is_none() {
+                    warn!("[ahci] no room to register {}", disk.name);
+                }
+            }
+            Ok(None) => {}
+            Err(error) => warn!("[ahci] port {}: {:?}", index, error),
+        }
+    }
+    Ok(())
+}
+
+struct Ahci;
+
+impl Driver for Ahci {
+    fn name(&self) -> &'static str {
+        "ahci"
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        device.pci().is_some_and(|device| {
+            (device.class, device.subclass, device.prog_if)
+                == (CLASS_STORAGE, SUBCLASS_SATA, PROG_IF_AHCI)
+        })
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), &'static str> {
+        probe(device.pci().ok_or("not a PCI device")?)
+    }
+}
+
+register_driver!(Ahci);
@@ -0,0 +1,653 @@
+//! ACPI devices read out of the AML in the DSDT and SSDTs, without running it.
+//!
+//! There is no interpreter. The walk follows the definition blocks' scopes and
+//! devices and reads `_HID` and `_CRS` where they are plain `Name` objects, as
+//! they are for the legacy devices: PS/2, serial ports, the RTC. Whatever a
+//! method computes is not known, `_STA` included, so every device counts as
+//! present. An object the walk cannot step over ends the scope it is in, and
+//! whatever follows it there is missed.
+
+use core::fmt;
+
+use log::info;
+use spin::Mutex;
+
+use super::acpi::{self, HEADER_SIZE};
+
+const MAX_DEVICES: usize = 64;
+/// Deepest path kept, scopes below it are skipped.
+const MAX_DEPTH: usize = 8;
+pub const MAX_RESOURCES: usize = 8;
+const MAX_HID: usize = 16;
+
+// opcodes
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ALIAS_OP: u8 = 0x06;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const VAR_PACKAGE_OP: u8 = 0x13;
+const METHOD_OP: u8 = 0x14;
+const EXTERNAL_OP: u8 = 0x15;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const IF_OP: u8 = 0xa0;
+const ELSE_OP: u8 = 0xa1;
+const WHILE_OP: u8 = 0xa2;
+const ONES_OP: u8 = 0xff;
+// after EXT_OP_PREFIX
+const MUTEX_OP: u8 = 0x01;
+const EVENT_OP: u8 = 0x02;
+const REVISION_OP: u8 = 0x30;
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+const PROCESSOR_OP: u8 = 0x83;
+const POWER_RES_OP: u8 = 0x84;
+const THERMAL_ZONE_OP: u8 = 0x85;
+const INDEX_FIELD_OP: u8 = 0x86;
+const BANK_FIELD_OP: u8 = 0x87;
+
+// name strings
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX: u8 = b'^';
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const NULL_NAME: u8 = 0x00;
+
+// resource descriptors, small ones by type and large ones by tag
+const SMALL_IRQ: u8 = 0x04;
+const SMALL_IO: u8 = 0x08;
+const SMALL_FIXED_IO: u8 = 0x09;
+const SMALL_END: u8 = 0x0f;
+const LARGE: u8 = 0x80;
+const LARGE_MEMORY32: u8 = 0x85;
+const LARGE_FIXED_MEMORY32: u8 = 0x86;
+const LARGE_DWORD_ADDRESS: u8 = 0x87;
+const LARGE_WORD_ADDRESS: u8 = 0x88;
+const LARGE_EXTENDED_IRQ: u8 = 0x89;
+const LARGE_QWORD_ADDRESS: u8 = 0x8a;
+const ADDRESS_MEMORY: u8 = 0;
+const ADDRESS_IO: u8 = 1;
+
+/// A four character name segment, like `_SB_` or `PCI0`.
+type Segment = [u8; 4];
+
+/// An absolute path in the namespace.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Path {
+    segments: [Segment; MAX_DEPTH],
+    depth: u8,
+}
+
+impl Path {
+    const ROOT: Path = Path {
+        segments: [[0; 4]; MAX_DEPTH],
+        depth: 0,
+    };
+
+    fn segments(&self) -> &[Segment] {
+        &self.segments[..self.depth as usize]
+    }
+
+    /// The path `name` means when it appears in scope `self`.
+    fn resolve(&self, name: &Name) -> Option<Path> {
+        let mut path = if name.absolute { Path::ROOT } else { *self };
+        path.depth = path.depth.checked_sub(name.parents)?;
+        for segment in name.segments() {
+            *path.segments.get_mut(path.depth as usize)? = *segment;
+            path.depth += 1;
+        }
+        Some(path)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth as usize
+    }
+
+    /// Whether `other` lies below this path.
+    pub fn contains(&self, other: &Path) -> bool {
+        other.depth > self.depth && other.segments().starts_with(self.segments())
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\\")?;
+        for (index, segment) in self.segments().iter().enumerate() {
+            if index != 0 {
+                f.write_str(".")?;
+            }
+            // segments are upper case letters, digits and underscores
+            f.write_str(core::str::from_utf8(segment).unwrap_or("????"))?;
+        }
+        Ok(())
+    }
+}
+
+/// A name string as written, relative to the scope it is in unless `absolute`.
+#[derive(Default)]
+struct Name {
+    absolute: bool,
+    parents: u8,
+    segments: [Segment; MAX_DEPTH],
+    count: u8,
+}
+
+impl Name {
+    fn segments(&self) -> &[Segment] {
+        &self.segments[..self.count as usize]
+    }
+
+    /// The one segment of a plain name like `_HID`.
+    fn single(&self) -> Option<Segment> {
+        (!self.absolute && self.parents == 0 && self.count == 1).then_some(self.segments[0])
+    }
+}
+
+/// A device's hardware ID, e.g. `PNP0303`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Hid {
+    bytes: [u8; MAX_HID],
+    len: u8,
+}
+
+impl Hid {
+    /// An ID given as text, cut short past [`MAX_HID`] characters.
+    pub fn new(id: &str) -> Self {
+        let len = id.len().min(MAX_HID);
+        let mut bytes = [0; MAX_HID];
+        bytes[..len].copy_from_slice(&id.as_bytes()[..len]);
+        Hid {
+            bytes,
+            len: len as u8,
+        }
+    }
+
+    /// An ID compressed into an integer by ASL's `EISAID()`.
+    fn from_eisa(value: u32) -> Self {
+        const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+        let bytes = value.to_le_bytes();
+        let vendor = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let product = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let mut hid = Hid {
+            bytes: [0; MAX_HID],
+            len: 7,
+        };
+        for (index, shift) in [10, 5, 0].into_iter().enumerate() {
+            hid.bytes[index] = b'@' + (vendor >> shift & 0x1f) as u8;
+        }
+        for (index, shift) in [12, 8, 4, 0].into_iter().enumerate() {
+            hid.bytes[3 + index] = DIGITS[(product >> shift & 0xf) as usize];
+        }
+        hid
+    }
+
+    fn from_string(id: &[u8]) -> Option<Self> {
+        let id = core::str::from_utf8(id).ok()?;
+        (id.len() <= MAX_HID && id.bytes().all(|byte| byte.is_ascii_graphic()))
+            .then(|| Hid::new(id))
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+}
+
+impl fmt::Display for Hid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Hid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// Something a device decodes, from its `_CRS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Io {
+        base: u16,
+        len: u16,
+    },
+    Memory {
+        base: u64,
+        len: u64,
+    },
+    /// An ISA IRQ, or a GSI from an extended descriptor.
+    Irq(u32),
+}
+
+#[derive(Clone, Copy)]
+pub struct AcpiDevice {
+    pub path: Path,
+    pub hid: Option<Hid>,
+    resources: [Option<Resource>; MAX_RESOURCES],
+}
+
+impl AcpiDevice {
+    /// What `_CRS` lists, the first [`MAX_RESOURCES`] of them.
+    pub fn resources(&self) -> impl Iterator<Item = Resource> {
+        self.resources.into_iter().flatten()
+    }
+}
+
+type Devices = [Option<AcpiDevice>; MAX_DEVICES];
+
+static DEVICES: Mutex<Devices> = Mutex::new([None; MAX_DEVICES]);
+
+/// A value a `Name` is given.
+enum Value<'a> {
+    Integer(u64),
+    String(&'a [u8]),
+    Buffer(&'a [u8]),
+    Other,
+}
+
+/// A cursor over AML, every read `None` when it would run past the end.
+struct Reader<'a> {
+    aml: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(aml: &'a [u8]) -> Self {
+        Reader { aml, at: 0 }
+    }
+
+    fn done(&self) -> bool {
+        self.at >= self.aml.len()
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        self.aml.get(self.at..).unwrap_or(&[])
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.aml.get(self.at).copied()
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.at += 1;
+        Some(byte)
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.aml.get(self.at..self.at + N)?.try_into().ok()?;
+        self.at += N;
+        Some(bytes)
+    }
+
+    /// A PkgLength and the rest of the package it measures, the reader moves past all of it.
+    fn package(&mut self) -> Option<&'a [u8]> {
+        let start = self.at;
+        let lead = self.byte()?;
+        let follow = (lead >> 6) as usize;
+        let mut len = if follow == 0 {
+            (lead & 0x3f) as usize
+        } else {
+            (lead & 0x0f) as usize
+        };
+        for index in 0..follow {
+            len |= (self.byte()? as usize) << (4 + 8 * index);
+        }
+        let body = self.aml.get(self.at..start.checked_add(len)?)?;
+        self.at = start + len;
+        Some(body)
+    }
+
+    fn name(&mut self) -> Option<Name> {
+        let mut name = Name::default();
+        if self.peek()? == ROOT_CHAR {
+            self.at += 1;
+            name.absolute = true;
+        }
+        while self.peek()? == PARENT_PREFIX {
+            self.at += 1;
+            name.parents = name.parents.checked_add(1)?;
+        }
+        let count = match self.peek()? {
+            NULL_NAME => {
+                self.at += 1;
+                0
+            }
+            DUAL_NAME_PREFIX => {
+                self.at += 1;
+                2
+            }
+            MULTI_NAME_PREFIX => {
+                self.at += 1;
+                self.byte()? as usize
+            }
+            b'A'..=b'Z' | b'_' => 1,
+            _ => return None,
+        };
+        if count > MAX_DEPTH {
+            return None;
+        }
+        for index in 0..count {
+            name.segments[index] = self.bytes()?;
+        }
+        name.count = count as u8;
+        Some(name)
+    }
+
+    fn data_object(&mut self) -> Option<Value<'a>> {
+        let value = match self.byte()? {
+            ZERO_OP => Value::Integer(0),
+            ONE_OP => Value::Integer(1),
+            ONES_OP => Value::Integer(u64::MAX),
+            BYTE_PREFIX => Value::Integer(self.byte()? as u64),
+            WORD_PREFIX => Value::Integer(u16::from_le_bytes(self.bytes()?) as u64),
+            DWORD_PREFIX => Value::Integer(u32::from_le_bytes(self.bytes()?) as u64),
+            QWORD_PREFIX => Value::Integer(u64::from_le_bytes(self.bytes()?)),
+            STRING_PREFIX => {
+                let rest = self.rest();
+                let len = rest.iter().position(|byte| *byte == 0)?;
+                self.at += len + 1;
+                Value::String(&rest[..len])
+            }
+            BUFFER_OP => {
+                let mut buffer = Reader::new(self.package()?);
+                // the size, the bytes that follow are what was given of it
+                buffer.term_arg()?;
+                Value::Buffer(buffer.rest())
+            }
+            PACKAGE_OP | VAR_PACKAGE_OP => {
+                self.package()?;
+                Value::Other
+            }
+            EXT_OP_PREFIX if self.peek() == Some(REVISION_OP) => {
+                self.at += 1;
+                Value::Other
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Step over an argument, only a constant or a name is known to end where it seems to.
+    fn term_arg(&mut self) -> Option<()> {
+        match self.peek()? {
+            ZERO_OP | ONE_OP | ONES_OP | BYTE_PREFIX | WORD_PREFIX | DWORD_PREFIX
+            | QWORD_PREFIX => {
+                self.data_object()?;
+            }
+            ROOT_CHAR
+            | PARENT_PREFIX
+            | DUAL_NAME_PREFIX
+            | MULTI_NAME_PREFIX
+            | b'A'..=b'Z'
+            | b'_' => {
+                self.name()?;
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+struct Walker<'a> {
+    devices: &'a mut Devices,
+    /// Scopes whose walk stopped at an object it could not step over.
+    cut_short: usize,
+}
+
+impl Walker<'_> {
+    fn find(&self, path: &Path) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|device| device.is_some_and(|device| device.path == *path))
+    }
+
+    fn add(&mut self, path: Path) -> Option<usize> {
+        if let Some(index) = self.find(&path) {
+            return Some(index);
+        }
+        let index = self.devices.iter().position(Option::is_none)?;
+        self.devices[index] = Some(AcpiDevice {
+            path,
+            hid: None,
+            resources: [None; MAX_RESOURCES],
+        });
+        Some(index)
+    }
+
+    /// Walk the objects of scope `scope`, which is device `device` if it is one.
+    fn term_list(&mut self, aml: &[u8], scope: &Path, device: Option<usize>) {
+        let mut reader = Reader::new(aml);
+        while !reader.done() {
+            if self.object(&mut reader, scope, device).is_none() {
+                self.cut_short += 1;
+                return;
+            }
+        }
+    }
+
+    fn object(&mut self, reader: &mut Reader, scope: &Path, device: Option<usize>) -> Option<()> {
+        match reader.byte()? {
+            SCOPE_OP => {
+                let mut body = Reader::new(reader.package()?);
+                let name = body.name()?;
+                if let Some(path) = scope.resolve(&name) {
+                    let device = self.find(&path);
+                    self.term_list(body.rest(), &path, device);
+                }
+            }
+            NAME_OP => {
+                let name = reader.name()?;
+                let value = reader.data_object()?;
+                if let (Some(index), Some(segment)) = (device, name.single()) {
+                    self.name(index, &segment, value);
+                }
+            }
+            // nothing in these is looked at
+            METHOD_OP | IF_OP | ELSE_OP | WHILE_OP => {
+                reader.package()?;
+            }
+            EXTERNAL_OP => {
+                reader.name()?;
+                reader.bytes::<2>()?;
+            }
+            ALIAS_OP => {
+                reader.name()?;
+                reader.name()?;
+            }
+            EXT_OP_PREFIX => match reader.byte()? {
+                DEVICE_OP => {
+                    let mut body = Reader::new(reader.package()?);
+                    let name = body.name()?;
+                    if let Some(path) = scope.resolve(&name) {
+                        let device = self.add(path);
+                        self.term_list(body.rest(), &path, device);
+                    }
+                }
+                PROCESSOR_OP | POWER_RES_OP | THERMAL_ZONE_OP | FIELD_OP | INDEX_FIELD_OP
+                | BANK_FIELD_OP => {
+                    reader.package()?;
+                }
+                MUTEX_OP => {
+                    reader.name()?;
+                    reader.byte()?;
+                }
+                EVENT_OP => {
+                    reader.name()?;
+                }
+                OP_REGION_OP => {
+                    reader.name()?;
+                    // the space, then the offset and length
+                    reader.byte()?;
+                    reader.term_arg()?;
+                    reader.term_arg()?;
+                }
+                _ => return None,
+            },
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn name(&mut self, index: usize, segment: &Segment, value: Value) {
+        let Some(device) = self.devices[index].as_mut() else {
+            return;
+        };
+        match (segment, value) {
+            (b"_HID", Value::Integer(id)) => device.hid = Some(Hid::from_eisa(id as u32)),
+            (b"_HID", Value::String(id)) => device.hid = Hid::from_string(id),
+            (b"_CRS", Value::Buffer(template)) => device.resources = resources(template),
+            _ => {}
+        }
+    }
+}
+
+/// The resources a resource template describes.
+fn resources(template: &[u8]) -> [Option<Resource>; MAX_RESOURCES] {
+    let mut resources = [None; MAX_RESOURCES];
+    let mut slots = resources.iter_mut();
+    let mut push = |resource| {
+        if let Some(slot) = slots.next() {
+            *slot = Some(resource);
+        }
+    };
+    let mut at = 0;
+    while let Some(&tag) = template.get(at) {
+        let (kind, body) = if tag & LARGE == 0 {
+            let len = (tag & 0x7) as usize;
+            ((tag >> 3) & 0xf, template.get(at + 1..at + 1 + len))
+        } else {
+            let Some(len) = template.get(at + 1..at + 3) else {
+                break;
+            };
+            let len = acpi::read_u16(len, 0) as usize;
+            (tag, template.get(at + 3..at + 3 + len))
+        };
+        let Some(body) = body else {
+            break;
+        };
+        at += body.len() + if tag & LARGE == 0 { 1 } else { 3 };
+        match kind {
+            SMALL_END => break,
+            SMALL_IRQ if body.len() >= 2 => {
+                let mask = acpi::read_u16(body, 0);
+                for irq in (0..16).filter(|irq| mask & 1 << irq != 0) {
+                    push(Resource::Irq(irq));
+                }
+            }
+            SMALL_IO if body.len() >= 7 => push(Resource::Io {
+                base: acpi::read_u16(body, 1),
+                len: body[6] as u16,
+            }),
+            SMALL_FIXED_IO if body.len() >= 3 => push(Resource::Io {
+                // only ten bits are decoded
+                base: acpi::read_u16(body, 0) & 0x3ff,
+                len: body[2] as u16,
+            }),
+            LARGE_MEMORY32 if body.len() >= 17 => push(Resource::Memory {
+                base: acpi::read_u32(body, 1) as u64,
+                len: acpi::read_u32(body, 13) as u64,
+            }),
+            LARGE_FIXED_MEMORY32 if body.len() >= 9 => push(Resource::Memory {
+                base: acpi::read_u32(body, 1) as u64,
+                len: acpi::read_u32(body, 5) as u64,
+            }),
+            LARGE_EXTENDED_IRQ if body.len() >= 2 => {
+                let count = body[1] as usize;
+                for index in 0..count {
+                    if let Some(gsi) = body.get(2 + index * 4..6 + index * 4) {
+                        push(Resource::Irq(acpi::read_u32(gsi, 0)));
+                    }
+                }
+            }
+            LARGE_WORD_ADDRESS | LARGE_DWORD_ADDRESS | LARGE_QWORD_ADDRESS => {
+                let width = match kind {
+                    LARGE_WORD_ADDRESS => 2,
+                    LARGE_DWORD_ADDRESS => 4,
+                    _ => 8,
+                };
+                if let Some(resource) = address_space(body, width) {
+                    push(resource);
+                }
+            }
+            _ => {}
+        }
+    }
+    resources
+}
+
+/// A word, dword or qword address space descriptor's range, for memory and I/O.
+fn address_space(body: &[u8], width: usize) -> Option<Resource> {
+    let field = |index: usize| {
+        let bytes = body.get(3 + index * width..3 + (index + 1) * width)?;
+        Some(match width {
+            2 => acpi::read_u16(bytes, 0) as u64,
+            4 => acpi::read_u32(bytes, 0) as u64,
+            _ => acpi::read_u64(bytes, 0),
+        })
+    };
+    // granularity, minimum, maximum, translation, length
+    let (base, len) = (field(1)?, field(4)?);
+    if len == 0 {
+        return None;
+    }
+    match *body.first()? {
+        ADDRESS_MEMORY => Some(Resource::Memory { base, len }),
+        ADDRESS_IO => Some(Resource::Io {
+            base: base as u16,
+            len: len as u16,
+        }),
+        _ => None,
+    }
+}
+
+/// Walk the DSDT and every SSDT, after [`acpi::init`].
+pub fn init() {
+    let Some(dsdt) = acpi::dsdt() else {
+        info!("[aml] no DSDT");
+        return;
+    };
+    let mut devices = DEVICES.lock();
+    let mut walker = Walker {
+        devices: &mut devices,
+        cut_short: 0,
+    };
+    walker.term_list(&dsdt[HEADER_SIZE..], &Path::ROOT, None);
+    acpi::find_all(b"SSDT", |ssdt| {
+        walker.term_list(&ssdt[HEADER_SIZE..], &Path::ROOT, None)
+    });
+    let cut_short = walker.cut_short;
+    let count = devices.iter().flatten().count();
+    info!(
+        "[aml] {} devices, {} with a hardware ID, {} scopes cut short",
+        count,
+        devices
+            .iter()
+            .flatten()
+            .filter(|device| device.hid.is_some())
+            .count(),
+        cut_short
+    );
+}
+
+pub fn get(index: usize) -> Option<AcpiDevice> {
+    DEVICES.lock().get(index).copied().flatten()
+}
+
+/// Call `visit` with each device and its index for [`get`], parents before their children.
+pub fn devices(mut visit: impl FnMut(usize, &AcpiDevice)) {
+    let devices = *DEVICES.lock();
+    for (index, device) in devices.iter().enumerate() {
+        if let Some(device) = device {
+            visit(index, device);
+        }
+    }
+}
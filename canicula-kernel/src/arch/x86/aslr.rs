@@ -0,0 +1,100 @@
+//! Randomized placement inside the kernel: where the vmap area starts, the gap
+//! before each mapping in it and the guard gap below each kernel stack. The
+//! loader already slides the kernel image itself.
+//!
+//! There are no loadable kernel modules to place. The boot modules are files the
+//! loader leaves in physical memory, read through the direct map, so nothing
+//! about them is at a predictable virtual address of their own.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use canicula_common::entry::{BootInfo, RelocationStatus};
+
+use super::random;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Guard pages placed below a kernel stack, the exact count is randomized.
+const STACK_GUARD_PAGES_MIN: u64 = 1;
+const STACK_GUARD_PAGES_MAX: u64 = 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static KERNEL_SLIDE: AtomicU64 = AtomicU64::new(0);
+
+/// Only reaches its own address through an `R_X86_64_RELATIVE` relocation.
+static RELOCATION_PROBE: &AtomicU64 = &KERNEL_SLIDE;
+
+/// `aslr=off` or `noaslr` on the command line, or the `aslr=off` build option,
+/// give a reproducible layout for debugging.
+pub fn init(boot_info: &BootInfo, aslr: Option<bool>) {
+    let build_option = !matches!(option_env!("aslr"), Some("off") | Some("false"));
+    set_enabled(aslr.unwrap_or(build_option));
+    if !enabled() {
+        log::info!("[aslr] kernel layout randomization disabled");
+    }
+
+    KERNEL_SLIDE.store(boot_info.kernel_slide, Ordering::Relaxed);
+    log::info!(
+        "[aslr] kernel loaded at {:#x}, {:#x} above its link address",
+        boot_info.kernel_base,
+        boot_info.kernel_slide
+    );
+
+    let relocation = boot_info.kernel_relocation;
+    match relocation.status {
+        RelocationStatus::Fixed => log::info!("[aslr] kernel linked for a fixed address"),
+        RelocationStatus::Relocated => {
+            log::info!("[aslr] loader applied {} relocations", relocation.count)
+        }
+    }
+    // a pointer stored in data is only right if the loader patched it
+    let probe = unsafe { core::ptr::read_volatile(&RELOCATION_PROBE) };
+    if !core::ptr::eq(probe, &KERNEL_SLIDE) {
+        log::error!(
+            "[aslr] kernel data points at {:p} instead of {:p}, relocations were not applied",
+            probe,
+            &KERNEL_SLIDE
+        );
+    }
+}
+
+/// Bytes between where the kernel was linked and where it runs.
+pub fn kernel_slide() -> u64 {
+    KERNEL_SLIDE.load(Ordering::Relaxed)
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// A random `align` multiple in `0..window`, always `0` when ASLR is disabled.
+pub fn slide(window: u64, align: u64) -> u64 {
+    let slots = window / align;
+    if !enabled() || slots <= 1 {
+        return 0;
+    }
+    random::below(slots) * align
+}
+
+/// Pick a base for a region of `size` bytes somewhere inside `start..end`.
+pub fn place(start: u64, end: u64, size: u64, align: u64) -> u64 {
+    let window = end.saturating_sub(start).saturating_sub(size);
+    start + slide(window + align, align)
+}
+
+/// Unmapped pages to leave below the next kernel stack.
+pub fn stack_guard_pages() -> u64 {
+    if !enabled() {
+        return STACK_GUARD_PAGES_MIN;
+    }
+    STACK_GUARD_PAGES_MIN + random::below(STACK_GUARD_PAGES_MAX - STACK_GUARD_PAGES_MIN + 1)
+}
+
+/// Size of the gap, in bytes, reserved below the next kernel stack.
+pub fn stack_gap() -> u64 {
+    stack_guard_pages() * PAGE_SIZE
+}
@@ -0,0 +1,72 @@
+//! Block devices: disks read and written in whole sectors.
+//!
+//! Drivers register their disks here, file systems find them by index.
+
+use canicula_common::fs::OperateError;
+use spin::Mutex;
+
+pub const SECTOR_SIZE: usize = 512;
+const MAX_DEVICES: usize = 8;
+
+pub trait BlockDevice: Sync {
+    fn name(&self) -> &'static str;
+
+    fn sector_count(&self) -> u64;
+
+    /// Read `buffer.len() / SECTOR_SIZE` sectors starting at `sector`.
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), OperateError>;
+
+    /// Write `buffer.len() / SECTOR_SIZE` sectors starting at `sector`.
+    // the mounted file systems are read-only, nothing writes to a disk yet
+    #[allow(dead_code)]
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), OperateError>;
+}
+
+static DEVICES: Mutex<[Option<&'static dyn BlockDevice>; MAX_DEVICES]> =
+    Mutex::new([None; MAX_DEVICES]);
+
+/// Make `device` available to [`get`], returns its index.
+pub fn register(device: &'static dyn BlockDevice) -> Option<usize> {
+    let mut devices = DEVICES.lock();
+    let index = devices.iter().position(Option::is_none)?;
+    devices[index] = Some(device);
+    Some(index)
+}
+
+pub fn get(index: usize) -> Option<&'static dyn BlockDevice> {
+    DEVICES.lock().get(index).copied().flatten()
+}
+
+/// Read bytes at any `offset`, through a sector sized bounce buffer at the
+/// unaligned ends.
+pub fn read_bytes(
+    device: &dyn BlockDevice,
+    offset: usize,
+    buffer: &mut [u8],
+) -> Result<usize, OperateError> {
+    let end = offset
+        .checked_add(buffer.len())
+        .filter(|end| *end as u64 <= device.sector_count() * SECTOR_SIZE as u64)
+        .ok_or(OperateError::Fault)?;
+
+    let mut position = offset;
+    let mut sector = [0u8; SECTOR_SIZE];
+    while position < end {
+        let done = position - offset;
+        let within = position % SECTOR_SIZE;
+        let whole = (end - position) / SECTOR_SIZE * SECTOR_SIZE;
+        if within == 0 && whole > 0 {
+            device.read_sectors(
+                (position / SECTOR_SIZE) as u64,
+                &mut buffer[done..done + whole],
+            )?;
+            position += whole;
+        } else {
+            let len = (SECTOR_SIZE - within).min(end - position);
+            device.read_sectors((position / SECTOR_SIZE) as u64, &mut sector)?;
+            buffer[done..done + len].copy_from_slice(&sector[within..within + len]);
+            position += len;
+        }
+    }
+    Ok(buffer.len())
+}
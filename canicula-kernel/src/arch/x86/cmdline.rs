@@ -0,0 +1,79 @@
+//! Options the kernel takes from the command line the loader hands over.
+//!
+//! Options it does not know are left alone, they may be meant for user space.
+
+use canicula_common::cmdline;
+use canicula_common::time::parse_utc_offset;
+use log::{warn, LevelFilter};
+
+use super::console::Output;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// `log_level=off|error|warn|info|debug|trace`, over the `log_level` build option.
+    pub log_level: Option<LevelFilter>,
+    /// `log_wall_clock=on|off`, over the `log_wall_clock` build option.
+    pub log_wall_clock: Option<bool>,
+    /// `utc_offset=+08:00|-0530|Z`, over the `utc_offset` build option.
+    pub utc_offset: Option<i16>,
+    /// `console=serial|framebuffer|all`.
+    pub console: Option<Output>,
+    /// `timer_hz=<ticks per second>`, over the `timer_hz` build option.
+    pub timer_hz: Option<u32>,
+    /// `aslr=on|off`, `noaslr` for off, over the `aslr` build option.
+    pub aslr: Option<bool>,
+    /// `false` with `nosmp`, the boot processor runs alone.
+    pub smp: bool,
+    /// `gdb`, stop at boot for a debugger on COM2.
+    pub gdb: bool,
+}
+
+impl Options {
+    pub fn parse(line: &str) -> Self {
+        let mut options = Options {
+            log_level: None,
+            log_wall_clock: None,
+            utc_offset: None,
+            console: None,
+            timer_hz: None,
+            aslr: None,
+            smp: true,
+            gdb: false,
+        };
+        for param in cmdline::params(line) {
+            match (param.key, param.value) {
+                ("log_level", Some(level)) => match level.parse() {
+                    Ok(level) => options.log_level = Some(level),
+                    Err(_) => warn!("[cmdline] unknown log level {}", level),
+                },
+                ("log_wall_clock", Some(wall_clock)) => match wall_clock {
+                    "on" | "true" => options.log_wall_clock = Some(true),
+                    "off" | "false" => options.log_wall_clock = Some(false),
+                    _ => warn!("[cmdline] expected on or off for log_wall_clock"),
+                },
+                ("utc_offset", Some(offset)) => match parse_utc_offset(offset) {
+                    Some(offset) => options.utc_offset = Some(offset),
+                    None => warn!("[cmdline] expected an offset like +08:00 for utc_offset"),
+                },
+                ("console", Some(output)) => match Output::parse(output) {
+                    Some(output) => options.console = Some(output),
+                    None => warn!("[cmdline] unknown console {}", output),
+                },
+                ("timer_hz", Some(hz)) => match hz.parse() {
+                    Ok(hz) => options.timer_hz = Some(hz),
+                    Err(_) => warn!("[cmdline] expected a number for timer_hz"),
+                },
+                ("aslr", Some(aslr)) => match aslr {
+                    "on" | "true" => options.aslr = Some(true),
+                    "off" | "false" => options.aslr = Some(false),
+                    _ => warn!("[cmdline] expected on or off for aslr"),
+                },
+                ("noaslr", None) => options.aslr = Some(false),
+                ("nosmp", None) => options.smp = false,
+                ("gdb", None) => options.gdb = true,
+                _ => {}
+            }
+        }
+        options
+    }
+}
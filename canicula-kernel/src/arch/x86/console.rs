@@ -0,0 +1,220 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::framebuffer;
+use super::interrupts;
+use super::serial::SerialPort;
+
+const COM1: u16 = 0x3f8;
+
+/// Longest record printed from interrupt context, the rest is cut off.
+const RECORD_SIZE: usize = 256;
+/// Output queued from interrupt context while the console was busy.
+const DEFERRED_SIZE: usize = 4096;
+
+lazy_static! {
+    static ref STDOUT: Mutex<SerialPort> = {
+        let mut serial = SerialPort::new(COM1);
+        serial.init();
+        Mutex::new(serial)
+    };
+}
+
+/// A fixed buffer to format into without a heap.
+struct Record {
+    bytes: [u8; RECORD_SIZE],
+    len: usize,
+}
+
+impl Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(RECORD_SIZE - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+// one per CPU, only the boot CPU runs for now
+static RECORD: Mutex<Record> = Mutex::new(Record {
+    bytes: [0; RECORD_SIZE],
+    len: 0,
+});
+
+struct Deferred {
+    bytes: [u8; DEFERRED_SIZE],
+    len: usize,
+}
+
+static DEFERRED: Mutex<Deferred> = Mutex::new(Deferred {
+    bytes: [0; DEFERRED_SIZE],
+    len: 0,
+});
+/// Interrupt context records lost because the queue was full or busy.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static OUTPUT: AtomicU8 = AtomicU8::new(Output::All as u8);
+
+/// Where console output goes, chosen with `console=` on the command line.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Serial = 1,
+    Framebuffer = 2,
+    All = 3,
+}
+
+impl Output {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "serial" => Some(Output::Serial),
+            "framebuffer" => Some(Output::Framebuffer),
+            "all" => Some(Output::All),
+            _ => None,
+        }
+    }
+}
+
+pub fn select(output: Output) {
+    OUTPUT.store(output as u8, Ordering::Relaxed);
+}
+
+fn to_serial() -> bool {
+    OUTPUT.load(Ordering::Relaxed) & Output::Serial as u8 != 0
+}
+
+fn to_framebuffer() -> bool {
+    OUTPUT.load(Ordering::Relaxed) & Output::Framebuffer as u8 != 0
+}
+
+pub fn print(args: fmt::Arguments) {
+    if interrupts::in_interrupt() {
+        print_from_interrupt(args);
+        return;
+    }
+
+    flush_deferred();
+    if to_serial() {
+        STDOUT.lock().write_fmt(args).unwrap();
+    }
+    if to_framebuffer() {
+        framebuffer::print(args);
+    }
+}
+
+/// Write out what interrupt handlers queued while the console was busy.
+fn flush_deferred() {
+    let mut deferred = DEFERRED.lock();
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if deferred.len == 0 && dropped == 0 {
+        return;
+    }
+
+    let mut serial = STDOUT.lock();
+    if to_serial() {
+        serial.write_bytes(&deferred.bytes[..deferred.len]);
+    }
+    if to_framebuffer() {
+        framebuffer::write_bytes(&deferred.bytes[..deferred.len]);
+    }
+    deferred.len = 0;
+    if dropped > 0 {
+        if to_serial() {
+            serial
+                .write_fmt(format_args!(
+                    "[console] {} interrupt records dropped\n",
+                    dropped
+                ))
+                .unwrap();
+        }
+        if to_framebuffer() {
+            framebuffer::print(format_args!(
+                "[console] {} interrupt records dropped\n",
+                dropped
+            ));
+        }
+    }
+}
+
+/// Print without blocking on a lock the interrupted code may hold.
+///
+/// The record is formatted into a static buffer and written if the console is
+/// free, otherwise queued for the next [`print`]. If the queue is busy or full
+/// the record is dropped and counted.
+fn print_from_interrupt(args: fmt::Arguments) {
+    let Some(mut record) = RECORD.try_lock() else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    record.len = 0;
+    let _ = record.write_fmt(args);
+    let bytes = &record.bytes[..record.len];
+
+    // only the enabled sinks are locked, a disabled one may stay busy for good
+    let serial = sink(to_serial(), || STDOUT.try_lock());
+    let framebuffer = sink(to_framebuffer(), framebuffer::try_lock);
+    if let (Some(mut serial), Some(mut framebuffer)) = (serial, framebuffer) {
+        let mut console = framebuffer.as_mut().and_then(|console| console.as_mut());
+        // keep the order, older queued records go out first
+        if let Some(mut deferred) = DEFERRED.try_lock() {
+            if let Some(serial) = serial.as_mut() {
+                serial.write_bytes(&deferred.bytes[..deferred.len]);
+            }
+            if let Some(console) = console.as_deref_mut() {
+                console.write_bytes(&deferred.bytes[..deferred.len]);
+            }
+            deferred.len = 0;
+        }
+        if let Some(serial) = serial.as_mut() {
+            serial.write_bytes(bytes);
+        }
+        if let Some(console) = console {
+            console.write_bytes(bytes);
+            console.present();
+        }
+        return;
+    }
+
+    let Some(mut deferred) = DEFERRED.try_lock() else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    let start = deferred.len;
+    if start + bytes.len() > DEFERRED_SIZE {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    deferred.bytes[start..start + bytes.len()].copy_from_slice(bytes);
+    deferred.len += bytes.len();
+}
+
+/// `Some(None)` for a disabled sink, `None` if an enabled one is busy.
+fn sink<T>(enabled: bool, try_lock: impl FnOnce() -> Option<T>) -> Option<Option<T>> {
+    if enabled {
+        try_lock().map(Some)
+    } else {
+        Some(None)
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($fmt: literal $(, $($arg: tt)+)?) => {
+        $crate::arch::x86::console::print(format_args!($fmt $(, $($arg)+)?))
+    }
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($fmt: literal $(, $($arg: tt)+)?) => {
+        $crate::arch::x86::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?))
+    }
+}
@@ -0,0 +1,294 @@
+//! What the processor supports, read once from CPUID, and the FPU and SIMD state.
+//!
+//! [`init`] turns on FXSAVE and, where there is one, XSAVE with every state
+//! component the kernel knows of. The kernel itself is built without SSE, so
+//! only code running on top of it touches that state. [`FpuState`] is where a
+//! context switch keeps it for each thread.
+
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+use log::info;
+use spin::Once;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+// leaves
+const LEAF_VENDOR: u32 = 0;
+const LEAF_FEATURES: u32 = 1;
+const LEAF_EXTENDED_FEATURES: u32 = 7;
+const LEAF_XSAVE: u32 = 0xd;
+const LEAF_EXTENDED_MAX: u32 = 0x8000_0000;
+const LEAF_EXTENDED_INFO: u32 = 0x8000_0001;
+const LEAF_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+/// Bytes [`FpuState`] holds, the largest XSAVE area the components enabled here need.
+const FPU_STATE_SIZE: usize = 4096;
+/// What FXSAVE writes.
+const FXSAVE_SIZE: usize = 512;
+/// Where MXCSR sits in the legacy area, and its value after reset.
+const FXSAVE_MXCSR: usize = 24;
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Features {
+    pub vendor: [u8; 12],
+    pub max_leaf: u32,
+    pub max_extended_leaf: u32,
+    // leaf 1, edx
+    pub fpu: bool,
+    pub tsc: bool,
+    pub apic: bool,
+    pub fxsr: bool,
+    pub sse: bool,
+    pub sse2: bool,
+    // leaf 1, ecx
+    pub sse3: bool,
+    pub ssse3: bool,
+    pub vmx: bool,
+    pub sse4_1: bool,
+    pub sse4_2: bool,
+    pub x2apic: bool,
+    pub pcid: bool,
+    pub tsc_deadline: bool,
+    pub xsave: bool,
+    pub avx: bool,
+    pub rdrand: bool,
+    pub hypervisor: bool,
+    // leaf 7, ebx and ecx
+    pub fsgsbase: bool,
+    pub smep: bool,
+    pub avx2: bool,
+    pub avx512f: bool,
+    pub rdseed: bool,
+    pub smap: bool,
+    pub umip: bool,
+    // extended leaves
+    pub svm: bool,
+    pub nx: bool,
+    pub page_1g: bool,
+    pub invariant_tsc: bool,
+    /// XSAVE state components the processor has, as XCR0 bits.
+    pub xsave_components: u64,
+}
+
+impl Features {
+    fn detect() -> Self {
+        let bit = |register: u32, bit: u32| register & (1 << bit) != 0;
+        let mut features = Features::default();
+        let vendor = unsafe { __cpuid(LEAF_VENDOR) };
+        features.max_leaf = vendor.eax;
+        for (index, register) in [vendor.ebx, vendor.edx, vendor.ecx].into_iter().enumerate() {
+            features.vendor[index * 4..index * 4 + 4].copy_from_slice(&register.to_le_bytes());
+        }
+
+        let leaf = unsafe { __cpuid(LEAF_FEATURES) };
+        features.fpu = bit(leaf.edx, 0);
+        features.tsc = bit(leaf.edx, 4);
+        features.apic = bit(leaf.edx, 9);
+        features.fxsr = bit(leaf.edx, 24);
+        features.sse = bit(leaf.edx, 25);
+        features.sse2 = bit(leaf.edx, 26);
+        features.sse3 = bit(leaf.ecx, 0);
+        features.ssse3 = bit(leaf.ecx, 9);
+        features.vmx = bit(leaf.ecx, 5);
+        features.sse4_1 = bit(leaf.ecx, 19);
+        features.sse4_2 = bit(leaf.ecx, 20);
+        features.x2apic = bit(leaf.ecx, 21);
+        features.pcid = bit(leaf.ecx, 17);
+        features.tsc_deadline = bit(leaf.ecx, 24);
+        features.xsave = bit(leaf.ecx, 26);
+        features.avx = bit(leaf.ecx, 28);
+        features.rdrand = bit(leaf.ecx, 30);
+        features.hypervisor = bit(leaf.ecx, 31);
+
+        if features.max_leaf >= LEAF_EXTENDED_FEATURES {
+            let leaf = unsafe { __cpuid_count(LEAF_EXTENDED_FEATURES, 0) };
+            features.fsgsbase = bit(leaf.ebx, 0);
+            features.smep = bit(leaf.ebx, 7);
+            features.avx2 = bit(leaf.ebx, 5);
+            features.avx512f = bit(leaf.ebx, 16);
+            features.rdseed = bit(leaf.ebx, 18);
+            features.smap = bit(leaf.ebx, 20);
+            features.umip = bit(leaf.ecx, 2);
+        }
+        if features.xsave && features.max_leaf >= LEAF_XSAVE {
+            let leaf = unsafe { __cpuid_count(LEAF_XSAVE, 0) };
+            features.xsave_components = (leaf.edx as u64) << 32 | leaf.eax as u64;
+        }
+
+        features.max_extended_leaf = unsafe { __cpuid(LEAF_EXTENDED_MAX).eax };
+        if features.max_extended_leaf >= LEAF_EXTENDED_INFO {
+            let leaf = unsafe { __cpuid(LEAF_EXTENDED_INFO) };
+            features.svm = bit(leaf.ecx, 2);
+            features.nx = bit(leaf.edx, 20);
+            features.page_1g = bit(leaf.edx, 26);
+        }
+        if features.max_extended_leaf >= LEAF_POWER_MANAGEMENT {
+            features.invariant_tsc = bit(unsafe { __cpuid(LEAF_POWER_MANAGEMENT).edx }, 8);
+        }
+        features
+    }
+
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+}
+
+static FEATURES: Once<Features> = Once::new();
+/// The state components XSAVE saves, empty when it is FXSAVE instead.
+static XSAVE_MASK: Once<XCr0Flags> = Once::new();
+/// The state a thread starts with, taken right after [`init`].
+static INITIAL_STATE: Once<FpuState> = Once::new();
+
+/// The processor's features, read on first use.
+pub fn features() -> &'static Features {
+    FEATURES.call_once(Features::detect)
+}
+
+/// The components to turn on: x87 and SSE, then AVX and AVX-512 when all their parts are there.
+fn xsave_components(features: &Features) -> XCr0Flags {
+    let available = XCr0Flags::from_bits_truncate(features.xsave_components);
+    let mut enabled = XCr0Flags::X87 | XCr0Flags::SSE;
+    if features.avx && available.contains(XCr0Flags::AVX) {
+        enabled |= XCr0Flags::AVX;
+        let avx512 = XCr0Flags::OPMASK | XCr0Flags::ZMM_HI256 | XCr0Flags::HI16_ZMM;
+        if features.avx512f && available.contains(avx512) {
+            enabled |= avx512;
+        }
+    }
+    enabled
+}
+
+/// Enable the FPU, SSE and, with XSAVE, AVX and AVX-512, then keep the state they start in.
+pub fn init() {
+    let features = features();
+    info!(
+        "[cpu] {}, sse {} sse2 {} xsave {} avx {} avx2 {} avx512 {}",
+        features.vendor(),
+        features.sse,
+        features.sse2,
+        features.xsave,
+        features.avx,
+        features.avx2,
+        features.avx512f
+    );
+    if !features.fxsr || !features.sse {
+        info!("[cpu] no FXSAVE or SSE, the FPU is left alone");
+        return;
+    }
+    unsafe {
+        // x87 errors as exceptions, no emulation and no lazy switching
+        Cr0::update(|cr0| {
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+        });
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+    let mask = if features.xsave {
+        let mut components = xsave_components(features);
+        unsafe {
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+            XCr0::write(components);
+        }
+        // the area XSAVE needs for what XCR0 now has
+        if unsafe { __cpuid_count(LEAF_XSAVE, 0).ebx } as usize > FPU_STATE_SIZE {
+            // leave AVX-512 off rather than overrun the save area
+            components &= XCr0Flags::X87 | XCr0Flags::SSE | XCr0Flags::AVX;
+            unsafe { XCr0::write(components) };
+        }
+        components
+    } else {
+        XCr0Flags::empty()
+    };
+    XSAVE_MASK.call_once(|| mask);
+    unsafe { asm!("fninit", options(nomem, nostack)) };
+    INITIAL_STATE.call_once(|| {
+        let mut state = FpuState::zeroed();
+        state.write_mxcsr(MXCSR_DEFAULT);
+        unsafe { asm!("ldmxcsr [{}]", in(reg) &MXCSR_DEFAULT, options(nostack)) };
+        state.save();
+        state
+    });
+    if mask.is_empty() {
+        info!("[cpu] FXSAVE state, {} bytes", FXSAVE_SIZE);
+    } else {
+        info!("[cpu] XSAVE state {:?}", mask);
+    }
+}
+
+/// A thread's x87, SSE and AVX registers while it is not running.
+#[repr(C, align(64))]
+#[derive(Clone)]
+pub struct FpuState([u8; FPU_STATE_SIZE]);
+
+impl FpuState {
+    const fn zeroed() -> Self {
+        FpuState([0; FPU_STATE_SIZE])
+    }
+
+    fn write_mxcsr(&mut self, mxcsr: u32) {
+        self.0[FXSAVE_MXCSR..FXSAVE_MXCSR + 4].copy_from_slice(&mxcsr.to_le_bytes());
+    }
+
+    /// The state a new thread starts with, the registers as reset by [`init`].
+    pub fn new() -> Self {
+        INITIAL_STATE.get().cloned().unwrap_or_else(|| {
+            let mut state = FpuState::zeroed();
+            state.write_mxcsr(MXCSR_DEFAULT);
+            state
+        })
+    }
+
+    /// Store the registers of the thread being switched away from.
+    pub fn save(&mut self) {
+        let Some(mask) = XSAVE_MASK.get() else {
+            return;
+        };
+        let area = self.0.as_mut_ptr();
+        unsafe {
+            if mask.is_empty() {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+            } else {
+                let mask = mask.bits();
+                asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") mask as u32,
+                    in("edx") (mask >> 32) as u32,
+                    options(nostack)
+                );
+            }
+        }
+    }
+
+    /// Load the registers of the thread being switched to.
+    // there is no scheduler to switch threads yet
+    #[allow(dead_code)]
+    pub fn restore(&self) {
+        let Some(mask) = XSAVE_MASK.get() else {
+            return;
+        };
+        let area = self.0.as_ptr();
+        unsafe {
+            if mask.is_empty() {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, readonly));
+            } else {
+                let mask = mask.bits();
+                asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") mask as u32,
+                    in("edx") (mask >> 32) as u32,
+                    options(nostack, readonly)
+                );
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
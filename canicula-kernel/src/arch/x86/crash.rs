@@ -0,0 +1,368 @@
+//! What the kernel prints when it panics: registers, control registers and a backtrace.
+//!
+//! The backtrace follows the frame pointer chain, so it needs the kernel built
+//! with frame pointers. Return addresses are named from the kernel's own dynamic
+//! symbol table, which `-export-dynamic` puts in the loaded image. It only has
+//! the symbols visible outside their crate, anything else shows as the nearest
+//! one below it, or as a bare address.
+
+use core::arch::asm;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use canicula_common::layout::KERNEL_STACK_SIZE;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+use super::{aslr, percpu};
+use crate::println;
+
+/// Frames printed at most, a corrupt chain could otherwise go on and on.
+const MAX_FRAMES: usize = 32;
+/// Lowest address of the kernel half.
+const KERNEL_HALF: u64 = 0xffff_8000_0000_0000;
+
+// dynamic section tags
+const DT_NULL: u64 = 0;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_STRSZ: u64 = 10;
+
+const SYMBOL_SIZE: u64 = 24;
+const STT_FUNC: u8 = 2;
+
+extern "C" {
+    static _DYNAMIC: [u64; 0];
+    static __text_start: u8;
+    static __text_end: u8;
+}
+
+/// Set by the first panic, a panic while reporting one only prints its message.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// General registers as the panic handler found them.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Registers {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rbp: u64,
+    rsp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rflags: u64,
+}
+
+impl Registers {
+    /// The registers at the call site, inlined so that is the panic handler.
+    ///
+    /// The register holding the address of the save area shows its own value.
+    #[inline(always)]
+    fn capture() -> Self {
+        let mut registers = Registers::default();
+        unsafe {
+            asm!(
+                "mov [{0}], rax",
+                "mov [{0} + 8], rbx",
+                "mov [{0} + 16], rcx",
+                "mov [{0} + 24], rdx",
+                "mov [{0} + 32], rsi",
+                "mov [{0} + 40], rdi",
+                "mov [{0} + 48], rbp",
+                "mov [{0} + 56], rsp",
+                "mov [{0} + 64], r8",
+                "mov [{0} + 72], r9",
+                "mov [{0} + 80], r10",
+                "mov [{0} + 88], r11",
+                "mov [{0} + 96], r12",
+                "mov [{0} + 104], r13",
+                "mov [{0} + 112], r14",
+                "mov [{0} + 120], r15",
+                "pushfq",
+                "pop qword ptr [{0} + 128]",
+                in(reg) &mut registers as *mut Registers,
+            );
+        }
+        registers
+    }
+
+    fn print(&self) {
+        println!(
+            "  rax {:016x} rbx {:016x} rcx {:016x}",
+            self.rax, self.rbx, self.rcx
+        );
+        println!(
+            "  rdx {:016x} rsi {:016x} rdi {:016x}",
+            self.rdx, self.rsi, self.rdi
+        );
+        println!(
+            "  rbp {:016x} rsp {:016x} r8  {:016x}",
+            self.rbp, self.rsp, self.r8
+        );
+        println!(
+            "  r9  {:016x} r10 {:016x} r11 {:016x}",
+            self.r9, self.r10, self.r11
+        );
+        println!(
+            "  r12 {:016x} r13 {:016x} r14 {:016x}",
+            self.r12, self.r13, self.r14
+        );
+        println!("  r15 {:016x} rflags {:016x}", self.r15, self.rflags);
+    }
+}
+
+/// The kernel's dynamic symbol table, at the addresses it runs at.
+struct Symbols {
+    symbols: u64,
+    count: u64,
+    strings: u64,
+    strings_size: u64,
+}
+
+impl Symbols {
+    /// Read the tables out of the kernel's own dynamic section.
+    ///
+    /// The section holds link addresses, the loader only relocated the data
+    /// pointing into the image.
+    fn find() -> Option<Self> {
+        let slide = aslr::kernel_slide();
+        let mut dynamic = core::ptr::addr_of!(_DYNAMIC) as *const u64;
+        let (mut symbols, mut hash, mut strings, mut strings_size) = (0, 0, 0, 0);
+        loop {
+            let (tag, value) = unsafe { (dynamic.read(), dynamic.add(1).read()) };
+            match tag {
+                DT_NULL => break,
+                DT_HASH => hash = value + slide,
+                DT_STRTAB => strings = value + slide,
+                DT_SYMTAB => symbols = value + slide,
+                DT_STRSZ => strings_size = value,
+                _ => {}
+            }
+            dynamic = unsafe { dynamic.add(2) };
+        }
+        if symbols == 0 || strings == 0 || hash == 0 {
+            return None;
+        }
+        // nchain in the SysV hash table is the number of symbols
+        let count = unsafe { ((hash + 4) as *const u32).read() } as u64;
+        Some(Symbols {
+            symbols,
+            count,
+            strings,
+            strings_size,
+        })
+    }
+
+    /// The function containing `address`, or the nearest one below it, and the offset into it.
+    fn lookup(&self, address: u64) -> Option<(&'static str, u64)> {
+        let slide = aslr::kernel_slide();
+        let mut best: Option<(u64, u32)> = None;
+        for index in 0..self.count {
+            let symbol = self.symbols + index * SYMBOL_SIZE;
+            let (name, info, value) = unsafe {
+                (
+                    (symbol as *const u32).read(),
+                    ((symbol + 4) as *const u8).read(),
+                    ((symbol + 8) as *const u64).read(),
+                )
+            };
+            if info & 0xf != STT_FUNC || value == 0 {
+                continue;
+            }
+            let start = value + slide;
+            if start <= address && best.is_none_or(|(best, _)| start > best) {
+                best = Some((start, name));
+            }
+        }
+        let (start, name) = best?;
+        Some((self.string(name as u64)?, address - start))
+    }
+
+    fn string(&self, offset: u64) -> Option<&'static str> {
+        if offset >= self.strings_size {
+            return None;
+        }
+        let start = (self.strings + offset) as *const u8;
+        let len = (0..self.strings_size - offset)
+            .find(|index| unsafe { start.add(*index as usize).read() } == 0)?;
+        let bytes = unsafe { core::slice::from_raw_parts(start, len as usize) };
+        core::str::from_utf8(bytes).ok()
+    }
+}
+
+/// A legacy mangled Rust name (`_ZN...E`) the way it reads in the source, without the hash.
+///
+/// Anything else is printed as it is.
+struct Demangled<'a>(&'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(mut rest) = self
+            .0
+            .strip_prefix("_ZN")
+            .and_then(|name| name.strip_suffix('E'))
+        else {
+            return f.write_str(self.0);
+        };
+        let mut first = true;
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let Some(len) = rest[..digits].parse::<usize>().ok() else {
+                return f.write_str(self.0);
+            };
+            let Some(segment) = rest.get(digits..digits + len) else {
+                return f.write_str(self.0);
+            };
+            rest = &rest[digits + len..];
+            // the last segment is the hash
+            if rest.is_empty() && segment.len() == 17 && segment.starts_with('h') {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_segment(f, segment)?;
+        }
+        Ok(())
+    }
+}
+
+/// One path segment, with the `$..$` escapes and `..` for `::` spelled out.
+fn write_segment(f: &mut fmt::Formatter<'_>, segment: &str) -> fmt::Result {
+    // an underscore keeps a segment from starting with an escape
+    let mut rest = segment
+        .strip_prefix('_')
+        .filter(|rest| rest.starts_with('$'))
+        .unwrap_or(segment);
+    while !rest.is_empty() {
+        if let Some(escaped) = rest.strip_prefix('$') {
+            let Some(end) = escaped.find('$') else {
+                return f.write_str(rest);
+            };
+            let text = match &escaped[..end] {
+                "LT" => "<",
+                "GT" => ">",
+                "RF" => "&",
+                "BP" => "*",
+                "SP" => "@",
+                "C" => ",",
+                "u20" => " ",
+                "u27" => "'",
+                "u5b" => "[",
+                "u5d" => "]",
+                "u7b" => "{",
+                "u7d" => "}",
+                "u7e" => "~",
+                other => other,
+            };
+            f.write_str(text)?;
+            rest = &escaped[end + 1..];
+        } else if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else {
+            let next = rest
+                .find(['$', '.'])
+                .filter(|next| *next > 0)
+                .unwrap_or(rest.len());
+            f.write_str(&rest[..next])?;
+            rest = &rest[next..];
+        }
+    }
+    Ok(())
+}
+
+fn in_text(address: u64) -> bool {
+    let start = core::ptr::addr_of!(__text_start) as u64;
+    let end = core::ptr::addr_of!(__text_end) as u64;
+    (start..end).contains(&address)
+}
+
+/// Print the return addresses up the frame pointer chain from `rbp`.
+///
+/// Each frame has to sit above the one before it on the same stack, so a
+/// corrupt chain ends the walk instead of faulting.
+fn backtrace(rbp: u64) {
+    let symbols = Symbols::find();
+    let bottom = rbp;
+    let mut frame = rbp;
+    println!("[crash] backtrace:");
+    for depth in 0..MAX_FRAMES {
+        if frame < KERNEL_HALF || frame & 0x7 != 0 || frame - bottom >= KERNEL_STACK_SIZE {
+            break;
+        }
+        let (next, return_address) = unsafe {
+            let frame = frame as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if !in_text(return_address) {
+            break;
+        }
+        // the call is the instruction before the one returned to
+        match symbols
+            .as_ref()
+            .and_then(|symbols| symbols.lookup(return_address - 1))
+        {
+            Some((name, offset)) => println!(
+                "  #{:<2} {:#018x} {}+{:#x}",
+                depth,
+                return_address,
+                Demangled(name),
+                offset + 1
+            ),
+            None => println!("  #{:<2} {:#018x}", depth, return_address),
+        }
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+}
+
+/// Print everything known about the panic, from the panic handler.
+#[inline(always)]
+pub fn report(info: &PanicInfo) {
+    let registers = Registers::capture();
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        println!("[crash] panicked again: {}", info.message());
+        return;
+    }
+    println!("[crash] kernel panic: {}", info.message());
+    if let Some(location) = info.location() {
+        println!(
+            "[crash]   at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        );
+    }
+    let cpu = percpu::current();
+    println!(
+        "[crash]   cpu {}, interrupt depth {}, kernel slide {:#x}",
+        cpu.cpu.load(Ordering::Relaxed),
+        cpu.interrupt_depth.load(Ordering::Relaxed),
+        aslr::kernel_slide()
+    );
+    println!("[crash] registers:");
+    registers.print();
+    println!(
+        "  cr0 {:016x} cr2 {:016x} cr3 {:016x} cr4 {:016x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        Cr3::read_raw().0.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+    backtrace(registers.rbp);
+}
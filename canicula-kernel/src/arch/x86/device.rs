@@ -0,0 +1,240 @@
+//! The devices the kernel knows of, as a tree for drivers to bind to.
+//!
+//! Below the root sit the PCI host bridge and the ACPI platform. Every function
+//! [`pci::init`] found hangs off the host bridge, or off the PCI-to-PCI bridge
+//! whose bus it is on. The ACPI side has the devices [`aml::init`] found with a
+//! hardware ID, nested as in the namespace. If the AML gave none it falls back
+//! to what the static tables tell: an i8042 controller unless the FADT says
+//! there is none, and the HPET if it has a table.
+
+use core::fmt;
+
+use log::info;
+use spin::Mutex;
+
+use super::acpi;
+use super::aml::{self, Hid, Resource};
+use super::pci::{self, PciDevice};
+
+const MAX_DEVICES: usize = 96;
+
+// FADT fields
+const FADT_REVISION: usize = 8;
+/// `IAPC_BOOT_ARCH`, there from revision 3 on.
+const FADT_BOOT_ARCH: usize = 109;
+const BOOT_ARCH_8042: u16 = 1 << 1;
+
+/// ACPI hardware IDs of the legacy devices.
+pub const HID_KEYBOARD: &str = "PNP0303";
+pub const HID_HPET: &str = "PNP0103";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Root,
+    /// The PCI host bridge, bus 0 behind it.
+    PciRoot,
+    Pci(PciDevice),
+    /// Where the devices from the ACPI tables hang.
+    AcpiRoot,
+    /// A device from ACPI by its hardware ID, and the [`aml::get`] index of
+    /// its namespace node unless it was inferred from the static tables.
+    Acpi {
+        hid: Hid,
+        node: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub id: DeviceId,
+    pub parent: Option<DeviceId>,
+    pub kind: DeviceKind,
+}
+
+impl Device {
+    pub fn pci(&self) -> Option<&PciDevice> {
+        match &self.kind {
+            DeviceKind::Pci(device) => Some(device),
+            _ => None,
+        }
+    }
+
+    pub fn hid(&self) -> Option<Hid> {
+        match self.kind {
+            DeviceKind::Acpi { hid, .. } => Some(hid),
+            _ => None,
+        }
+    }
+
+    /// What an ACPI device's `_CRS` lists, nothing for the others.
+    pub fn resources(&self) -> impl Iterator<Item = Resource> {
+        let node = match self.kind {
+            DeviceKind::Acpi { node, .. } => node.and_then(aml::get),
+            _ => None,
+        };
+        node.into_iter().flat_map(|node| node.resources())
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            DeviceKind::Root => f.write_str("root"),
+            DeviceKind::PciRoot => f.write_str("pci"),
+            DeviceKind::Pci(device) => write!(
+                f,
+                "pci {:02x}:{:02x}.{} {:04x}:{:04x}",
+                device.address.bus,
+                device.address.device,
+                device.address.function,
+                device.vendor,
+                device.device
+            ),
+            DeviceKind::AcpiRoot => f.write_str("acpi"),
+            DeviceKind::Acpi { hid, node } => match node.and_then(aml::get) {
+                Some(node) => write!(f, "acpi {} {}", hid, node.path),
+                None => write!(f, "acpi {}", hid),
+            },
+        }
+    }
+}
+
+type Devices = [Option<Device>; MAX_DEVICES];
+
+static DEVICES: Mutex<Devices> = Mutex::new([None; MAX_DEVICES]);
+
+fn add(devices: &mut Devices, parent: Option<DeviceId>, kind: DeviceKind) -> Option<DeviceId> {
+    let index = devices.iter().position(Option::is_none)?;
+    let id = DeviceId(index);
+    devices[index] = Some(Device { id, parent, kind });
+    Some(id)
+}
+
+/// Whether the machine has an i8042, which only a revision 3 FADT can deny.
+fn has_8042() -> bool {
+    let Some(fadt) = acpi::find(b"FACP") else {
+        return true;
+    };
+    if fadt[FADT_REVISION] < 3 || fadt.len() < FADT_BOOT_ARCH + 2 {
+        return true;
+    }
+    acpi::read_u16(fadt, FADT_BOOT_ARCH) & BOOT_ARCH_8042 != 0
+}
+
+/// Add the namespace's devices with a hardware ID below `acpi_root`, each below
+/// the nearest one above it in the namespace, and tell how many there were.
+fn add_namespace(devices: &mut Devices, acpi_root: Option<DeviceId>) -> usize {
+    let has_8042 = has_8042();
+    let mut added: [Option<(aml::Path, DeviceId)>; MAX_DEVICES] = [None; MAX_DEVICES];
+    let mut count = 0;
+    aml::devices(|node, device| {
+        let Some(hid) = device.hid else {
+            return;
+        };
+        // the AML describes a keyboard controller whether or not the board has one
+        if hid.as_str() == HID_KEYBOARD && !has_8042 {
+            return;
+        }
+        let parent = added
+            .iter()
+            .flatten()
+            .filter(|(path, _)| path.contains(&device.path))
+            .max_by_key(|(path, _)| path.depth())
+            .map(|(_, id)| *id)
+            .or(acpi_root);
+        let kind = DeviceKind::Acpi {
+            hid,
+            node: Some(node),
+        };
+        if let Some(id) = add(devices, parent, kind) {
+            added[count] = Some((device.path, id));
+            count += 1;
+        }
+    });
+    count
+}
+
+/// Build the tree, after [`pci::init`] and [`aml::init`].
+pub fn init() {
+    let mut devices = [None; MAX_DEVICES];
+    let root = add(&mut devices, None, DeviceKind::Root);
+    let pci_root = add(&mut devices, root, DeviceKind::PciRoot);
+    pci::devices(|device| {
+        add(&mut devices, pci_root, DeviceKind::Pci(*device));
+    });
+    // devices on a bus other than 0 go below the bridge to it
+    for index in 0..MAX_DEVICES {
+        let Some(bus) = devices[index]
+            .and_then(|device| Some(device.pci()?.address.bus))
+            .filter(|bus| *bus != 0)
+        else {
+            continue;
+        };
+        let bridge = devices
+            .iter()
+            .flatten()
+            .find(|bridge| {
+                bridge
+                    .pci()
+                    .is_some_and(|bridge| bridge.secondary_bus() == Some(bus))
+            })
+            .map(|bridge| bridge.id);
+        if let (Some(bridge), Some(device)) = (bridge, devices[index].as_mut()) {
+            device.parent = Some(bridge);
+        }
+    }
+    let acpi_root = add(&mut devices, root, DeviceKind::AcpiRoot);
+    if add_namespace(&mut devices, acpi_root) == 0 {
+        let inferred = |hid| DeviceKind::Acpi {
+            hid: Hid::new(hid),
+            node: None,
+        };
+        if has_8042() {
+            add(&mut devices, acpi_root, inferred(HID_KEYBOARD));
+        }
+        if acpi::find(b"HPET").is_some() {
+            add(&mut devices, acpi_root, inferred(HID_HPET));
+        }
+    }
+    let count = devices.iter().flatten().count();
+    if count == MAX_DEVICES {
+        info!("[device] table full, later devices are left out");
+    }
+    info!("[device] {} devices", count);
+    *DEVICES.lock() = devices;
+}
+
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn get(id: DeviceId) -> Option<Device> {
+    DEVICES.lock().get(id.0).copied().flatten()
+}
+
+/// Call `visit` with every device, parents before their children.
+pub fn devices(mut visit: impl FnMut(&Device)) {
+    let devices = *DEVICES.lock();
+    for device in devices.iter().flatten() {
+        visit(device);
+    }
+}
+
+/// Call `visit` with each device directly below `parent`.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn children(parent: DeviceId, mut visit: impl FnMut(&Device)) {
+    let devices = *DEVICES.lock();
+    for device in devices
+        .iter()
+        .flatten()
+        .filter(|device| device.parent == Some(parent))
+    {
+        visit(device);
+    }
+}
+
+/// The root of the tree, `None` before [`init`].
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn root() -> Option<Device> {
+    get(DeviceId(0))
+}
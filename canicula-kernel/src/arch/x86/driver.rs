@@ -0,0 +1,435 @@
+use core::arch::{asm, global_asm};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use log::{error, info};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use super::device::{self, Device, DeviceId};
+use super::interrupts::Exception;
+use super::sync::IrqMutex;
+use super::{ioapic, percpu, pic};
+
+const MAX_BINDINGS: usize = 32;
+
+/// A driver, bound by [`probe_all`] to each device it matches.
+///
+/// Drivers are put in the table with [`register_driver!`], every call into one
+/// runs with fault containment: a fault or panic disables that binding.
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
+    /// Whether `device` is one for this driver, without changing anything on it.
+    fn matches(&self, device: &Device) -> bool;
+    fn probe(&self, device: &Device) -> Result<(), &'static str>;
+    /// Stop using `device`, it may be probed again afterwards.
+    fn remove(&self, _device: &Device) {}
+    /// Legacy IRQ line `device` raises, masked if the driver faults.
+    fn irq(&self, _device: &Device) -> Option<u8> {
+        None
+    }
+    fn interrupt(&self, _device: &Device) {}
+    /// Save what `device` loses over a sleep state, before the machine goes down.
+    fn suspend(&self, _device: &Device) -> Result<(), &'static str> {
+        Ok(())
+    }
+    /// Bring `device` back after waking, from what `suspend` saved.
+    fn resume(&self, _device: &Device) {}
+}
+
+/// Add a driver to the table [`probe_all`] binds devices from, e.g.
+/// `register_driver!(Ahci);` next to `impl Driver for Ahci`.
+///
+/// The linker gathers the entries of every module in the `.drivers` section,
+/// so a driver needs no call from the kernel's entry.
+macro_rules! register_driver {
+    ($driver:expr) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".drivers"]
+            static DRIVER: &'static dyn super::driver::Driver = &$driver;
+        };
+    };
+}
+pub(crate) use register_driver;
+
+extern "C" {
+    static __drivers_start: u8;
+    static __drivers_end: u8;
+}
+
+/// Every driver put in the table with [`register_driver!`].
+fn drivers() -> &'static [&'static dyn Driver] {
+    let start = core::ptr::addr_of!(__drivers_start) as *const &'static dyn Driver;
+    let end = core::ptr::addr_of!(__drivers_end) as usize;
+    let len = (end - start as usize) / core::mem::size_of::<&dyn Driver>();
+    unsafe { core::slice::from_raw_parts(start, len) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverState {
+    /// Bound, the driver's `probe` has not returned yet.
+    Probing,
+    Running,
+    ProbeFailed(&'static str),
+    Faulted(Fault),
+    /// Running before [`suspend_all`], until [`resume_all`].
+    Suspended,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub exception: Exception,
+    pub error_code: Option<u64>,
+    /// The faulting address for page faults.
+    pub address: Option<u64>,
+    pub instruction_pointer: u64,
+    pub stack_pointer: u64,
+}
+
+/// A driver bound to a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BindingId(usize);
+
+#[derive(Clone, Copy)]
+struct Binding {
+    driver: &'static dyn Driver,
+    device: Device,
+    state: DriverState,
+}
+
+// `interrupt` looks the owner of an IRQ up from the handler
+static BINDINGS: IrqMutex<[Option<Binding>; MAX_BINDINGS]> = IrqMutex::new([None; MAX_BINDINGS]);
+
+static CONTAINMENT: AtomicBool = AtomicBool::new(true);
+// set by the exception handlers
+static LAST_FAULT: IrqMutex<Option<Fault>> = IrqMutex::new(None);
+
+// driver_call(entry, data, recovery_stack) calls entry(data) and returns 0,
+// or 1 if the call was abandoned through driver_recover.
+global_asm!(
+    r#"
+    .global driver_call
+driver_call:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    sub rsp, 8
+    mov [rdx], rsp
+
+    mov rax, rdi
+    mov rdi, rsi
+    call rax
+    xor eax, eax
+
+driver_call_return:
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+    .global driver_recover
+driver_recover:
+    mov eax, 1
+    jmp driver_call_return
+"#
+);
+
+extern "sysv64" {
+    fn driver_call(entry: extern "sysv64" fn(*mut u8), data: *mut u8, stack: *mut u64) -> u32;
+    fn driver_recover() -> !;
+}
+
+/// Build with `driver_containment=off` to let driver faults bring the kernel down.
+pub fn init() {
+    let enabled = !matches!(
+        option_env!("driver_containment"),
+        Some("off") | Some("false")
+    );
+    CONTAINMENT.store(enabled, Ordering::Relaxed);
+}
+
+fn binding(id: BindingId) -> Option<Binding> {
+    BINDINGS.lock()[id.0]
+}
+
+fn set_state(id: BindingId, state: DriverState) {
+    if let Some(binding) = BINDINGS.lock()[id.0].as_mut() {
+        binding.state = state;
+    }
+}
+
+/// The binding of `device`, if a driver took it.
+pub fn bound(device: DeviceId) -> Option<(BindingId, &'static str, DriverState)> {
+    BINDINGS
+        .lock()
+        .iter()
+        .enumerate()
+        .find_map(|(index, binding)| {
+            let binding = binding.as_ref()?;
+            (binding.device.id == device)
+                .then(|| (BindingId(index), binding.driver.name(), binding.state))
+        })
+}
+
+/// Bind `driver` to `device` and probe it.
+fn bind(driver: &'static dyn Driver, device: Device) -> DriverState {
+    let id = {
+        let mut bindings = BINDINGS.lock();
+        let Some(index) = bindings.iter().position(Option::is_none) else {
+            error!("[driver] no room to bind {} to {}", driver.name(), device);
+            return DriverState::ProbeFailed("too many bindings");
+        };
+        bindings[index] = Some(Binding {
+            driver,
+            device,
+            state: DriverState::Probing,
+        });
+        BindingId(index)
+    };
+
+    let mut result = Ok(());
+    let state = match run(id, || result = driver.probe(&device)) {
+        Err(fault) => DriverState::Faulted(fault),
+        Ok(()) => match result {
+            Ok(()) => DriverState::Running,
+            Err(reason) => DriverState::ProbeFailed(reason),
+        },
+    };
+
+    match state {
+        DriverState::Running => info!("[driver] {} is running on {}", driver.name(), device),
+        DriverState::ProbeFailed(reason) => {
+            error!(
+                "[driver] {} probe failed on {}: {}",
+                driver.name(),
+                device,
+                reason
+            )
+        }
+        _ => {}
+    }
+    set_state(id, state);
+    state
+}
+
+/// Bind every device nobody took yet to the first driver that matches it.
+///
+/// Runs after [`device::init`], and again whenever devices were added.
+pub fn probe_all() {
+    let drivers = drivers();
+    device::devices(|device| {
+        if bound(device.id).is_some() {
+            return;
+        }
+        if let Some(driver) = drivers.iter().find(|driver| driver.matches(device)) {
+            bind(*driver, *device);
+        }
+    });
+}
+
+/// Unbind `id`, calling the driver's `remove` if it was running.
+///
+/// The device is left for [`probe_all`] to bind again.
+// nothing unplugs devices yet
+#[allow(dead_code)]
+pub fn remove(id: BindingId) {
+    let Some(binding) = binding(id) else {
+        return;
+    };
+    if binding.state == DriverState::Running {
+        if let Err(fault) = run(id, || binding.driver.remove(&binding.device)) {
+            set_state(id, DriverState::Faulted(fault));
+            return;
+        }
+        info!(
+            "[driver] {} removed from {}",
+            binding.driver.name(),
+            binding.device
+        );
+    }
+    BINDINGS.lock()[id.0] = None;
+}
+
+/// Suspend every running driver, last bound first, ahead of a sleep state.
+///
+/// If one refuses, those already suspended are resumed and its reason returned.
+pub fn suspend_all() -> Result<(), &'static str> {
+    for index in (0..MAX_BINDINGS).rev() {
+        let id = BindingId(index);
+        let Some(binding) = binding(id).filter(|binding| binding.state == DriverState::Running)
+        else {
+            continue;
+        };
+        let mut result = Ok(());
+        if let Err(fault) = run(id, || result = binding.driver.suspend(&binding.device)) {
+            set_state(id, DriverState::Faulted(fault));
+            continue;
+        }
+        if let Err(reason) = result {
+            error!(
+                "[driver] {} cannot suspend {}: {}",
+                binding.driver.name(),
+                binding.device,
+                reason
+            );
+            resume_all();
+            return Err(reason);
+        }
+        set_state(id, DriverState::Suspended);
+    }
+    Ok(())
+}
+
+/// Resume the drivers [`suspend_all`] suspended, first bound first.
+pub fn resume_all() {
+    for index in 0..MAX_BINDINGS {
+        let id = BindingId(index);
+        let Some(binding) = binding(id).filter(|binding| binding.state == DriverState::Suspended)
+        else {
+            continue;
+        };
+        match run(id, || binding.driver.resume(&binding.device)) {
+            Ok(()) => set_state(id, DriverState::Running),
+            Err(fault) => set_state(id, DriverState::Faulted(fault)),
+        }
+    }
+}
+
+/// Forward `irq` to the running driver that owns it.
+pub fn interrupt(irq: u8) {
+    let owner = BINDINGS
+        .lock()
+        .iter()
+        .enumerate()
+        .find_map(|(index, binding)| {
+            let binding = binding.as_ref()?;
+            (binding.state == DriverState::Running
+                && binding.driver.irq(&binding.device) == Some(irq))
+            .then_some((BindingId(index), *binding))
+        });
+
+    if let Some((id, binding)) = owner {
+        if let Err(fault) = run(id, || binding.driver.interrupt(&binding.device)) {
+            set_state(id, DriverState::Faulted(fault));
+        }
+    }
+}
+
+/// Call into driver `id`, turning a fault or panic inside it into `Err`.
+///
+/// Whatever the driver left half done (locks held, partially written state) is
+/// not cleaned up, the driver is just never called again.
+fn run<F: FnMut()>(id: BindingId, mut entry: F) -> Result<(), Fault> {
+    if !CONTAINMENT.load(Ordering::Relaxed) {
+        entry();
+        return Ok(());
+    }
+
+    extern "sysv64" fn trampoline<T: FnMut()>(data: *mut u8) {
+        let entry = unsafe { &mut *(data as *mut T) };
+        entry();
+    }
+
+    let cpu = percpu::current();
+    let previous_driver = cpu.active_driver.swap(id.0 + 1, Ordering::SeqCst);
+    let previous_stack = cpu.recovery_stack.load(Ordering::SeqCst);
+    let recovered = unsafe {
+        driver_call(
+            trampoline::<F>,
+            &mut entry as *mut F as *mut u8,
+            cpu.recovery_stack.as_ptr(),
+        )
+    };
+    cpu.active_driver.store(previous_driver, Ordering::SeqCst);
+    cpu.recovery_stack.store(previous_stack, Ordering::SeqCst);
+
+    if recovered == 0 {
+        return Ok(());
+    }
+
+    let fault = LAST_FAULT.lock().take().unwrap();
+    let binding = binding(id).unwrap();
+    report(binding.driver.name(), &fault);
+    if let Some(irq) = binding.driver.irq(&binding.device) {
+        pic::mask(irq);
+        ioapic::mask_isa(irq);
+        error!("[driver] masked irq {}", irq);
+    }
+    Err(fault)
+}
+
+fn report(name: &str, fault: &Fault) {
+    error!("[driver] {} faulted and was disabled", name);
+    error!("[driver]   exception: {:?}", fault.exception);
+    if let Some(error_code) = fault.error_code {
+        error!("[driver]   error code: {:#x}", error_code);
+    }
+    if let Some(address) = fault.address {
+        error!("[driver]   address: {:#x}", address);
+    }
+    error!("[driver]   rip: {:#x}", fault.instruction_pointer);
+    error!("[driver]   rsp: {:#x}", fault.stack_pointer);
+}
+
+fn active() -> bool {
+    CONTAINMENT.load(Ordering::Relaxed)
+        && percpu::current().active_driver.load(Ordering::SeqCst) != 0
+}
+
+/// Called by the exception handlers, returns `false` if the fault is not a driver's.
+///
+/// For a driver fault the interrupted context is replaced so that `iretq`
+/// resumes in `driver_recover` on the stack of the kernel code that called the driver.
+pub fn contain(frame: &mut InterruptStackFrame, fault: Fault) -> bool {
+    if !active() {
+        return false;
+    }
+
+    *LAST_FAULT.lock() = Some(fault);
+    let stack = percpu::current().recovery_stack.load(Ordering::SeqCst);
+    unsafe {
+        frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(driver_recover as *const () as u64);
+            frame.stack_pointer = VirtAddr::new(stack);
+        });
+    }
+    true
+}
+
+/// Called by the panic handler, only returns if the panic is not a driver's.
+pub fn contain_panic(info: &PanicInfo) {
+    if !active() {
+        return;
+    }
+
+    let (instruction_pointer, stack_pointer): (u64, u64);
+    unsafe {
+        asm!("lea {}, [rip]", "mov {}, rsp", out(reg) instruction_pointer, out(reg) stack_pointer);
+    }
+    error!("[driver] panicked: {}", info.message());
+    *LAST_FAULT.lock() = Some(Fault {
+        exception: Exception::Panic,
+        error_code: None,
+        address: None,
+        instruction_pointer,
+        stack_pointer,
+    });
+
+    let stack = percpu::current().recovery_stack.load(Ordering::SeqCst);
+    unsafe {
+        asm!(
+            "mov rsp, {stack}",
+            "jmp driver_recover",
+            stack = in(reg) stack,
+            options(noreturn)
+        );
+    }
+}
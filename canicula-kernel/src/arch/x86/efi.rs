@@ -0,0 +1,181 @@
+use canicula_common::entry::{
+    BootInfo, EfiMemoryDescriptor, FirmwareTables, EFI_MEMORY_RO, EFI_MEMORY_RUNTIME, EFI_MEMORY_XP,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::{info, warn};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::FlagUpdateError;
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, Size2MiB, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+const EFI_PAGE_SIZE: u64 = 0x1000;
+/// Offset of `ResetSystem` in `EFI_RUNTIME_SERVICES`, past the table header and ten services.
+const RESET_SYSTEM: u64 = 24 + 10 * 8;
+
+/// `EFI_RUNTIME_SERVICES` if the loader found it, `0` otherwise.
+static RUNTIME_SERVICES: AtomicU64 = AtomicU64::new(0);
+
+/// The `EFI_RESET_TYPE`s the shell uses.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub enum ResetType {
+    Cold = 0,
+    Shutdown = 2,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Protection {
+    regions: usize,
+    pages: u64,
+    /// Pages inside a huge page shared with memory that needs other permissions.
+    skipped: u64,
+    unmapped: u64,
+}
+
+/// Apply the permissions of the EFI memory attributes table to the runtime regions
+/// in the firmware's identity mapping, which leaves them RWX.
+///
+/// Huge pages covering both code and data are left alone, splitting them needs a
+/// frame allocator the kernel does not have yet.
+pub fn init(boot_info: &'static BootInfo) {
+    check_tables(&boot_info.firmware);
+    let runtime_services = boot_info.firmware.efi_runtime_services;
+    if runtime_services != 0 && signed(runtime_services, b"RUNTSERV") {
+        RUNTIME_SERVICES.store(runtime_services, Ordering::Relaxed);
+    }
+
+    let table = &boot_info.memory_attributes;
+    if table.entry_count == 0 {
+        warn!("[efi] no memory attributes table, runtime regions stay RWX");
+        return;
+    }
+
+    // the loaded tables may be read-only in the identity mapping, the physmap alias is not
+    let mut page_table = unsafe {
+        let offset = VirtAddr::new(boot_info.physical_memory_offset);
+        let level_4 = offset + Cr3::read().0.start_address().as_u64();
+        OffsetPageTable::new(&mut *level_4.as_mut_ptr::<PageTable>(), offset)
+    };
+
+    let mut protection = Protection::default();
+    for descriptor in unsafe { table.entries() } {
+        if descriptor.attribute & EFI_MEMORY_RUNTIME == 0 {
+            continue;
+        }
+        protect(&mut page_table, &descriptor, &mut protection);
+        protection.regions += 1;
+    }
+
+    info!(
+        "[efi] protected {} runtime regions, {} pages",
+        protection.regions, protection.pages
+    );
+    if protection.skipped > 0 {
+        warn!(
+            "[efi] {} pages share a huge page with other permissions and stay RWX",
+            protection.skipped
+        );
+    }
+    if protection.unmapped > 0 {
+        warn!("[efi] {} runtime pages are not mapped", protection.unmapped);
+    }
+}
+
+/// Log the firmware tables the loader found, warning about any whose signature is off.
+///
+/// They are read through the firmware's identity mapping, like the runtime services
+/// will be called.
+fn check_tables(tables: &FirmwareTables) {
+    let checks: [(&str, u64, &[u8]); 5] = [
+        ("ACPI RSDP", tables.rsdp, b"RSD PTR "),
+        ("SMBIOS", tables.smbios, b"_SM_"),
+        ("SMBIOS 3", tables.smbios3, b"_SM3_"),
+        ("system table", tables.efi_system_table, b"IBI SYST"),
+        ("runtime services", tables.efi_runtime_services, b"RUNTSERV"),
+    ];
+    for (name, address, signature) in checks {
+        if address == 0 {
+            info!("[efi] no {}", name);
+        } else if signed(address, signature) {
+            info!("[efi] {} at {:#x}", name, address);
+        } else {
+            warn!("[efi] {} at {:#x} has a bad signature", name, address);
+        }
+    }
+}
+
+fn signed(address: u64, signature: &[u8]) -> bool {
+    let found = unsafe { core::slice::from_raw_parts(address as *const u8, signature.len()) };
+    found == signature
+}
+
+/// Reset or power off the machine through the firmware, only returns if it cannot.
+///
+/// The runtime services are called in the firmware's identity mapping, the
+/// kernel never moved them with `SetVirtualAddressMap`.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn reset_system(kind: ResetType) {
+    let table = RUNTIME_SERVICES.load(Ordering::Relaxed);
+    if table == 0 {
+        warn!("[efi] no runtime services to reset with");
+        return;
+    }
+    type ResetSystem = extern "efiapi" fn(u32, usize, usize, *const u8);
+    let reset = unsafe { *((table + RESET_SYSTEM) as *const ResetSystem) };
+    reset(kind as u32, 0, 0, core::ptr::null());
+}
+
+fn flags(descriptor: &EfiMemoryDescriptor) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if descriptor.attribute & EFI_MEMORY_RO == 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if descriptor.attribute & EFI_MEMORY_XP != 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn protect(
+    page_table: &mut OffsetPageTable,
+    descriptor: &EfiMemoryDescriptor,
+    protection: &mut Protection,
+) {
+    let flags = flags(descriptor);
+    let start = descriptor.physical_start;
+    let end = start + descriptor.page_count * EFI_PAGE_SIZE;
+
+    let mut address = start;
+    while address < end {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(address));
+        let result = unsafe { page_table.update_flags(page, flags) };
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                protection.pages += 1;
+                address += Size4KiB::SIZE;
+            }
+            Err(FlagUpdateError::PageNotMapped) => {
+                protection.unmapped += 1;
+                address += Size4KiB::SIZE;
+            }
+            Err(FlagUpdateError::ParentEntryHugePage) => {
+                let huge = Page::<Size2MiB>::containing_address(VirtAddr::new(address));
+                let next = huge.start_address().as_u64() + Size2MiB::SIZE;
+                let covered = huge.start_address().as_u64() == address && next <= end;
+                match covered.then(|| unsafe { page_table.update_flags(huge, flags) }) {
+                    Some(Ok(flush)) => {
+                        flush.flush();
+                        protection.pages += Size2MiB::SIZE / Size4KiB::SIZE;
+                    }
+                    _ => protection.skipped += (next.min(end) - address) / Size4KiB::SIZE,
+                }
+                address = next;
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use canicula_common::fs::OperateError;
+use canicula_common::qemu::TestExit;
+use canicula_ext4::reader::ROOT_INODE;
+use log::{error, info};
+use spin::Once;
+
+use super::ext4fs::Ext4;
+use super::qemu::QEMU_EXIT_HANDLE;
+use super::vfs::{self, Fd, FileKind};
+
+/// ext4 image built by `make test-ext4` from `tests/ext4`, read as a RAM disk.
+static IMAGE: &[u8] = include_bytes!(env!("CANICULA_EXT4_IMAGE"));
+static VOLUME: Once<Ext4> = Once::new();
+
+/// Files of `tests/ext4` and the contents the image must hold for them.
+const FILES: [(&str, &[u8]); 3] = [
+    (
+        "/hello.txt",
+        include_bytes!("../../../tests/ext4/hello.txt"),
+    ),
+    (
+        "/docs/nested.txt",
+        include_bytes!("../../../tests/ext4/docs/nested.txt"),
+    ),
+    (
+        "/large.bin",
+        include_bytes!("../../../tests/ext4/large.bin"),
+    ),
+];
+
+/// Odd sized so reads straddle block boundaries.
+const CHUNK_SIZE: usize = 1000;
+
+type Step = fn() -> Result<(), &'static str>;
+
+const STEPS: [(&str, Step); 5] = [
+    ("mount", mount),
+    ("read files", read_files),
+    ("read at offsets", read_at_offsets),
+    ("directories", directories),
+    ("missing entries", missing_entries),
+];
+
+fn read_bytes(offset: usize, buffer: &mut [u8]) -> Result<usize, OperateError> {
+    let end = offset
+        .checked_add(buffer.len())
+        .filter(|end| *end <= IMAGE.len())
+        .ok_or(OperateError::Fault)?;
+    buffer.copy_from_slice(&IMAGE[offset..end]);
+    Ok(buffer.len())
+}
+
+/// Mount the embedded image at `/`, run every step and exit QEMU with the result.
+pub fn run() -> ! {
+    info!("[ext4-test] {} byte image", IMAGE.len());
+
+    let mounted =
+        Ext4::mount(read_bytes).and_then(|volume| vfs::mount("/", VOLUME.call_once(|| volume)));
+    if let Err(err) = mounted {
+        error!("[ext4-test] mount failed: {:?}", err);
+        QEMU_EXIT_HANDLE.exit_failure();
+    }
+
+    let mut failed = 0;
+    for (name, step) in STEPS {
+        match step() {
+            Ok(()) => info!("[ext4-test] {} ... ok", name),
+            Err(reason) => {
+                error!("[ext4-test] {} ... FAILED: {}", name, reason);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed == 0 {
+        info!("[ext4-test] all {} steps passed", STEPS.len());
+        QEMU_EXIT_HANDLE.exit_success();
+    }
+    error!("[ext4-test] {} of {} steps failed", failed, STEPS.len());
+    QEMU_EXIT_HANDLE.exit_failure();
+}
+
+/// Open `path`, hand it to `check` and close it whatever `check` returns.
+fn with_file(
+    path: &str,
+    check: impl FnOnce(Fd) -> Result<(), &'static str>,
+) -> Result<(), &'static str> {
+    let fd = vfs::open(path).map_err(|_| "open failed")?;
+    let result = check(fd);
+    vfs::close(fd).map_err(|_| "close failed")?;
+    result
+}
+
+fn mount() -> Result<(), &'static str> {
+    let mut name = None;
+    vfs::mounts(|path, fs| {
+        if path == "/" {
+            name = Some(fs);
+        }
+    });
+    if name != Some("ext4") {
+        return Err("image is not mounted at /");
+    }
+    let root = vfs::stat("/").map_err(|_| "stat failed")?;
+    if root.inode != ROOT_INODE as u64 || root.kind != FileKind::Directory {
+        return Err("/ is not the root directory");
+    }
+    Ok(())
+}
+
+fn read_files() -> Result<(), &'static str> {
+    for (path, expected) in FILES {
+        with_file(path, |fd| {
+            let stat = vfs::fstat(fd).map_err(|_| "stat failed")?;
+            if stat.kind != FileKind::File {
+                return Err("not a regular file");
+            }
+            if stat.size != expected.len() as u64 {
+                return Err("size mismatch");
+            }
+
+            let mut chunk = [0u8; CHUNK_SIZE];
+            let mut offset = 0;
+            loop {
+                let read = vfs::read(fd, &mut chunk).map_err(|_| "read failed")?;
+                if read == 0 {
+                    break;
+                }
+                if offset + read > expected.len()
+                    || chunk[..read] != expected[offset..offset + read]
+                {
+                    return Err("content mismatch");
+                }
+                offset += read;
+            }
+            if offset != expected.len() {
+                return Err("short read");
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+fn read_at_offsets() -> Result<(), &'static str> {
+    let (path, expected) = FILES[2];
+    with_file(path, |fd| {
+        let mut chunk = [0u8; 64];
+        for offset in [0, 4095, 4096, 8190, expected.len() - 10] {
+            vfs::seek(fd, offset as u64).map_err(|_| "seek failed")?;
+            let read = vfs::read(fd, &mut chunk).map_err(|_| "read failed")?;
+            let end = (offset + chunk.len()).min(expected.len());
+            if read != end - offset || chunk[..read] != expected[offset..end] {
+                return Err("content mismatch");
+            }
+        }
+
+        vfs::seek(fd, expected.len() as u64).map_err(|_| "seek failed")?;
+        let read = vfs::read(fd, &mut chunk).map_err(|_| "read failed")?;
+        if read != 0 {
+            return Err("read past the end of the file");
+        }
+        Ok(())
+    })
+}
+
+fn directories() -> Result<(), &'static str> {
+    for path in ["/", "/docs", "/docs/"] {
+        let stat = vfs::stat(path).map_err(|_| "directory not found")?;
+        if stat.kind != FileKind::Directory {
+            return Err("not a directory");
+        }
+    }
+    with_file("/docs", |fd| match vfs::read(fd, &mut [0u8; 16]) {
+        Err(OperateError::IsDirectory) => Ok(()),
+        _ => Err("read a directory as a file"),
+    })?;
+
+    let (mut nested, mut parent) = (None, None);
+    vfs::read_dir("/docs", |inode, name| {
+        match name {
+            b"nested.txt" => nested = Some(inode),
+            b".." => parent = Some(inode),
+            _ => {}
+        }
+        true
+    })
+    .map_err(|_| "read_dir failed")?;
+    if parent != Some(ROOT_INODE as u64) {
+        return Err("parent is not the root directory");
+    }
+    let stat = vfs::stat("/docs/nested.txt").map_err(|_| "stat failed")?;
+    if nested != Some(stat.inode) {
+        return Err("entry not found");
+    }
+    if stat.size != FILES[1].1.len() as u64 {
+        return Err("size mismatch");
+    }
+    Ok(())
+}
+
+fn missing_entries() -> Result<(), &'static str> {
+    for path in ["/missing.txt", "/docs/missing.txt", "/hello.txt/child"] {
+        if vfs::stat(path).is_ok() {
+            return Err("found an entry that does not exist");
+        }
+    }
+    Ok(())
+}
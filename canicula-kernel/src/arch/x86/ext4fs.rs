@@ -0,0 +1,132 @@
+//! canicula-ext4 behind the [`FileSystem`] trait, read-only like its reader.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use canicula_common::fs::OperateError;
+use canicula_ext4::reader::ROOT_INODE;
+use canicula_ext4::{Ext4Reader, Inode, ReadBytes};
+use log::info;
+use spin::{Mutex, Once};
+
+use super::block;
+use super::frames::{self, Owner};
+use super::vfs::{self, FileKind, FileSystem, Stat};
+
+/// Largest ext4 block size, the reader needs one block of scratch space.
+const MAX_BLOCK_SIZE: usize = 0x10000;
+
+pub struct Ext4 {
+    reader: Mutex<Ext4Reader<'static>>,
+}
+
+impl Ext4 {
+    /// Mount the volume `read_bytes` reads from, with a scratch block from the frame allocator.
+    pub fn mount(read_bytes: ReadBytes) -> Result<Self, OperateError> {
+        let scratch = frames::allocate_dma(MAX_BLOCK_SIZE, Owner::Filesystem)
+            .ok_or(OperateError::DeviceNoFreeSpace)?;
+        let buffer =
+            unsafe { core::slice::from_raw_parts_mut(scratch.virtual_address, scratch.size) };
+        match Ext4Reader::mount(read_bytes, buffer) {
+            Ok(reader) => Ok(Ext4 {
+                reader: Mutex::new(reader),
+            }),
+            Err(error) => {
+                frames::free_dma(scratch);
+                Err(error)
+            }
+        }
+    }
+}
+
+/// The block device [`read_disk`] reads from, the ext4 reader only takes a plain fn.
+static DISK: AtomicUsize = AtomicUsize::new(usize::MAX);
+static DISK_VOLUME: Once<Ext4> = Once::new();
+
+fn read_disk(offset: usize, buffer: &mut [u8]) -> Result<usize, OperateError> {
+    let disk = block::get(DISK.load(Ordering::Relaxed)).ok_or(OperateError::NotFoundDev)?;
+    block::read_bytes(disk, offset, buffer)
+}
+
+/// Mount the first block device holding an ext4 volume at `path`.
+///
+/// Only one disk volume can be mounted, the reader is tied to [`read_disk`].
+pub fn mount_disk(path: &str) -> Result<(), OperateError> {
+    if DISK_VOLUME.is_completed() {
+        return Err(OperateError::Busy);
+    }
+    let mut index = 0;
+    let volume = loop {
+        let disk = block::get(index).ok_or(OperateError::NotFoundDev)?;
+        DISK.store(index, Ordering::Relaxed);
+        if let Ok(volume) = Ext4::mount(read_disk) {
+            info!("[ext4] volume found on {}", disk.name());
+            break DISK_VOLUME.call_once(|| volume);
+        }
+        index += 1;
+    };
+    vfs::mount(path, volume)
+}
+
+fn number(inode: u64) -> Result<u32, OperateError> {
+    u32::try_from(inode).map_err(|_| OperateError::NotFound)
+}
+
+fn kind(inode: &Inode) -> FileKind {
+    if inode.is_file() {
+        FileKind::File
+    } else if inode.is_dir() {
+        FileKind::Directory
+    } else if inode.is_symlink() {
+        FileKind::Symlink
+    } else {
+        FileKind::Other
+    }
+}
+
+impl FileSystem for Ext4 {
+    fn name(&self) -> &'static str {
+        "ext4"
+    }
+
+    fn root(&self) -> u64 {
+        ROOT_INODE as u64
+    }
+
+    fn lookup(&self, directory: u64, name: &str) -> Result<Option<u64>, OperateError> {
+        let mut reader = self.reader.lock();
+        let directory = reader.inode(number(directory)?)?;
+        if !directory.is_dir() {
+            return Err(OperateError::NotDirectory);
+        }
+        Ok(reader.lookup(&directory, name)?.map(u64::from))
+    }
+
+    fn stat(&self, inode: u64) -> Result<Stat, OperateError> {
+        let inode = self.reader.lock().inode(number(inode)?)?;
+        Ok(Stat {
+            inode: inode.number() as u64,
+            kind: kind(&inode),
+            size: inode.size(),
+            mode: inode.mode() & 0o7777,
+        })
+    }
+
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, OperateError> {
+        let mut reader = self.reader.lock();
+        let inode = reader.inode(number(inode)?)?;
+        reader.read_file(&inode, offset, buffer)
+    }
+
+    fn read_dir(
+        &self,
+        directory: u64,
+        visit: &mut dyn FnMut(u64, &[u8]) -> bool,
+    ) -> Result<(), OperateError> {
+        let mut reader = self.reader.lock();
+        let directory = reader.inode(number(directory)?)?;
+        if !directory.is_dir() {
+            return Err(OperateError::NotDirectory);
+        }
+        reader.read_dir(&directory, |inode, name| visit(inode as u64, name))
+    }
+}
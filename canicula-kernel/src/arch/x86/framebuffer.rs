@@ -0,0 +1,619 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use canicula_common::entry::{BootInfo, FrameBufferInfo, PixelFormat, PixelMasks};
+use canicula_common::font::Font;
+use canicula_common::unicode::{self, Utf8Decoder};
+use log::{info, warn};
+use spin::{Mutex, MutexGuard};
+
+use super::time;
+
+/// The VGA text palette as 0xRRGGBB, the second half is the bright variant.
+const PALETTE: [u32; 16] = [
+    0x000000, 0xaa0000, 0x00aa00, 0xaa5500, 0x0000aa, 0xaa00aa, 0x00aaaa, 0xaaaaaa, 0x555555,
+    0xff5555, 0x55ff55, 0xffff55, 0x5555ff, 0xff55ff, 0x55ffff, 0xffffff,
+];
+const DEFAULT_FOREGROUND: u32 = PALETTE[7];
+const DEFAULT_BACKGROUND: u32 = PALETTE[0];
+
+const MAX_ESCAPE_PARAMETERS: usize = 4;
+
+/// Seconds a display owner may go without [`DisplayLease::heartbeat`] before
+/// the console takes the display back.
+pub const WATCHDOG_TIMEOUT: i64 = 5;
+
+static FRAMEBUFFER: Mutex<Option<FrameBufferConsole>> = Mutex::new(None);
+static NEXT_LEASE: AtomicU64 = AtomicU64::new(1);
+
+enum Escape {
+    None,
+    Start,
+    /// Inside `ESC [`, collecting numeric parameters.
+    Csi {
+        parameters: [u16; MAX_ESCAPE_PARAMETERS],
+        count: usize,
+    },
+}
+
+/// A rectangle of pixels, `x` and `y` are its top left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    /// The part of the rectangle within a `width` by `height` screen.
+    fn clip(self, width: usize, height: usize) -> Option<Rect> {
+        let right = self.x.saturating_add(self.width).min(width);
+        let bottom = self.y.saturating_add(self.height).min(height);
+        if self.x >= right || self.y >= bottom {
+            return None;
+        }
+        Some(Rect {
+            width: right - self.x,
+            height: bottom - self.y,
+            ..self
+        })
+    }
+
+    /// The smallest rectangle covering both.
+    fn union(self, other: Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// A client that took the display with [`acquire`].
+struct Owner {
+    lease: u64,
+    /// Unix time of the last heartbeat.
+    alive_at: i64,
+    /// Bounding box of what the client drew, repainted when it hands back.
+    damage: Option<Rect>,
+}
+
+/// A text console drawn with a bitmap font on the linear framebuffer.
+///
+/// With a back buffer from the loader everything is drawn in RAM first and
+/// [`FrameBufferConsole::present`] copies what changed to the screen in one go,
+/// so scrolling reads RAM instead of slow video memory and never shows half a line.
+pub struct FrameBufferConsole {
+    base: *mut u32,
+    back: Option<*mut u32>,
+    /// What changed in the back buffer since the last present.
+    dirty: Option<Rect>,
+    width: usize,
+    height: usize,
+    stride: usize,
+    masks: PixelMasks,
+    font: Font<'static>,
+    scale: usize,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    foreground: u32,
+    background: u32,
+    escape: Escape,
+    decoder: Utf8Decoder,
+    /// While someone owns the display text only goes to the serial port.
+    owner: Option<Owner>,
+}
+
+// the framebuffer is only ever touched through the FRAMEBUFFER lock
+unsafe impl Send for FrameBufferConsole {}
+
+impl FrameBufferConsole {
+    pub fn new(info: &FrameBufferInfo, font: Font<'static>, scale: usize) -> Option<Self> {
+        if info.address == 0 || info.pixel_format == PixelFormat::Unknown {
+            return None;
+        }
+
+        let width = info.width as usize;
+        let height = info.height as usize;
+        let columns = width / (font.width() * scale);
+        let rows = height / (font.height() * scale);
+        if columns == 0 || rows == 0 {
+            return None;
+        }
+
+        let mut console = FrameBufferConsole {
+            base: info.address as *mut u32,
+            back: (info.back_buffer != 0).then_some(info.back_buffer as *mut u32),
+            dirty: None,
+            width,
+            height,
+            stride: info.stride as usize,
+            masks: info.masks,
+            font,
+            scale,
+            columns,
+            rows,
+            column: 0,
+            row: 0,
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            escape: Escape::None,
+            decoder: Utf8Decoder::new(),
+            owner: None,
+        };
+        console.clear();
+        console.present();
+        Some(console)
+    }
+
+    pub fn is_double_buffered(&self) -> bool {
+        self.back.is_some()
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn clear(&mut self) {
+        let screen = Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        };
+        self.fill(screen, self.background);
+        self.column = 0;
+        self.row = 0;
+    }
+
+    fn cell_width(&self) -> usize {
+        self.font.width() * self.scale
+    }
+
+    fn cell_height(&self) -> usize {
+        self.font.height() * self.scale
+    }
+
+    fn encode(&self, color: u32) -> u32 {
+        self.masks.encode(color)
+    }
+
+    /// Where drawing goes, the back buffer if there is one.
+    fn canvas(&self) -> *mut u32 {
+        self.back.unwrap_or(self.base)
+    }
+
+    fn put(&mut self, x: usize, y: usize, pixel: u32) {
+        unsafe { self.canvas().add(y * self.stride + x).write_volatile(pixel) };
+    }
+
+    /// Remember that `rect` has to be copied to the screen, it is already clipped.
+    fn mark(&mut self, rect: Rect) {
+        if self.back.is_some() {
+            self.dirty = Some(self.dirty.map_or(rect, |dirty| dirty.union(rect)));
+        }
+    }
+
+    /// Copy what changed in the back buffer to the framebuffer.
+    pub fn present(&mut self) {
+        let (Some(back), Some(dirty)) = (self.back, self.dirty.take()) else {
+            return;
+        };
+        for y in dirty.y..dirty.y + dirty.height {
+            let offset = y * self.stride + dirty.x;
+            unsafe {
+                core::ptr::copy_nonoverlapping(back.add(offset), self.base.add(offset), dirty.width)
+            };
+        }
+    }
+
+    /// Fill `rect` with an 0xRRGGBB `color`, whatever of it is on screen.
+    fn fill(&mut self, rect: Rect, color: u32) {
+        let Some(rect) = rect.clip(self.width, self.height) else {
+            return;
+        };
+        let pixel = self.encode(color);
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                self.put(x, y, pixel);
+            }
+        }
+        self.mark(rect);
+    }
+
+    /// Draw `ch` over `cells` cells, glyphs narrower than that are centered.
+    fn draw(&mut self, ch: char, cells: usize) {
+        let glyph = self.font.glyph(ch);
+        let foreground = self.encode(self.foreground);
+        let background = self.encode(self.background);
+        let left = self.column * self.cell_width();
+        let top = self.row * self.cell_height();
+        let width = cells * self.cell_width();
+        let padding = width.saturating_sub(glyph.width() * self.scale) / 2;
+
+        for y in 0..self.cell_height() {
+            for x in 0..width {
+                let glyph_x = x.wrapping_sub(padding) / self.scale;
+                let pixel = if glyph_x < glyph.width() && glyph.pixel(glyph_x, y / self.scale) {
+                    foreground
+                } else {
+                    background
+                };
+                self.put(left + x, top + y, pixel);
+            }
+        }
+        self.mark(Rect {
+            x: left,
+            y: top,
+            width,
+            height: self.cell_height(),
+        });
+    }
+
+    fn newline(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+
+        let line = self.cell_height() * self.stride;
+        let visible = self.rows * self.cell_height() * self.stride;
+        let canvas = self.canvas();
+        unsafe { core::ptr::copy(canvas.add(line), canvas, visible - line) };
+        self.mark(Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.rows * self.cell_height(),
+        });
+
+        let last = Rect {
+            x: 0,
+            y: (self.rows - 1) * self.cell_height(),
+            width: self.width,
+            height: self.cell_height(),
+        };
+        self.fill(last, self.background);
+    }
+
+    pub fn write_char(&mut self, ch: char) {
+        if self.owner.is_some() {
+            return;
+        }
+        match core::mem::replace(&mut self.escape, Escape::None) {
+            Escape::None => {}
+            Escape::Start if ch == '[' => {
+                self.escape = Escape::Csi {
+                    parameters: [0; MAX_ESCAPE_PARAMETERS],
+                    count: 1,
+                };
+                return;
+            }
+            Escape::Start => return,
+            Escape::Csi {
+                mut parameters,
+                mut count,
+            } => {
+                match ch {
+                    '0'..='9' => {
+                        let parameter = &mut parameters[count - 1];
+                        *parameter = parameter
+                            .saturating_mul(10)
+                            .saturating_add(ch as u16 - '0' as u16);
+                    }
+                    ';' => count = (count + 1).min(MAX_ESCAPE_PARAMETERS),
+                    'm' => {
+                        self.select_graphic_rendition(&parameters[..count]);
+                        return;
+                    }
+                    // any other final byte ends a sequence we do not support
+                    '\u{40}'..='\u{7e}' => return,
+                    _ => {}
+                }
+                self.escape = Escape::Csi { parameters, count };
+                return;
+            }
+        }
+
+        match ch {
+            '\u{1b}' => self.escape = Escape::Start,
+            '\n' => self.newline(),
+            '\r' => self.column = 0,
+            '\u{8}' => self.column = self.column.saturating_sub(1),
+            _ if ch.is_control() => {}
+            _ => {
+                // combining marks are dropped rather than drawn over the previous cell
+                let cells = unicode::char_width(ch).min(self.columns);
+                if cells == 0 {
+                    return;
+                }
+                if self.column + cells > self.columns {
+                    self.newline();
+                }
+                self.draw(ch, cells);
+                self.column += cells;
+            }
+        }
+    }
+
+    /// Write raw UTF-8, a character split across calls is completed by the next one.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        let mut decoder = self.decoder;
+        decoder.decode(bytes, |ch| self.write_char(ch));
+        self.decoder = decoder;
+    }
+
+    fn select_graphic_rendition(&mut self, parameters: &[u16]) {
+        for parameter in parameters {
+            match *parameter {
+                0 => {
+                    self.foreground = DEFAULT_FOREGROUND;
+                    self.background = DEFAULT_BACKGROUND;
+                }
+                30..=37 => self.foreground = PALETTE[*parameter as usize - 30],
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.background = PALETTE[*parameter as usize - 40],
+                49 => self.background = DEFAULT_BACKGROUND,
+                90..=97 => self.foreground = PALETTE[*parameter as usize - 90 + 8],
+                100..=107 => self.background = PALETTE[*parameter as usize - 100 + 8],
+                _ => {}
+            }
+        }
+    }
+
+    /// Give the display back to the console, clearing what the owner drew.
+    fn hand_back(&mut self) {
+        if let Some(Owner {
+            damage: Some(damage),
+            ..
+        }) = self.owner.take()
+        {
+            self.fill(damage, self.background);
+        }
+    }
+
+    /// Take the display back if its owner missed the watchdog, true if it did.
+    fn reclaim_if_stale(&mut self) -> bool {
+        let Some(owner) = &self.owner else {
+            return false;
+        };
+        if time::unix_timestamp() - owner.alive_at <= WATCHDOG_TIMEOUT {
+            return false;
+        }
+        self.hand_back();
+        true
+    }
+
+    /// The owner behind `lease`, `None` once the display was reclaimed from it.
+    fn owner(&mut self, lease: u64) -> Option<&mut Owner> {
+        self.owner.as_mut().filter(|owner| owner.lease == lease)
+    }
+}
+
+impl Write for FrameBufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.chars().for_each(|ch| self.write_char(ch));
+        Ok(())
+    }
+}
+
+/// Bring up the framebuffer console with the loader supplied font, or the built-in one.
+///
+/// Build with `font_scale=1` or `font_scale=2` to override the automatic HiDPI scaling.
+pub fn init(boot_info: &'static BootInfo) {
+    let info = &boot_info.framebuffer;
+
+    let font = if boot_info.font.is_empty() {
+        Font::builtin()
+    } else {
+        let data = unsafe {
+            core::slice::from_raw_parts(
+                boot_info.font.address as *const u8,
+                boot_info.font.size as usize,
+            )
+        };
+        Font::parse(data).unwrap_or_else(|error| {
+            warn!("[framebuffer] cannot use the loaded font: {:?}", error);
+            Font::builtin()
+        })
+    };
+
+    let scale = match option_env!("font_scale").and_then(|scale| scale.parse().ok()) {
+        Some(scale @ 1..=4) => scale,
+        _ => font.scale_for(info.width as usize, info.height as usize),
+    };
+
+    let Some(console) = FrameBufferConsole::new(info, font, scale) else {
+        warn!("[framebuffer] no usable framebuffer: {:?}", info);
+        return;
+    };
+    info!(
+        "[framebuffer] {}x{} console, {}x{} font at {}x{}",
+        console.columns(),
+        console.rows(),
+        font.width(),
+        font.height(),
+        scale,
+        if console.is_double_buffered() {
+            ", double buffered"
+        } else {
+            ""
+        }
+    );
+    *FRAMEBUFFER.lock() = Some(console);
+}
+
+pub fn print(args: fmt::Arguments) {
+    if let Some(console) = FRAMEBUFFER.lock().as_mut() {
+        reclaim_if_stale(console);
+        console.write_fmt(args).unwrap();
+        console.present();
+    }
+}
+
+pub fn write_bytes(bytes: &[u8]) {
+    if let Some(console) = FRAMEBUFFER.lock().as_mut() {
+        reclaim_if_stale(console);
+        console.write_bytes(bytes);
+        console.present();
+    }
+}
+
+/// Run the display watchdog, only from the print paths that may block.
+fn reclaim_if_stale(console: &mut FrameBufferConsole) {
+    if console.reclaim_if_stale() {
+        // the logger would print through the lock held here
+        let _ = console.write_str("[framebuffer] display owner stopped responding, reclaimed\n");
+    }
+}
+
+/// The console lock without spinning, `None` while someone else holds it.
+pub fn try_lock() -> Option<MutexGuard<'static, Option<FrameBufferConsole>>> {
+    FRAMEBUFFER.try_lock()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayError {
+    /// There is no framebuffer console to take the display from.
+    NoDisplay,
+    /// Another client owns the display.
+    Busy,
+    /// The watchdog took the display back, the lease is void.
+    Reclaimed,
+    /// Fewer pixels than the rectangle covers.
+    BufferTooSmall,
+}
+
+/// Exclusive use of the display, e.g. for a GUI or a wasm app.
+///
+/// The console draws nothing while the lease is held, kernel output still goes
+/// to the serial port. Dropping the lease hands the display back and the console
+/// clears what the client drew over. A client that does not call
+/// [`DisplayLease::heartbeat`] for [`WATCHDOG_TIMEOUT`] seconds loses the display
+/// the next time the console prints.
+pub struct DisplayLease {
+    lease: u64,
+    width: usize,
+    height: usize,
+}
+
+/// Take the display away from the console.
+// there is no GUI or wasm app to hold a lease yet
+#[allow(dead_code)]
+pub fn acquire() -> Result<DisplayLease, DisplayError> {
+    let mut framebuffer = FRAMEBUFFER.lock();
+    let console = framebuffer.as_mut().ok_or(DisplayError::NoDisplay)?;
+    console.reclaim_if_stale();
+    if console.owner.is_some() {
+        return Err(DisplayError::Busy);
+    }
+    let lease = NEXT_LEASE.fetch_add(1, Ordering::Relaxed);
+    console.owner = Some(Owner {
+        lease,
+        alive_at: time::unix_timestamp(),
+        damage: None,
+    });
+    let (width, height) = (console.width, console.height);
+    drop(framebuffer);
+
+    info!("[framebuffer] display acquired by lease {}", lease);
+    Ok(DisplayLease {
+        lease,
+        width,
+        height,
+    })
+}
+
+// only a lease holder draws
+#[allow(dead_code)]
+impl DisplayLease {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Tell the watchdog the client is still alive.
+    pub fn heartbeat(&self) -> Result<(), DisplayError> {
+        let now = time::unix_timestamp();
+        self.with_console(|console| {
+            if let Some(owner) = console.owner(self.lease) {
+                owner.alive_at = now;
+            }
+        })
+    }
+
+    /// Fill `rect` with an 0xRRGGBB `color`.
+    pub fn fill(&mut self, rect: Rect, color: u32) -> Result<(), DisplayError> {
+        self.with_console(|console| {
+            console.fill(rect, color);
+            damage(console, self.lease, rect);
+        })
+    }
+
+    /// Copy 0xRRGGBB `pixels` into `rect`, row by row without padding.
+    pub fn blit(&mut self, rect: Rect, pixels: &[u32]) -> Result<(), DisplayError> {
+        if pixels.len() < rect.width * rect.height {
+            return Err(DisplayError::BufferTooSmall);
+        }
+        self.with_console(|console| {
+            let Some(visible) = rect.clip(console.width, console.height) else {
+                return;
+            };
+            for y in 0..visible.height {
+                let row = &pixels[(visible.y - rect.y + y) * rect.width..];
+                for x in 0..visible.width {
+                    let pixel = console.encode(row[visible.x - rect.x + x]);
+                    console.put(visible.x + x, visible.y + y, pixel);
+                }
+            }
+            console.mark(visible);
+            damage(console, self.lease, visible);
+        })
+    }
+
+    /// Hand the display back to the console, same as dropping the lease.
+    pub fn release(self) {}
+
+    fn with_console(&self, f: impl FnOnce(&mut FrameBufferConsole)) -> Result<(), DisplayError> {
+        let mut framebuffer = FRAMEBUFFER.lock();
+        let console = framebuffer.as_mut().ok_or(DisplayError::NoDisplay)?;
+        if console.owner(self.lease).is_none() {
+            return Err(DisplayError::Reclaimed);
+        }
+        f(console);
+        console.present();
+        Ok(())
+    }
+}
+
+impl Drop for DisplayLease {
+    fn drop(&mut self) {
+        let released = self.with_console(|console| console.hand_back()).is_ok();
+        if released {
+            info!("[framebuffer] display released by lease {}", self.lease);
+        }
+    }
+}
+
+fn damage(console: &mut FrameBufferConsole, lease: u64, rect: Rect) {
+    let Some(rect) = rect.clip(console.width, console.height) else {
+        return;
+    };
+    if let Some(owner) = console.owner(lease) {
+        owner.damage = Some(owner.damage.map_or(rect, |damage| damage.union(rect)));
+    }
+}
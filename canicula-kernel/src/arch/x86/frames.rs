@@ -0,0 +1,414 @@
+//! Physical frames: a buddy allocator over the usable memory the loader reported.
+//!
+//! Free blocks of 2^order frames are kept in one list per order, linked through
+//! their first bytes in the map of physical memory. One state byte per frame
+//! marks where a free block starts and its order, which is all freeing needs
+//! to find a block's buddy and merge with it. The first state byte of an
+//! allocated block names its [`Owner`], so usage is counted per subsystem.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use canicula_common::entry::{BootInfo, MemoryKind, SMP_TRAMPOLINE_LIMIT};
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::interrupts;
+
+pub const FRAME_SIZE: u64 = 0x1000;
+/// Blocks go up to 2^(ORDERS - 1) frames, 4MiB.
+pub const ORDERS: usize = 11;
+
+/// Set in the state byte of the first frame of a free block, next to its order.
+const FREE: u8 = 0x80;
+/// End of a free list.
+const NONE: u64 = u64::MAX;
+pub const OWNERS: usize = 6;
+
+/// The subsystem frames are allocated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    /// Kernel mappings, stacks and the page tables behind them.
+    Vm,
+    /// Rings and buffers of storage controllers and other devices.
+    Drivers,
+    /// Rings and packet buffers of network devices.
+    Network,
+    /// File system caches and scratch blocks.
+    Filesystem,
+    /// Shared memory objects.
+    Shm,
+    /// Memory, nested page tables and control blocks of virtual machines.
+    Guest,
+}
+
+impl Owner {
+    pub const ALL: [Owner; OWNERS] = [
+        Owner::Vm,
+        Owner::Drivers,
+        Owner::Network,
+        Owner::Filesystem,
+        Owner::Shm,
+        Owner::Guest,
+    ];
+
+    #[cfg_attr(feature = "ext4-test", allow(dead_code))]
+    pub fn name(self) -> &'static str {
+        match self {
+            Owner::Vm => "vm",
+            Owner::Drivers => "drivers",
+            Owner::Network => "network",
+            Owner::Filesystem => "filesystem",
+            Owner::Shm => "shm",
+            Owner::Guest => "guest",
+        }
+    }
+
+    /// The state byte of a block it allocated, `0` is left for frames never allocated.
+    fn tag(self) -> u8 {
+        self as u8 + 1
+    }
+}
+
+static FRAMES: Mutex<Option<BuddyAllocator>> = Mutex::new(None);
+/// Where the loader mapped physical memory.
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Kept in the first bytes of every free block.
+#[repr(C)]
+struct Link {
+    next: u64,
+    prev: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    /// Free blocks of each order.
+    pub free_blocks: [u64; ORDERS],
+    pub free_frames: u64,
+    /// Frames the allocator manages, free or not.
+    pub total_frames: u64,
+    /// Usage by [`Owner`], in its order.
+    pub owners: [Usage; OWNERS],
+}
+
+/// Frames one [`Owner`] holds.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub frames: u64,
+    /// The most it held at once.
+    pub peak_frames: u64,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+pub struct BuddyAllocator {
+    /// Where physical memory is mapped.
+    offset: u64,
+    /// One byte per frame starting at physical address `0`.
+    state: &'static mut [u8],
+    heads: [u64; ORDERS],
+    stats: FrameStats,
+}
+
+const fn align_up(address: u64) -> u64 {
+    (address + FRAME_SIZE - 1) & !(FRAME_SIZE - 1)
+}
+
+fn frame(address: u64) -> usize {
+    (address / FRAME_SIZE) as usize
+}
+
+/// The order of the smallest block holding `count` frames.
+pub fn order_for(count: u64) -> usize {
+    count.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+impl BuddyAllocator {
+    /// An allocator without free memory for frames below `state.len()` frames,
+    /// physical memory has to be mapped at `offset`.
+    pub fn new(offset: u64, state: &'static mut [u8]) -> Self {
+        state.fill(0);
+        BuddyAllocator {
+            offset,
+            state,
+            heads: [NONE; ORDERS],
+            stats: FrameStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    fn limit(&self) -> u64 {
+        self.state.len() as u64 * FRAME_SIZE
+    }
+
+    fn link(&self, address: u64) -> *mut Link {
+        (self.offset + address) as *mut Link
+    }
+
+    fn push(&mut self, address: u64, order: usize) {
+        let head = self.heads[order];
+        unsafe {
+            self.link(address).write(Link {
+                next: head,
+                prev: NONE,
+            });
+            if head != NONE {
+                (*self.link(head)).prev = address;
+            }
+        }
+        self.heads[order] = address;
+        self.state[frame(address)] = FREE | order as u8;
+        self.stats.free_blocks[order] += 1;
+    }
+
+    fn remove(&mut self, address: u64, order: usize) {
+        let Link { next, prev } = unsafe { self.link(address).read() };
+        unsafe {
+            if prev != NONE {
+                (*self.link(prev)).next = next;
+            }
+            if next != NONE {
+                (*self.link(next)).prev = prev;
+            }
+        }
+        if prev == NONE {
+            self.heads[order] = next;
+        }
+        self.state[frame(address)] = 0;
+        self.stats.free_blocks[order] -= 1;
+    }
+
+    /// Hand the frames within `start..end` to the allocator, nothing may use them.
+    pub fn add(&mut self, start: u64, end: u64) {
+        let mut start = align_up(start);
+        let end = (end & !(FRAME_SIZE - 1)).min(self.limit());
+        while start < end {
+            let frames = (end - start) / FRAME_SIZE;
+            let order = ((start / FRAME_SIZE).trailing_zeros() as usize)
+                .min(frames.ilog2() as usize)
+                .min(ORDERS - 1);
+            self.stats.total_frames += 1 << order;
+            self.free(start, order);
+            start += FRAME_SIZE << order;
+        }
+    }
+
+    /// Take a block of 2^`order` frames aligned to its size for `owner`.
+    pub fn allocate(&mut self, order: usize, owner: Owner) -> Option<u64> {
+        let found = (order..ORDERS).find(|&order| self.heads[order] != NONE)?;
+        let address = self.heads[found];
+        self.remove(address, found);
+        // the upper halves of the split stay free
+        for order in (order..found).rev() {
+            self.push(address + (FRAME_SIZE << order), order);
+        }
+        self.stats.free_frames -= 1 << order;
+        self.state[frame(address)] = owner.tag();
+        let usage = &mut self.stats.owners[owner as usize];
+        usage.frames += 1 << order;
+        usage.peak_frames = usage.peak_frames.max(usage.frames);
+        usage.allocations += 1;
+        Some(address)
+    }
+
+    /// Give back a block [`BuddyAllocator::allocate`] returned for the same `order`.
+    pub fn free(&mut self, address: u64, order: usize) {
+        debug_assert!(
+            self.state[frame(address)] & FREE == 0,
+            "[frames] {:#x} freed twice",
+            address
+        );
+        self.stats.free_frames += 1 << order;
+        let tag = core::mem::take(&mut self.state[frame(address)]);
+        if let Some(owner) = Owner::ALL.get((tag as usize).wrapping_sub(1)) {
+            let usage = &mut self.stats.owners[*owner as usize];
+            usage.frames -= 1 << order;
+            usage.frees += 1;
+        }
+
+        let mut address = address;
+        let mut order = order;
+        while order + 1 < ORDERS {
+            let buddy = address ^ (FRAME_SIZE << order);
+            if self.state.get(frame(buddy)) != Some(&(FREE | order as u8)) {
+                break;
+            }
+            self.remove(buddy, order);
+            address = address.min(buddy);
+            order += 1;
+        }
+        self.push(address, order);
+    }
+}
+
+/// Manage the usable memory in the map.
+///
+/// Memory below [`SMP_TRAMPOLINE_LIMIT`] is left for real mode code and memory
+/// above what the loader mapped is out of reach. The state bytes take the start
+/// of the first usable region large enough for them.
+pub fn init(boot_info: &'static BootInfo) {
+    OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let memory_map = unsafe { boot_info.memory_map.entries() };
+    let limit = boot_info.physical_memory_size;
+    let usable = || {
+        memory_map
+            .iter()
+            .filter(|entry| entry.kind == MemoryKind::Usable)
+            .map(|entry| {
+                (
+                    align_up(entry.region.address.max(SMP_TRAMPOLINE_LIMIT)),
+                    entry.end().min(limit) & !(FRAME_SIZE - 1),
+                )
+            })
+            .filter(|(start, end)| start < end)
+    };
+
+    let Some(top) = usable().map(|(_, end)| end).max() else {
+        warn!("[frames] no usable memory");
+        return;
+    };
+    let state_size = top / FRAME_SIZE;
+    let Some((state_start, _)) = usable().find(|(start, end)| start + state_size <= *end) else {
+        warn!("[frames] no room for {} bytes of frame state", state_size);
+        return;
+    };
+    let state_end = align_up(state_start + state_size);
+    let state = unsafe {
+        core::slice::from_raw_parts_mut(
+            (boot_info.physical_memory_offset + state_start) as *mut u8,
+            state_size as usize,
+        )
+    };
+
+    let mut frames = BuddyAllocator::new(boot_info.physical_memory_offset, state);
+    for (start, end) in usable() {
+        if start == state_start {
+            frames.add(state_end, end);
+        } else {
+            frames.add(start, end);
+        }
+    }
+
+    let stats = frames.stats();
+    info!(
+        "[frames] {} MiB in {} frames, state at {:#x}",
+        (stats.free_frames * FRAME_SIZE) >> 20,
+        stats.free_frames,
+        state_start
+    );
+    *FRAMES.lock() = Some(frames);
+}
+
+fn with_frames<T>(f: impl FnOnce(&mut BuddyAllocator) -> Option<T>) -> Option<T> {
+    interrupts::assert_allocation_allowed();
+    FRAMES.lock().as_mut().and_then(f)
+}
+
+/// Physical address of 2^`order` free frames aligned to their size, counted for `owner`.
+pub fn allocate(order: usize, owner: Owner) -> Option<u64> {
+    if order >= ORDERS {
+        return None;
+    }
+    with_frames(|frames| frames.allocate(order, owner))
+}
+
+/// Give back frames from [`allocate`] with the same `order`.
+pub fn free(address: u64, order: usize) {
+    with_frames(|frames| {
+        frames.free(address, order);
+        Some(())
+    });
+}
+
+/// At least `count` physically contiguous frames, e.g. for DMA.
+///
+/// The block is rounded up to a power of two, [`free_contiguous`] takes the same `count`.
+pub fn allocate_contiguous(count: u64, owner: Owner) -> Option<u64> {
+    allocate(order_for(count), owner)
+}
+
+pub fn free_contiguous(address: u64, count: u64) {
+    free(address, order_for(count));
+}
+
+/// Where the CPU reaches the physical address `address`.
+pub fn to_virtual(address: u64) -> *mut u8 {
+    (OFFSET.load(Ordering::Relaxed) + address) as *mut u8
+}
+
+/// Zeroed frames for a device to reach by DMA, see [`allocate_dma`].
+#[derive(Debug)]
+pub struct Dma {
+    /// The address the device is given.
+    pub physical: u64,
+    pub virtual_address: *mut u8,
+    pub size: usize,
+}
+
+/// At least `size` bytes of physically contiguous, zeroed memory for `owner`.
+pub fn allocate_dma(size: usize, owner: Owner) -> Option<Dma> {
+    let count = (size as u64).div_ceil(FRAME_SIZE);
+    let physical = allocate_contiguous(count, owner)?;
+    let virtual_address = to_virtual(physical);
+    let size = (count * FRAME_SIZE) as usize;
+    unsafe { core::ptr::write_bytes(virtual_address, 0, size) };
+    Some(Dma {
+        physical,
+        virtual_address,
+        size,
+    })
+}
+
+pub fn free_dma(dma: Dma) {
+    free_contiguous(dma.physical, dma.size as u64 / FRAME_SIZE);
+}
+
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn stats() -> Option<FrameStats> {
+    with_frames(|frames| Some(frames.stats()))
+}
+
+/// Single frames for the `x86_64` mapper, e.g. to allocate page tables, owned by [`Owner::Vm`].
+pub struct KernelFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for KernelFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        allocate(0, Owner::Vm).map(|address| PhysFrame::containing_address(PhysAddr::new(address)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for KernelFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        free(frame.start_address().as_u64(), 0);
+    }
+}
+
+/// Single frames for the page fault handler.
+///
+/// The fault may have interrupted code holding the allocator lock, so these
+/// fail instead of waiting for it. A frame that cannot be given back is lost.
+pub struct FaultFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for FaultFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let address = FRAMES.try_lock()?.as_mut()?.allocate(0, Owner::Vm)?;
+        Some(PhysFrame::containing_address(PhysAddr::new(address)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for FaultFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        if let Some(frames) = FRAMES
+            .try_lock()
+            .as_mut()
+            .and_then(|frames| frames.as_mut())
+        {
+            frames.free(frame.start_address().as_u64(), 0);
+        }
+    }
+}
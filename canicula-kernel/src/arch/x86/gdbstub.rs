@@ -0,0 +1,707 @@
+//! A remote GDB stub on COM2, entered from the breakpoint and debug exceptions.
+//!
+//! With `gdb` on the command line the kernel stops as soon as the stub is set up
+//! and waits for `target remote` on the second serial port, under QEMU e.g.
+//! `-serial tcp::1234,server,nowait` after the console's `-serial`. From then on
+//! an `int3`, a breakpoint GDB asked for or a finished single step hands the
+//! CPU to the debugger until it says to go on. Interrupts stay off meanwhile,
+//! so the rest of the kernel stands still.
+//!
+//! It speaks the plain remote protocol: the registers of the trapped context,
+//! memory, software breakpoints patched in as `int3` and single steps with the
+//! trap flag. Nothing listens to the port while the kernel runs, so a Ctrl-C in
+//! GDB only takes effect at the next stop.
+
+use core::arch::{asm, global_asm};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use super::interrupts::Context;
+use super::serial::SerialPort;
+use super::{aslr, frames};
+
+const COM2: u16 = 0x2f8;
+
+/// Largest packet either way, in characters, what `qSupported` tells GDB.
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+/// The signal every stop is reported as.
+const SIGTRAP: u8 = 5;
+
+const BREAKPOINT_VECTOR: u64 = 3;
+
+/// Registers in GDB's amd64 order: 17 of 8 bytes up to `rip`, then `eflags`
+/// and the segment selectors of 4. The FPU and SSE state that follow are left
+/// out, GDB shows them as unavailable.
+const REGISTERS: usize = 24;
+const WIDE_REGISTERS: usize = 17;
+
+const PAGE_PRESENT: u64 = 1 << 0;
+const PAGE_HUGE: u64 = 1 << 7;
+const PAGE_ADDRESS: u64 = 0x000f_ffff_ffff_f000;
+
+/// Everything the entry stubs saved, the interrupt frame on top of the general registers.
+#[repr(C)]
+pub struct TrapFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    vector: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+impl TrapFrame {
+    /// Register `number` in GDB's numbering and its size in bytes.
+    fn register(&self, number: usize) -> Option<(u64, usize)> {
+        let value = match number {
+            0 => self.rax,
+            1 => self.rbx,
+            2 => self.rcx,
+            3 => self.rdx,
+            4 => self.rsi,
+            5 => self.rdi,
+            6 => self.rbp,
+            7 => self.rsp,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            16 => self.rip,
+            17 => self.rflags,
+            18 => self.cs,
+            19 => self.ss,
+            20..REGISTERS => segment(number),
+            _ => return None,
+        };
+        Some((value, if number < WIDE_REGISTERS { 8 } else { 4 }))
+    }
+
+    /// Change register `number`, the segment selectors stay as they are.
+    fn set_register(&mut self, number: usize, value: u64) -> bool {
+        let register = match number {
+            0 => &mut self.rax,
+            1 => &mut self.rbx,
+            2 => &mut self.rcx,
+            3 => &mut self.rdx,
+            4 => &mut self.rsi,
+            5 => &mut self.rdi,
+            6 => &mut self.rbp,
+            7 => &mut self.rsp,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            16 => &mut self.rip,
+            17 => &mut self.rflags,
+            18..REGISTERS => return true,
+            _ => return false,
+        };
+        *register = value;
+        true
+    }
+}
+
+/// `ds`, `es`, `fs` or `gs`, which the trap does not change.
+fn segment(number: usize) -> u64 {
+    let selector: u16;
+    unsafe {
+        match number {
+            20 => asm!("mov {0:x}, ds", out(reg) selector, options(nomem, nostack)),
+            21 => asm!("mov {0:x}, es", out(reg) selector, options(nomem, nostack)),
+            22 => asm!("mov {0:x}, fs", out(reg) selector, options(nomem, nostack)),
+            _ => asm!("mov {0:x}, gs", out(reg) selector, options(nomem, nostack)),
+        }
+    }
+    selector as u64
+}
+
+// Both entries push the vector and every general register in TrapFrame order,
+// then call trap with the frame. The CPU leaves the stack 8 bytes off 16 after
+// its own five pushes, the sixteen here keep it that way, hence the extra 8.
+global_asm!(
+    r#"
+    .global gdb_debug_entry
+gdb_debug_entry:
+    push 1
+    jmp gdb_trap_common
+
+    .global gdb_breakpoint_entry
+gdb_breakpoint_entry:
+    push 3
+
+gdb_trap_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    sub rsp, 8
+    cld
+    call {trap}
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 8
+    iretq
+"#,
+    trap = sym trap,
+);
+
+extern "C" {
+    fn gdb_debug_entry();
+    fn gdb_breakpoint_entry();
+}
+
+/// Where the IDT sends the debug exception.
+pub fn debug_entry() -> VirtAddr {
+    VirtAddr::new(gdb_debug_entry as *const () as u64)
+}
+
+/// Where the IDT sends the breakpoint exception.
+pub fn breakpoint_entry() -> VirtAddr {
+    VirtAddr::new(gdb_breakpoint_entry as *const () as u64)
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    /// The byte the `int3` took the place of.
+    original: u8,
+}
+
+/// An outgoing packet's contents.
+struct Packet {
+    bytes: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Add `bytes` as hex digits, whatever does not fit is dropped.
+    fn hex(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let _ = write!(self, "{:02x}", byte);
+        }
+    }
+}
+
+impl Write for Packet {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(PACKET_SIZE - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// What the debugger asked for after a command.
+enum Next {
+    Reply,
+    Continue,
+    Step,
+    Detach,
+}
+
+struct Stub {
+    port: SerialPort,
+    input: [u8; PACKET_SIZE],
+    output: Packet,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// A debugger has talked to the stub and not detached yet.
+    attached: bool,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// only ever taken in the trap handler, with interrupts off
+static STUB: Mutex<Stub> = Mutex::new(Stub {
+    port: SerialPort::new(COM2),
+    input: [0; PACKET_SIZE],
+    output: Packet {
+        bytes: [0; PACKET_SIZE],
+        len: 0,
+    },
+    breakpoints: [None; MAX_BREAKPOINTS],
+    attached: false,
+});
+
+/// Set up COM2 and stop for the debugger, if `enabled` or built with `gdb=on`.
+///
+/// Needs [`frames::init`], memory is checked against the page tables through its map.
+pub fn init(enabled: bool) {
+    let enabled = enabled || option_env!("gdb") == Some("on");
+    if !enabled {
+        return;
+    }
+    STUB.lock().port.init();
+    ENABLED.store(true, Ordering::SeqCst);
+    info!("[gdb] waiting for the debugger on COM2");
+    unsafe { asm!("int3") };
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+extern "sysv64" fn trap(frame: &mut TrapFrame) {
+    let _context = Context::enter();
+    // a step ends with this trap, the debugger sets the flag again for the next one
+    frame.rflags &= !RFlags::TRAP_FLAG.bits();
+    if !is_enabled() {
+        match frame.vector {
+            BREAKPOINT_VECTOR => warn!("[interrupts] breakpoint at {:#x}", frame.rip),
+            _ => warn!("[interrupts] debug exception at {:#x}", frame.rip),
+        }
+        return;
+    }
+    let Some(mut stub) = STUB.try_lock() else {
+        // a breakpoint in the stub itself
+        warn!("[gdb] trap at {:#x} inside the debugger", frame.rip);
+        return;
+    };
+    stub.serve(frame);
+}
+
+impl Stub {
+    /// Answer the debugger until it lets the trapped context go on.
+    fn serve(&mut self, frame: &mut TrapFrame) {
+        if self.attached {
+            self.stop_reply();
+            self.send();
+        }
+        loop {
+            let len = self.receive();
+            self.attached = true;
+            self.output.clear();
+            let next = command(
+                &self.input[..len],
+                frame,
+                &mut self.output,
+                &mut self.breakpoints,
+            );
+            match next {
+                Next::Reply => self.send(),
+                Next::Continue => return,
+                Next::Step => {
+                    frame.rflags |= RFlags::TRAP_FLAG.bits();
+                    return;
+                }
+                Next::Detach => {
+                    // `k` takes no reply
+                    if self.output.len != 0 {
+                        self.send();
+                    }
+                    for breakpoint in self.breakpoints.iter_mut() {
+                        if let Some(breakpoint) = breakpoint.take() {
+                            write_byte(breakpoint.address, breakpoint.original);
+                        }
+                    }
+                    self.attached = false;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn stop_reply(&mut self) {
+        self.output.clear();
+        let _ = write!(self.output, "S{:02x}", SIGTRAP);
+    }
+
+    /// Wait for a packet with a good checksum, acknowledge it and return its length.
+    fn receive(&mut self) -> usize {
+        'packet: loop {
+            while self.port.receive() != b'$' {}
+            let mut len = 0;
+            let mut sum = 0u8;
+            loop {
+                let byte = self.port.receive();
+                match byte {
+                    b'#' => break,
+                    // the debugger gave up on the last one and started over
+                    b'$' => continue 'packet,
+                    _ => {}
+                }
+                if len == PACKET_SIZE {
+                    self.port.send(b'-');
+                    continue 'packet;
+                }
+                self.input[len] = byte;
+                len += 1;
+                sum = sum.wrapping_add(byte);
+            }
+            let high = hex_digit(self.port.receive());
+            let low = hex_digit(self.port.receive());
+            match high.zip(low) {
+                Some((high, low)) if high << 4 | low == sum => {
+                    self.port.send(b'+');
+                    return len;
+                }
+                _ => self.port.send(b'-'),
+            }
+        }
+    }
+
+    /// Send the output packet until the debugger acknowledges it.
+    fn send(&mut self) {
+        let data = &self.output.bytes[..self.output.len];
+        let sum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        loop {
+            self.port.send(b'$');
+            self.port.write_bytes(data);
+            let _ = write!(self.port, "#{:02x}", sum);
+            if self.port.receive() != b'-' {
+                return;
+            }
+        }
+    }
+}
+
+/// Carry out one command, leaving any reply in `output`.
+fn command(
+    packet: &[u8],
+    frame: &mut TrapFrame,
+    output: &mut Packet,
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+) -> Next {
+    let Some((&kind, arguments)) = packet.split_first() else {
+        return Next::Reply;
+    };
+    let ok = match kind {
+        b'?' => {
+            let _ = write!(output, "S{:02x}", SIGTRAP);
+            return Next::Reply;
+        }
+        b'g' => {
+            for number in 0..REGISTERS {
+                if let Some((value, size)) = frame.register(number) {
+                    output.hex(&value.to_le_bytes()[..size]);
+                }
+            }
+            return Next::Reply;
+        }
+        b'G' => write_registers(arguments, frame),
+        b'p' => {
+            match parse_hex(arguments).and_then(|number| frame.register(number as usize)) {
+                Some((value, size)) => output.hex(&value.to_le_bytes()[..size]),
+                None => error(output),
+            }
+            return Next::Reply;
+        }
+        b'P' => split(arguments, b'=')
+            .and_then(|(number, value)| Some((parse_hex(number)?, decode_le(value)?)))
+            .is_some_and(|(number, value)| frame.set_register(number as usize, value)),
+        b'm' => {
+            match split(arguments, b',')
+                .and_then(|(address, len)| Some((parse_hex(address)?, parse_hex(len)?)))
+            {
+                Some((address, len)) => read_memory(address, len, breakpoints, output),
+                None => error(output),
+            }
+            return Next::Reply;
+        }
+        b'M' => split(arguments, b',')
+            .and_then(|(address, rest)| {
+                let (len, data) = split(rest, b':')?;
+                Some((parse_hex(address)?, parse_hex(len)?, data))
+            })
+            .is_some_and(|(address, len, data)| {
+                data.len() as u64 == len * 2 && write_memory(address, data, breakpoints)
+            }),
+        b'Z' | b'z' => {
+            // only software breakpoints, `type,address,kind`
+            let Some((b"0", rest)) = split(arguments, b',') else {
+                return Next::Reply;
+            };
+            match split(rest, b',').and_then(|(address, _)| parse_hex(address)) {
+                Some(address) if kind == b'Z' => insert_breakpoint(address, breakpoints),
+                Some(address) => {
+                    remove_breakpoint(address, breakpoints);
+                    true
+                }
+                None => false,
+            }
+        }
+        b'c' | b's' => {
+            if let Some(address) = parse_hex(arguments) {
+                frame.rip = address;
+            }
+            return if kind == b'c' {
+                Next::Continue
+            } else {
+                Next::Step
+            };
+        }
+        b'D' => {
+            let _ = output.write_str("OK");
+            return Next::Detach;
+        }
+        // nothing left to kill, let the kernel run on
+        b'k' => return Next::Detach,
+        b'H' => true,
+        b'q' => {
+            query(arguments, output);
+            return Next::Reply;
+        }
+        // anything else is not supported, an empty reply says so
+        _ => return Next::Reply,
+    };
+    if ok {
+        let _ = output.write_str("OK");
+    } else {
+        error(output);
+    }
+    Next::Reply
+}
+
+fn query(query: &[u8], output: &mut Packet) {
+    if query.starts_with(b"Supported") {
+        let _ = write!(output, "PacketSize={:x}", PACKET_SIZE);
+    } else if query == b"Attached" {
+        let _ = output.write_str("1");
+    } else if query == b"Offsets" {
+        // where the kernel runs against the addresses it was linked at
+        let slide = aslr::kernel_slide();
+        let _ = write!(output, "Text={:x};Data={:x};Bss={:x}", slide, slide, slide);
+    }
+}
+
+fn error(output: &mut Packet) {
+    output.clear();
+    let _ = output.write_str("E01");
+}
+
+fn write_registers(hex: &[u8], frame: &mut TrapFrame) -> bool {
+    let mut rest = hex;
+    for number in 0..REGISTERS {
+        let size = if number < WIDE_REGISTERS { 8 } else { 4 };
+        // GDB may send fewer registers than it knows about
+        let Some(value) = rest.get(..size * 2) else {
+            break;
+        };
+        let Some(value) = decode_le(value) else {
+            return false;
+        };
+        frame.set_register(number, value);
+        rest = &rest[size * 2..];
+    }
+    true
+}
+
+fn read_memory(
+    address: u64,
+    len: u64,
+    breakpoints: &[Option<Breakpoint>; MAX_BREAKPOINTS],
+    output: &mut Packet,
+) {
+    let len = len.min(PACKET_SIZE as u64 / 2);
+    for offset in 0..len {
+        let Some(byte_address) = address.checked_add(offset) else {
+            break;
+        };
+        if (offset == 0 || byte_address & 0xfff == 0) && !mapped(byte_address) {
+            // a short read is fine, but not an empty one
+            if offset == 0 {
+                error(output);
+            }
+            return;
+        }
+        // the debugger sees what its breakpoints replaced
+        let byte = breakpoints
+            .iter()
+            .flatten()
+            .find(|breakpoint| breakpoint.address == byte_address)
+            .map(|breakpoint| breakpoint.original)
+            .unwrap_or_else(|| unsafe { (byte_address as *const u8).read_volatile() });
+        output.hex(&[byte]);
+    }
+}
+
+fn write_memory(
+    address: u64,
+    hex: &[u8],
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+) -> bool {
+    let len = hex.len() as u64 / 2;
+    let Some(last) = address.checked_add(len.saturating_sub(1)) else {
+        return false;
+    };
+    let all_mapped = (address & !0xfff..=last)
+        .step_by(0x1000)
+        .all(|page| mapped(page.max(address)));
+    if !all_mapped {
+        return false;
+    }
+    for (offset, digits) in hex.chunks_exact(2).enumerate() {
+        let Some(byte) = decode_byte(digits) else {
+            return false;
+        };
+        let byte_address = address + offset as u64;
+        // a write under a breakpoint lands once it is taken out
+        let breakpoint = breakpoints
+            .iter_mut()
+            .flatten()
+            .find(|breakpoint| breakpoint.address == byte_address);
+        match breakpoint {
+            Some(breakpoint) => breakpoint.original = byte,
+            None => write_byte(byte_address, byte),
+        }
+    }
+    true
+}
+
+fn insert_breakpoint(
+    address: u64,
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+) -> bool {
+    if breakpoints
+        .iter()
+        .flatten()
+        .any(|breakpoint| breakpoint.address == address)
+    {
+        return true;
+    }
+    if !mapped(address) {
+        return false;
+    }
+    let Some(slot) = breakpoints.iter_mut().find(|slot| slot.is_none()) else {
+        return false;
+    };
+    let original = unsafe { (address as *const u8).read_volatile() };
+    write_byte(address, INT3);
+    *slot = Some(Breakpoint { address, original });
+    true
+}
+
+fn remove_breakpoint(address: u64, breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS]) {
+    for slot in breakpoints.iter_mut() {
+        if let Some(breakpoint) = slot.filter(|breakpoint| breakpoint.address == address) {
+            write_byte(breakpoint.address, breakpoint.original);
+            *slot = None;
+        }
+    }
+}
+
+/// Write one byte of mapped memory, kernel text included.
+fn write_byte(address: u64, byte: u8) {
+    // read-only pages stop the kernel too while CR0.WP is set
+    let cr0 = Cr0::read();
+    unsafe {
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        (address as *mut u8).write_volatile(byte);
+        Cr0::write(cr0);
+    }
+}
+
+/// Whether a page is mapped at `address`, walking the page tables through the physical map.
+fn mapped(address: u64) -> bool {
+    if VirtAddr::try_new(address).is_err() {
+        return false;
+    }
+    let mut table = Cr3::read().0.start_address().as_u64();
+    for level in (0..4).rev() {
+        let index = (address >> (12 + 9 * level)) & 0x1ff;
+        let entry = unsafe { (frames::to_virtual(table + index * 8) as *const u64).read() };
+        if entry & PAGE_PRESENT == 0 {
+            return false;
+        }
+        if level != 0 && entry & PAGE_HUGE != 0 {
+            return true;
+        }
+        table = entry & PAGE_ADDRESS;
+    }
+    true
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn decode_byte(digits: &[u8]) -> Option<u8> {
+    Some(hex_digit(digits[0])? << 4 | hex_digit(digits[1])?)
+}
+
+/// A big-endian hex number, the way addresses and lengths are written.
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |value, digit| {
+        Some(value << 4 | hex_digit(*digit)? as u64)
+    })
+}
+
+/// A register value, its bytes in target order.
+fn decode_le(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 || digits.len() & 1 != 0 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    for (byte, digits) in bytes.iter_mut().zip(digits.chunks_exact(2)) {
+        *byte = decode_byte(digits)?;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = bytes.iter().position(|byte| *byte == separator)?;
+    Some((&bytes[..at], &bytes[at + 1..]))
+}
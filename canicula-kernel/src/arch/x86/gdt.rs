@@ -0,0 +1,56 @@
+//! The kernel's own GDT with a TSS, which is where the CPU finds the stacks
+//! of exception handlers that cannot trust the stack they interrupted.
+
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// Interrupt stack table slot of the double fault handler.
+pub const DOUBLE_FAULT_IST: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 5 * 0x1000;
+
+// the CPU aligns the stack pointer it takes from the IST itself
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+lazy_static! {
+    static ref TSS: TaskStateSegment = {
+        let mut tss = TaskStateSegment::new();
+        // a kernel stack overflow double faults, its handler needs a stack that works
+        let stack = VirtAddr::from_ptr(&raw const DOUBLE_FAULT_STACK);
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST as usize] =
+            stack + DOUBLE_FAULT_STACK_SIZE as u64;
+        tss
+    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code = gdt.append(Descriptor::kernel_code_segment());
+        let data = gdt.append(Descriptor::kernel_data_segment());
+        let tss = gdt.append(Descriptor::tss_segment(&TSS));
+        (gdt, Selectors { code, data, tss })
+    };
+}
+
+/// Replace the firmware's GDT, before the IDT is loaded.
+///
+/// FS and GS are left alone, reloading them would clear the per-CPU base.
+pub fn init() {
+    let (gdt, selectors) = &*GDT;
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.code);
+        // `iretq` reloads SS, it has to be a selector of this table
+        SS::set_reg(selectors.data);
+        DS::set_reg(selectors.data);
+        ES::set_reg(selectors.data);
+        load_tss(selectors.tss);
+    }
+}
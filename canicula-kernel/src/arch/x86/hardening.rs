@@ -0,0 +1,198 @@
+//! Protections the kernel turns on at boot: SMEP, SMAP, UMIP and W^X.
+//!
+//! SMEP and SMAP keep the kernel from running or touching user pages by
+//! accident, [`copy_from_user`] and [`copy_to_user`] open SMAP up for the one
+//! copy meant to. UMIP keeps user code from reading the descriptor tables. No
+//! kernel page may be writable and executable at once: text loses write
+//! access and anything else loses execute. The identity map inherited from the
+//! firmware is only reported, its runtime services run from it.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use canicula_common::layout::KERNEL_SPACE_START;
+use log::{info, warn};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::paging::PageTableFlags;
+
+use super::{cpu, page_audit, vm};
+
+/// Writable and executable runs of pages fixed in one pass, more are left for the next boot.
+const MAX_VIOLATIONS: usize = 32;
+
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+}
+
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    /// The range is not all below the kernel half.
+    BadAddress,
+}
+
+/// What [`init`] found of writable and executable pages.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Report {
+    /// Kernel regions made read-only or non-executable.
+    pub fixed: usize,
+    /// Kernel regions that could not be changed.
+    pub failed: usize,
+    /// Regions of the firmware's identity map, left as they are.
+    pub identity: usize,
+}
+
+/// Enable what the processor has of SMEP, SMAP and UMIP, then enforce W^X, after [`vm::init`].
+pub fn init() -> Report {
+    let features = cpu::features();
+    let mut flags = Cr4Flags::empty();
+    if features.smep {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features.smap {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    if features.umip {
+        flags |= Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION;
+    }
+    unsafe { Cr4::update(|cr4| cr4.insert(flags)) };
+    SMAP.store(features.smap, Ordering::Relaxed);
+    info!(
+        "[hardening] smep {} smap {} umip {}",
+        features.smep, features.smap, features.umip
+    );
+    let report = enforce_wx();
+    if report.fixed + report.failed + report.identity == 0 {
+        info!("[hardening] no writable and executable pages");
+    } else {
+        warn!("[hardening] {:?}", report);
+    }
+    report
+}
+
+/// Kernel text, page aligned.
+fn text() -> (u64, u64) {
+    let start = core::ptr::addr_of!(__text_start) as u64 & !(vm::PAGE_SIZE - 1);
+    let end = (core::ptr::addr_of!(__text_end) as u64).next_multiple_of(vm::PAGE_SIZE);
+    (start, end)
+}
+
+/// Take write access from kernel text and execute access from everything else.
+fn enforce_wx() -> Report {
+    let mut report = Report::default();
+    let mut violations = [None; MAX_VIOLATIONS];
+    let mut count = 0;
+    // the walk reads the live tables, so they are changed only after it
+    page_audit::walk(|region| {
+        if !region.writable() || !region.executable() {
+            return;
+        }
+        if region.start < KERNEL_SPACE_START {
+            report.identity += 1;
+            warn!(
+                "[hardening] identity mapping {:#x}..{:#x} is writable and executable",
+                region.start, region.end
+            );
+            return;
+        }
+        match violations.get_mut(count) {
+            Some(slot) => {
+                *slot = Some((region.start, region.end));
+                count += 1;
+            }
+            None => report.failed += 1,
+        }
+    });
+    let (text_start, text_end) = text();
+    for (start, end) in violations.into_iter().flatten() {
+        // the part in text loses write access, before and after it execute access
+        let middle = (start.max(text_start).min(end), end.min(text_end).max(start));
+        let parts = [
+            (start, middle.0, false),
+            (middle.0, middle.1, true),
+            (middle.1, end, false),
+        ];
+        for (start, end, text) in parts.into_iter().filter(|(start, end, _)| start < end) {
+            fix(start, end, text, &mut report);
+        }
+    }
+    report
+}
+
+fn fix(start: u64, end: u64, text: bool, report: &mut Report) {
+    let result = vm::update_flags(start, end - start, |flags| {
+        if text {
+            flags - PageTableFlags::WRITABLE
+        } else {
+            flags | PageTableFlags::NO_EXECUTE
+        }
+    });
+    match result {
+        Ok(()) => {
+            report.fixed += 1;
+            warn!(
+                "[hardening] {:#x}..{:#x} was writable and executable, now {}",
+                start,
+                end,
+                if text { "read-only" } else { "non-executable" }
+            );
+        }
+        Err(error) => {
+            report.failed += 1;
+            warn!(
+                "[hardening] cannot fix {:#x}..{:#x}: {:?}",
+                start, end, error
+            );
+        }
+    }
+}
+
+/// Run `f` with SMAP lifted, for code that reads or writes user pages on purpose.
+pub fn user_access<T>(f: impl FnOnce() -> T) -> T {
+    let smap = SMAP.load(Ordering::Relaxed);
+    if smap {
+        unsafe { asm!("stac", options(nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+    result
+}
+
+fn check_user(address: u64, len: usize) -> Result<(), UserCopyError> {
+    match address.checked_add(len as u64) {
+        Some(end) if end <= KERNEL_SPACE_START => Ok(()),
+        _ => Err(UserCopyError::BadAddress),
+    }
+}
+
+/// Copy `destination.len()` bytes from user memory at `source`.
+///
+/// The pages have to be mapped, a fault during the copy is not recovered from.
+// for system calls, there is no user mode to make them yet
+#[allow(dead_code)]
+pub fn copy_from_user(destination: &mut [u8], source: u64) -> Result<(), UserCopyError> {
+    check_user(source, destination.len())?;
+    user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(
+            source as *const u8,
+            destination.as_mut_ptr(),
+            destination.len(),
+        )
+    });
+    Ok(())
+}
+
+/// Copy `source` to user memory at `destination`, under the same terms as [`copy_from_user`].
+// the other half of `copy_from_user`, no system calls yet
+#[allow(dead_code)]
+pub fn copy_to_user(destination: u64, source: &[u8]) -> Result<(), UserCopyError> {
+    check_user(destination, source.len())?;
+    user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(source.as_ptr(), destination as *mut u8, source.len())
+    });
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! The HPET main counter, a fixed frequency clock to calibrate the TSC against.
+//!
+//! The block comes from the ACPI HPET table. Its comparators are left alone,
+//! nothing takes timer interrupts from it.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use log::{info, warn};
+
+use super::acpi;
+use super::vm;
+
+/// Address of the base address structure in the HPET table.
+const TABLE_ADDRESS: usize = acpi::HEADER_SIZE + 8;
+
+// registers
+const CAPABILITIES: u64 = 0x00;
+const CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+
+const CAPABILITY_64BIT: u64 = 1 << 13;
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+/// Longest tick the specification allows, 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const REGISTERS_SIZE: u64 = 0x400;
+
+/// Virtual address of the registers, zero without an HPET.
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Length of a counter tick in femtoseconds.
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+/// Whether the main counter is 64 bits wide rather than 32.
+static WIDE: AtomicBool = AtomicBool::new(false);
+
+fn read(register: u64) -> u64 {
+    let base = BASE.load(Ordering::Acquire);
+    unsafe { ((base + register) as *const u64).read_volatile() }
+}
+
+fn write(register: u64, value: u64) {
+    let base = BASE.load(Ordering::Acquire);
+    unsafe { ((base + register) as *mut u64).write_volatile(value) }
+}
+
+/// Find and start the main counter, after [`acpi::init`] and [`vm::init`].
+pub fn init() {
+    let Some(table) = acpi::find(b"HPET") else {
+        info!("[hpet] no HPET table");
+        return;
+    };
+    let physical = acpi::read_u64(table, TABLE_ADDRESS + 4);
+    let base = match vm::map_mmio(physical, REGISTERS_SIZE) {
+        Ok(base) => base,
+        Err(error) => {
+            warn!("[hpet] cannot map {:#x}: {:?}", physical, error);
+            return;
+        }
+    };
+    BASE.store(base, Ordering::Release);
+
+    let capabilities = read(CAPABILITIES);
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        warn!("[hpet] bad tick of {} fs", period);
+        BASE.store(0, Ordering::Release);
+        return;
+    }
+    PERIOD.store(period, Ordering::Relaxed);
+    WIDE.store(capabilities & CAPABILITY_64BIT != 0, Ordering::Relaxed);
+    write(CONFIGURATION, read(CONFIGURATION) | CONFIGURATION_ENABLE);
+    info!(
+        "[hpet] at {:#x}, {} kHz",
+        physical,
+        1_000_000_000_000 / period
+    );
+}
+
+/// The main counter, `None` without an HPET.
+pub fn counter() -> Option<u64> {
+    (BASE.load(Ordering::Acquire) != 0).then(|| read(MAIN_COUNTER))
+}
+
+/// Length of a tick in femtoseconds.
+pub fn period_fs() -> u64 {
+    PERIOD.load(Ordering::Relaxed)
+}
+
+/// Ticks from `start` to `end`, across a wrap of a 32-bit counter too.
+pub fn elapsed(start: u64, end: u64) -> u64 {
+    if WIDE.load(Ordering::Relaxed) {
+        end.wrapping_sub(start)
+    } else {
+        (end as u32).wrapping_sub(start as u32) as u64
+    }
+}
@@ -0,0 +1,658 @@
+//! A small IPv4 stack on the first network interface: ARP, IPv4, ICMP echo and
+//! UDP sockets, with TCP in [`super::tcp`].
+//!
+//! There is no heap and no scheduler, so sockets are fixed tables and nothing
+//! runs in the background. Whoever waits on the network calls [`poll`], which
+//! takes received frames off the interface and lets TCP retransmit.
+//! The address comes from the `ip_address`, `ip_netmask` and `ip_gateway` build
+//! options and defaults to what QEMU's user networking hands out.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+
+use canicula_common::fs::OperateError;
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use super::net::{self, MacAddress, NetworkDevice, MAX_FRAME_SIZE};
+use super::{random, tcp};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+    pub const BROADCAST: Ipv4Address = Ipv4Address([255; 4]);
+
+    /// Dotted decimal, e.g. `10.0.2.15`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut address = [0u8; 4];
+        let mut parts = text.split('.');
+        for byte in &mut address {
+            *byte = parts.next()?.parse().ok()?;
+        }
+        parts.next().is_none().then_some(Ipv4Address(address))
+    }
+
+    fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// There is no network interface.
+    NoInterface,
+    /// The next hop's MAC address is not known yet, an ARP request went out.
+    Unresolved,
+    /// Nothing to read yet, or no room to queue more.
+    WouldBlock,
+    AddressInUse,
+    TableFull,
+    TooLarge,
+    /// Not an open socket, or not in a state that allows this.
+    InvalidSocket,
+    /// The connection was reset or is closed.
+    Closed,
+    Device(OperateError),
+}
+
+impl From<OperateError> for NetError {
+    fn from(error: OperateError) -> Self {
+        NetError::Device(error)
+    }
+}
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERNET_HEADER: usize = 14;
+const BROADCAST_MAC: MacAddress = [0xff; 6];
+
+const ARP_PACKET: usize = 28;
+const ARP_HARDWARE_ETHERNET: u16 = 1;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const ARP_CACHE_SIZE: usize = 16;
+
+const IPV4_HEADER: usize = 20;
+/// Don't fragment, nothing here reassembles fragments either.
+const IPV4_DONT_FRAGMENT: u16 = 0x4000;
+/// More fragments and the fragment offset.
+const IPV4_FRAGMENT: u16 = 0x3fff;
+const TTL: u8 = 64;
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+/// Largest IPv4 payload that fits one frame.
+pub const MAX_IP_PAYLOAD: usize = MAX_FRAME_SIZE - ETHERNET_HEADER - IPV4_HEADER;
+
+const ICMP_HEADER: usize = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+/// Bytes of pattern after the echo header.
+const ECHO_DATA: usize = 32;
+
+const UDP_HEADER: usize = 8;
+pub const MAX_UDP_PAYLOAD: usize = MAX_IP_PAYLOAD - UDP_HEADER;
+const MAX_UDP_SOCKETS: usize = 8;
+/// Datagrams queued per socket, later ones are dropped until it is read.
+const UDP_QUEUE: usize = 4;
+
+/// Ports handed out to sockets that do not pick one.
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+/// Frames taken off the interface per [`poll`].
+const POLL_BUDGET: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Interface {
+    device: &'static dyn NetworkDevice,
+    mac: MacAddress,
+    address: Ipv4Address,
+    netmask: Ipv4Address,
+    gateway: Ipv4Address,
+}
+
+impl Interface {
+    fn on_link(&self, address: Ipv4Address) -> bool {
+        let mask = self.netmask.to_u32();
+        address.to_u32() & mask == self.address.to_u32() & mask
+    }
+
+    /// Addressed to this host, directly or by broadcast.
+    fn accepts(&self, address: Ipv4Address) -> bool {
+        let subnet_broadcast = self.address.to_u32() | !self.netmask.to_u32();
+        address == self.address
+            || address == Ipv4Address::BROADCAST
+            || address.to_u32() == subnet_broadcast
+    }
+}
+
+struct ArpCache {
+    entries: [Option<(Ipv4Address, MacAddress)>; ARP_CACHE_SIZE],
+    /// The slot replaced next, entries are evicted round robin.
+    next: usize,
+}
+
+impl ArpCache {
+    fn get(&self, address: Ipv4Address) -> Option<MacAddress> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|(known, _)| *known == address)
+            .map(|(_, mac)| *mac)
+    }
+
+    fn insert(&mut self, address: Ipv4Address, mac: MacAddress) {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|(known, _)| *known == address)
+        {
+            entry.1 = mac;
+            return;
+        }
+        self.entries[self.next] = Some((address, mac));
+        self.next = (self.next + 1) % ARP_CACHE_SIZE;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Datagram {
+    source: Ipv4Address,
+    port: u16,
+    len: usize,
+    data: [u8; MAX_UDP_PAYLOAD],
+}
+
+struct UdpEndpoint {
+    port: u16,
+    queue: [Datagram; UDP_QUEUE],
+    /// The oldest queued datagram.
+    head: usize,
+    queued: usize,
+}
+
+/// A bound UDP socket, see [`udp_bind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpSocket(pub usize);
+
+static INTERFACE: Once<Interface> = Once::new();
+static ARP: Mutex<ArpCache> = Mutex::new(ArpCache {
+    entries: [None; ARP_CACHE_SIZE],
+    next: 0,
+});
+static UDP: Mutex<[Option<UdpEndpoint>; MAX_UDP_SOCKETS]> =
+    Mutex::new([const { None }; MAX_UDP_SOCKETS]);
+static NEXT_PORT: AtomicU16 = AtomicU16::new(0);
+static NEXT_IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+/// Identifier and sequence number of the last echo reply, see [`echo_replied`].
+static LAST_ECHO_REPLY: AtomicU32 = AtomicU32::new(u32::MAX);
+
+fn read16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn write16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+fn mac_at(bytes: &[u8], offset: usize) -> MacAddress {
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&bytes[offset..offset + 6]);
+    mac
+}
+
+fn address_at(bytes: &[u8], offset: usize) -> Ipv4Address {
+    let mut address = [0; 4];
+    address.copy_from_slice(&bytes[offset..offset + 4]);
+    Ipv4Address(address)
+}
+
+/// Add `data` to a one's complement sum as big endian 16-bit words.
+fn sum(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The Internet checksum of `data`, `0` over data that carries a correct one.
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum(0, data))
+}
+
+/// The UDP and TCP checksum, over the IPv4 pseudo header and `segment`.
+pub fn transport_checksum(
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: u8,
+    segment: &[u8],
+) -> u16 {
+    let pseudo = sum(sum(0, &source.0), &destination.0) + protocol as u32 + segment.len() as u32;
+    fold(sum(pseudo, segment))
+}
+
+/// Bring the stack up on the first network interface, after its driver.
+pub fn init() {
+    let Some(device) = net::get(0) else {
+        return;
+    };
+    let option = |value: Option<&str>, default: [u8; 4]| {
+        value
+            .and_then(Ipv4Address::parse)
+            .unwrap_or(Ipv4Address(default))
+    };
+    let interface = INTERFACE.call_once(|| Interface {
+        device,
+        mac: device.mac_address(),
+        address: option(option_env!("ip_address"), [10, 0, 2, 15]),
+        netmask: option(option_env!("ip_netmask"), [255, 255, 255, 0]),
+        gateway: option(option_env!("ip_gateway"), [10, 0, 2, 2]),
+    });
+    NEXT_PORT.store(random::next_u64() as u16, Ordering::Relaxed);
+    info!(
+        "[inet] {} is {}/{} via {}",
+        device.name(),
+        interface.address,
+        interface.netmask.to_u32().count_ones(),
+        interface.gateway
+    );
+}
+
+/// This host's address, if the stack is up.
+pub fn address() -> Option<Ipv4Address> {
+    INTERFACE.get().map(|interface| interface.address)
+}
+
+/// A port from the ephemeral range, not checked against bound ones.
+pub fn ephemeral_port() -> u16 {
+    let count = EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start() + 1;
+    EPHEMERAL_PORTS.start() + NEXT_PORT.fetch_add(1, Ordering::Relaxed) % count
+}
+
+/// Handle the frames that arrived since the last call, then let TCP retransmit.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn poll() {
+    let Some(interface) = INTERFACE.get() else {
+        return;
+    };
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+    for _ in 0..POLL_BUDGET {
+        match interface.device.receive_frame(&mut frame) {
+            Ok(Some(len)) => receive(interface, &frame[..len]),
+            Ok(None) => break,
+            Err(error) => {
+                warn!("[inet] {}: {:?}", interface.device.name(), error);
+                break;
+            }
+        }
+    }
+    tcp::poll();
+}
+
+/// Fill in the Ethernet header in front of the `len` bytes at `frame[ETHERNET_HEADER..]`
+/// and send it.
+fn transmit(
+    interface: &Interface,
+    destination: MacAddress,
+    ethertype: u16,
+    frame: &mut [u8; MAX_FRAME_SIZE],
+    len: usize,
+) -> Result<(), NetError> {
+    frame[..6].copy_from_slice(&destination);
+    frame[6..12].copy_from_slice(&interface.mac);
+    write16(frame, 12, ethertype);
+    interface
+        .device
+        .send_frame(&frame[..ETHERNET_HEADER + len])?;
+    Ok(())
+}
+
+fn send_arp(
+    interface: &Interface,
+    operation: u16,
+    target_mac: MacAddress,
+    target: Ipv4Address,
+) -> Result<(), NetError> {
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+    let packet = &mut frame[ETHERNET_HEADER..ETHERNET_HEADER + ARP_PACKET];
+    write16(packet, 0, ARP_HARDWARE_ETHERNET);
+    write16(packet, 2, ETHERTYPE_IPV4);
+    packet[4] = 6;
+    packet[5] = 4;
+    write16(packet, 6, operation);
+    packet[8..14].copy_from_slice(&interface.mac);
+    packet[14..18].copy_from_slice(&interface.address.0);
+    packet[18..24].copy_from_slice(&target_mac);
+    packet[24..28].copy_from_slice(&target.0);
+    let destination = if operation == ARP_REQUEST {
+        BROADCAST_MAC
+    } else {
+        target_mac
+    };
+    transmit(
+        interface,
+        destination,
+        ETHERTYPE_ARP,
+        &mut frame,
+        ARP_PACKET,
+    )
+}
+
+fn receive(interface: &Interface, frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER {
+        return;
+    }
+    let destination = mac_at(frame, 0);
+    if destination != interface.mac && destination != BROADCAST_MAC {
+        return;
+    }
+    let payload = &frame[ETHERNET_HEADER..];
+    match read16(frame, 12) {
+        ETHERTYPE_ARP => receive_arp(interface, payload),
+        ETHERTYPE_IPV4 => receive_ipv4(interface, mac_at(frame, 6), payload),
+        _ => {}
+    }
+}
+
+fn receive_arp(interface: &Interface, packet: &[u8]) {
+    if packet.len() < ARP_PACKET
+        || read16(packet, 0) != ARP_HARDWARE_ETHERNET
+        || read16(packet, 2) != ETHERTYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let sender_mac = mac_at(packet, 8);
+    let sender = address_at(packet, 14);
+    let target = address_at(packet, 24);
+    if target != interface.address {
+        return;
+    }
+    ARP.lock().insert(sender, sender_mac);
+    if read16(packet, 6) == ARP_REQUEST {
+        if let Err(error) = send_arp(interface, ARP_REPLY, sender_mac, sender) {
+            warn!("[inet] cannot answer ARP: {:?}", error);
+        }
+    }
+}
+
+fn receive_ipv4(interface: &Interface, source_mac: MacAddress, packet: &[u8]) {
+    if packet.len() < IPV4_HEADER || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header = (packet[0] & 0xf) as usize * 4;
+    let total = read16(packet, 2) as usize;
+    if header < IPV4_HEADER || total < header || total > packet.len() {
+        return;
+    }
+    // frames can be padded beyond the packet
+    let packet = &packet[..total];
+    if checksum(&packet[..header]) != 0 || read16(packet, 6) & IPV4_FRAGMENT != 0 {
+        return;
+    }
+    let source = address_at(packet, 12);
+    let destination = address_at(packet, 16);
+    if !interface.accepts(destination) {
+        return;
+    }
+    if interface.on_link(source) {
+        ARP.lock().insert(source, source_mac);
+    }
+
+    let payload = &packet[header..];
+    match packet[9] {
+        PROTOCOL_ICMP if destination == interface.address => receive_icmp(source, payload),
+        PROTOCOL_UDP => receive_udp(source, destination, payload),
+        PROTOCOL_TCP if destination == interface.address => {
+            tcp::receive(source, destination, payload)
+        }
+        _ => {}
+    }
+}
+
+/// Send `payload` to `destination` in one IPv4 packet.
+///
+/// Fails with [`NetError::Unresolved`] while the next hop's MAC address is
+/// being asked for, the caller polls and tries again.
+pub fn send_ip(destination: Ipv4Address, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    let interface = INTERFACE.get().ok_or(NetError::NoInterface)?;
+    if payload.len() > MAX_IP_PAYLOAD {
+        return Err(NetError::TooLarge);
+    }
+    let mac = if interface.accepts(destination) && destination != interface.address {
+        BROADCAST_MAC
+    } else {
+        let next_hop = if interface.on_link(destination) {
+            destination
+        } else {
+            interface.gateway
+        };
+        let known = ARP.lock().get(next_hop);
+        match known {
+            Some(mac) => mac,
+            None => {
+                send_arp(interface, ARP_REQUEST, [0; 6], next_hop)?;
+                return Err(NetError::Unresolved);
+            }
+        }
+    };
+
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+    let len = IPV4_HEADER + payload.len();
+    let packet = &mut frame[ETHERNET_HEADER..ETHERNET_HEADER + len];
+    packet[0] = 0x45;
+    write16(packet, 2, len as u16);
+    write16(
+        packet,
+        4,
+        NEXT_IDENTIFICATION.fetch_add(1, Ordering::Relaxed),
+    );
+    write16(packet, 6, IPV4_DONT_FRAGMENT);
+    packet[8] = TTL;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&interface.address.0);
+    packet[16..20].copy_from_slice(&destination.0);
+    let header_checksum = checksum(&packet[..IPV4_HEADER]);
+    write16(packet, 10, header_checksum);
+    packet[IPV4_HEADER..].copy_from_slice(payload);
+    transmit(interface, mac, ETHERTYPE_IPV4, &mut frame, len)
+}
+
+fn receive_icmp(source: Ipv4Address, message: &[u8]) {
+    if message.len() < ICMP_HEADER || checksum(message) != 0 || message[1] != 0 {
+        return;
+    }
+    match message[0] {
+        ICMP_ECHO_REQUEST => {
+            let mut reply = [0u8; MAX_IP_PAYLOAD];
+            let reply = &mut reply[..message.len()];
+            reply.copy_from_slice(message);
+            reply[0] = ICMP_ECHO_REPLY;
+            write16(reply, 2, 0);
+            let reply_checksum = checksum(reply);
+            write16(reply, 2, reply_checksum);
+            // a lost reply is like a lost request, the peer asks again
+            let _ = send_ip(source, PROTOCOL_ICMP, reply);
+        }
+        ICMP_ECHO_REPLY => {
+            let echo = (read16(message, 4) as u32) << 16 | read16(message, 6) as u32;
+            LAST_ECHO_REPLY.store(echo, Ordering::Relaxed);
+        }
+        _ => {}
+    }
+}
+
+/// Send an echo request, see [`echo_replied`] for the reply.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn send_echo(destination: Ipv4Address, identifier: u16, sequence: u16) -> Result<(), NetError> {
+    let mut message = [0u8; ICMP_HEADER + ECHO_DATA];
+    message[0] = ICMP_ECHO_REQUEST;
+    write16(&mut message, 4, identifier);
+    write16(&mut message, 6, sequence);
+    for (index, byte) in message[ICMP_HEADER..].iter_mut().enumerate() {
+        *byte = b'a' + (index % 26) as u8;
+    }
+    let message_checksum = checksum(&message);
+    write16(&mut message, 2, message_checksum);
+    send_ip(destination, PROTOCOL_ICMP, &message)
+}
+
+/// Whether the last echo reply that came in answered this request.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn echo_replied(identifier: u16, sequence: u16) -> bool {
+    LAST_ECHO_REPLY.load(Ordering::Relaxed) == (identifier as u32) << 16 | sequence as u32
+}
+
+fn receive_udp(source: Ipv4Address, destination: Ipv4Address, datagram: &[u8]) {
+    if datagram.len() < UDP_HEADER {
+        return;
+    }
+    let len = read16(datagram, 4) as usize;
+    if len < UDP_HEADER || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    // a zero checksum means the sender did not compute one
+    if read16(datagram, 6) != 0
+        && transport_checksum(source, destination, PROTOCOL_UDP, datagram) != 0
+    {
+        return;
+    }
+
+    let port = read16(datagram, 2);
+    let mut sockets = UDP.lock();
+    let Some(endpoint) = sockets.iter_mut().flatten().find(|udp| udp.port == port) else {
+        return;
+    };
+    if endpoint.queued == UDP_QUEUE {
+        return;
+    }
+    let data = &datagram[UDP_HEADER..];
+    let slot = &mut endpoint.queue[(endpoint.head + endpoint.queued) % UDP_QUEUE];
+    slot.source = source;
+    slot.port = read16(datagram, 0);
+    slot.len = data.len();
+    slot.data[..data.len()].copy_from_slice(data);
+    endpoint.queued += 1;
+}
+
+/// Bind a UDP socket to `port`, or to an ephemeral port for `0`.
+// the socket calls wait for programs to make them, only ping uses the stack
+#[allow(dead_code)]
+pub fn udp_bind(port: u16) -> Result<UdpSocket, NetError> {
+    let mut sockets = UDP.lock();
+    let port = if port == 0 { ephemeral_port() } else { port };
+    if sockets.iter().flatten().any(|udp| udp.port == port) {
+        return Err(NetError::AddressInUse);
+    }
+    let index = sockets
+        .iter()
+        .position(Option::is_none)
+        .ok_or(NetError::TableFull)?;
+    sockets[index] = Some(UdpEndpoint {
+        port,
+        queue: [Datagram {
+            source: Ipv4Address::UNSPECIFIED,
+            port: 0,
+            len: 0,
+            data: [0; MAX_UDP_PAYLOAD],
+        }; UDP_QUEUE],
+        head: 0,
+        queued: 0,
+    });
+    Ok(UdpSocket(index))
+}
+
+// no socket to close
+#[allow(dead_code)]
+pub fn udp_close(socket: UdpSocket) -> Result<(), NetError> {
+    UDP.lock()
+        .get_mut(socket.0)
+        .and_then(Option::take)
+        .map(|_| ())
+        .ok_or(NetError::InvalidSocket)
+}
+
+/// The port `socket` is bound to.
+pub fn udp_port(socket: UdpSocket) -> Result<u16, NetError> {
+    UDP.lock()
+        .get(socket.0)
+        .and_then(Option::as_ref)
+        .map(|udp| udp.port)
+        .ok_or(NetError::InvalidSocket)
+}
+
+// nothing sends datagrams yet
+#[allow(dead_code)]
+pub fn udp_send_to(
+    socket: UdpSocket,
+    destination: Ipv4Address,
+    port: u16,
+    data: &[u8],
+) -> Result<(), NetError> {
+    if data.len() > MAX_UDP_PAYLOAD {
+        return Err(NetError::TooLarge);
+    }
+    let source = address().ok_or(NetError::NoInterface)?;
+    let mut datagram = [0u8; MAX_IP_PAYLOAD];
+    let len = UDP_HEADER + data.len();
+    let datagram = &mut datagram[..len];
+    write16(datagram, 0, udp_port(socket)?);
+    write16(datagram, 2, port);
+    write16(datagram, 4, len as u16);
+    datagram[UDP_HEADER..].copy_from_slice(data);
+    // all ones stands for zero, zero means no checksum
+    let datagram_checksum = match transport_checksum(source, destination, PROTOCOL_UDP, datagram) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    write16(datagram, 6, datagram_checksum);
+    send_ip(destination, PROTOCOL_UDP, datagram)
+}
+
+/// Take the oldest queued datagram: its length, sender and sender's port.
+///
+/// A datagram longer than `buffer` is cut short.
+// nothing reads datagrams yet
+#[allow(dead_code)]
+pub fn udp_receive_from(
+    socket: UdpSocket,
+    buffer: &mut [u8],
+) -> Result<(usize, Ipv4Address, u16), NetError> {
+    let mut sockets = UDP.lock();
+    let endpoint = sockets
+        .get_mut(socket.0)
+        .and_then(Option::as_mut)
+        .ok_or(NetError::InvalidSocket)?;
+    if endpoint.queued == 0 {
+        return Err(NetError::WouldBlock);
+    }
+    let datagram = &endpoint.queue[endpoint.head];
+    let len = datagram.len.min(buffer.len());
+    buffer[..len].copy_from_slice(&datagram.data[..len]);
+    let from = (len, datagram.source, datagram.port);
+    endpoint.head = (endpoint.head + 1) % UDP_QUEUE;
+    endpoint.queued -= 1;
+    Ok(from)
+}
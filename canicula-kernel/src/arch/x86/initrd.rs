@@ -0,0 +1,49 @@
+//! The initrd as the root file system, when the loader handed over an ext4 image.
+
+use canicula_common::entry::BootInfo;
+use canicula_common::fs::OperateError;
+use log::{info, warn};
+use spin::Once;
+
+use super::ext4fs::Ext4;
+use super::vfs;
+
+/// The image [`read_bytes`] reads from, the ext4 reader only takes a plain fn.
+static IMAGE: Once<&'static [u8]> = Once::new();
+static ROOT: Once<Ext4> = Once::new();
+
+fn read_bytes(offset: usize, buffer: &mut [u8]) -> Result<usize, OperateError> {
+    let image = IMAGE.get().ok_or(OperateError::NotFoundDev)?;
+    let end = offset
+        .checked_add(buffer.len())
+        .filter(|end| *end <= image.len())
+        .ok_or(OperateError::Fault)?;
+    buffer.copy_from_slice(&image[offset..end]);
+    Ok(buffer.len())
+}
+
+/// Mount the initrd at `/`, after [`super::frames::init`].
+pub fn init(boot_info: &'static BootInfo) {
+    if boot_info.initrd.is_empty() {
+        return;
+    }
+    // the loader's pages stay identity mapped
+    IMAGE.call_once(|| unsafe {
+        core::slice::from_raw_parts(
+            boot_info.initrd.address as *const u8,
+            boot_info.initrd.size as usize,
+        )
+    });
+
+    let root = match Ext4::mount(read_bytes) {
+        Ok(root) => ROOT.call_once(|| root),
+        Err(error) => {
+            info!("[initrd] not mounted, no ext4 image: {:?}", error);
+            return;
+        }
+    };
+    match vfs::mount("/", root) {
+        Ok(()) => info!("[initrd] ext4 image mounted at /"),
+        Err(error) => warn!("[initrd] cannot mount at /: {:?}", error),
+    }
+}
@@ -0,0 +1,349 @@
+use core::sync::atomic::Ordering;
+
+use lazy_static::lazy_static;
+use log::error;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+use super::driver::{self, Fault};
+use super::sync::IrqMutex;
+use super::vm::{self, PageFault, RegionKind};
+use super::{gdbstub, gdt, keyboard, lapic, percpu, pic};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    DivideError,
+    InvalidOpcode,
+    SegmentNotPresent,
+    StackSegmentFault,
+    GeneralProtection,
+    PageFault,
+    AlignmentCheck,
+    /// Not a CPU exception, a Rust panic raised by driver code.
+    Panic,
+}
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error);
+        // these two save every register, for the debugger
+        unsafe {
+            idt.debug.set_handler_addr(gdbstub::debug_entry());
+            idt.breakpoint.set_handler_addr(gdbstub::breakpoint_entry());
+        }
+        idt.invalid_opcode.set_handler_fn(invalid_opcode);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST);
+        }
+        idt.segment_not_present.set_handler_fn(segment_not_present);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault);
+        idt.page_fault.set_handler_fn(page_fault);
+        idt.alignment_check.set_handler_fn(alignment_check);
+        idt[pic::IRQ_BASE + keyboard::IRQ].set_handler_fn(keyboard_interrupt);
+        idt[pic::IRQ_BASE + SPURIOUS_IRQ].set_handler_fn(spurious_interrupt);
+        for (index, stub) in DYNAMIC_STUBS.into_iter().enumerate() {
+            idt[DYNAMIC_VECTOR_BASE + index as u8].set_handler_fn(stub);
+        }
+        idt[lapic::SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt);
+        idt
+    };
+}
+
+/// First vector handed out by [`allocate_vector`], right after the PIC's.
+pub const DYNAMIC_VECTOR_BASE: u8 = pic::IRQ_BASE + 16;
+const DYNAMIC_VECTORS: usize = 32;
+
+/// What each dynamic vector runs, `None` while it is free.
+type VectorHandlers = [Option<fn()>; DYNAMIC_VECTORS];
+
+static VECTOR_HANDLERS: IrqMutex<VectorHandlers> = IrqMutex::new([None; DYNAMIC_VECTORS]);
+
+macro_rules! dynamic_stubs {
+    ($($index:literal)*) => {
+        [$({
+            extern "x86-interrupt" fn stub(_frame: InterruptStackFrame) {
+                dynamic_interrupt($index);
+            }
+            stub as extern "x86-interrupt" fn(InterruptStackFrame)
+        },)*]
+    };
+}
+
+/// One entry point per dynamic vector, each knowing its own index.
+const DYNAMIC_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); DYNAMIC_VECTORS] = dynamic_stubs!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+    16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+);
+
+/// The PIC raises IRQ 7 for a request that went away before it was acknowledged.
+const SPURIOUS_IRQ: u8 = 7;
+
+pub fn init() {
+    gdt::init();
+    pic::init();
+    IDT.load();
+}
+
+/// Start taking device interrupts, after the drivers unmasked their lines.
+pub fn enable() {
+    x86_64::instructions::interrupts::enable();
+}
+
+/// Take a free vector for `handler`, e.g. to point a device's MSI at.
+///
+/// The local APIC delivers these, the handler is acknowledged there.
+pub fn allocate_vector(handler: fn()) -> Option<u8> {
+    let mut handlers = VECTOR_HANDLERS.lock();
+    let index = handlers.iter().position(Option::is_none)?;
+    handlers[index] = Some(handler);
+    Some(DYNAMIC_VECTOR_BASE + index as u8)
+}
+
+/// Give back a vector from [`allocate_vector`], once nothing raises it any more.
+pub fn free_vector(vector: u8) {
+    let index = vector.wrapping_sub(DYNAMIC_VECTOR_BASE) as usize;
+    if let Some(handler) = VECTOR_HANDLERS.lock().get_mut(index) {
+        *handler = None;
+    }
+}
+
+/// Marks the running handler for [`in_interrupt`], held for the whole handler.
+pub(super) struct Context;
+
+impl Context {
+    pub(super) fn enter() -> Self {
+        let cpu = percpu::current();
+        cpu.interrupt_depth.fetch_add(1, Ordering::SeqCst);
+        cpu.interrupts.fetch_add(1, Ordering::Relaxed);
+        Context
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        percpu::current()
+            .interrupt_depth
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Whether an exception or interrupt handler is running.
+///
+/// Code that may have been interrupted could be holding any lock, so interrupt
+/// context must not block on one.
+pub fn in_interrupt() -> bool {
+    percpu::current().interrupt_depth.load(Ordering::SeqCst) != 0
+}
+
+/// For the allocators: allocating in interrupt context could spin forever on
+/// the allocator lock held by the interrupted code.
+pub fn assert_allocation_allowed() {
+    debug_assert!(
+        !in_interrupt(),
+        "[interrupts] heap allocation in interrupt context"
+    );
+}
+
+/// Hand the fault to the driver that raised it, the fault comes back if no driver did.
+fn contain(
+    frame: &mut InterruptStackFrame,
+    exception: Exception,
+    error_code: Option<u64>,
+    address: Option<u64>,
+) -> Result<(), Fault> {
+    let fault = Fault {
+        exception,
+        error_code,
+        address,
+        instruction_pointer: frame.instruction_pointer.as_u64(),
+        stack_pointer: frame.stack_pointer.as_u64(),
+    };
+    if driver::contain(frame, fault) {
+        Ok(())
+    } else {
+        Err(fault)
+    }
+}
+
+/// Hand the fault to the driver that raised it, or give up on the kernel.
+fn fault(
+    frame: &mut InterruptStackFrame,
+    exception: Exception,
+    error_code: Option<u64>,
+    address: Option<u64>,
+) {
+    if let Err(fault) = contain(frame, exception, error_code, address) {
+        panic!("[interrupts] unhandled {:?}", fault);
+    }
+}
+
+/// Spell out what the CPU says about a page fault.
+fn report_page_fault(fault: &Fault, error_code: PageFaultErrorCode, resolution: PageFault) {
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+    let mode = if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        "user"
+    } else {
+        "kernel"
+    };
+    let cause = if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        "reserved bit set in a page table entry"
+    } else if error_code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+        "protection key violation"
+    } else if error_code.contains(PageFaultErrorCode::SHADOW_STACK) {
+        "shadow stack access"
+    } else if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "page protection violation"
+    } else {
+        "page not present"
+    };
+
+    error!(
+        "[interrupts] page fault: {} mode {} of {:#x}",
+        mode,
+        access,
+        fault.address.unwrap_or(0)
+    );
+    error!("[interrupts]   cause: {}", cause);
+    error!("[interrupts]   error code: {:#x}", error_code.bits());
+    match resolution {
+        PageFault::Guard(region) => error!(
+            "[interrupts]   in the guard region {:#x}..{:#x}",
+            region.start, region.end
+        ),
+        PageFault::Failed(region, reason) => error!(
+            "[interrupts]   in the lazy region {:#x}..{:#x}, mapping failed: {:?}",
+            region.start, region.end, reason
+        ),
+        PageFault::Busy => error!("[interrupts]   page tables locked, regions not checked"),
+        PageFault::Resolved | PageFault::Invalid => {}
+    }
+    error!("[interrupts]   rip: {:#x}", fault.instruction_pointer);
+    error!("[interrupts]   rsp: {:#x}", fault.stack_pointer);
+}
+
+extern "x86-interrupt" fn divide_error(mut frame: InterruptStackFrame) {
+    let _context = Context::enter();
+    fault(&mut frame, Exception::DivideError, None, None);
+}
+
+extern "x86-interrupt" fn invalid_opcode(mut frame: InterruptStackFrame) {
+    let _context = Context::enter();
+    fault(&mut frame, Exception::InvalidOpcode, None, None);
+}
+
+extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, _error_code: u64) -> ! {
+    let _context = Context::enter();
+    // a push onto an overflowed stack faults on its guard, and delivering
+    // that page fault needs the same stack
+    let stack_pointer = frame.stack_pointer.as_u64();
+    let guard = [Cr2::read_raw(), stack_pointer]
+        .into_iter()
+        .find_map(vm::try_region)
+        .filter(|region| region.kind == RegionKind::Guard);
+    if let Some(region) = guard {
+        error!(
+            "[interrupts] kernel stack overflow, rsp {:#x}, guard {:#x}..{:#x}",
+            stack_pointer, region.start, region.end
+        );
+    }
+    // the stack may be gone, there is nothing left to recover to
+    panic!(
+        "[interrupts] double fault at {:#x}",
+        frame.instruction_pointer
+    );
+}
+
+extern "x86-interrupt" fn segment_not_present(mut frame: InterruptStackFrame, error_code: u64) {
+    let _context = Context::enter();
+    fault(
+        &mut frame,
+        Exception::SegmentNotPresent,
+        Some(error_code),
+        None,
+    );
+}
+
+extern "x86-interrupt" fn stack_segment_fault(mut frame: InterruptStackFrame, error_code: u64) {
+    let _context = Context::enter();
+    fault(
+        &mut frame,
+        Exception::StackSegmentFault,
+        Some(error_code),
+        None,
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault(
+    mut frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _context = Context::enter();
+    fault(
+        &mut frame,
+        Exception::GeneralProtection,
+        Some(error_code),
+        None,
+    );
+}
+
+extern "x86-interrupt" fn page_fault(
+    mut frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let _context = Context::enter();
+    let address = Cr2::read_raw();
+    let present = error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    let resolution = vm::page_fault(address, present);
+    if resolution == PageFault::Resolved {
+        return;
+    }
+    if let Err(fault) = contain(
+        &mut frame,
+        Exception::PageFault,
+        Some(error_code.bits()),
+        Some(address),
+    ) {
+        report_page_fault(&fault, error_code, resolution);
+        panic!("[interrupts] unhandled page fault at {:#x}", address);
+    }
+}
+
+extern "x86-interrupt" fn alignment_check(mut frame: InterruptStackFrame, error_code: u64) {
+    let _context = Context::enter();
+    fault(
+        &mut frame,
+        Exception::AlignmentCheck,
+        Some(error_code),
+        None,
+    );
+}
+
+extern "x86-interrupt" fn keyboard_interrupt(_frame: InterruptStackFrame) {
+    let _context = Context::enter();
+    driver::interrupt(keyboard::IRQ);
+    pic::end_of_interrupt(keyboard::IRQ);
+}
+
+fn dynamic_interrupt(index: usize) {
+    let _context = Context::enter();
+    let handler = VECTOR_HANDLERS.lock()[index];
+    if let Some(handler) = handler {
+        handler();
+    }
+    lapic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_interrupt(_frame: InterruptStackFrame) {
+    // not a real request, acknowledging it would end a real one
+}
@@ -0,0 +1,299 @@
+//! I/O APICs, routing global system interrupts (GSIs) to vectors on the boot CPU.
+//!
+//! The I/O APICs and the interrupt source overrides come from the ACPI MADT.
+//! An override says where an ISA IRQ landed when it is not on the GSI of the
+//! same number, and with which polarity and trigger mode. Every redirection
+//! entry starts masked, [`route`] unmasks the ones in use.
+
+use log::{info, warn};
+
+use super::acpi;
+use super::interrupts;
+use super::lapic;
+use super::sync::IrqMutex;
+use super::vm;
+
+// MADT
+const MADT_ENTRIES: usize = acpi::HEADER_SIZE + 8;
+const ENTRY_IOAPIC: u8 = 1;
+const ENTRY_SOURCE_OVERRIDE: u8 = 2;
+const BUS_ISA: u8 = 0;
+
+// MPS INTI flags of an interrupt source override
+const POLARITY_MASK: u16 = 0x3;
+const POLARITY_LOW: u16 = 0x3;
+const TRIGGER_MASK: u16 = 0x3 << 2;
+const TRIGGER_LEVEL: u16 = 0x3 << 2;
+
+// registers, reached through the select and window pair
+const REGISTER_SELECT: u64 = 0x00;
+const REGISTER_WINDOW: u64 = 0x10;
+const VERSION: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_POLARITY_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+const REGISTERS_SIZE: u64 = 0x20;
+const MAX_IOAPICS: usize = 4;
+const ISA_IRQS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    High,
+    Low,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// Where an ISA IRQ arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Source {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoApicError {
+    /// No MADT, or no I/O APIC in it.
+    Unavailable,
+    /// No I/O APIC has an input for the GSI.
+    NoInput,
+    /// [`lapic::init`] found no APIC to deliver to.
+    NoLocalApic,
+    /// Every dynamic vector is taken.
+    NoVector,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    id: u8,
+    /// Virtual address of the registers.
+    base: u64,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ((self.base + REGISTER_SELECT) as *mut u32).write_volatile(register);
+            ((self.base + REGISTER_WINDOW) as *const u32).read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ((self.base + REGISTER_SELECT) as *mut u32).write_volatile(register);
+            ((self.base + REGISTER_WINDOW) as *mut u32).write_volatile(value);
+        }
+    }
+
+    fn serves(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.inputs
+    }
+
+    /// Low half of the redirection entry of input `input`, the high half is the destination.
+    fn entry(input: u32) -> u32 {
+        REDIRECTION_TABLE + input * 2
+    }
+}
+
+struct Routing {
+    ioapics: [Option<IoApic>; MAX_IOAPICS],
+    /// Where each ISA IRQ arrives, from the overrides or the ISA defaults.
+    isa: [Source; ISA_IRQS],
+}
+
+// the select and window pair is one access, interrupt handlers mask entries too
+static ROUTING: IrqMutex<Routing> = IrqMutex::new(Routing {
+    ioapics: [None; MAX_IOAPICS],
+    isa: [Source {
+        gsi: 0,
+        polarity: Polarity::High,
+        trigger: Trigger::Edge,
+    }; ISA_IRQS],
+});
+
+/// The override's polarity and trigger, where `0` means the ISA default.
+fn source(gsi: u32, flags: u16) -> Source {
+    Source {
+        gsi,
+        polarity: match flags & POLARITY_MASK {
+            POLARITY_LOW => Polarity::Low,
+            _ => Polarity::High,
+        },
+        trigger: match flags & TRIGGER_MASK {
+            TRIGGER_LEVEL => Trigger::Level,
+            _ => Trigger::Edge,
+        },
+    }
+}
+
+/// Read the MADT and mask every input of every I/O APIC, after [`acpi::init`] and [`vm::init`].
+pub fn init() {
+    let Some(madt) = acpi::find(b"APIC") else {
+        info!("[ioapic] no MADT, IRQs stay on the PIC");
+        return;
+    };
+    let mut routing = ROUTING.lock();
+    for (irq, source) in routing.isa.iter_mut().enumerate() {
+        source.gsi = irq as u32;
+    }
+
+    let mut found = 0;
+    let mut offset = MADT_ENTRIES;
+    while offset + 2 <= madt.len() {
+        let (kind, len) = (madt[offset], madt[offset + 1] as usize);
+        if len < 2 || offset + len > madt.len() {
+            break;
+        }
+        let entry = &madt[offset..offset + len];
+        match kind {
+            ENTRY_IOAPIC if len >= 12 => {
+                let physical = acpi::read_u32(entry, 4) as u64;
+                let base = match vm::map_mmio(physical, REGISTERS_SIZE) {
+                    Ok(base) => base,
+                    Err(error) => {
+                        warn!("[ioapic] cannot map {:#x}: {:?}", physical, error);
+                        offset += len;
+                        continue;
+                    }
+                };
+                let mut ioapic = IoApic {
+                    id: entry[2],
+                    base,
+                    gsi_base: acpi::read_u32(entry, 8),
+                    inputs: 0,
+                };
+                ioapic.inputs = ((ioapic.read(VERSION) >> 16) & 0xff) + 1;
+                for input in 0..ioapic.inputs {
+                    ioapic.write(IoApic::entry(input), REDIRECTION_MASKED);
+                }
+                info!(
+                    "[ioapic] I/O APIC {} at {:#x}, GSI {}..{}",
+                    ioapic.id,
+                    physical,
+                    ioapic.gsi_base,
+                    ioapic.gsi_base + ioapic.inputs
+                );
+                match routing.ioapics.get_mut(found) {
+                    Some(slot) => *slot = Some(ioapic),
+                    None => warn!("[ioapic] no room for I/O APIC {}", ioapic.id),
+                }
+                found += 1;
+            }
+            ENTRY_SOURCE_OVERRIDE if len >= 10 && entry[2] == BUS_ISA => {
+                let irq = entry[3] as usize;
+                let source = source(acpi::read_u32(entry, 4), acpi::read_u16(entry, 8));
+                if let Some(slot) = routing.isa.get_mut(irq) {
+                    *slot = source;
+                }
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    if found == 0 {
+        info!("[ioapic] no I/O APIC in the MADT, IRQs stay on the PIC");
+    }
+}
+
+pub fn is_available() -> bool {
+    ROUTING.lock().ioapics[0].is_some()
+}
+
+/// Where ISA IRQ `irq` arrives, `None` for anything past IRQ 15.
+pub fn isa_source(irq: u8) -> Option<Source> {
+    ROUTING.lock().isa.get(irq as usize).copied()
+}
+
+/// Deliver `gsi` to the boot CPU on `vector` and unmask it.
+pub fn route(
+    gsi: u32,
+    vector: u8,
+    polarity: Polarity,
+    trigger: Trigger,
+) -> Result<(), IoApicError> {
+    let apic = lapic::id().ok_or(IoApicError::NoLocalApic)?;
+    let routing = ROUTING.lock();
+    if routing.ioapics[0].is_none() {
+        return Err(IoApicError::Unavailable);
+    }
+    let ioapic = routing
+        .ioapics
+        .iter()
+        .flatten()
+        .find(|ioapic| ioapic.serves(gsi))
+        .ok_or(IoApicError::NoInput)?;
+
+    let mut low = vector as u32;
+    if polarity == Polarity::Low {
+        low |= REDIRECTION_POLARITY_LOW;
+    }
+    if trigger == Trigger::Level {
+        low |= REDIRECTION_LEVEL;
+    }
+    let entry = IoApic::entry(gsi - ioapic.gsi_base);
+    // the destination first, the entry stays masked until the low half lands
+    ioapic.write(entry, REDIRECTION_MASKED);
+    ioapic.write(entry + 1, (apic as u32) << 24);
+    ioapic.write(entry, low);
+    Ok(())
+}
+
+/// Stop `gsi` from being delivered, or let it through again.
+pub fn set_masked(gsi: u32, masked: bool) {
+    let routing = ROUTING.lock();
+    let Some(ioapic) = routing
+        .ioapics
+        .iter()
+        .flatten()
+        .find(|ioapic| ioapic.serves(gsi))
+    else {
+        return;
+    };
+    let entry = IoApic::entry(gsi - ioapic.gsi_base);
+    let low = ioapic.read(entry) & !REDIRECTION_MASKED;
+    ioapic.write(
+        entry,
+        if masked {
+            low | REDIRECTION_MASKED
+        } else {
+            low
+        },
+    );
+}
+
+/// Send ISA IRQ `irq` to a new vector running `handler`, returns the vector.
+///
+/// The PIC line of the IRQ has to stay masked, or it arrives twice.
+pub fn route_isa(irq: u8, handler: fn()) -> Result<u8, IoApicError> {
+    if !is_available() {
+        return Err(IoApicError::Unavailable);
+    }
+    let source = isa_source(irq).ok_or(IoApicError::NoInput)?;
+    let vector = interrupts::allocate_vector(handler).ok_or(IoApicError::NoVector)?;
+    if let Err(error) = route(source.gsi, vector, source.polarity, source.trigger) {
+        interrupts::free_vector(vector);
+        return Err(error);
+    }
+    info!(
+        "[ioapic] IRQ {} on GSI {}, vector {}",
+        irq, source.gsi, vector
+    );
+    Ok(vector)
+}
+
+/// Mask ISA IRQ `irq` at the I/O APIC, doing nothing without one.
+pub fn mask_isa(irq: u8) {
+    if let Some(source) = isa_source(irq).filter(|_| is_available()) {
+        set_masked(source.gsi, true);
+    }
+}
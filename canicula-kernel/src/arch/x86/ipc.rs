@@ -0,0 +1,254 @@
+//! Message passing through named ports, each a bounded queue of small messages.
+//!
+//! Anything can send, interrupt handlers included, and a full port turns the
+//! message away rather than wait. Receiving can wait for a message, on one
+//! port or on the first of several with [`select`]. There are no threads yet,
+//! so waiting halts the CPU until an interrupt handler sends something.
+
+use x86_64::instructions::interrupts;
+
+use super::interrupts::in_interrupt;
+use super::sync::IrqMutex;
+
+const MAX_PORTS: usize = 16;
+/// Messages a port holds before sends to it fail.
+const QUEUE_LEN: usize = 16;
+pub const MAX_MESSAGE: usize = 64;
+const MAX_NAME: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    /// No port by that name or id, or it was closed.
+    NoPort,
+    NameTaken,
+    NameTooLong,
+    /// Every port slot is in use.
+    NoSlot,
+    /// The message is longer than [`MAX_MESSAGE`].
+    TooLarge,
+    /// The port's queue is full, the message was not sent.
+    Full,
+    /// Nothing to receive and waiting was not asked for.
+    Empty,
+    /// Waiting was asked for in interrupt context, which must not wait.
+    WouldBlock,
+}
+
+/// A port, which stops working once it is closed even if its slot is reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortId {
+    index: usize,
+    generation: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Message {
+    bytes: [u8; MAX_MESSAGE],
+    len: u8,
+}
+
+impl Message {
+    const EMPTY: Message = Message {
+        bytes: [0; MAX_MESSAGE],
+        len: 0,
+    };
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PortStats {
+    pub queued: usize,
+    pub sent: u64,
+    /// Sends that found the queue full.
+    pub dropped: u64,
+}
+
+struct Port {
+    name: [u8; MAX_NAME],
+    name_len: u8,
+    generation: u32,
+    queue: [Message; QUEUE_LEN],
+    head: usize,
+    stats: PortStats,
+}
+
+impl Port {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("")
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Result<(), IpcError> {
+        if self.stats.queued == QUEUE_LEN {
+            self.stats.dropped += 1;
+            return Err(IpcError::Full);
+        }
+        let message = &mut self.queue[(self.head + self.stats.queued) % QUEUE_LEN];
+        message.bytes[..bytes.len()].copy_from_slice(bytes);
+        message.len = bytes.len() as u8;
+        self.stats.queued += 1;
+        self.stats.sent += 1;
+        Ok(())
+    }
+
+    /// Move the oldest message into `buffer`, cut short if it does not fit, and give its length.
+    fn pop(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        if self.stats.queued == 0 {
+            return None;
+        }
+        let message = &self.queue[self.head];
+        let len = (message.len as usize).min(buffer.len());
+        buffer[..len].copy_from_slice(&message.bytes[..len]);
+        self.head = (self.head + 1) % QUEUE_LEN;
+        self.stats.queued -= 1;
+        Some(message.len as usize)
+    }
+}
+
+struct Ports {
+    slots: [Option<Port>; MAX_PORTS],
+    /// Handed to the next port created, so stale ids do not reach it.
+    generation: u32,
+}
+
+impl Ports {
+    fn get(&mut self, port: PortId) -> Result<&mut Port, IpcError> {
+        self.slots
+            .get_mut(port.index)
+            .and_then(Option::as_mut)
+            .filter(|slot| slot.generation == port.generation)
+            .ok_or(IpcError::NoPort)
+    }
+}
+
+static PORTS: IrqMutex<Ports> = IrqMutex::new(Ports {
+    slots: [const { None }; MAX_PORTS],
+    generation: 0,
+});
+
+/// Open a port that others find by `name`.
+// ports are for the services and drivers that will talk through them, none is written yet
+#[allow(dead_code)]
+pub fn create(name: &str) -> Result<PortId, IpcError> {
+    if name.len() > MAX_NAME {
+        return Err(IpcError::NameTooLong);
+    }
+    let mut ports = PORTS.lock();
+    if ports.slots.iter().flatten().any(|port| port.name() == name) {
+        return Err(IpcError::NameTaken);
+    }
+    let index = ports
+        .slots
+        .iter()
+        .position(Option::is_none)
+        .ok_or(IpcError::NoSlot)?;
+    ports.generation = ports.generation.wrapping_add(1);
+    let generation = ports.generation;
+    let mut port = Port {
+        name: [0; MAX_NAME],
+        name_len: name.len() as u8,
+        generation,
+        queue: [Message::EMPTY; QUEUE_LEN],
+        head: 0,
+        stats: PortStats::default(),
+    };
+    port.name[..name.len()].copy_from_slice(name.as_bytes());
+    ports.slots[index] = Some(port);
+    Ok(PortId { index, generation })
+}
+
+// no port has a client to find it yet
+#[allow(dead_code)]
+pub fn lookup(name: &str) -> Option<PortId> {
+    let ports = PORTS.lock();
+    ports.slots.iter().enumerate().find_map(|(index, port)| {
+        let port = port.as_ref().filter(|port| port.name() == name)?;
+        Some(PortId {
+            index,
+            generation: port.generation,
+        })
+    })
+}
+
+/// Close `port`, dropping what is queued on it.
+// no port is opened to close
+#[allow(dead_code)]
+pub fn close(port: PortId) -> Result<(), IpcError> {
+    let mut ports = PORTS.lock();
+    ports.get(port)?;
+    ports.slots[port.index] = None;
+    Ok(())
+}
+
+/// Queue `message` on `port`, failing with [`IpcError::Full`] instead of waiting for room.
+// nothing sends yet
+#[allow(dead_code)]
+pub fn send(port: PortId, message: &[u8]) -> Result<(), IpcError> {
+    if message.len() > MAX_MESSAGE {
+        return Err(IpcError::TooLarge);
+    }
+    PORTS.lock().get(port)?.push(message)
+}
+
+/// The oldest message on `port` into `buffer`, see [`receive`].
+// nothing receives yet
+#[allow(dead_code)]
+pub fn try_receive(port: PortId, buffer: &mut [u8]) -> Result<usize, IpcError> {
+    PORTS.lock().get(port)?.pop(buffer).ok_or(IpcError::Empty)
+}
+
+/// Wait for a message on `port` and move it into `buffer`.
+///
+/// Gives the message's length, which is more than `buffer` took if it was cut short.
+// nothing waits on a port yet
+#[allow(dead_code)]
+pub fn receive(port: PortId, buffer: &mut [u8]) -> Result<usize, IpcError> {
+    let (_, len) = select(&[port], buffer)?;
+    Ok(len)
+}
+
+/// Wait for a message on any of `ports`, give the index of the one it came
+/// from and its length, like [`receive`]. Earlier ports are looked at first.
+// nothing waits on several ports yet
+#[allow(dead_code)]
+pub fn select(ports: &[PortId], buffer: &mut [u8]) -> Result<(usize, usize), IpcError> {
+    if ports.is_empty() {
+        return Err(IpcError::NoPort);
+    }
+    if in_interrupt() {
+        return Err(IpcError::WouldBlock);
+    }
+    loop {
+        // checking and halting with interrupts off cannot miss the wake up
+        interrupts::disable();
+        if let Some(received) = poll(ports, buffer) {
+            interrupts::enable();
+            return received;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// What [`select`] would give without waiting, `None` while every port is empty.
+fn poll(ports: &[PortId], buffer: &mut [u8]) -> Option<Result<(usize, usize), IpcError>> {
+    let mut slots = PORTS.lock();
+    for (index, port) in ports.iter().enumerate() {
+        match slots.get(*port) {
+            Ok(port) => {
+                if let Some(len) = port.pop(buffer) {
+                    return Some(Ok((index, len)));
+                }
+            }
+            Err(error) => return Some(Err(error)),
+        }
+    }
+    None
+}
+
+/// Call `visit` with each open port's name and counters.
+// for a listing of the ports, nothing shows one yet
+#[allow(dead_code)]
+pub fn ports(mut visit: impl FnMut(&str, PortStats)) {
+    let ports = PORTS.lock();
+    for port in ports.slots.iter().flatten() {
+        visit(port.name(), port.stats);
+    }
+}
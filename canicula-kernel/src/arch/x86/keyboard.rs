@@ -0,0 +1,216 @@
+//! PS/2 keyboard behind the i8042 controller.
+//!
+//! The controller translates whatever the keyboard sends to scancode set 1,
+//! which is what the tables below decode for a US layout. Keys without a
+//! character, arrows and function keys among them, are dropped.
+//!
+//! The ports and the IRQ are the usual ones unless the device's `_CRS` says
+//! otherwise. The PIC only has IRQ 1 wired up, another one needs the I/O APIC.
+
+use core::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use super::aml::Resource;
+use super::device::{self, Device};
+use super::driver::{self, register_driver, Driver};
+use super::sync::IrqMutex;
+use super::{ioapic, pic, tty};
+
+pub const IRQ: u8 = 1;
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+
+/// The IRQ, data port and status port in use, from the device's resources.
+static LINE: AtomicU8 = AtomicU8::new(IRQ);
+static DATA_PORT: AtomicU16 = AtomicU16::new(DATA);
+static STATUS_PORT: AtomicU16 = AtomicU16::new(STATUS);
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// Controller command pulsing the CPU reset line.
+const PULSE_RESET: u8 = 0xfe;
+
+/// Set on the scancode of a key being released.
+const RELEASE: u8 = 0x80;
+/// Prefix of the keys added after the XT keyboard.
+const EXTENDED: u8 = 0xe0;
+
+const LEFT_SHIFT: u8 = 0x2a;
+const RIGHT_SHIFT: u8 = 0x36;
+const CONTROL: u8 = 0x1d;
+const CAPS_LOCK: u8 = 0x3a;
+const ENTER: u8 = 0x1c;
+
+/// Characters by scancode, `0` where a key has none.
+const NORMAL: &[u8; 58] =
+    b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const SHIFTED: &[u8; 58] =
+    b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+struct Keyboard {
+    shift: bool,
+    control: bool,
+    caps_lock: bool,
+    /// The last byte was [`EXTENDED`].
+    extended: bool,
+}
+
+impl Keyboard {
+    /// The character typed with `scancode`, if any.
+    fn decode(&mut self, scancode: u8) -> Option<u8> {
+        if scancode == EXTENDED {
+            self.extended = true;
+            return None;
+        }
+        let extended = core::mem::replace(&mut self.extended, false);
+        let pressed = scancode & RELEASE == 0;
+        let key = scancode & !RELEASE;
+
+        match key {
+            LEFT_SHIFT | RIGHT_SHIFT if !extended => self.shift = pressed,
+            // right control is the extended one
+            CONTROL => self.control = pressed,
+            CAPS_LOCK if pressed => self.caps_lock = !self.caps_lock,
+            // keypad enter
+            ENTER if extended && pressed => return Some(b'\n'),
+            _ if extended || !pressed => {}
+            _ => {
+                let table = if self.shift { SHIFTED } else { NORMAL };
+                let mut byte = *table.get(key as usize).filter(|byte| **byte != 0)?;
+                if self.caps_lock && byte.is_ascii_alphabetic() {
+                    byte ^= 0x20;
+                }
+                if self.control && byte.is_ascii_alphabetic() {
+                    byte &= 0x1f;
+                }
+                return Some(byte);
+            }
+        }
+        None
+    }
+}
+
+static KEYBOARD: IrqMutex<Keyboard> = IrqMutex::new(Keyboard {
+    shift: false,
+    control: false,
+    caps_lock: false,
+    extended: false,
+});
+
+fn data_port() -> Port<u8> {
+    Port::new(DATA_PORT.load(Ordering::Relaxed))
+}
+
+fn status_port() -> Port<u8> {
+    Port::new(STATUS_PORT.load(Ordering::Relaxed))
+}
+
+/// Take the ports and IRQ from `device`'s resources where it lists them.
+fn configure(device: &Device) -> Result<(), &'static str> {
+    let mut ports = device.resources().filter_map(|resource| match resource {
+        Resource::Io { base, .. } => Some(base),
+        _ => None,
+    });
+    // the data port is listed before the status port
+    if let (Some(data), Some(status)) = (ports.next(), ports.next()) {
+        DATA_PORT.store(data, Ordering::Relaxed);
+        STATUS_PORT.store(status, Ordering::Relaxed);
+    }
+    let line = device.resources().find_map(|resource| match resource {
+        Resource::Irq(line) => Some(line),
+        _ => None,
+    });
+    if let Some(line) = line {
+        let line = u8::try_from(line)
+            .ok()
+            .filter(|line| *line < 16)
+            .ok_or("IRQ beyond the ISA lines")?;
+        LINE.store(line, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+fn probe() -> Result<(), &'static str> {
+    let mut status = status_port();
+    let mut data = data_port();
+    unsafe {
+        // a missing controller reads as a floating bus
+        if status.read() == 0xff {
+            return Err("no i8042 controller");
+        }
+        // drop whatever was typed before the kernel listened
+        while status.read() & STATUS_OUTPUT_FULL != 0 {
+            data.read();
+        }
+    }
+    // the PIC line only if there is no I/O APIC to take the IRQ
+    let line = LINE.load(Ordering::Relaxed);
+    if ioapic::route_isa(line, routed_interrupt).is_err() {
+        if line != IRQ {
+            return Err("IRQ other than 1 without an I/O APIC");
+        }
+        pic::unmask(IRQ);
+    }
+    Ok(())
+}
+
+/// The IRQ through the I/O APIC, which the local APIC acknowledges.
+fn routed_interrupt() {
+    driver::interrupt(LINE.load(Ordering::Relaxed));
+}
+
+fn interrupt() {
+    let scancode = unsafe { data_port().read() };
+    if let Some(byte) = KEYBOARD.lock().decode(scancode) {
+        tty::input(byte);
+    }
+}
+
+struct Ps2Keyboard;
+
+impl Driver for Ps2Keyboard {
+    fn name(&self) -> &'static str {
+        "ps2-keyboard"
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        device
+            .hid()
+            .is_some_and(|hid| hid.as_str() == device::HID_KEYBOARD)
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), &'static str> {
+        configure(device)?;
+        probe()
+    }
+
+    fn remove(&self, _device: &Device) {
+        ioapic::mask_isa(LINE.load(Ordering::Relaxed));
+        pic::mask(IRQ);
+    }
+
+    fn irq(&self, _device: &Device) -> Option<u8> {
+        Some(LINE.load(Ordering::Relaxed))
+    }
+
+    fn interrupt(&self, _device: &Device) {
+        interrupt();
+    }
+}
+
+register_driver!(Ps2Keyboard);
+
+/// Reset the machine through the controller's line to the CPU, returns if nothing happened.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn reset_cpu() {
+    let mut status = status_port();
+    // a missing controller never drains its input buffer
+    for _ in 0..0x10000 {
+        if unsafe { status.read() } & STATUS_INPUT_FULL == 0 {
+            unsafe { status.write(PULSE_RESET) };
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,208 @@
+//! The boot CPU's local APIC: its ID, end of interrupt and the timer.
+//!
+//! Legacy IRQs without an I/O APIC still come from the 8259 PIC through the
+//! APIC's LINT0 pin in virtual wire mode, as the firmware left it. Everything
+//! else is delivered by the APIC, so those handlers end with [`end_of_interrupt`]
+//! here rather than at the PIC.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::{info, warn};
+use x86_64::registers::model_specific::Msr;
+
+use super::{cpu, vm};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS: u64 = 0x000f_ffff_ffff_f000;
+
+// register offsets
+const ID: u64 = 0x20;
+const TASK_PRIORITY: u64 = 0x80;
+const EOI: u64 = 0xb0;
+const SPURIOUS: u64 = 0xf0;
+const LVT_TIMER: u64 = 0x320;
+const LVT_LINT0: u64 = 0x350;
+const LVT_LINT1: u64 = 0x360;
+const LVT_ERROR: u64 = 0x370;
+const INITIAL_COUNT: u64 = 0x380;
+const CURRENT_COUNT: u64 = 0x390;
+const DIVIDE_CONFIGURATION: u64 = 0x3e0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 0b01 << 17;
+const TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+/// The timer counts down once every 16 bus clocks.
+const DIVIDE_BY_16: u32 = 0b0011;
+
+/// Raised by the APIC for an interrupt that went away before it was taken.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const REGISTERS_SIZE: u64 = 0x400;
+
+/// What [`save`] keeps, in the order [`restore`] writes it back: the APIC
+/// enabled first, the timer count last since writing it starts the timer.
+const SAVED_REGISTERS: [u64; 8] = [
+    SPURIOUS,
+    TASK_PRIORITY,
+    LVT_LINT0,
+    LVT_LINT1,
+    LVT_ERROR,
+    DIVIDE_CONFIGURATION,
+    LVT_TIMER,
+    INITIAL_COUNT,
+];
+
+/// Virtual address of the registers, zero until [`init`] mapped them.
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+fn read(register: u64) -> u32 {
+    let base = BASE.load(Ordering::Acquire);
+    unsafe { ((base + register) as *const u32).read_volatile() }
+}
+
+fn write(register: u64, value: u32) {
+    let base = BASE.load(Ordering::Acquire);
+    unsafe { ((base + register) as *mut u32).write_volatile(value) }
+}
+
+/// Map the registers and make sure the APIC takes interrupts.
+///
+/// An APIC the firmware switched to x2APIC mode is left alone, it has no registers to map.
+pub fn init() {
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    if apic_base & APIC_BASE_ENABLE == 0 {
+        warn!("[lapic] disabled by the firmware");
+        return;
+    }
+    if apic_base & APIC_BASE_X2APIC != 0 {
+        warn!("[lapic] in x2APIC mode, not supported");
+        return;
+    }
+    let physical = apic_base & APIC_BASE_ADDRESS;
+    let base = match vm::map_mmio(physical, REGISTERS_SIZE) {
+        Ok(base) => base,
+        Err(error) => {
+            warn!("[lapic] cannot map the registers: {:?}", error);
+            return;
+        }
+    };
+    BASE.store(base, Ordering::Release);
+
+    let spurious = read(SPURIOUS);
+    if spurious & SPURIOUS_ENABLE == 0 {
+        write(SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+    info!("[lapic] APIC {} at {:#x}", read(ID) >> 24, physical);
+}
+
+pub fn is_available() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+/// APIC ID of the boot CPU, where MSIs are sent.
+pub fn id() -> Option<u8> {
+    is_available().then(|| (read(ID) >> 24) as u8)
+}
+
+/// Acknowledge the interrupt being handled, for vectors the APIC delivered on its own.
+pub fn end_of_interrupt() {
+    if is_available() {
+        write(EOI, 0);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Count the initial count down once.
+    OneShot,
+    /// Count it down over and over.
+    Periodic,
+    /// Fire when the TSC reaches the deadline from [`set_tsc_deadline`].
+    TscDeadline,
+}
+
+/// Whether the timer has [`TimerMode::TscDeadline`].
+pub fn has_tsc_deadline() -> bool {
+    cpu::features().tsc_deadline
+}
+
+/// Program the timer, raising `vector` when it fires or nothing for `None`.
+///
+/// The count runs at the bus clock divided by 16, and is ignored in TSC-deadline mode.
+pub fn set_timer(mode: TimerMode, vector: Option<u8>, initial_count: u32) {
+    if !is_available() {
+        return;
+    }
+    let mut lvt = match mode {
+        TimerMode::OneShot => 0,
+        TimerMode::Periodic => TIMER_PERIODIC,
+        TimerMode::TscDeadline => TIMER_TSC_DEADLINE,
+    };
+    lvt |= match vector {
+        Some(vector) => vector as u32,
+        None => LVT_MASKED,
+    };
+    write(DIVIDE_CONFIGURATION, DIVIDE_BY_16);
+    write(LVT_TIMER, lvt);
+    if mode != TimerMode::TscDeadline {
+        write(INITIAL_COUNT, initial_count);
+    }
+}
+
+/// What is left of the timer's count.
+pub fn timer_count() -> u32 {
+    if is_available() {
+        read(CURRENT_COUNT)
+    } else {
+        0
+    }
+}
+
+/// Fire the timer in TSC-deadline mode once the TSC reaches `tsc`, `0` disarms it.
+pub fn set_tsc_deadline(tsc: u64) {
+    unsafe { Msr::new(IA32_TSC_DEADLINE).write(tsc) };
+}
+
+/// The APIC's programmable state, lost over a sleep state that powers the CPU off.
+#[derive(Debug, Clone, Copy)]
+pub struct Saved {
+    registers: [u32; SAVED_REGISTERS.len()],
+    tsc_deadline: u64,
+}
+
+/// Read what [`restore`] needs to set the APIC up again after waking.
+pub fn save() -> Option<Saved> {
+    if !is_available() {
+        return None;
+    }
+    let mut registers = [0; SAVED_REGISTERS.len()];
+    for (value, register) in registers.iter_mut().zip(SAVED_REGISTERS) {
+        *value = read(register);
+    }
+    let tsc_deadline = if has_tsc_deadline() {
+        unsafe { Msr::new(IA32_TSC_DEADLINE).read() }
+    } else {
+        0
+    };
+    Some(Saved {
+        registers,
+        tsc_deadline,
+    })
+}
+
+/// Program the APIC back to what [`save`] read, the mapping is kept over sleep.
+pub fn restore(saved: &Saved) {
+    if !is_available() {
+        return;
+    }
+    for (value, register) in saved.registers.iter().zip(SAVED_REGISTERS) {
+        write(register, *value);
+    }
+    if saved.tsc_deadline != 0 {
+        set_tsc_deadline(saved.tsc_deadline);
+    }
+}
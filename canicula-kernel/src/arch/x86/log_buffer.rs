@@ -0,0 +1,113 @@
+//! The kernel log kept in memory, for `dmesg` to show what the console did not.
+//!
+//! Records go into a fixed ring of slots, the oldest overwritten once it is full.
+//! Each slot holds the time since boot, the level and the message, cut short
+//! past [`MAX_MESSAGE`] bytes. Records are numbered from boot, so a reader that
+//! fell behind can tell how many it missed.
+
+use core::fmt::{self, Write};
+
+use log::Level;
+
+use super::sync::IrqMutex;
+
+const SLOTS: usize = 256;
+pub const MAX_MESSAGE: usize = 224;
+
+#[derive(Clone, Copy)]
+pub struct Entry {
+    /// Nanoseconds since boot, zero before the clock was calibrated.
+    pub time: u64,
+    pub level: Level,
+    len: u8,
+    message: [u8; MAX_MESSAGE],
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry {
+        time: 0,
+        level: Level::Info,
+        len: 0,
+        message: [0; MAX_MESSAGE],
+    };
+
+    #[cfg_attr(feature = "ext4-test", allow(dead_code))]
+    pub fn message(&self) -> &str {
+        // cut at a character boundary when written
+        core::str::from_utf8(&self.message[..self.len as usize]).unwrap_or("")
+    }
+}
+
+/// Fills a message, dropping whatever does not fit.
+struct Truncating<'a> {
+    entry: &'a mut Entry,
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let len = self.entry.len as usize;
+        let mut take = text.len().min(MAX_MESSAGE - len);
+        while !text.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.entry.message[len..len + take].copy_from_slice(&text.as_bytes()[..take]);
+        self.entry.len = (len + take) as u8;
+        Ok(())
+    }
+}
+
+struct Ring {
+    slots: [Entry; SLOTS],
+    /// Number of the oldest record [`clear`] left, older ones may be gone anyway.
+    first: u64,
+    /// Number of the next record, also the count of records since boot.
+    next: u64,
+}
+
+impl Ring {
+    fn oldest(&self) -> u64 {
+        self.first.max(self.next.saturating_sub(SLOTS as u64))
+    }
+}
+
+// loggers run in interrupt handlers too
+static RING: IrqMutex<Ring> = IrqMutex::new(Ring {
+    slots: [Entry::EMPTY; SLOTS],
+    first: 0,
+    next: 0,
+});
+
+pub fn record(time: u64, level: Level, args: fmt::Arguments) {
+    let mut ring = RING.lock();
+    let index = (ring.next % SLOTS as u64) as usize;
+    ring.next += 1;
+    let entry = &mut ring.slots[index];
+    entry.time = time;
+    entry.level = level;
+    entry.len = 0;
+    let _ = Truncating { entry }.write_fmt(args);
+}
+
+/// Numbers of the oldest record still kept and of the next one to be written.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn range() -> (u64, u64) {
+    let ring = RING.lock();
+    (ring.oldest(), ring.next)
+}
+
+/// Record number `sequence`, `None` if it was overwritten or not written yet.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn get(sequence: u64) -> Option<Entry> {
+    let ring = RING.lock();
+    if sequence < ring.oldest() || sequence >= ring.next {
+        return None;
+    }
+    Some(ring.slots[(sequence % SLOTS as u64) as usize])
+}
+
+/// Forget every record, numbering goes on where it was.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn clear() {
+    let mut ring = RING.lock();
+    ring.first = ring.next;
+}
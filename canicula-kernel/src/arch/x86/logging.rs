@@ -0,0 +1,118 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::println;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use super::cmdline::Options;
+use super::{log_buffer, time};
+
+static WALL_CLOCK: AtomicBool = AtomicBool::new(false);
+/// The most verbose level printed on the console, a [`LevelFilter`] as `usize`.
+static CONSOLE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+
+/// The most verbose level kept in the log buffer, whatever the console shows.
+const BUFFER_LEVEL: LevelFilter = LevelFilter::Info;
+
+struct SimpleLogger;
+
+impl Log for SimpleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let uptime = time::monotonic_ns();
+        if record.level() <= BUFFER_LEVEL {
+            log_buffer::record(uptime, record.level(), *record.args());
+        }
+        if record.level() > console_level() {
+            return;
+        }
+        let color = color(record.level());
+        if WALL_CLOCK.load(Ordering::Relaxed) {
+            println!(
+                "\u{1B}[{}m[{}] [{:>5}] {}\u{1B}[0m",
+                color,
+                time::now(),
+                record.level(),
+                record.args(),
+            );
+        } else if time::tsc_frequency().is_some() {
+            println!(
+                "\u{1B}[{}m[{:>5}.{:06}] [{:>5}] {}\u{1B}[0m",
+                color,
+                uptime / 1_000_000_000,
+                uptime / 1000 % 1_000_000,
+                record.level(),
+                record.args(),
+            );
+        } else {
+            println!(
+                "\u{1B}[{}m[{:>5}] {}\u{1B}[0m",
+                color,
+                record.level(),
+                record.args(),
+            );
+        }
+    }
+    fn flush(&self) {}
+}
+
+/// ANSI color of the records of `level`.
+pub fn color(level: Level) -> u8 {
+    match level {
+        // Red
+        Level::Error => 31,
+        // BrightYellow
+        Level::Warn => 93,
+        // Blue
+        Level::Info => 34,
+        // Green
+        Level::Debug => 32,
+        // BrightBlack
+        Level::Trace => 90,
+    }
+}
+
+/// Prefix every record with the local wall-clock time rather than the time since boot.
+pub fn set_wall_clock(enabled: bool) {
+    WALL_CLOCK.store(enabled, Ordering::Relaxed);
+}
+
+pub fn console_level() -> LevelFilter {
+    LevelFilter::iter()
+        .nth(CONSOLE_LEVEL.load(Ordering::Relaxed))
+        .unwrap_or(LevelFilter::Off)
+}
+
+/// Print records up to `level` on the console, the log buffer keeps what it did.
+pub fn set_console_level(level: LevelFilter) {
+    CONSOLE_LEVEL.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(level.max(BUFFER_LEVEL));
+}
+
+/// Take over what the command line asks for, after [`init`] set the built in defaults.
+pub fn configure(options: &Options) {
+    if let Some(level) = options.log_level {
+        set_console_level(level);
+    }
+    if let Some(wall_clock) = options.log_wall_clock {
+        set_wall_clock(wall_clock);
+    }
+}
+
+pub fn init() {
+    static LOGGER: SimpleLogger = SimpleLogger;
+    log::set_logger(&LOGGER).unwrap();
+    set_console_level(match option_env!("log_level") {
+        Some("ERROR") => LevelFilter::Error,
+        Some("WARN") => LevelFilter::Warn,
+        Some("INFO") => LevelFilter::Info,
+        Some("DEBUG") => LevelFilter::Debug,
+        Some("TRACE") => LevelFilter::Trace,
+        _ => LevelFilter::Off,
+    });
+    set_wall_clock(option_env!("log_wall_clock").is_some_and(|v| v == "true"));
+}
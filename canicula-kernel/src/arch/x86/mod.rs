@@ -1,8 +1,167 @@
 use core::{arch::asm, panic::PanicInfo};
 
-pub fn entry() -> ! {
-    loop {
-        hlt();
+use canicula_common::entry::{BootInfo, MemoryKind};
+use canicula_common::layout;
+
+use log::*;
+
+use crate::println;
+
+mod acpi;
+mod ahci;
+mod aml;
+mod aslr;
+mod block;
+mod cmdline;
+mod console;
+mod cpu;
+mod crash;
+mod device;
+mod driver;
+mod efi;
+#[cfg(feature = "ext4-test")]
+mod ext4_test;
+mod ext4fs;
+mod framebuffer;
+mod frames;
+mod gdbstub;
+mod gdt;
+mod hardening;
+mod hpet;
+mod inet;
+mod initrd;
+mod interrupts;
+mod ioapic;
+mod ipc;
+mod keyboard;
+mod lapic;
+mod log_buffer;
+mod logging;
+mod msi;
+mod net;
+mod page_audit;
+mod pci;
+mod percpu;
+mod pic;
+mod pit;
+mod power;
+#[cfg(feature = "ext4-test")]
+mod qemu;
+mod random;
+mod rtc;
+mod serial;
+#[cfg(not(feature = "ext4-test"))]
+mod shell;
+mod shm;
+mod sync;
+mod tcp;
+mod time;
+mod timer;
+mod tty;
+mod vfs;
+mod virtio;
+mod virtio_blk;
+mod virtio_net;
+mod virtualization;
+mod vm;
+
+pub fn entry(boot_info: &'static BootInfo) -> ! {
+    percpu::init();
+    logging::init();
+    if let Err(message) = boot_info
+        .check()
+        .and_then(|()| layout::check_handoff(boot_info))
+    {
+        panic!("[kernel] {}", message);
+    }
+    // the loader's pages stay identity mapped
+    let cmdline = unsafe { boot_info.cmdline() };
+    let options = cmdline::Options::parse(cmdline);
+    time::init(options.utc_offset);
+    logging::configure(&options);
+    if let Some(output) = options.console {
+        console::select(output);
+    }
+    framebuffer::init(boot_info);
+    cpu::init();
+    interrupts::init();
+    driver::init();
+    interrupts::enable();
+    efi::init(boot_info);
+    aslr::init(boot_info, options.aslr);
+    page_audit::init(boot_info);
+    frames::init(boot_info);
+    vm::init(boot_info);
+    hardening::init();
+    gdbstub::init(options.gdb);
+    acpi::init(boot_info);
+    lapic::init();
+    ioapic::init();
+    hpet::init();
+    time::calibrate();
+    timer::init(options.timer_hz);
+    power::init();
+    initrd::init(boot_info);
+    pci::init();
+    aml::init();
+    device::init();
+    driver::probe_all();
+    inet::init();
+    // without an initrd the first ext4 disk is the root
+    if vfs::stat("/").is_err() {
+        if let Err(error) = ext4fs::mount_disk("/") {
+            info!("[kernel] no root file system: {:?}", error);
+        }
+    }
+    println!("[kernel] Hello, world!");
+    info!("[kernel] booted at {}", time::now());
+    info!(
+        "[kernel] {} MiB of physical memory mapped at {:#x}",
+        boot_info.physical_memory_size >> 20,
+        boot_info.physical_memory_offset
+    );
+    info!("[kernel] command line: {:?}", cmdline);
+    let memory_map = unsafe { boot_info.memory_map.entries() };
+    let usable: u64 = memory_map
+        .iter()
+        .filter(|entry| entry.kind == MemoryKind::Usable)
+        .map(|entry| entry.region.size)
+        .sum();
+    info!(
+        "[kernel] {} memory map entries, {} KiB usable",
+        memory_map.len(),
+        usable / 1024
+    );
+    if !boot_info.initrd.is_empty() {
+        info!(
+            "[kernel] initrd at {:#x}, {} bytes",
+            boot_info.initrd.address, boot_info.initrd.size
+        );
+    }
+    info!("[kernel] booted on CPU {}", boot_info.boot_cpu);
+    if !options.smp {
+        info!("[kernel] nosmp, application processors stay parked");
+    } else if let Some(x86) = boot_info.x86_64().filter(|x86| x86.smp_trampoline != 0) {
+        info!("[kernel] SMP trampoline page at {:#x}", x86.smp_trampoline);
+    }
+    for module in unsafe { boot_info.modules.iter() } {
+        info!(
+            "[kernel] module {} at {:#x}, {} bytes",
+            unsafe { module.path() },
+            module.content.address,
+            module.content.size
+        );
+    }
+
+    #[cfg(feature = "ext4-test")]
+    ext4_test::run();
+
+    #[cfg(not(feature = "ext4-test"))]
+    {
+        shell::run(boot_info);
+        loop {
+            hlt();
+        }
     }
 }
 
@@ -14,6 +173,11 @@ fn hlt() {
 }
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    driver::contain_panic(info);
+    crash::report(info);
+    x86_64::instructions::interrupts::disable();
+    loop {
+        hlt();
+    }
 }
@@ -0,0 +1,235 @@
+//! Message signalled interrupts for PCI devices.
+//!
+//! A device with MSI or MSI-X writes its interrupts straight to the boot CPU's
+//! local APIC, on a vector from [`interrupts::allocate_vector`], instead of
+//! sharing a legacy IRQ line through the PIC. Setting either up turns the
+//! device's INTx line off.
+
+use log::info;
+
+use super::interrupts;
+use super::lapic;
+use super::pci::{
+    Bar, PciDevice, CAPABILITY_MSI, CAPABILITY_MSIX, COMMAND_BUS_MASTER, COMMAND_INTERRUPT_DISABLE,
+};
+use super::vm::{self, VmError};
+
+// MSI capability
+const MSI_CONTROL: u8 = 0x02;
+const MSI_ADDRESS: u8 = 0x04;
+const MSI_ADDRESS_HIGH: u8 = 0x08;
+const MSI_DATA_32: u8 = 0x08;
+const MSI_DATA_64: u8 = 0x0c;
+
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_ENABLE: u16 = 0x7 << 4;
+const MSI_64BIT: u16 = 1 << 7;
+
+// MSI-X capability
+const MSIX_CONTROL: u8 = 0x02;
+const MSIX_TABLE: u8 = 0x04;
+
+const MSIX_TABLE_SIZE: u16 = 0x7ff;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_BIR: u32 = 0x7;
+
+// MSI-X table entry
+const ENTRY_SIZE: u64 = 16;
+const ENTRY_ADDRESS: u64 = 0x0;
+const ENTRY_ADDRESS_HIGH: u64 = 0x4;
+const ENTRY_DATA: u64 = 0x8;
+const ENTRY_CONTROL: u64 = 0xc;
+const ENTRY_MASKED: u32 = 1 << 0;
+
+/// Where a message lands: the local APIC of the CPU in bits 12..20.
+const MESSAGE_ADDRESS: u32 = 0xfee0_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The device has no MSI, or no MSI-X, capability.
+    Unsupported,
+    /// [`lapic::init`] found no APIC to deliver to.
+    NoLocalApic,
+    /// Every dynamic vector is taken.
+    NoVector,
+    /// The MSI-X table is not in a memory BAR.
+    BadTable,
+    /// Past the end of the MSI-X table.
+    NoEntry,
+    Map(VmError),
+}
+
+/// Address and data of a message raising `vector` on the CPU with APIC `apic`,
+/// edge triggered with fixed delivery.
+fn message(apic: u8, vector: u8) -> (u32, u32) {
+    (MESSAGE_ADDRESS | (apic as u32) << 12, vector as u32)
+}
+
+/// A vector for `handler` and the message raising it.
+fn route(handler: fn()) -> Result<(u8, u32, u32), MsiError> {
+    let apic = lapic::id().ok_or(MsiError::NoLocalApic)?;
+    let vector = interrupts::allocate_vector(handler).ok_or(MsiError::NoVector)?;
+    let (address, data) = message(apic, vector);
+    Ok((vector, address, data))
+}
+
+/// Send the device's MSI to a new vector running `handler`, returns the vector.
+///
+/// Only one message is enabled, whatever the device asks for.
+// the drivers still share legacy IRQ lines, none of them asks for MSI yet
+#[allow(dead_code)]
+pub fn enable_msi(device: &PciDevice, handler: fn()) -> Result<u8, MsiError> {
+    let capability = device
+        .capability(CAPABILITY_MSI)
+        .ok_or(MsiError::Unsupported)?;
+    let (vector, address, data) = route(handler)?;
+    let config = device.address;
+    let control = config.read16(capability + MSI_CONTROL) & !(MSI_ENABLE | MSI_MULTIPLE_ENABLE);
+    config.write16(capability + MSI_CONTROL, control);
+
+    config.write32(capability + MSI_ADDRESS, address);
+    let data_offset = if control & MSI_64BIT != 0 {
+        config.write32(capability + MSI_ADDRESS_HIGH, 0);
+        MSI_DATA_64
+    } else {
+        MSI_DATA_32
+    };
+    config.write16(capability + data_offset, data as u16);
+
+    device.enable(COMMAND_INTERRUPT_DISABLE | COMMAND_BUS_MASTER);
+    config.write16(capability + MSI_CONTROL, control | MSI_ENABLE);
+    info!(
+        "[msi] {:02x}:{:02x}.{} MSI on vector {}",
+        config.bus, config.device, config.function, vector
+    );
+    Ok(vector)
+}
+
+/// Stop the device's MSI, back to its INTx line, and give back `vector`.
+// goes with `enable_msi`
+#[allow(dead_code)]
+pub fn disable_msi(device: &PciDevice, vector: u8) {
+    if let Some(capability) = device.capability(CAPABILITY_MSI) {
+        let config = device.address;
+        let control = config.read16(capability + MSI_CONTROL);
+        config.write16(capability + MSI_CONTROL, control & !MSI_ENABLE);
+        device.disable(COMMAND_INTERRUPT_DISABLE);
+    }
+    interrupts::free_vector(vector);
+}
+
+/// A device's MSI-X table, each entry routed on its own.
+pub struct MsixTable {
+    device: PciDevice,
+    capability: u8,
+    /// Virtual address of the first entry.
+    base: u64,
+    size: u16,
+}
+
+// no driver uses MSI-X yet
+#[allow(dead_code)]
+impl MsixTable {
+    /// Map the device's table and enable MSI-X with every entry masked.
+    pub fn new(device: &PciDevice) -> Result<Self, MsiError> {
+        let capability = device
+            .capability(CAPABILITY_MSIX)
+            .ok_or(MsiError::Unsupported)?;
+        let config = device.address;
+        let control = config.read16(capability + MSIX_CONTROL);
+        let size = (control & MSIX_TABLE_SIZE) + 1;
+        let table = config.read32(capability + MSIX_TABLE);
+        let Some(Bar::Memory { address, .. }) = device.bar((table & MSIX_BIR) as u8) else {
+            return Err(MsiError::BadTable);
+        };
+        let base = vm::map_mmio(
+            address + (table & !MSIX_BIR) as u64,
+            size as u64 * ENTRY_SIZE,
+        )
+        .map_err(MsiError::Map)?;
+
+        let msix = MsixTable {
+            device: *device,
+            capability,
+            base,
+            size,
+        };
+        // the function mask holds everything back while the entries are masked one by one
+        config.write16(
+            capability + MSIX_CONTROL,
+            control | MSIX_ENABLE | MSIX_FUNCTION_MASK,
+        );
+        for entry in 0..size {
+            msix.write(entry, ENTRY_CONTROL, ENTRY_MASKED);
+        }
+        device.enable(COMMAND_INTERRUPT_DISABLE | COMMAND_BUS_MASTER);
+        config.write16(
+            capability + MSIX_CONTROL,
+            (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+        );
+        info!(
+            "[msi] {:02x}:{:02x}.{} MSI-X with {} entries",
+            config.bus, config.device, config.function, size
+        );
+        Ok(msix)
+    }
+
+    fn write(&self, entry: u16, field: u64, value: u32) {
+        let address = self.base + entry as u64 * ENTRY_SIZE + field;
+        unsafe { (address as *mut u32).write_volatile(value) }
+    }
+
+    fn read(&self, entry: u16, field: u64) -> u32 {
+        let address = self.base + entry as u64 * ENTRY_SIZE + field;
+        unsafe { (address as *const u32).read_volatile() }
+    }
+
+    /// Number of entries in the table.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Send entry `entry` to a new vector running `handler` and unmask it, returns the vector.
+    pub fn route(&self, entry: u16, handler: fn()) -> Result<u8, MsiError> {
+        if entry >= self.size {
+            return Err(MsiError::NoEntry);
+        }
+        let (vector, address, data) = route(handler)?;
+        self.set_masked(entry, true);
+        self.write(entry, ENTRY_ADDRESS, address);
+        self.write(entry, ENTRY_ADDRESS_HIGH, 0);
+        self.write(entry, ENTRY_DATA, data);
+        self.set_masked(entry, false);
+        Ok(vector)
+    }
+
+    /// Mask entry `entry` and give back `vector`, the one it was routed to.
+    pub fn release(&self, entry: u16, vector: u8) {
+        if entry < self.size {
+            self.set_masked(entry, true);
+        }
+        interrupts::free_vector(vector);
+    }
+
+    pub fn set_masked(&self, entry: u16, masked: bool) {
+        if entry >= self.size {
+            return;
+        }
+        let control = self.read(entry, ENTRY_CONTROL) & !ENTRY_MASKED;
+        let control = if masked {
+            control | ENTRY_MASKED
+        } else {
+            control
+        };
+        self.write(entry, ENTRY_CONTROL, control);
+    }
+
+    /// Turn MSI-X off again, the device goes back to its INTx line.
+    pub fn disable(self) {
+        let config = self.device.address;
+        let control = config.read16(self.capability + MSIX_CONTROL);
+        config.write16(self.capability + MSIX_CONTROL, control & !MSIX_ENABLE);
+        self.device.disable(COMMAND_INTERRUPT_DISABLE);
+    }
+}
@@ -0,0 +1,41 @@
+//! Network interfaces: Ethernet frames in and out, without any protocol on top.
+//!
+//! Drivers register their interfaces here, the network stack finds them by index.
+
+use canicula_common::fs::OperateError;
+use spin::Mutex;
+
+pub type MacAddress = [u8; 6];
+
+/// Largest Ethernet frame without the frame check sequence, for a 1500 byte MTU.
+pub const MAX_FRAME_SIZE: usize = 1514;
+const MAX_DEVICES: usize = 4;
+
+pub trait NetworkDevice: Sync {
+    fn name(&self) -> &'static str;
+
+    fn mac_address(&self) -> MacAddress;
+
+    /// Send one frame, destination MAC address first.
+    fn send_frame(&self, frame: &[u8]) -> Result<(), OperateError>;
+
+    /// Take the next received frame, `None` if there is none yet.
+    ///
+    /// Returns the length of the frame, which is cut short if it does not fit `buffer`.
+    fn receive_frame(&self, buffer: &mut [u8]) -> Result<Option<usize>, OperateError>;
+}
+
+static DEVICES: Mutex<[Option<&'static dyn NetworkDevice>; MAX_DEVICES]> =
+    Mutex::new([None; MAX_DEVICES]);
+
+/// Make `device` available to [`get`], returns its index.
+pub fn register(device: &'static dyn NetworkDevice) -> Option<usize> {
+    let mut devices = DEVICES.lock();
+    let index = devices.iter().position(Option::is_none)?;
+    devices[index] = Some(device);
+    Some(index)
+}
+
+pub fn get(index: usize) -> Option<&'static dyn NetworkDevice> {
+    DEVICES.lock().get(index).copied().flatten()
+}
@@ -0,0 +1,264 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use canicula_common::entry::BootInfo;
+// page tables are read through the loader's map of physical memory
+use canicula_common::layout::{
+    KERNEL_SPACE_START, PHYSICAL_MEMORY_OFFSET, PHYSICAL_MEMORY_SIZE_MIN,
+};
+use log::{info, warn};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+
+/// Bytes covered by one entry at level 4, 3, 2 and 1.
+const ENTRY_SIZE: [u64; 4] = [1 << 39, 1 << 30, 1 << 21, 1 << 12];
+
+/// Bytes of physical memory the loader mapped, tables above are not reachable.
+static PHYSICAL_MEMORY_SIZE: AtomicU64 = AtomicU64::new(PHYSICAL_MEMORY_SIZE_MIN);
+
+/// Flags whose effective value depends on every level of the walk.
+const INHERITED: PageTableFlags = PageTableFlags::WRITABLE
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+extern "C" {
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+/// A run of pages with contiguous physical frames and identical effective flags.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub physical: u64,
+    pub flags: PageTableFlags,
+}
+
+impl Region {
+    pub fn writable(&self) -> bool {
+        self.flags.contains(PageTableFlags::WRITABLE)
+    }
+
+    pub fn executable(&self) -> bool {
+        !self.flags.contains(PageTableFlags::NO_EXECUTE)
+    }
+
+    pub fn user_accessible(&self) -> bool {
+        self.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.start == self.physical
+    }
+
+    fn extends(&self, start: u64, physical: u64, flags: PageTableFlags) -> bool {
+        self.end == start
+            && self.physical + (self.end - self.start) == physical
+            && self.flags == flags
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Report {
+    pub regions: usize,
+    pub writable_executable: usize,
+    pub user_kernel: usize,
+    pub identity: usize,
+    pub section_mismatches: usize,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.writable_executable == 0
+            && self.user_kernel == 0
+            && self.identity == 0
+            && self.section_mismatches == 0
+    }
+}
+
+/// Build with `page_audit=true` to check the kernel page tables at boot.
+pub fn init(boot_info: &BootInfo) {
+    PHYSICAL_MEMORY_SIZE.store(boot_info.physical_memory_size, Ordering::Relaxed);
+    if option_env!("page_audit").is_some_and(|v| v == "true") {
+        audit();
+    }
+}
+
+/// Walk the active page tables and log every suspicious mapping.
+///
+/// The UEFI identity map is still inherited by the kernel, so its regions are
+/// reported until the kernel switches to page tables of its own.
+pub fn audit() -> Report {
+    let mut report = Report::default();
+
+    walk(|region| {
+        report.regions += 1;
+
+        if region.is_identity() {
+            report.identity += 1;
+            warn!(
+                "[page audit] identity mapping {:#x}..{:#x} {:?}",
+                region.start, region.end, region.flags
+            );
+            return;
+        }
+        if region.writable() && region.executable() {
+            report.writable_executable += 1;
+            warn!(
+                "[page audit] writable and executable {:#x}..{:#x} -> {:#x}",
+                region.start, region.end, region.physical
+            );
+        }
+        if region.start >= KERNEL_SPACE_START && region.user_accessible() {
+            report.user_kernel += 1;
+            warn!(
+                "[page audit] user accessible kernel page {:#x}..{:#x}",
+                region.start, region.end
+            );
+        }
+    });
+
+    report.section_mismatches = check_sections();
+
+    if report.is_clean() {
+        info!("[page audit] {} regions, no issues", report.regions);
+    } else {
+        warn!("[page audit] {:?}", report);
+    }
+    report
+}
+
+/// Call `f` for every mapped region of the address space, in address order.
+pub fn walk(mut f: impl FnMut(&Region)) {
+    let (frame, _) = Cr3::read();
+    let mut current: Option<Region> = None;
+
+    walk_table(
+        frame.start_address().as_u64(),
+        0,
+        0,
+        INHERITED - PageTableFlags::NO_EXECUTE,
+        &mut |start, physical, size, flags| {
+            if let Some(region) = current.as_mut() {
+                if region.extends(start, physical, flags) {
+                    region.end += size;
+                    return;
+                }
+                f(region);
+            }
+            current = Some(Region {
+                start,
+                end: start + size,
+                physical,
+                flags,
+            });
+        },
+    );
+
+    if let Some(region) = current {
+        f(&region);
+    }
+}
+
+fn walk_table(
+    table: u64,
+    level: usize,
+    base: u64,
+    parent: PageTableFlags,
+    f: &mut impl FnMut(u64, u64, u64, PageTableFlags),
+) {
+    if table >= PHYSICAL_MEMORY_SIZE.load(Ordering::Relaxed) {
+        warn!("[page audit] page table at {:#x} is not reachable", table);
+        return;
+    }
+    let table = unsafe { &*((table + PHYSICAL_MEMORY_OFFSET) as *const PageTable) };
+
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let mut start = base + index as u64 * ENTRY_SIZE[level];
+        // sign extend the upper half
+        if level == 0 && index >= 256 {
+            start |= 0xFFFF_0000_0000_0000;
+        }
+
+        let effective =
+            (parent & flags & (PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE))
+                | ((parent | flags) & PageTableFlags::NO_EXECUTE);
+
+        let leaf = level == 3 || (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE));
+        if leaf {
+            let flags =
+                (flags - INHERITED - PageTableFlags::ACCESSED - PageTableFlags::DIRTY) | effective;
+            f(start, entry.addr().as_u64(), ENTRY_SIZE[level], flags);
+        } else {
+            walk_table(entry.addr().as_u64(), level + 1, start, effective, f);
+        }
+    }
+}
+
+/// Compare the mappings of each kernel section with its ELF permissions.
+fn check_sections() -> usize {
+    let sections = [
+        (
+            ".rodata",
+            core::ptr::addr_of!(__rodata_start) as u64,
+            core::ptr::addr_of!(__rodata_end) as u64,
+            false,
+            false,
+        ),
+        (
+            ".text",
+            core::ptr::addr_of!(__text_start) as u64,
+            core::ptr::addr_of!(__text_end) as u64,
+            false,
+            true,
+        ),
+        (
+            ".data",
+            core::ptr::addr_of!(__data_start) as u64,
+            core::ptr::addr_of!(__data_end) as u64,
+            true,
+            false,
+        ),
+    ];
+
+    let mut mismatches = 0;
+    for (name, start, end, writable, executable) in sections {
+        let start = start & !0xfff;
+        let end = (end + 0xfff) & !0xfff;
+        let mut mapped = 0;
+
+        walk(|region| {
+            if region.end <= start || region.start >= end {
+                return;
+            }
+            mapped += region.end.min(end) - region.start.max(start);
+            if region.writable() != writable || region.executable() != executable {
+                mismatches += 1;
+                warn!(
+                    "[page audit] {} at {:#x}..{:#x} is mapped {:?}",
+                    name, region.start, region.end, region.flags
+                );
+            }
+        });
+
+        if mapped != end - start {
+            mismatches += 1;
+            warn!(
+                "[page audit] {} is only partially mapped ({:#x} of {:#x} bytes)",
+                name,
+                mapped,
+                end - start
+            );
+        }
+    }
+    mismatches
+}
@@ -0,0 +1,282 @@
+//! PCI devices, found through the legacy configuration ports.
+//!
+//! The ports reach the first 256 bytes of each function's configuration space,
+//! which covers the header and the capability list. Extended configuration space
+//! needs ECAM from the ACPI MCFG table, which the kernel does not read yet.
+
+use log::info;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+const VENDOR_ID: u8 = 0x00;
+const DEVICE_ID: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+const STATUS: u8 = 0x06;
+const CLASS: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0e;
+const BAR0: u8 = 0x10;
+const SECONDARY_BUS: u8 = 0x19;
+const CAPABILITIES_POINTER: u8 = 0x34;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// Keeps the device off its legacy INTx line, for MSI and MSI-X.
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+/// More than fit in 256 bytes, so a looping list still ends.
+const MAX_CAPABILITIES: usize = 48;
+
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const HEADER_BRIDGE: u8 = 0x01;
+/// Read from the vendor ID of a function that is not there.
+const NO_VENDOR: u16 = 0xffff;
+
+const MAX_DEVICES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    fn config_address(&self, offset: u8) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xfc) as u32
+    }
+
+    pub fn read32(&self, offset: u8) -> u32 {
+        // the address and data ports are one transaction
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        })
+    }
+
+    pub fn write32(&self, offset: u8, value: u32) {
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        })
+    }
+
+    pub fn read16(&self, offset: u8) -> u16 {
+        (self.read32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read8(&self, offset: u8) -> u8 {
+        (self.read32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn write16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read32(offset) & !(0xffff << shift);
+        self.write32(offset, dword | (value as u32) << shift);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { address: u64, size: u64 },
+    Io { port: u16, size: u16 },
+}
+
+/// One function of a device on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+impl PciDevice {
+    /// Base address register `index`, sized by writing all ones to it.
+    ///
+    /// `None` for an unused register and the upper half of a 64-bit one.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if index >= 6 {
+            return None;
+        }
+        let offset = BAR0 + index * 4;
+        let address = self.address;
+        let command = address.read16(COMMAND);
+        // a moving BAR must not decode while it is probed
+        address.write16(
+            COMMAND,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+
+        let low = address.read32(offset);
+        address.write32(offset, u32::MAX);
+        let low_mask = address.read32(offset);
+        address.write32(offset, low);
+
+        let bar = if low & 1 != 0 {
+            let size = (!(low_mask & !0x3) as u16).wrapping_add(1);
+            (low_mask != 0).then_some(Bar::Io {
+                port: (low & !0x3) as u16,
+                size,
+            })
+        } else if low & 0x6 == 0x4 && index < 5 {
+            let high = address.read32(offset + 4);
+            address.write32(offset + 4, u32::MAX);
+            let high_mask = address.read32(offset + 4);
+            address.write32(offset + 4, high);
+            let mask = (high_mask as u64) << 32 | (low_mask & !0xf) as u64;
+            (mask != 0).then_some(Bar::Memory {
+                address: (high as u64) << 32 | (low & !0xf) as u64,
+                size: !mask + 1,
+            })
+        } else {
+            let mask = low_mask & !0xf;
+            (mask != 0).then_some(Bar::Memory {
+                address: (low & !0xf) as u64,
+                size: (!mask).wrapping_add(1) as u64,
+            })
+        };
+
+        address.write16(COMMAND, command);
+        bar
+    }
+
+    /// Set bits of the command register, e.g. to let the device master DMA.
+    pub fn enable(&self, bits: u16) {
+        let command = self.address.read16(COMMAND);
+        self.address.write16(COMMAND, command | bits);
+    }
+
+    /// Clear bits of the command register.
+    pub fn disable(&self, bits: u16) {
+        let command = self.address.read16(COMMAND);
+        self.address.write16(COMMAND, command & !bits);
+    }
+
+    /// The bus behind a PCI-to-PCI bridge, `None` for anything else.
+    pub fn secondary_bus(&self) -> Option<u8> {
+        (self.address.read8(HEADER_TYPE) & 0x7f == HEADER_BRIDGE)
+            .then(|| self.address.read8(SECONDARY_BUS))
+    }
+
+    /// Call `visit` with the ID and configuration offset of each capability, in list order.
+    pub fn capabilities(&self, mut visit: impl FnMut(u8, u8)) {
+        let address = self.address;
+        if address.read16(STATUS) & STATUS_CAPABILITIES == 0 {
+            return;
+        }
+        // the low two bits are reserved
+        let mut offset = address.read8(CAPABILITIES_POINTER) & !0x3;
+        for _ in 0..MAX_CAPABILITIES {
+            // the header is all below 0x40
+            if offset < 0x40 {
+                break;
+            }
+            visit(address.read8(offset), offset);
+            offset = address.read8(offset + 1) & !0x3;
+        }
+    }
+
+    /// Offset of the first capability with ID `id`.
+    pub fn capability(&self, id: u8) -> Option<u8> {
+        let mut found = None;
+        self.capabilities(|capability, offset| {
+            if capability == id && found.is_none() {
+                found = Some(offset);
+            }
+        });
+        found
+    }
+}
+
+static DEVICES: Mutex<[Option<PciDevice>; MAX_DEVICES]> = Mutex::new([None; MAX_DEVICES]);
+
+fn scan_bus(bus: u8, devices: &mut [Option<PciDevice>; MAX_DEVICES], found: &mut usize) {
+    for device in 0..32 {
+        for function in 0..8 {
+            let address = PciAddress {
+                bus,
+                device,
+                function,
+            };
+            let vendor = address.read16(VENDOR_ID);
+            if vendor == NO_VENDOR {
+                if function == 0 {
+                    break;
+                }
+                continue;
+            }
+            let class = address.read32(CLASS);
+            let header = address.read8(HEADER_TYPE);
+            if let Some(slot) = devices.get_mut(*found) {
+                *slot = Some(PciDevice {
+                    address,
+                    vendor,
+                    device: address.read16(DEVICE_ID),
+                    class: (class >> 24) as u8,
+                    subclass: (class >> 16) as u8,
+                    prog_if: (class >> 8) as u8,
+                });
+            }
+            *found += 1;
+
+            if header & 0x7f == HEADER_BRIDGE {
+                let secondary = address.read8(SECONDARY_BUS);
+                if secondary > bus {
+                    scan_bus(secondary, devices, found);
+                }
+            }
+            if function == 0 && header & HEADER_MULTIFUNCTION == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Walk the buses behind the host bridge and remember what is there.
+pub fn init() {
+    let mut devices = [None; MAX_DEVICES];
+    let mut found = 0;
+    scan_bus(0, &mut devices, &mut found);
+    if found > MAX_DEVICES {
+        info!(
+            "[pci] {} functions, only the first {} are kept",
+            found, MAX_DEVICES
+        );
+    }
+    for device in devices.iter().flatten() {
+        info!(
+            "[pci] {:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}{:02x}{:02x}",
+            device.address.bus,
+            device.address.device,
+            device.address.function,
+            device.vendor,
+            device.device,
+            device.class,
+            device.subclass,
+            device.prog_if
+        );
+    }
+    *DEVICES.lock() = devices;
+}
+
+/// Call `visit` with every device found by [`init`].
+pub fn devices(mut visit: impl FnMut(&PciDevice)) {
+    let devices = *DEVICES.lock();
+    for device in devices.iter().flatten() {
+        visit(device);
+    }
+}
@@ -0,0 +1,82 @@
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+pub const MAX_CPUS: usize = 64;
+
+/// Declares the fields of [`PerCpu`] with the value each CPU starts with.
+///
+/// Every field is only touched by its own CPU, atomics keep the area `Sync`
+/// and safe against the interrupt handlers running on that CPU.
+macro_rules! per_cpu {
+    ($($(#[$meta:meta])* $name:ident: $ty:ty = $init:expr;)*) => {
+        /// One CPU's data, `gs:0` points at the area of the running CPU.
+        #[repr(C)]
+        pub struct PerCpu {
+            /// Address of the area itself, has to stay the first field.
+            this: AtomicPtr<PerCpu>,
+            /// Index of the CPU owning the area.
+            pub cpu: AtomicUsize,
+            $($(#[$meta])* pub $name: $ty,)*
+        }
+
+        impl PerCpu {
+            const fn new() -> Self {
+                PerCpu {
+                    this: AtomicPtr::new(ptr::null_mut()),
+                    cpu: AtomicUsize::new(0),
+                    $($name: $init,)*
+                }
+            }
+        }
+    };
+}
+
+per_cpu! {
+    /// Exception and interrupt handlers running, nested ones count once each.
+    interrupt_depth: AtomicUsize = AtomicUsize::new(0);
+    /// Exceptions and interrupts taken since the CPU came up.
+    interrupts: AtomicU64 = AtomicU64::new(0);
+    /// Index + 1 of the driver running, `0` outside driver code.
+    active_driver: AtomicUsize = AtomicUsize::new(0);
+    /// Stack pointer a faulting driver is unwound to.
+    recovery_stack: AtomicU64 = AtomicU64::new(0);
+}
+
+static AREAS: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+
+/// Set up the area of the boot CPU, before anything reads [`current`].
+pub fn init() {
+    bring_up(0);
+}
+
+/// Point GS of the calling CPU at the area of `cpu`.
+///
+/// The kernel always runs with its area in `GS_BASE`. `KERNEL_GS_BASE` holds the
+/// user value, the syscall entry will `swapgs` on the way in and out of the kernel.
+pub fn bring_up(cpu: usize) {
+    let area = &AREAS[cpu];
+    let this = area as *const PerCpu as *mut PerCpu;
+    area.this.store(this, Ordering::Relaxed);
+    area.cpu.store(cpu, Ordering::Relaxed);
+
+    GsBase::write(VirtAddr::from_ptr(this));
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// The area of the running CPU.
+#[inline(always)]
+pub fn current() -> &'static PerCpu {
+    let this: *const PerCpu;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) this,
+            options(nostack, readonly, preserves_flags)
+        );
+        &*this
+    }
+}
@@ -0,0 +1,79 @@
+use x86_64::instructions::port::Port;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+/// Vector of IRQ 0 after [`init`], right above the CPU exceptions.
+pub const IRQ_BASE: u8 = 32;
+/// The line the second PIC is chained to.
+const CASCADE_IRQ: u8 = 2;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+fn data_port(irq: u8) -> (Port<u8>, u8) {
+    if irq < 8 {
+        (Port::new(PIC1_DATA), irq)
+    } else {
+        (Port::new(PIC2_DATA), irq - 8)
+    }
+}
+
+/// Give the PICs time to take a command, writing to the POST port does nothing else.
+fn io_wait() {
+    unsafe { Port::<u8>::new(0x80).write(0) };
+}
+
+/// Move the IRQs to [`IRQ_BASE`] and mask every line, drivers unmask theirs.
+///
+/// The firmware leaves IRQ 0 to 7 on the vectors of CPU exceptions.
+pub fn init() {
+    let writes = [
+        // ICW1: edge triggered, cascaded, ICW4 follows
+        (PIC1_COMMAND, 0x11),
+        (PIC2_COMMAND, 0x11),
+        // ICW2: vector offsets
+        (PIC1_DATA, IRQ_BASE),
+        (PIC2_DATA, IRQ_BASE + 8),
+        // ICW3: where the second PIC hangs and its cascade identity
+        (PIC1_DATA, 1 << CASCADE_IRQ),
+        (PIC2_DATA, CASCADE_IRQ),
+        // ICW4: 8086 mode
+        (PIC1_DATA, 0x01),
+        (PIC2_DATA, 0x01),
+        // everything but the cascade masked
+        (PIC1_DATA, !(1 << CASCADE_IRQ)),
+        (PIC2_DATA, 0xff),
+    ];
+    for (port, value) in writes {
+        unsafe { Port::<u8>::new(port).write(value) };
+        io_wait();
+    }
+}
+
+/// Tell the PICs the handler of `irq` is done, they hold back lower priorities until then.
+pub fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            Port::<u8>::new(PIC2_COMMAND).write(END_OF_INTERRUPT);
+        }
+        Port::<u8>::new(PIC1_COMMAND).write(END_OF_INTERRUPT);
+    }
+}
+
+/// Stop the legacy 8259 PIC from delivering `irq`.
+pub fn mask(irq: u8) {
+    let (mut port, line) = data_port(irq);
+    unsafe {
+        let mask = port.read();
+        port.write(mask | 1 << line);
+    }
+}
+
+pub fn unmask(irq: u8) {
+    let (mut port, line) = data_port(irq);
+    unsafe {
+        let mask = port.read();
+        port.write(mask & !(1 << line));
+    }
+}
@@ -0,0 +1,53 @@
+//! Channel 2 of the 8254 PIT, as a one-shot countdown to time things against.
+//!
+//! Channel 2 is the one gated from port 0x61 rather than wired to IRQ 0, so it
+//! counts without raising interrupts. The speaker it normally drives stays off.
+
+use x86_64::instructions::port::Port;
+
+/// Input clock of every channel.
+pub const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const PORT_B: u16 = 0x61;
+
+const PORT_B_GATE: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUTPUT: u8 = 1 << 5;
+
+/// Channel 2, low then high byte of the count, mode 0 (interrupt on terminal count), binary.
+const COUNTDOWN: u8 = 0b1011_0000;
+
+/// Port reads before a countdown that never ends is given up on, far beyond 55 ms.
+const SPIN_LIMIT: usize = 1_000_000;
+
+/// Start counting `ticks` down from now.
+pub fn start(ticks: u16) {
+    unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let value = port_b.read();
+        port_b.write((value & !PORT_B_SPEAKER) | PORT_B_GATE);
+        Port::<u8>::new(COMMAND).write(COUNTDOWN);
+        let mut channel = Port::<u8>::new(CHANNEL_2);
+        channel.write(ticks as u8);
+        channel.write((ticks >> 8) as u8);
+    }
+}
+
+/// Whether the countdown from [`start`] reached zero.
+pub fn done() -> bool {
+    unsafe { Port::<u8>::new(PORT_B).read() & PORT_B_OUTPUT != 0 }
+}
+
+/// Spin for `ticks`, `false` if the PIT never got there.
+pub fn wait(ticks: u16) -> bool {
+    start(ticks);
+    for _ in 0..SPIN_LIMIT {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
@@ -0,0 +1,342 @@
+//! Power management from what the FADT describes in fixed hardware.
+//!
+//! The reset register restarts the machine. The fixed power button raises the
+//! SCI, which is handed to whoever set an event handler. Sleep states need
+//! their `SLP_TYP` values from `\_S3` and friends in the AML, which nothing here
+//! can read, so only the saving and restoring of state around sleep is in
+//! place. Without AML the general purpose events are kept off, nothing could
+//! run their handler methods.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::{info, warn};
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+use super::efi::{self, ResetType};
+use super::ioapic::{self, Polarity, Trigger};
+use super::sync::IrqMutex;
+use super::{acpi, driver, interrupts, keyboard, lapic, time, vm};
+
+// FADT fields
+const FADT_SCI_INT: usize = 46;
+const FADT_SMI_CMD: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_EVT_BLK: usize = 56;
+const FADT_PM1B_EVT_BLK: usize = 60;
+const FADT_PM1A_CNT_BLK: usize = 64;
+const FADT_PM1B_CNT_BLK: usize = 68;
+const FADT_GPE0_BLK: usize = 80;
+const FADT_GPE1_BLK: usize = 84;
+const FADT_PM1_EVT_LEN: usize = 88;
+const FADT_GPE0_BLK_LEN: usize = 92;
+const FADT_GPE1_BLK_LEN: usize = 93;
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REG: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+/// Where the fields above end, older FADTs stop before the reset register.
+const FADT_RESET_END: usize = 129;
+
+const FLAG_PWR_BUTTON: u32 = 1 << 4;
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+const FLAG_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+// generic address structure
+const GAS_SPACE: usize = 0;
+const GAS_ADDRESS: usize = 4;
+const SPACE_MEMORY: u8 = 0;
+const SPACE_IO: u8 = 1;
+
+const PM1_POWER_BUTTON: u16 = 1 << 8;
+const PM1_CONTROL_SCI_EN: u16 = 1 << 0;
+
+/// The firmware gets this long to switch to ACPI mode.
+const ACPI_ENABLE_TIMEOUT_MS: u64 = 3000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerEvent {
+    /// The fixed power button was pressed.
+    PowerButton,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// No FADT, or a hardware-reduced platform without fixed hardware.
+    NoFadt,
+    /// The reset register is missing or in a space this does not reach.
+    NoResetRegister,
+    /// The sleep type for the state lives in the AML.
+    NoSleepType,
+    /// A driver would not suspend, with its reason.
+    Driver(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepState {
+    /// Suspend to RAM.
+    #[allow(dead_code)]
+    S3,
+}
+
+/// The register blocks of the fixed hardware, by I/O port.
+struct Fadt {
+    sci: u16,
+    flags: u32,
+    smi_command: u16,
+    acpi_enable: u8,
+    /// PM1a and PM1b, a zero port for the one that is not there.
+    pm1_event: [u16; 2],
+    pm1_control: [u16; 2],
+    /// Bytes in each PM1 event block, the status half then the enable half.
+    pm1_event_len: u8,
+    gpe: [(u16, u8); 2],
+    /// Address space, address and value for a reset.
+    reset: Option<(u8, u64, u8)>,
+}
+
+impl Fadt {
+    fn parse(table: &[u8]) -> Self {
+        let port = |offset| acpi::read_u32(table, offset) as u16;
+        let flags = acpi::read_u32(table, FADT_FLAGS);
+        let reset = (flags & FLAG_RESET_REG_SUP != 0 && table.len() >= FADT_RESET_END).then(|| {
+            (
+                table[FADT_RESET_REG + GAS_SPACE],
+                acpi::read_u64(table, FADT_RESET_REG + GAS_ADDRESS),
+                table[FADT_RESET_VALUE],
+            )
+        });
+        Fadt {
+            sci: acpi::read_u16(table, FADT_SCI_INT),
+            flags,
+            smi_command: port(FADT_SMI_CMD),
+            acpi_enable: table[FADT_ACPI_ENABLE],
+            pm1_event: [port(FADT_PM1A_EVT_BLK), port(FADT_PM1B_EVT_BLK)],
+            pm1_control: [port(FADT_PM1A_CNT_BLK), port(FADT_PM1B_CNT_BLK)],
+            pm1_event_len: table[FADT_PM1_EVT_LEN],
+            gpe: [
+                (port(FADT_GPE0_BLK), table[FADT_GPE0_BLK_LEN]),
+                (port(FADT_GPE1_BLK), table[FADT_GPE1_BLK_LEN]),
+            ],
+            reset,
+        }
+    }
+
+    fn pm1_blocks(&self) -> impl Iterator<Item = u16> + '_ {
+        self.pm1_event.iter().copied().filter(|port| *port != 0)
+    }
+
+    /// The PM1 status bits set in either block, cleared as they are read.
+    fn take_pm1_status(&self) -> u16 {
+        let mut status = 0;
+        for block in self.pm1_blocks() {
+            let mut port = Port::<u16>::new(block);
+            unsafe {
+                let bits = port.read();
+                // status bits clear when ones are written to them
+                port.write(bits);
+                status |= bits;
+            }
+        }
+        status
+    }
+
+    fn set_pm1_enable(&self, enable: u16) {
+        let offset = self.pm1_event_len as u16 / 2;
+        for block in self.pm1_blocks() {
+            unsafe { Port::<u16>::new(block + offset).write(enable) };
+        }
+    }
+
+    /// Turn every general purpose event off and clear what is pending.
+    fn quiet_gpes(&self) {
+        for (block, len) in self.gpe.iter().filter(|(block, _)| *block != 0) {
+            let half = *len as u16 / 2;
+            for index in 0..half {
+                unsafe {
+                    Port::<u8>::new(block + half + index).write(0);
+                    Port::<u8>::new(block + index).write(0xff);
+                }
+            }
+        }
+    }
+
+    fn sci_enabled(&self) -> bool {
+        self.pm1_control[0] != 0
+            && unsafe { Port::<u16>::new(self.pm1_control[0]).read() } & PM1_CONTROL_SCI_EN != 0
+    }
+
+    /// Ask the firmware to hand the fixed hardware over, as it is left in legacy mode.
+    fn enable_acpi(&self) -> bool {
+        if self.sci_enabled() {
+            return true;
+        }
+        if self.smi_command == 0 || self.acpi_enable == 0 {
+            return false;
+        }
+        unsafe { Port::<u8>::new(self.smi_command).write(self.acpi_enable) };
+        for _ in 0..ACPI_ENABLE_TIMEOUT_MS {
+            if self.sci_enabled() {
+                return true;
+            }
+            time::delay_ns(1_000_000);
+        }
+        false
+    }
+}
+
+static FADT: Once<Fadt> = Once::new();
+static EVENT_HANDLER: IrqMutex<Option<fn(PowerEvent)>> = IrqMutex::new(None);
+static POWER_BUTTON_PRESSES: AtomicU64 = AtomicU64::new(0);
+
+/// Read the FADT, switch to ACPI mode and take the power button's SCI.
+///
+/// After [`acpi::init`], [`ioapic::init`] and [`time::calibrate`].
+pub fn init() {
+    let Some(table) = acpi::find(b"FACP") else {
+        warn!("[power] no FADT");
+        return;
+    };
+    if table.len() < FADT_FLAGS + 4 {
+        warn!("[power] FADT too short");
+        return;
+    }
+    let fadt = FADT.call_once(|| Fadt::parse(table));
+    if fadt.reset.is_none() {
+        info!("[power] no reset register");
+    }
+    if fadt.flags & FLAG_HW_REDUCED_ACPI != 0 {
+        info!("[power] hardware-reduced ACPI, no fixed events");
+        return;
+    }
+    if fadt.flags & FLAG_PWR_BUTTON != 0 {
+        info!("[power] the power button is a control method device, not handled");
+        return;
+    }
+    if fadt.pm1_event[0] == 0 || fadt.pm1_event_len < 4 {
+        warn!("[power] no PM1 event block");
+        return;
+    }
+    if !fadt.enable_acpi() {
+        warn!("[power] the firmware did not switch to ACPI mode");
+        return;
+    }
+    fadt.set_pm1_enable(0);
+    fadt.quiet_gpes();
+    fadt.take_pm1_status();
+    if let Err(error) = route_sci(fadt.sci) {
+        warn!("[power] cannot route SCI {}: {:?}", fadt.sci, error);
+        return;
+    }
+    fadt.set_pm1_enable(PM1_POWER_BUTTON);
+    info!("[power] power button on SCI {}", fadt.sci);
+}
+
+/// The SCI is a shared, level triggered, active low interrupt unless the MADT overrides it.
+fn route_sci(sci: u16) -> Result<u8, ioapic::IoApicError> {
+    if sci < 16 {
+        return ioapic::route_isa(sci as u8, sci_interrupt);
+    }
+    let vector = interrupts::allocate_vector(sci_interrupt).ok_or(ioapic::IoApicError::NoVector)?;
+    ioapic::route(sci as u32, vector, Polarity::Low, Trigger::Level)
+        .inspect_err(|_| interrupts::free_vector(vector))?;
+    Ok(vector)
+}
+
+fn sci_interrupt() {
+    let Some(fadt) = FADT.get() else {
+        return;
+    };
+    let status = fadt.take_pm1_status();
+    if status & PM1_POWER_BUTTON != 0 {
+        POWER_BUTTON_PRESSES.fetch_add(1, Ordering::Relaxed);
+        deliver(PowerEvent::PowerButton);
+    }
+}
+
+fn deliver(event: PowerEvent) {
+    match *EVENT_HANDLER.lock() {
+        Some(handler) => handler(event),
+        None => info!("[power] {:?}, nothing handles it", event),
+    }
+}
+
+/// Have `handler` called with each power event, from the SCI handler.
+// until something registers, power events are only logged
+#[allow(dead_code)]
+pub fn set_event_handler(handler: fn(PowerEvent)) {
+    *EVENT_HANDLER.lock() = Some(handler);
+}
+
+/// Times the power button was pressed since [`init`].
+// nothing asks for the count yet, the presses are logged
+#[allow(dead_code)]
+pub fn power_button_presses() -> u64 {
+    POWER_BUTTON_PRESSES.load(Ordering::Relaxed)
+}
+
+/// Write the FADT's reset value to its reset register.
+fn acpi_reset() -> Result<(), PowerError> {
+    let (space, address, value) = FADT
+        .get()
+        .and_then(|fadt| fadt.reset)
+        .ok_or(PowerError::NoResetRegister)?;
+    match space {
+        SPACE_IO => unsafe { Port::<u8>::new(address as u16).write(value) },
+        SPACE_MEMORY => {
+            let page = address & !0xfff;
+            let base = vm::map_mmio(page, 0x1000).map_err(|_| PowerError::NoResetRegister)?;
+            unsafe { ((base + (address - page)) as *mut u8).write_volatile(value) };
+        }
+        _ => return Err(PowerError::NoResetRegister),
+    }
+    Ok(())
+}
+
+/// Restart the machine through the reset register, the firmware or the i8042,
+/// only returns if none of them did.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn reboot() {
+    if acpi_reset().is_ok() {
+        // the write takes a moment to land
+        time::delay_ns(100_000_000);
+        warn!("[power] the reset register did not reset");
+    }
+    efi::reset_system(ResetType::Cold);
+    keyboard::reset_cpu();
+}
+
+/// What the kernel set aside to come back from a sleep state.
+pub struct Saved {
+    lapic: Option<lapic::Saved>,
+}
+
+/// Suspend the drivers and save the local APIC, ready for the machine to sleep.
+pub fn prepare_sleep(_state: SleepState) -> Result<Saved, PowerError> {
+    if FADT.get().is_none() {
+        return Err(PowerError::NoFadt);
+    }
+    driver::suspend_all().map_err(PowerError::Driver)?;
+    Ok(Saved {
+        lapic: lapic::save(),
+    })
+}
+
+/// Undo [`prepare_sleep`] after waking, or after sleep did not happen.
+pub fn finish_wake(saved: Saved) {
+    if let Some(lapic) = &saved.lapic {
+        lapic::restore(lapic);
+    }
+    driver::resume_all();
+}
+
+/// Put the machine in `state` and return once it woke up.
+///
+/// Entering a sleep state writes its `SLP_TYP` to PM1 control, the value only
+/// the AML has, so for now this goes through the preparation, undoes it and fails.
+// not offered to the shell while it can only fail
+#[allow(dead_code)]
+pub fn suspend(state: SleepState) -> Result<(), PowerError> {
+    let saved = prepare_sleep(state)?;
+    finish_wake(saved);
+    Err(PowerError::NoSleepType)
+}
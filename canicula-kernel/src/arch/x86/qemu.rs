@@ -0,0 +1,30 @@
+use canicula_common::qemu::{TestExit, TestResult};
+use x86_64::instructions::port::Port;
+
+/// I/O port of QEMU's `isa-debug-exit` device, see `make test-ext4`.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// QEMU's `isa-debug-exit` device, which exits with `(code << 1) | 1` when `code`
+/// is written to it.
+pub struct DebugExit {
+    port: u16,
+}
+
+impl DebugExit {
+    pub const fn new(port: u16) -> Self {
+        DebugExit { port }
+    }
+}
+
+impl TestExit for DebugExit {
+    fn exit(&self, result: TestResult) -> ! {
+        let code = (result.host_status() - 1) >> 1;
+        unsafe { Port::new(self.port).write(code) };
+
+        loop {
+            super::hlt();
+        }
+    }
+}
+
+pub const QEMU_EXIT_HANDLE: DebugExit = DebugExit::new(DEBUG_EXIT_PORT);
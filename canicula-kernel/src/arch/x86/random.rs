@@ -0,0 +1,93 @@
+use core::arch::x86_64::{_rdrand64_step, _rdtsc};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::{cpu, rtc};
+
+const RDRAND_RETRIES: usize = 10;
+
+lazy_static! {
+    static ref GENERATOR: Mutex<Xoshiro256> = Mutex::new(Xoshiro256::from_seed(seed()));
+}
+
+/// A hardware random number, `None` if RDRAND is missing or keeps failing.
+pub fn hardware_u64() -> Option<u64> {
+    if !cpu::features().rdrand {
+        return None;
+    }
+
+    for _ in 0..RDRAND_RETRIES {
+        let mut value = 0;
+        if unsafe { rdrand64(&mut value) } == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand64(value: &mut u64) -> i32 {
+    _rdrand64_step(value)
+}
+
+fn seed() -> u64 {
+    let timestamp = rtc::read().to_unix_timestamp() as u64;
+    let tsc = unsafe { _rdtsc() };
+    let mut seed = tsc.rotate_left(32) ^ timestamp;
+    if let Some(hardware) = hardware_u64() {
+        seed ^= hardware;
+    } else {
+        log::warn!("[random] RDRAND unavailable, seeding from TSC and RTC only");
+    }
+    seed
+}
+
+/// xoshiro256** seeded through splitmix64, not suitable for cryptography.
+struct Xoshiro256 {
+    state: [u64; 4],
+}
+
+impl Xoshiro256 {
+    fn from_seed(mut seed: u64) -> Self {
+        let mut state = [0; 4];
+        for word in state.iter_mut() {
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *word = z ^ (z >> 31);
+        }
+        Xoshiro256 { state }
+    }
+
+    fn next(&mut self) -> u64 {
+        let result = self.state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.state[1] << 17;
+
+        self.state[2] ^= self.state[0];
+        self.state[3] ^= self.state[1];
+        self.state[1] ^= self.state[2];
+        self.state[0] ^= self.state[3];
+        self.state[2] ^= t;
+        self.state[3] = self.state[3].rotate_left(45);
+
+        result
+    }
+}
+
+pub fn next_u64() -> u64 {
+    GENERATOR.lock().next()
+}
+
+/// A uniformly distributed value in `0..bound`, `bound` must not be zero.
+pub fn below(bound: u64) -> u64 {
+    // reject the biased tail
+    let zone = u64::MAX - u64::MAX % bound;
+    loop {
+        let value = next_u64();
+        if value < zone {
+            return value % bound;
+        }
+    }
+}
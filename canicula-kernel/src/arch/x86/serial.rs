@@ -0,0 +1,78 @@
+use core::fmt::{self, Write};
+
+use x86_64::instructions::port::Port;
+
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// A 16550 compatible UART on the legacy I/O ports.
+pub struct SerialPort {
+    data: Port<u8>,
+    interrupt_enable: Port<u8>,
+    fifo_control: Port<u8>,
+    line_control: Port<u8>,
+    modem_control: Port<u8>,
+    line_status: Port<u8>,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        SerialPort {
+            data: Port::new(base),
+            interrupt_enable: Port::new(base + 1),
+            fifo_control: Port::new(base + 2),
+            line_control: Port::new(base + 3),
+            modem_control: Port::new(base + 4),
+            line_status: Port::new(base + 5),
+        }
+    }
+
+    /// 38400 baud, 8 data bits, no parity, one stop bit.
+    pub fn init(&mut self) {
+        unsafe {
+            self.interrupt_enable.write(0x00);
+            // enable DLAB and set the divisor to 3
+            self.line_control.write(0x80);
+            self.data.write(0x03);
+            self.interrupt_enable.write(0x00);
+            // 8N1, clear DLAB
+            self.line_control.write(0x03);
+            // enable and clear FIFOs with a 14 byte threshold
+            self.fifo_control.write(0xc7);
+            // data terminal ready, request to send, OUT2
+            self.modem_control.write(0x0b);
+        }
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        unsafe {
+            while self.line_status.read() & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            self.data.write(byte);
+        }
+    }
+
+    /// Wait for the next byte from the line.
+    pub fn receive(&mut self) -> u8 {
+        unsafe {
+            while self.line_status.read() & LINE_STATUS_DATA_READY == 0 {
+                core::hint::spin_loop();
+            }
+            self.data.read()
+        }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.send(*byte);
+        }
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
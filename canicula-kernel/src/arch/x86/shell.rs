@@ -0,0 +1,388 @@
+//! A shell on the console terminal to poke at the running kernel.
+//!
+//! There is no scheduler yet, so the boot CPU runs the shell once the kernel
+//! is up and idles after `exit`.
+
+use canicula_common::entry::{BootInfo, MemoryKind};
+
+use super::device::{self, Device};
+use super::driver::{self, DriverState};
+use super::efi::{self, ResetType};
+use super::frames::{self, Owner, FRAME_SIZE};
+use super::inet::{self, Ipv4Address, NetError};
+use super::{log_buffer, logging, power, time, tty, vfs};
+use crate::{print, println};
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    /// Takes the rest of the line, returns `false` to leave the shell.
+    run: fn(&'static BootInfo, &str) -> bool,
+}
+
+const COMMANDS: [Command; 14] = [
+    Command {
+        name: "help",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "mem",
+        help: "summarize the memory map",
+        run: mem,
+    },
+    Command {
+        name: "memstat",
+        help: "show the frames each subsystem holds",
+        run: memstat,
+    },
+    Command {
+        name: "date",
+        help: "show the time of the real time clock and the uptime",
+        run: date,
+    },
+    Command {
+        name: "cmdline",
+        help: "show the kernel command line",
+        run: cmdline,
+    },
+    Command {
+        name: "devices",
+        help: "show the device tree and the drivers bound to it",
+        run: devices,
+    },
+    Command {
+        name: "ls",
+        help: "list a directory, / by default",
+        run: ls,
+    },
+    Command {
+        name: "cat",
+        help: "print a file",
+        run: cat,
+    },
+    Command {
+        name: "dmesg",
+        help: "show the kernel log, clear to empty it",
+        run: dmesg,
+    },
+    Command {
+        name: "loglevel",
+        help: "show or set the most verbose level on the console",
+        run: loglevel,
+    },
+    Command {
+        name: "ping",
+        help: "send four echo requests to an IPv4 address",
+        run: ping,
+    },
+    Command {
+        name: "reboot",
+        help: "restart the machine",
+        run: reboot,
+    },
+    Command {
+        name: "poweroff",
+        help: "turn the machine off",
+        run: poweroff,
+    },
+    Command {
+        name: "exit",
+        help: "leave the shell and idle",
+        run: |_, _| false,
+    },
+];
+
+/// Network polls to wait for each echo reply.
+const PING_POLLS: usize = 200_000;
+
+const MEMORY_KINDS: [MemoryKind; 6] = [
+    MemoryKind::Usable,
+    MemoryKind::Bootloader,
+    MemoryKind::Reserved,
+    MemoryKind::AcpiReclaimable,
+    MemoryKind::AcpiNvs,
+    MemoryKind::RuntimeServices,
+];
+
+/// Read and run commands until `exit`.
+pub fn run(boot_info: &'static BootInfo) {
+    println!("[shell] type help for a list of commands");
+    let mut line = [0u8; 256];
+    loop {
+        print!("canicula> ");
+        let len = tty::read(&mut line);
+        let Ok(line) = core::str::from_utf8(&line[..len]) else {
+            println!("[shell] input is not UTF-8");
+            continue;
+        };
+        let line = line.trim();
+        let (name, arguments) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        if name.is_empty() {
+            continue;
+        }
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => {
+                if !(command.run)(boot_info, arguments.trim()) {
+                    return;
+                }
+            }
+            None => println!("{}: unknown command, try help", name),
+        }
+    }
+}
+
+fn help(_: &'static BootInfo, _: &str) -> bool {
+    for command in &COMMANDS {
+        println!("  {:<10} {}", command.name, command.help);
+    }
+    true
+}
+
+fn mem(boot_info: &'static BootInfo, _: &str) -> bool {
+    let memory_map = unsafe { boot_info.memory_map.entries() };
+    for kind in MEMORY_KINDS {
+        let (regions, size) = memory_map
+            .iter()
+            .filter(|entry| entry.kind == kind)
+            .fold((0, 0), |(regions, size), entry| {
+                (regions + 1, size + entry.region.size)
+            });
+        if regions > 0 {
+            println!(
+                "  {:>10} KiB in {:>3} regions, {:?}",
+                size / 1024,
+                regions,
+                kind
+            );
+        }
+    }
+    println!(
+        "  {} MiB of physical memory mapped at {:#x}",
+        boot_info.physical_memory_size >> 20,
+        boot_info.physical_memory_offset
+    );
+    if let Some(stats) = frames::stats() {
+        println!(
+            "  {} of {} frames free, {} KiB",
+            stats.free_frames,
+            stats.total_frames,
+            stats.free_frames * FRAME_SIZE / 1024
+        );
+        for (order, blocks) in stats.free_blocks.iter().enumerate() {
+            if *blocks > 0 {
+                println!("  {:>10} free blocks of {} frames", blocks, 1 << order);
+            }
+        }
+    }
+    true
+}
+
+fn memstat(_: &'static BootInfo, _: &str) -> bool {
+    let Some(stats) = frames::stats() else {
+        println!("  no frame allocator");
+        return true;
+    };
+    println!(
+        "  {:<10} {:>10} {:>10} {:>8} {:>8}",
+        "owner", "KiB", "peak KiB", "allocs", "frees"
+    );
+    let mut held = 0;
+    for owner in Owner::ALL {
+        let usage = stats.owners[owner as usize];
+        held += usage.frames;
+        println!(
+            "  {:<10} {:>10} {:>10} {:>8} {:>8}",
+            owner.name(),
+            usage.frames * FRAME_SIZE / 1024,
+            usage.peak_frames * FRAME_SIZE / 1024,
+            usage.allocations,
+            usage.frees
+        );
+    }
+    println!(
+        "  {} KiB held, {} KiB free of {} KiB",
+        held * FRAME_SIZE / 1024,
+        stats.free_frames * FRAME_SIZE / 1024,
+        stats.total_frames * FRAME_SIZE / 1024
+    );
+    true
+}
+
+fn date(_: &'static BootInfo, _: &str) -> bool {
+    println!("  {}", time::now());
+    if time::tsc_frequency().is_some() {
+        let uptime = time::monotonic_ns();
+        println!(
+            "  up {}.{:06} s",
+            uptime / 1_000_000_000,
+            uptime / 1000 % 1_000_000
+        );
+    }
+    true
+}
+
+fn cmdline(boot_info: &'static BootInfo, _: &str) -> bool {
+    // the loader's pages stay identity mapped
+    println!("  {}", unsafe { boot_info.cmdline() });
+    true
+}
+
+fn ls(_: &'static BootInfo, path: &str) -> bool {
+    let path = if path.is_empty() { "/" } else { path };
+    let listed = vfs::read_dir(path, |inode, name| {
+        print!("  {:>8} ", inode);
+        tty::write(name);
+        println!();
+        true
+    });
+    if let Err(error) = listed {
+        println!("ls: {}: {:?}", path, error);
+    }
+    true
+}
+
+fn cat(_: &'static BootInfo, path: &str) -> bool {
+    let fd = match vfs::open(path) {
+        Ok(fd) => fd,
+        Err(error) => {
+            println!("cat: {}: {:?}", path, error);
+            return true;
+        }
+    };
+    let mut buffer = [0u8; 512];
+    loop {
+        match vfs::read(fd, &mut buffer) {
+            Ok(0) => break,
+            Ok(read) => {
+                tty::write(&buffer[..read]);
+            }
+            Err(error) => {
+                println!("cat: {}: {:?}", path, error);
+                break;
+            }
+        }
+    }
+    let _ = vfs::close(fd);
+    true
+}
+
+fn devices(_: &'static BootInfo, _: &str) -> bool {
+    if let Some(root) = device::root() {
+        show_device(&root, 0);
+    }
+    true
+}
+
+fn show_device(device: &Device, depth: usize) {
+    match driver::bound(device.id) {
+        Some((_, name, state)) => {
+            let state = match state {
+                DriverState::Probing => "probing",
+                DriverState::Running => "running",
+                DriverState::ProbeFailed(_) => "probe failed",
+                DriverState::Faulted(_) => "faulted",
+                DriverState::Suspended => "suspended",
+            };
+            println!(
+                "  {:width$}{}  [{} {}]",
+                "",
+                device,
+                name,
+                state,
+                width = depth * 2
+            )
+        }
+        None => println!("  {:width$}{}", "", device, width = depth * 2),
+    }
+    device::children(device.id, |child| show_device(child, depth + 1));
+}
+
+fn dmesg(_: &'static BootInfo, argument: &str) -> bool {
+    match argument {
+        "" => {}
+        "clear" => {
+            log_buffer::clear();
+            return true;
+        }
+        _ => {
+            println!("dmesg: expected nothing or clear");
+            return true;
+        }
+    }
+    let (first, next) = log_buffer::range();
+    for sequence in first..next {
+        let Some(entry) = log_buffer::get(sequence) else {
+            continue;
+        };
+        println!(
+            "\u{1B}[{}m[{:>5}.{:06}] [{:>5}] {}\u{1B}[0m",
+            logging::color(entry.level),
+            entry.time / 1_000_000_000,
+            entry.time / 1000 % 1_000_000,
+            entry.level,
+            entry.message()
+        );
+    }
+    true
+}
+
+fn loglevel(_: &'static BootInfo, argument: &str) -> bool {
+    if argument.is_empty() {
+        println!("  {}", logging::console_level());
+        return true;
+    }
+    match argument.parse() {
+        Ok(level) => logging::set_console_level(level),
+        Err(_) => println!("loglevel: expected off, error, warn, info, debug or trace"),
+    }
+    true
+}
+
+fn ping(_: &'static BootInfo, address: &str) -> bool {
+    let Some(destination) = Ipv4Address::parse(address) else {
+        println!("ping: expected an address like 10.0.2.2");
+        return true;
+    };
+    let identifier = time::unix_timestamp() as u16;
+    for sequence in 0..4 {
+        let mut sent = false;
+        let mut replied = false;
+        for _ in 0..PING_POLLS {
+            if !sent {
+                match inet::send_echo(destination, identifier, sequence) {
+                    Ok(()) => sent = true,
+                    // the ARP reply comes in with a poll
+                    Err(NetError::Unresolved) => {}
+                    Err(error) => {
+                        println!("ping: {:?}", error);
+                        return true;
+                    }
+                }
+            }
+            inet::poll();
+            if sent && inet::echo_replied(identifier, sequence) {
+                replied = true;
+                break;
+            }
+        }
+        if replied {
+            println!("  reply from {}: seq={}", destination, sequence);
+        } else {
+            println!("  no reply from {}: seq={}", destination, sequence);
+        }
+    }
+    true
+}
+
+fn reboot(_: &'static BootInfo, _: &str) -> bool {
+    power::reboot();
+    println!("[shell] the machine did not reset");
+    true
+}
+
+fn poweroff(_: &'static BootInfo, _: &str) -> bool {
+    efi::reset_system(ResetType::Shutdown);
+    println!("[shell] the machine did not power off");
+    true
+}
@@ -0,0 +1,211 @@
+//! Shared memory objects: frames that several mappings reach at once.
+//!
+//! An object lives while its creator holds it or anything maps it, the last
+//! one to let go frees its frames. Mappings are read-only or writable, never
+//! executable. There are no processes yet, so the kernel's address space is
+//! the only one and every mapping goes in its vmap area.
+
+use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+
+use super::frames::{self, Owner};
+use super::vm::{self, VmError, PAGE_SIZE};
+
+const MAX_OBJECTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    /// A size of zero.
+    Empty,
+    OutOfFrames,
+    /// Every object slot is in use.
+    NoSlot,
+    /// The id is of an object that is gone, or the creator already released it.
+    NoObject,
+    Vm(VmError),
+}
+
+impl From<VmError> for ShmError {
+    fn from(error: VmError) -> Self {
+        ShmError::Vm(error)
+    }
+}
+
+/// An object, which stops working once it is freed even if its slot is reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShmId {
+    index: usize,
+    generation: u32,
+}
+
+/// Where [`map`] put an object, to be given back to [`unmap`].
+#[derive(Debug)]
+pub struct Mapping {
+    pub id: ShmId,
+    pub address: u64,
+    pub size: u64,
+}
+
+// read by whoever lists the objects, nothing does yet
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct ShmStats {
+    pub size: u64,
+    pub mappings: usize,
+    /// The creator has not released it yet.
+    pub held: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Object {
+    generation: u32,
+    physical: u64,
+    pages: u64,
+    held: bool,
+    mappings: usize,
+}
+
+struct Objects {
+    slots: [Option<Object>; MAX_OBJECTS],
+    /// Handed to the next object created, so stale ids do not reach it.
+    generation: u32,
+}
+
+impl Objects {
+    fn get(&mut self, id: ShmId) -> Result<&mut Object, ShmError> {
+        self.slots
+            .get_mut(id.index)
+            .and_then(Option::as_mut)
+            .filter(|object| object.generation == id.generation)
+            .ok_or(ShmError::NoObject)
+    }
+
+    /// Free the object's frames if nothing holds it any more.
+    fn collect(&mut self, id: ShmId) {
+        let Ok(object) = self.get(id) else {
+            return;
+        };
+        if object.held || object.mappings > 0 {
+            return;
+        }
+        frames::free_contiguous(object.physical, object.pages);
+        self.slots[id.index] = None;
+    }
+}
+
+static OBJECTS: Mutex<Objects> = Mutex::new(Objects {
+    slots: [None; MAX_OBJECTS],
+    generation: 0,
+});
+
+/// A new zeroed object of at least `size` bytes, held by the caller until [`release`].
+// waits for processes to share memory between
+#[allow(dead_code)]
+pub fn create(size: u64) -> Result<ShmId, ShmError> {
+    if size == 0 {
+        return Err(ShmError::Empty);
+    }
+    let pages = size.div_ceil(PAGE_SIZE);
+    let mut objects = OBJECTS.lock();
+    let index = objects
+        .slots
+        .iter()
+        .position(Option::is_none)
+        .ok_or(ShmError::NoSlot)?;
+    let physical = frames::allocate_contiguous(pages, Owner::Shm).ok_or(ShmError::OutOfFrames)?;
+    unsafe {
+        core::ptr::write_bytes(
+            frames::to_virtual(physical),
+            0,
+            (pages * PAGE_SIZE) as usize,
+        )
+    };
+    objects.generation = objects.generation.wrapping_add(1);
+    let generation = objects.generation;
+    objects.slots[index] = Some(Object {
+        generation,
+        physical,
+        pages,
+        held: true,
+        mappings: 0,
+    });
+    Ok(ShmId { index, generation })
+}
+
+/// Drop the creator's hold on `id`, the object goes once its last mapping does.
+// nothing holds an object to release
+#[allow(dead_code)]
+pub fn release(id: ShmId) -> Result<(), ShmError> {
+    let mut objects = OBJECTS.lock();
+    let object = objects.get(id)?;
+    if !object.held {
+        return Err(ShmError::NoObject);
+    }
+    object.held = false;
+    objects.collect(id);
+    Ok(())
+}
+
+/// Map the whole of `id`, writable unless `read_only`.
+// nothing maps an object yet
+#[allow(dead_code)]
+pub fn map(id: ShmId, read_only: bool) -> Result<Mapping, ShmError> {
+    let (physical, size) = {
+        let mut objects = OBJECTS.lock();
+        let object = objects.get(id)?;
+        // counted before mapping, so a release meanwhile leaves the frames alone
+        object.mappings += 1;
+        (object.physical, object.pages * PAGE_SIZE)
+    };
+    let mut flags = PageTableFlags::NO_EXECUTE;
+    if !read_only {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    match vm::map_anywhere(physical, size, flags) {
+        Ok(address) => Ok(Mapping { id, address, size }),
+        Err(error) => {
+            put(id);
+            Err(error.into())
+        }
+    }
+}
+
+/// Undo a [`map`].
+// goes with `map`
+#[allow(dead_code)]
+pub fn unmap(mapping: Mapping) -> Result<(), ShmError> {
+    vm::unmap_region(mapping.address, mapping.size)?;
+    put(mapping.id);
+    Ok(())
+}
+
+fn put(id: ShmId) {
+    let mut objects = OBJECTS.lock();
+    if let Ok(object) = objects.get(id) {
+        object.mappings -= 1;
+    }
+    objects.collect(id);
+}
+
+/// Call `visit` with each object there is.
+// for a listing of the objects, nothing shows one yet
+#[allow(dead_code)]
+pub fn objects(mut visit: impl FnMut(ShmId, ShmStats)) {
+    let objects = OBJECTS.lock();
+    for (index, object) in objects.slots.iter().enumerate() {
+        if let Some(object) = object {
+            let id = ShmId {
+                index,
+                generation: object.generation,
+            };
+            visit(
+                id,
+                ShmStats {
+                    size: object.pages * PAGE_SIZE,
+                    mappings: object.mappings,
+                    held: object.held,
+                },
+            );
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Locks shared with interrupt handlers.
+//!
+//! A handler spinning on a `spin::Mutex` held by the code it interrupted waits
+//! forever. [`IrqMutex`] keeps interrupts off on the CPU for as long as it is
+//! held, so that cannot happen there.
+//!
+//! Every static an interrupt or exception handler locks is either an
+//! [`IrqMutex`] or only ever taken there with `try_lock`, giving up if it is
+//! held, like the console, the page tables and the frame allocator.
+
+use core::ops::{Deref, DerefMut};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+/// Holds the lock with interrupts off, dropping it restores the interrupt flag.
+pub struct IrqMutexGuard<'a, T> {
+    // dropped before the interrupt flag is restored
+    guard: Option<MutexGuard<'a, T>>,
+    enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqMutexGuard {
+            guard: Some(self.inner.lock()),
+            enabled,
+        }
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
@@ -0,0 +1,791 @@
+//! A basic TCP over [`super::inet`].
+//!
+//! Connections live in a fixed table with a send and a receive ring each.
+//! Segments that arrive out of order are dropped and left for the peer to send
+//! again. Retransmission is timed on the timer tick, checked whenever
+//! [`super::inet::poll`] runs, and `TIME-WAIT` lasts twice [`MSL_MS`]. Without
+//! the tick nothing is sent again and `TIME-WAIT` ends at once.
+
+use spin::Mutex;
+
+use super::inet::{self, Ipv4Address, NetError, MAX_IP_PAYLOAD, PROTOCOL_TCP};
+use super::{random, timer};
+
+const MAX_CONNECTIONS: usize = 8;
+/// Size of each connection's send and receive ring.
+const BUFFER_SIZE: usize = 4096;
+
+const HEADER: usize = 20;
+/// The maximum segment size option, sent with SYN.
+const OPTION_MSS: u8 = 2;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+/// What a peer that does not say otherwise takes.
+const DEFAULT_MSS: usize = 536;
+const MSS: usize = MAX_IP_PAYLOAD - HEADER;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// Time without an acknowledgement before unacknowledged data is sent again,
+/// doubled with every retransmission of the same data.
+const INITIAL_RTO_MS: u64 = 1000;
+/// Retransmissions of the same data before the connection is given up.
+const MAX_RETRANSMITS: u32 = 8;
+/// Maximum segment lifetime, `TIME-WAIT` lasts twice this like on Linux.
+const MSL_MS: u64 = 30_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+/// `a` comes before `b` in sequence space.
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Milliseconds on the timer tick, zero while it is not running.
+fn now_ms() -> u64 {
+    timer::hz().map_or(0, |hz| timer::ticks() * 1000 / hz as u64)
+}
+
+struct Ring {
+    data: [u8; BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    const EMPTY: Ring = Ring {
+        data: [0; BUFFER_SIZE],
+        start: 0,
+        len: 0,
+    };
+
+    fn free(&self) -> usize {
+        BUFFER_SIZE - self.len
+    }
+
+    /// Append as much of `bytes` as fits, returns how much did.
+    fn push(&mut self, bytes: &[u8]) -> usize {
+        let count = bytes.len().min(self.free());
+        for (index, byte) in bytes[..count].iter().enumerate() {
+            self.data[(self.start + self.len + index) % BUFFER_SIZE] = *byte;
+        }
+        self.len += count;
+        count
+    }
+
+    /// Copy out bytes from `offset` on without taking them.
+    fn peek(&self, offset: usize, buffer: &mut [u8]) -> usize {
+        let count = buffer.len().min(self.len.saturating_sub(offset));
+        for (index, byte) in buffer[..count].iter_mut().enumerate() {
+            *byte = self.data[(self.start + offset + index) % BUFFER_SIZE];
+        }
+        count
+    }
+
+    fn consume(&mut self, count: usize) {
+        let count = count.min(self.len);
+        self.start = (self.start + count) % BUFFER_SIZE;
+        self.len -= count;
+    }
+
+    fn pop(&mut self, buffer: &mut [u8]) -> usize {
+        let count = self.peek(0, buffer);
+        self.consume(count);
+        count
+    }
+}
+
+struct Connection {
+    state: State,
+    /// Of the handle, see [`Connections::get`].
+    generation: u32,
+    local_port: u16,
+    remote: Ipv4Address,
+    remote_port: u16,
+    /// The listening socket this came in on, until [`accept`] takes it.
+    listener: Option<usize>,
+    /// Closed by its owner, the slot is freed once the connection is done.
+    released: bool,
+    initial_sequence: u32,
+    /// Oldest unacknowledged sequence number.
+    send_unacked: u32,
+    send_next: u32,
+    /// Sequence number of the first byte in `send`.
+    send_base: u32,
+    send_window: usize,
+    mss: usize,
+    receive_next: u32,
+    /// Acknowledged data is dropped, what is left was sent or is yet to be.
+    send: Ring,
+    receive: Ring,
+    /// Our FIN follows the data in `send`, in `SynReceived` once the handshake is done.
+    fin_queued: bool,
+    /// [`now_ms`] when the retransmission timer last started, in `TimeWait` when that began.
+    since: u64,
+    retransmits: u32,
+}
+
+impl Connection {
+    fn new(local_port: u16, remote: Ipv4Address, remote_port: u16, state: State) -> Self {
+        let initial_sequence = random::next_u64() as u32;
+        Connection {
+            state,
+            generation: 0,
+            local_port,
+            remote,
+            remote_port,
+            listener: None,
+            released: false,
+            initial_sequence,
+            send_unacked: initial_sequence,
+            send_next: initial_sequence,
+            send_base: initial_sequence.wrapping_add(1),
+            send_window: DEFAULT_MSS,
+            mss: DEFAULT_MSS,
+            receive_next: 0,
+            send: Ring::EMPTY,
+            receive: Ring::EMPTY,
+            fin_queued: false,
+            since: now_ms(),
+            retransmits: 0,
+        }
+    }
+
+    fn matches(&self, local_port: u16, remote: Ipv4Address, remote_port: u16) -> bool {
+        self.state != State::Listen
+            && self.local_port == local_port
+            && self.remote == remote
+            && self.remote_port == remote_port
+    }
+
+    /// Our FIN's sequence number, once it is queued.
+    fn fin_sequence(&self) -> u32 {
+        self.send_base.wrapping_add(self.send.len as u32)
+    }
+
+    /// Data or a FIN that the window or an unresolved next hop held back.
+    fn has_unsent(&self) -> bool {
+        let offset = self.send_next.wrapping_sub(self.send_base) as usize;
+        offset < self.send.len || (self.fin_queued && offset == self.send.len)
+    }
+
+    fn fin_acked(&self) -> bool {
+        self.fin_queued && before(self.fin_sequence(), self.send_unacked)
+    }
+
+    /// Send one segment with our acknowledgement and receive window.
+    fn segment(&self, flags: u8, sequence: u32, payload: &[u8]) -> Result<(), NetError> {
+        send_segment(
+            Endpoints {
+                local_port: self.local_port,
+                remote: self.remote,
+                remote_port: self.remote_port,
+            },
+            flags,
+            sequence,
+            self.receive_next,
+            self.receive.free(),
+            payload,
+        )
+    }
+
+    /// SYN, with an acknowledgement unless we are the one opening.
+    fn send_syn(&self) -> Result<(), NetError> {
+        let flags = if self.state == State::SynSent {
+            SYN
+        } else {
+            SYN | ACK
+        };
+        self.segment(flags, self.initial_sequence, &[])
+    }
+
+    /// Send whatever data and FIN the window allows.
+    fn output(&mut self) {
+        if !matches!(
+            self.state,
+            State::Established
+                | State::CloseWait
+                | State::FinWait1
+                | State::Closing
+                | State::LastAck
+        ) {
+            return;
+        }
+        let mut payload = [0u8; MSS];
+        loop {
+            let offset = self.send_next.wrapping_sub(self.send_base) as usize;
+            if offset > self.send.len {
+                // the FIN went out already
+                return;
+            }
+            let in_flight = self.send_next.wrapping_sub(self.send_unacked) as usize;
+            let room = self.send_window.saturating_sub(in_flight);
+            let count = self.mss.min(room).min(self.send.len - offset);
+            if count > 0 {
+                let count = self.send.peek(offset, &mut payload[..count]);
+                if self
+                    .segment(PSH | ACK, self.send_next, &payload[..count])
+                    .is_err()
+                {
+                    return;
+                }
+                self.send_next = self.send_next.wrapping_add(count as u32);
+                continue;
+            }
+            if offset == self.send.len
+                && self.fin_queued
+                && self.segment(FIN | ACK, self.send_next, &[]).is_ok()
+            {
+                self.send_next = self.send_next.wrapping_add(1);
+            }
+            return;
+        }
+    }
+
+    /// Go back to the oldest unacknowledged byte and send everything again.
+    fn retransmit(&mut self) {
+        self.send_next = self.send_unacked;
+        match self.state {
+            State::SynSent | State::SynReceived => {
+                let _ = self.send_syn();
+                self.send_next = self.initial_sequence.wrapping_add(1);
+            }
+            _ => self.output(),
+        }
+    }
+
+    fn reset(&mut self) {
+        let _ = self.segment(RST | ACK, self.send_next, &[]);
+        self.state = State::Closed;
+    }
+
+    /// An acknowledgement for `acknowledged`, with the peer's window.
+    fn acknowledge(&mut self, acknowledged: u32, window: usize) {
+        self.send_window = window;
+        if !before(self.send_unacked, acknowledged) || before(self.send_next, acknowledged) {
+            return;
+        }
+        self.send_unacked = acknowledged;
+        if before(self.send_base, acknowledged) {
+            let count = acknowledged.wrapping_sub(self.send_base) as usize;
+            let data = count.min(self.send.len);
+            self.send.consume(data);
+            self.send_base = self.send_base.wrapping_add(data as u32);
+        }
+        self.since = now_ms();
+        self.retransmits = 0;
+    }
+
+    /// `TimeWait`, or `Closed` without the tick to time it.
+    fn time_wait(&self) -> State {
+        match timer::hz() {
+            Some(_) => State::TimeWait,
+            None => State::Closed,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Endpoints {
+    local_port: u16,
+    remote: Ipv4Address,
+    remote_port: u16,
+}
+
+/// Build and send a segment, SYN carries our maximum segment size.
+fn send_segment(
+    endpoints: Endpoints,
+    flags: u8,
+    sequence: u32,
+    acknowledged: u32,
+    window: usize,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let source = inet::address().ok_or(NetError::NoInterface)?;
+    let mut segment = [0u8; MAX_IP_PAYLOAD];
+    let options = if flags & SYN != 0 { 4 } else { 0 };
+    let header = HEADER + options;
+    let len = header + payload.len();
+    let segment = &mut segment[..len];
+    segment[0..2].copy_from_slice(&endpoints.local_port.to_be_bytes());
+    segment[2..4].copy_from_slice(&endpoints.remote_port.to_be_bytes());
+    segment[4..8].copy_from_slice(&sequence.to_be_bytes());
+    if flags & ACK != 0 {
+        segment[8..12].copy_from_slice(&acknowledged.to_be_bytes());
+    }
+    segment[12] = (header as u8 / 4) << 4;
+    segment[13] = flags;
+    segment[14..16].copy_from_slice(&(window.min(u16::MAX as usize) as u16).to_be_bytes());
+    if options != 0 {
+        segment[HEADER] = OPTION_MSS;
+        segment[HEADER + 1] = 4;
+        segment[HEADER + 2..HEADER + 4].copy_from_slice(&(MSS as u16).to_be_bytes());
+    }
+    segment[header..].copy_from_slice(payload);
+    let checksum = inet::transport_checksum(source, endpoints.remote, PROTOCOL_TCP, segment);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    inet::send_ip(endpoints.remote, PROTOCOL_TCP, segment)
+}
+
+/// An open TCP socket, see [`listen`] and [`connect`]. It stops working once
+/// closed even if its slot is reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpSocket {
+    index: usize,
+    generation: u32,
+}
+
+struct Connections {
+    slots: [Option<Connection>; MAX_CONNECTIONS],
+    /// Handed to the next connection, so stale handles do not reach it.
+    generation: u32,
+}
+
+impl Connections {
+    fn free_slot(&self) -> Result<usize, NetError> {
+        self.slots
+            .iter()
+            .position(Option::is_none)
+            .ok_or(NetError::TableFull)
+    }
+
+    /// Put `connection` in a free slot under a new generation.
+    fn insert(&mut self, mut connection: Connection) -> Result<TcpSocket, NetError> {
+        let index = self.free_slot()?;
+        self.generation = self.generation.wrapping_add(1);
+        connection.generation = self.generation;
+        self.slots[index] = Some(connection);
+        Ok(TcpSocket {
+            index,
+            generation: self.generation,
+        })
+    }
+
+    /// The connection behind `socket`, unless it was closed.
+    fn get(&mut self, socket: TcpSocket) -> Result<&mut Connection, NetError> {
+        self.slots
+            .get_mut(socket.index)
+            .and_then(Option::as_mut)
+            .filter(|connection| connection.generation == socket.generation && !connection.released)
+            .ok_or(NetError::InvalidSocket)
+    }
+}
+
+static CONNECTIONS: Mutex<Connections> = Mutex::new(Connections {
+    slots: [const { None }; MAX_CONNECTIONS],
+    generation: 0,
+});
+
+/// The peer's maximum segment size, if its SYN carries one.
+fn parse_mss(options: &[u8]) -> Option<usize> {
+    let mut position = 0;
+    while position < options.len() {
+        match options[position] {
+            OPTION_END => return None,
+            OPTION_NOP => position += 1,
+            kind => {
+                let len = *options.get(position + 1)? as usize;
+                if len < 2 || position + len > options.len() {
+                    return None;
+                }
+                if kind == OPTION_MSS && len == 4 {
+                    let mss = u16::from_be_bytes([options[position + 2], options[position + 3]]);
+                    return Some(mss as usize);
+                }
+                position += len;
+            }
+        }
+    }
+    None
+}
+
+/// Answer a segment no connection wants with a reset.
+fn refuse(source: Ipv4Address, segment: &[u8], flags: u8, payload: usize) {
+    let endpoints = Endpoints {
+        local_port: u16::from_be_bytes([segment[2], segment[3]]),
+        remote: source,
+        remote_port: u16::from_be_bytes([segment[0], segment[1]]),
+    };
+    let sequence = u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]);
+    let acknowledged = u32::from_be_bytes([segment[8], segment[9], segment[10], segment[11]]);
+    let _ = if flags & ACK != 0 {
+        send_segment(endpoints, RST, acknowledged, 0, 0, &[])
+    } else {
+        let length = payload as u32 + (flags & SYN != 0) as u32 + (flags & FIN != 0) as u32;
+        send_segment(
+            endpoints,
+            RST | ACK,
+            0,
+            sequence.wrapping_add(length),
+            0,
+            &[],
+        )
+    };
+}
+
+/// Handle a segment from [`super::inet`].
+pub fn receive(source: Ipv4Address, destination: Ipv4Address, segment: &[u8]) {
+    if segment.len() < HEADER
+        || inet::transport_checksum(source, destination, PROTOCOL_TCP, segment) != 0
+    {
+        return;
+    }
+    let header = (segment[12] >> 4) as usize * 4;
+    if header < HEADER || header > segment.len() {
+        return;
+    }
+    let remote_port = u16::from_be_bytes([segment[0], segment[1]]);
+    let local_port = u16::from_be_bytes([segment[2], segment[3]]);
+    let sequence = u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]);
+    let acknowledged = u32::from_be_bytes([segment[8], segment[9], segment[10], segment[11]]);
+    let flags = segment[13];
+    let window = u16::from_be_bytes([segment[14], segment[15]]) as usize;
+    let options = &segment[HEADER..header];
+    let payload = &segment[header..];
+
+    let mut connections = CONNECTIONS.lock();
+    let found = connections.slots.iter().position(|connection| {
+        connection
+            .as_ref()
+            .is_some_and(|connection| connection.matches(local_port, source, remote_port))
+    });
+    let Some(index) = found else {
+        let listener = connections.slots.iter().position(|connection| {
+            connection.as_ref().is_some_and(|connection| {
+                connection.state == State::Listen && connection.local_port == local_port
+            })
+        });
+        match listener {
+            Some(listener) if flags & (SYN | ACK | RST) == SYN => {
+                if connections.free_slot().is_err() {
+                    return;
+                }
+                let mut connection =
+                    Connection::new(local_port, source, remote_port, State::SynReceived);
+                connection.listener = Some(listener);
+                connection.receive_next = sequence.wrapping_add(1);
+                connection.send_window = window;
+                connection.mss = parse_mss(options).unwrap_or(DEFAULT_MSS).min(MSS);
+                // in flight even if it did not go out, so it is sent again
+                let _ = connection.send_syn();
+                connection.send_next = connection.initial_sequence.wrapping_add(1);
+                let _ = connections.insert(connection);
+            }
+            _ if flags & RST == 0 => refuse(source, segment, flags, payload.len()),
+            _ => {}
+        }
+        return;
+    };
+    let Some(connection) = connections.slots[index].as_mut() else {
+        return;
+    };
+
+    if connection.state == State::SynSent {
+        let expected = connection.initial_sequence.wrapping_add(1);
+        if flags & ACK != 0 && acknowledged != expected {
+            if flags & RST == 0 {
+                refuse(source, segment, flags, payload.len());
+            }
+            return;
+        }
+        if flags & RST != 0 {
+            connection.state = State::Closed;
+        } else if flags & SYN != 0 && flags & ACK != 0 {
+            connection.receive_next = sequence.wrapping_add(1);
+            connection.mss = parse_mss(options).unwrap_or(DEFAULT_MSS).min(MSS);
+            connection.acknowledge(acknowledged, window);
+            connection.state = State::Established;
+            let _ = connection.segment(ACK, connection.send_next, &[]);
+            connection.output();
+        }
+        return;
+    }
+
+    if flags & RST != 0 {
+        if sequence == connection.receive_next {
+            connection.state = State::Closed;
+        }
+    } else if sequence != connection.receive_next {
+        // out of order or a duplicate, say where we are
+        let _ = connection.segment(ACK, connection.send_next, &[]);
+        if connection.state == State::TimeWait {
+            // the peer sent its FIN again, our ACK may have been lost
+            connection.since = now_ms();
+        }
+        return;
+    } else if flags & ACK != 0 {
+        connection.acknowledge(acknowledged, window);
+        if connection.state == State::SynReceived
+            && !before(acknowledged, connection.initial_sequence.wrapping_add(1))
+        {
+            // a close that came during the handshake sends its FIN now
+            connection.state = if connection.fin_queued {
+                State::FinWait1
+            } else {
+                State::Established
+            };
+        }
+        if connection.fin_acked() {
+            connection.state = match connection.state {
+                State::FinWait1 => State::FinWait2,
+                State::Closing => connection.time_wait(),
+                State::LastAck => State::Closed,
+                state => state,
+            };
+        }
+    }
+
+    let mut answer = false;
+    if flags & RST == 0 && sequence == connection.receive_next {
+        let mut taken = 0;
+        if matches!(
+            connection.state,
+            State::Established | State::FinWait1 | State::FinWait2
+        ) && !payload.is_empty()
+        {
+            taken = connection.receive.push(payload);
+            connection.receive_next = connection.receive_next.wrapping_add(taken as u32);
+            answer = true;
+        }
+        if flags & FIN != 0 && taken == payload.len() {
+            connection.receive_next = connection.receive_next.wrapping_add(1);
+            answer = true;
+            connection.state = match connection.state {
+                State::SynReceived | State::Established => State::CloseWait,
+                State::FinWait1 if connection.fin_acked() => connection.time_wait(),
+                State::FinWait1 => State::Closing,
+                State::FinWait2 => connection.time_wait(),
+                state => state,
+            };
+            if connection.state == State::TimeWait {
+                connection.since = now_ms();
+            }
+        }
+    }
+    if answer {
+        let _ = connection.segment(ACK, connection.send_next, &[]);
+    }
+    connection.output();
+    if connection.state == State::Closed && connection.released {
+        connections.slots[index] = None;
+    }
+}
+
+/// Send again what went unacknowledged for too long and end `TIME-WAIT`s that
+/// are over, on the timer tick.
+pub fn poll() {
+    if timer::hz().is_none() {
+        return;
+    }
+    let now = now_ms();
+    let mut connections = CONNECTIONS.lock();
+    for slot in connections.slots.iter_mut() {
+        let Some(connection) = slot.as_mut() else {
+            continue;
+        };
+        let elapsed = now.saturating_sub(connection.since);
+        if connection.state == State::TimeWait {
+            if elapsed >= 2 * MSL_MS {
+                connection.state = State::Closed;
+            }
+        } else if connection.send_unacked == connection.send_next && !connection.has_unsent() {
+            // nothing to time, the clock starts with what is sent next
+            connection.since = now;
+            continue;
+        } else if elapsed >= INITIAL_RTO_MS << connection.retransmits {
+            connection.since = now;
+            connection.retransmits += 1;
+            if connection.retransmits > MAX_RETRANSMITS {
+                connection.reset();
+            } else {
+                connection.retransmit();
+            }
+        }
+        if connection.state == State::Closed && connection.released {
+            *slot = None;
+        }
+    }
+}
+
+/// Wait for connections on `port`, see [`accept`].
+// like the UDP calls, nothing opens sockets before there are programs
+#[allow(dead_code)]
+pub fn listen(port: u16) -> Result<TcpSocket, NetError> {
+    let mut connections = CONNECTIONS.lock();
+    if connections
+        .slots
+        .iter()
+        .flatten()
+        .any(|connection| connection.state == State::Listen && connection.local_port == port)
+    {
+        return Err(NetError::AddressInUse);
+    }
+    connections.insert(Connection::new(
+        port,
+        Ipv4Address::UNSPECIFIED,
+        0,
+        State::Listen,
+    ))
+}
+
+/// A connection that came in on `listener` and finished its handshake.
+// nothing listens yet
+#[allow(dead_code)]
+pub fn accept(listener: TcpSocket) -> Result<TcpSocket, NetError> {
+    let mut connections = CONNECTIONS.lock();
+    if connections.get(listener)?.state != State::Listen {
+        return Err(NetError::InvalidSocket);
+    }
+    let (index, connection) = connections
+        .slots
+        .iter_mut()
+        .enumerate()
+        .find_map(|(index, connection)| {
+            connection
+                .as_mut()
+                .filter(|connection| {
+                    connection.listener == Some(listener.index)
+                        && connection.state != State::SynReceived
+                })
+                .map(|connection| (index, connection))
+        })
+        .ok_or(NetError::WouldBlock)?;
+    connection.listener = None;
+    Ok(TcpSocket {
+        index,
+        generation: connection.generation,
+    })
+}
+
+/// Open a connection to `port` at `destination`.
+///
+/// Returns at once in `SynSent`, poll and check [`state`] for `Established`.
+// nothing connects out yet
+#[allow(dead_code)]
+pub fn connect(destination: Ipv4Address, port: u16) -> Result<TcpSocket, NetError> {
+    let mut connections = CONNECTIONS.lock();
+    connections.free_slot()?;
+    let mut connection = Connection::new(inet::ephemeral_port(), destination, port, State::SynSent);
+    // counted as in flight even if it did not go out, an unresolved next hop
+    // is retried like a lost SYN
+    let _ = connection.send_syn();
+    connection.send_next = connection.initial_sequence.wrapping_add(1);
+    connections.insert(connection)
+}
+
+fn with_connection<T>(
+    socket: TcpSocket,
+    run: impl FnOnce(&mut Connection) -> Result<T, NetError>,
+) -> Result<T, NetError> {
+    run(CONNECTIONS.lock().get(socket)?)
+}
+
+// no sockets to ask about yet
+#[allow(dead_code)]
+pub fn state(socket: TcpSocket) -> Result<State, NetError> {
+    with_connection(socket, |connection| Ok(connection.state))
+}
+
+/// Queue `data` and send what the peer's window allows, returns how much was queued.
+// no sockets to send on yet
+#[allow(dead_code)]
+pub fn send(socket: TcpSocket, data: &[u8]) -> Result<usize, NetError> {
+    with_connection(socket, |connection| match connection.state {
+        State::Established | State::CloseWait if !connection.fin_queued => {
+            let queued = connection.send.push(data);
+            connection.output();
+            if queued == 0 && !data.is_empty() {
+                return Err(NetError::WouldBlock);
+            }
+            Ok(queued)
+        }
+        State::SynSent | State::SynReceived => Err(NetError::WouldBlock),
+        State::Listen => Err(NetError::InvalidSocket),
+        _ => Err(NetError::Closed),
+    })
+}
+
+/// Take received data, `0` once the peer closed its side and everything was read.
+// no sockets to read from yet
+#[allow(dead_code)]
+pub fn receive_data(socket: TcpSocket, buffer: &mut [u8]) -> Result<usize, NetError> {
+    with_connection(socket, |connection| {
+        let was_full = connection.receive.free() < connection.mss;
+        let count = connection.receive.pop(buffer);
+        if count > 0 {
+            // tell the peer the window opened again
+            if was_full && connection.receive.free() >= connection.mss {
+                let _ = connection.segment(ACK, connection.send_next, &[]);
+            }
+            return Ok(count);
+        }
+        match connection.state {
+            State::CloseWait | State::Closing | State::LastAck => Ok(0),
+            State::Closed => Err(NetError::Closed),
+            State::Listen => Err(NetError::InvalidSocket),
+            _ => Err(NetError::WouldBlock),
+        }
+    })
+}
+
+/// Close our side once the queued data is sent, the socket can't be used after.
+// no sockets to close yet
+#[allow(dead_code)]
+pub fn close(socket: TcpSocket) -> Result<(), NetError> {
+    let mut connections = CONNECTIONS.lock();
+    let connection = connections.get(socket)?;
+    connection.released = true;
+    match connection.state {
+        State::Established => {
+            connection.fin_queued = true;
+            connection.state = State::FinWait1;
+            connection.output();
+        }
+        // our SYN is not acknowledged yet, the FIN waits for that in `receive`
+        State::SynReceived => connection.fin_queued = true,
+        State::CloseWait => {
+            connection.fin_queued = true;
+            connection.state = State::LastAck;
+            connection.output();
+        }
+        State::Listen => {
+            // connections that were never accepted go with it
+            for pending in connections.slots.iter_mut() {
+                if pending
+                    .as_ref()
+                    .is_some_and(|pending| pending.listener == Some(socket.index))
+                {
+                    if let Some(connection) = pending.as_mut() {
+                        connection.reset();
+                    }
+                    *pending = None;
+                }
+            }
+            connections.slots[socket.index] = None;
+            return Ok(());
+        }
+        State::SynSent => connection.state = State::Closed,
+        _ => {}
+    }
+    if connection.state == State::Closed {
+        connections.slots[socket.index] = None;
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicI16, AtomicI64, AtomicU64, Ordering};
+
+use canicula_common::time::{parse_utc_offset, DateTime};
+use log::{info, warn};
+
+use super::{cpu, hpet, pit, rtc, timer};
+
+static UTC_OFFSET: AtomicI16 = AtomicI16::new(0);
+
+/// TSC ticks per second, zero until [`calibrate`].
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// The TSC at [`init`], where the monotonic clock starts.
+static TSC_BOOT: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds since the Unix epoch at monotonic zero, from the RTC.
+static REALTIME_BOOT: AtomicI64 = AtomicI64::new(0);
+
+/// How long the TSC is counted against the reference.
+const CALIBRATION_MS: u64 = 10;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+/// Reads of the HPET before calibrating against one that stopped is given up on.
+const SPIN_LIMIT: usize = 100_000_000;
+/// Longest single PIT countdown for [`delay_ns`] before calibration, about 50 ms.
+const PIT_CHUNK: u16 = 60_000;
+
+/// Start the monotonic clock, local time is `utc_offset` minutes east of UTC
+/// or else at the `utc_offset` build option.
+pub fn init(utc_offset: Option<i16>) {
+    TSC_BOOT.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+    let offset = utc_offset
+        .or_else(|| option_env!("utc_offset").and_then(parse_utc_offset))
+        .unwrap_or(0);
+    set_utc_offset(offset);
+}
+
+/// TSC ticks per second, counted over [`CALIBRATION_MS`] of the HPET.
+fn calibrate_hpet() -> Option<u64> {
+    let period = hpet::period_fs();
+    let start = hpet::counter()?;
+    let ticks = CALIBRATION_MS * 1_000_000_000_000 / period;
+    let tsc_start = unsafe { _rdtsc() };
+    let mut now = start;
+    for _ in 0..SPIN_LIMIT {
+        if hpet::elapsed(start, now) >= ticks {
+            break;
+        }
+        core::hint::spin_loop();
+        now = hpet::counter()?;
+    }
+    if hpet::elapsed(start, now) < ticks {
+        return None;
+    }
+    let tsc = unsafe { _rdtsc() } - tsc_start;
+    let femtoseconds = hpet::elapsed(start, now) as u128 * period as u128;
+    Some((tsc as u128 * 1_000_000_000_000_000 / femtoseconds) as u64)
+}
+
+/// TSC ticks per second, counted over one [`CALIBRATION_MS`] PIT countdown.
+fn calibrate_pit() -> Option<u64> {
+    let ticks = pit::FREQUENCY * CALIBRATION_MS / 1000;
+    let tsc_start = unsafe { _rdtsc() };
+    if !pit::wait(ticks as u16) {
+        return None;
+    }
+    let tsc = unsafe { _rdtsc() } - tsc_start;
+    Some(tsc * pit::FREQUENCY / ticks)
+}
+
+/// Measure the TSC against the HPET, or the PIT without one, and start the clocks.
+///
+/// Runs after [`hpet::init`]. Before this the monotonic clock reads zero and the
+/// realtime clock only has the RTC's seconds.
+pub fn calibrate() {
+    // an interrupt in the middle would stretch the TSC count
+    let calibrated = x86_64::instructions::interrupts::without_interrupts(|| {
+        calibrate_hpet()
+            .map(|hz| (hz, "HPET"))
+            .or_else(|| calibrate_pit().map(|hz| (hz, "PIT")))
+    });
+    let Some((hz, reference)) = calibrated.filter(|(hz, _)| *hz != 0) else {
+        warn!("[time] cannot calibrate the TSC, no monotonic clock");
+        return;
+    };
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    let realtime = unix_timestamp() * NANOS_PER_SECOND as i64 - monotonic_ns() as i64;
+    REALTIME_BOOT.store(realtime, Ordering::Relaxed);
+    info!(
+        "[time] TSC at {}.{:03} MHz against the {}{}",
+        hz / 1_000_000,
+        hz / 1000 % 1000,
+        reference,
+        if cpu::features().invariant_tsc {
+            ""
+        } else {
+            ", not invariant"
+        }
+    );
+}
+
+/// TSC ticks per second, `None` before [`calibrate`].
+pub fn tsc_frequency() -> Option<u64> {
+    Some(TSC_HZ.load(Ordering::Relaxed)).filter(|hz| *hz != 0)
+}
+
+/// Nanoseconds since boot, never going back. Zero until [`calibrate`].
+pub fn monotonic_ns() -> u64 {
+    let Some(hz) = tsc_frequency() else {
+        return 0;
+    };
+    let ticks = unsafe { _rdtsc() }.saturating_sub(TSC_BOOT.load(Ordering::Relaxed));
+    (ticks as u128 * NANOS_PER_SECOND as u128 / hz as u128) as u64
+}
+
+/// Nanoseconds since the Unix epoch: the RTC at calibration, moved on by the monotonic clock.
+// file systems and the shell only need whole seconds so far
+#[allow(dead_code)]
+pub fn realtime_ns() -> i64 {
+    if tsc_frequency().is_none() {
+        return unix_timestamp() * NANOS_PER_SECOND as i64;
+    }
+    REALTIME_BOOT.load(Ordering::Relaxed) + monotonic_ns() as i64
+}
+
+/// Spin for at least `ns` nanoseconds, timed by the PIT before [`calibrate`].
+pub fn delay_ns(ns: u64) {
+    if tsc_frequency().is_none() {
+        let mut ticks = (ns as u128 * pit::FREQUENCY as u128).div_ceil(NANOS_PER_SECOND as u128);
+        while ticks > 0 {
+            let chunk = ticks.min(PIT_CHUNK as u128);
+            if !pit::wait(chunk as u16) {
+                return;
+            }
+            ticks -= chunk;
+        }
+        return;
+    }
+    let end = monotonic_ns().saturating_add(ns);
+    while monotonic_ns() < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// Wait at least `ns` nanoseconds, halting until each timer tick once [`timer::init`] started them.
+///
+/// Wakes up to a tick late. Without the tick, or with interrupts off, it spins like [`delay_ns`].
+// every wait so far happens during boot, before the tick starts
+#[allow(dead_code)]
+pub fn sleep_ns(ns: u64) {
+    if timer::hz().is_none() || !x86_64::instructions::interrupts::are_enabled() {
+        delay_ns(ns);
+        return;
+    }
+    let end = monotonic_ns().saturating_add(ns);
+    while monotonic_ns() < end {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Minutes east of UTC used when presenting local time.
+pub fn utc_offset() -> i16 {
+    UTC_OFFSET.load(Ordering::Relaxed)
+}
+
+pub fn set_utc_offset(offset: i16) {
+    UTC_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// Current time in UTC.
+pub fn now_utc() -> DateTime {
+    rtc::read()
+}
+
+/// Current time in the configured timezone.
+pub fn now() -> DateTime {
+    now_utc().with_utc_offset(utc_offset())
+}
+
+/// Seconds since the Unix epoch, the timestamp format used by on-disk filesystems.
+pub fn unix_timestamp() -> i64 {
+    now_utc().to_unix_timestamp()
+}
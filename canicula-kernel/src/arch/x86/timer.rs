@@ -0,0 +1,116 @@
+//! The timer tick, from the local APIC timer at a configurable rate.
+//!
+//! With TSC-deadline mode each tick arms the next one a whole period after the
+//! last deadline, so the ticks follow the calibrated TSC without drifting.
+//! Without it the timer runs in periodic mode, its count measured against the
+//! TSC first, since the APIC timer's bus clock is not known.
+
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use log::{info, warn};
+
+use super::lapic::{self, TimerMode};
+use super::{interrupts, time};
+
+const DEFAULT_HZ: u32 = 100;
+const MAX_HZ: u32 = 1000;
+/// How long the APIC timer is counted against the TSC in periodic mode.
+const CALIBRATION_NS: u64 = 10_000_000;
+
+/// Ticks per second, zero while the timer is not running.
+static HZ: AtomicU32 = AtomicU32::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// TSC ticks per timer tick, zero in periodic mode.
+static DEADLINE_PERIOD: AtomicU64 = AtomicU64::new(0);
+/// The TSC the armed deadline fires at.
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    let period = DEADLINE_PERIOD.load(Ordering::Relaxed);
+    if period == 0 {
+        return;
+    }
+    let now = unsafe { _rdtsc() };
+    let mut next = DEADLINE.load(Ordering::Relaxed) + period;
+    // after a long stall, go on from now rather than firing the missed ticks back to back
+    if next <= now {
+        next = now + period;
+    }
+    DEADLINE.store(next, Ordering::Relaxed);
+    lapic::set_tsc_deadline(next);
+}
+
+/// Count the APIC timer over [`CALIBRATION_NS`] of the TSC, returns its counts per second.
+fn calibrate() -> u64 {
+    lapic::set_timer(TimerMode::OneShot, None, u32::MAX);
+    time::delay_ns(CALIBRATION_NS);
+    let counted = u32::MAX - lapic::timer_count();
+    lapic::set_timer(TimerMode::OneShot, None, 0);
+    counted as u64 * 1_000_000_000 / CALIBRATION_NS
+}
+
+/// Start ticking `hz` times a second, or at the `timer_hz` build option, 100 by default.
+///
+/// Needs [`lapic::init`] and [`time::calibrate`].
+pub fn init(hz: Option<u32>) {
+    let hz = hz
+        .or_else(|| option_env!("timer_hz").and_then(|hz| hz.parse().ok()))
+        .unwrap_or(DEFAULT_HZ);
+    let hz = if (1..=MAX_HZ).contains(&hz) {
+        hz
+    } else {
+        warn!("[timer] {} Hz is out of range, using {}", hz, DEFAULT_HZ);
+        DEFAULT_HZ
+    };
+    if !lapic::is_available() {
+        warn!("[timer] no local APIC, no timer tick");
+        return;
+    }
+    let Some(tsc_hz) = time::tsc_frequency() else {
+        warn!("[timer] no calibrated TSC, no timer tick");
+        return;
+    };
+    let Some(vector) = interrupts::allocate_vector(tick) else {
+        warn!("[timer] no free vector");
+        return;
+    };
+
+    if lapic::has_tsc_deadline() {
+        let period = tsc_hz / hz as u64;
+        DEADLINE_PERIOD.store(period, Ordering::Relaxed);
+        HZ.store(hz, Ordering::Relaxed);
+        lapic::set_timer(TimerMode::TscDeadline, Some(vector), 0);
+        let first = unsafe { _rdtsc() } + period;
+        DEADLINE.store(first, Ordering::Relaxed);
+        lapic::set_tsc_deadline(first);
+        info!("[timer] {} Hz in TSC-deadline mode", hz);
+        return;
+    }
+
+    let rate = calibrate();
+    let count = rate / hz as u64;
+    if count == 0 || count > u32::MAX as u64 {
+        warn!("[timer] APIC timer at {} Hz cannot tick at {} Hz", rate, hz);
+        interrupts::free_vector(vector);
+        return;
+    }
+    HZ.store(hz, Ordering::Relaxed);
+    lapic::set_timer(TimerMode::Periodic, Some(vector), count as u32);
+    info!(
+        "[timer] {} Hz in periodic mode, APIC timer at {} kHz",
+        hz,
+        rate / 1000
+    );
+}
+
+/// Ticks per second, `None` while the timer is not running.
+pub fn hz() -> Option<u32> {
+    Some(HZ.load(Ordering::Relaxed)).filter(|hz| *hz != 0)
+}
+
+/// Ticks since [`init`].
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
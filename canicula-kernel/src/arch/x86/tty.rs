@@ -0,0 +1,218 @@
+//! The console terminal: keyboard input through a line discipline, output to the console.
+//!
+//! In canonical mode typed characters collect in a line that backspace and ^U
+//! edit, and [`read`] hands out whole lines once Enter is pressed. In raw mode
+//! every byte is readable as soon as it is typed. With echo on the terminal
+//! prints what is typed itself.
+
+use x86_64::instructions::interrupts;
+
+use super::sync::IrqMutex;
+use crate::print;
+
+/// Typed bytes waiting for [`read`].
+const INPUT_SIZE: usize = 1024;
+/// Longest line in canonical mode, newline included.
+const LINE_SIZE: usize = 256;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+/// ^U, erase the line.
+const KILL: u8 = 0x15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode {
+    pub canonical: bool,
+    pub echo: bool,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode {
+            canonical: true,
+            echo: true,
+        }
+    }
+}
+
+struct Tty {
+    mode: Mode,
+    /// A ring of bytes [`read`] can return.
+    input: [u8; INPUT_SIZE],
+    head: usize,
+    len: usize,
+    /// Newlines in `input`, canonical reads wait for one.
+    lines: usize,
+    /// The line being edited in canonical mode.
+    line: [u8; LINE_SIZE],
+    line_len: usize,
+}
+
+static TTY: IrqMutex<Tty> = IrqMutex::new(Tty {
+    mode: Mode {
+        canonical: true,
+        echo: true,
+    },
+    input: [0; INPUT_SIZE],
+    head: 0,
+    len: 0,
+    lines: 0,
+    line: [0; LINE_SIZE],
+    line_len: 0,
+});
+
+impl Tty {
+    fn receive(&mut self, byte: u8) {
+        let byte = if byte == b'\r' { b'\n' } else { byte };
+        if !self.mode.canonical {
+            if self.push(&[byte]) {
+                self.echo(byte);
+            }
+            return;
+        }
+
+        match byte {
+            BACKSPACE | DELETE => {
+                if self.line_len > 0 {
+                    self.line_len -= 1;
+                    self.erase(1);
+                }
+            }
+            KILL => {
+                self.erase(self.line_len);
+                self.line_len = 0;
+            }
+            b'\n' => {
+                self.line[self.line_len] = b'\n';
+                let line = self.line;
+                // a line that does not fit is lost as a whole
+                self.push(&line[..self.line_len + 1]);
+                self.line_len = 0;
+                self.echo(b'\n');
+            }
+            // the last byte stays free for the newline
+            _ if self.line_len + 1 < LINE_SIZE => {
+                self.line[self.line_len] = byte;
+                self.line_len += 1;
+                self.echo(byte);
+            }
+            _ => {}
+        }
+    }
+
+    /// Append `bytes` to the input, nothing of them if they do not fit.
+    fn push(&mut self, bytes: &[u8]) -> bool {
+        if self.len + bytes.len() > INPUT_SIZE {
+            return false;
+        }
+        for &byte in bytes {
+            self.input[(self.head + self.len) % INPUT_SIZE] = byte;
+            self.len += 1;
+            if byte == b'\n' {
+                self.lines += 1;
+            }
+        }
+        true
+    }
+
+    /// Move input into `buffer`, `None` if a read has to wait.
+    fn take(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        if buffer.is_empty() {
+            return Some(0);
+        }
+        if self.len == 0 || (self.mode.canonical && self.lines == 0) {
+            return None;
+        }
+
+        let mut count = 0;
+        while count < buffer.len() && self.len > 0 {
+            let byte = self.input[self.head];
+            self.head = (self.head + 1) % INPUT_SIZE;
+            self.len -= 1;
+            buffer[count] = byte;
+            count += 1;
+            if byte == b'\n' {
+                self.lines -= 1;
+                if self.mode.canonical {
+                    break;
+                }
+            }
+        }
+        Some(count)
+    }
+
+    fn echo(&self, byte: u8) {
+        if !self.mode.echo {
+            return;
+        }
+        match byte {
+            b'\n' | b'\t' | 0x20..=0x7e => print!("{}", byte as char),
+            // control characters show up as ^X
+            0..=0x1f => print!("^{}", (byte + b'@') as char),
+            _ => {}
+        }
+    }
+
+    /// Rub out the last `count` echoed characters.
+    fn erase(&self, count: usize) {
+        if self.mode.echo {
+            for _ in 0..count {
+                print!("\x08 \x08");
+            }
+        }
+    }
+}
+
+/// Feed a byte typed on the keyboard, from its interrupt handler.
+pub fn input(byte: u8) {
+    TTY.lock().receive(byte);
+}
+
+/// Read typed input into `buffer`, waiting until there is some.
+///
+/// In canonical mode this returns at most one line, ending in `\n` unless it
+/// did not fit into `buffer`. Interrupts are enabled when it returns.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn read(buffer: &mut [u8]) -> usize {
+    loop {
+        // checking and halting with interrupts off cannot miss the wake up
+        interrupts::disable();
+        if let Some(count) = TTY.lock().take(buffer) {
+            interrupts::enable();
+            return count;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Print `bytes` to the console, invalid UTF-8 as replacement characters.
+#[cfg_attr(feature = "ext4-test", allow(dead_code))]
+pub fn write(bytes: &[u8]) -> usize {
+    for chunk in bytes.utf8_chunks() {
+        print!("{}", chunk.valid());
+        if !chunk.invalid().is_empty() {
+            print!("{}", char::REPLACEMENT_CHARACTER);
+        }
+    }
+    bytes.len()
+}
+
+// the shell reads whole lines, raw mode waits for full-screen programs
+#[allow(dead_code)]
+pub fn mode() -> Mode {
+    TTY.lock().mode
+}
+
+/// Switch modes, a line being edited becomes readable when canonical mode ends.
+// goes with `mode`
+#[allow(dead_code)]
+pub fn set_mode(mode: Mode) {
+    let mut tty = TTY.lock();
+    if tty.mode.canonical && !mode.canonical {
+        let line = tty.line;
+        let len = tty.line_len;
+        tty.push(&line[..len]);
+        tty.line_len = 0;
+    }
+    tty.mode = mode;
+}
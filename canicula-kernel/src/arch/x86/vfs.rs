@@ -0,0 +1,381 @@
+//! The virtual file system: one tree of absolute paths over every mounted file system.
+//!
+//! A file system implements [`FileSystem`] in terms of its own inode numbers
+//! and is mounted at a directory. Paths are resolved from the mount with the
+//! longest matching prefix, and recent lookups are kept in a small dentry cache.
+//! There is no heap, so mounts, open files and the cache are fixed tables.
+
+use canicula_common::fs::OperateError;
+use spin::Mutex;
+
+pub const MAX_MOUNTS: usize = 8;
+pub const MAX_FILES: usize = 32;
+/// Longest path, after `.` and `..` are resolved.
+pub const PATH_MAX: usize = 256;
+const DENTRY_CACHE_SIZE: usize = 64;
+/// Longer names are looked up every time.
+const DENTRY_NAME_MAX: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub inode: u64,
+    pub kind: FileKind,
+    pub size: u64,
+    /// Permission bits, as the file system stores them.
+    pub mode: u16,
+}
+
+/// A mountable file system. Inodes are whatever numbers it hands out.
+pub trait FileSystem: Sync {
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> u64;
+
+    /// The inode of the entry called `name` in `directory`.
+    fn lookup(&self, directory: u64, name: &str) -> Result<Option<u64>, OperateError>;
+
+    fn stat(&self, inode: u64) -> Result<Stat, OperateError>;
+
+    /// Read from byte `offset`, short only at the end of the file.
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, OperateError>;
+
+    fn write(&self, _inode: u64, _offset: u64, _buffer: &[u8]) -> Result<usize, OperateError> {
+        Err(OperateError::ReadOnly)
+    }
+
+    /// Call `visit` with the inode and name of each entry until it returns `false`.
+    fn read_dir(
+        &self,
+        directory: u64,
+        visit: &mut dyn FnMut(u64, &[u8]) -> bool,
+    ) -> Result<(), OperateError>;
+}
+
+/// An absolute path without `.`, `..`, empty components or a trailing `/`.
+#[derive(Clone, Copy)]
+struct Path {
+    bytes: [u8; PATH_MAX],
+    len: usize,
+}
+
+impl Path {
+    fn new(path: &str) -> Result<Self, OperateError> {
+        if !path.starts_with('/') {
+            return Err(OperateError::NotFound);
+        }
+        let mut normal = Path {
+            bytes: [0; PATH_MAX],
+            len: 0,
+        };
+        for name in path.split('/') {
+            match name {
+                "" | "." => {}
+                // `/..` is `/`
+                ".." => normal.len = normal.as_str().rfind('/').unwrap_or(0),
+                _ => {
+                    let end = normal.len + 1 + name.len();
+                    if end > PATH_MAX {
+                        return Err(OperateError::Fault);
+                    }
+                    normal.bytes[normal.len] = b'/';
+                    normal.bytes[normal.len + 1..end].copy_from_slice(name.as_bytes());
+                    normal.len = end;
+                }
+            }
+        }
+        Ok(normal)
+    }
+
+    /// Empty for the root.
+    fn as_str(&self) -> &str {
+        // built from whole `str` components
+        unsafe { core::str::from_utf8_unchecked(&self.bytes[..self.len]) }
+    }
+
+    /// The rest of the path below `prefix`, if it is one of its ancestors or itself.
+    fn strip(&self, prefix: &Path) -> Option<&str> {
+        let rest = self.as_str().strip_prefix(prefix.as_str())?;
+        (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+    }
+}
+
+struct Mount {
+    path: Path,
+    fs: &'static dyn FileSystem,
+}
+
+#[derive(Clone, Copy)]
+struct Dentry {
+    mount: usize,
+    parent: u64,
+    name: [u8; DENTRY_NAME_MAX],
+    name_len: usize,
+    inode: u64,
+}
+
+impl Dentry {
+    fn is(&self, mount: usize, parent: u64, name: &str) -> bool {
+        self.mount == mount
+            && self.parent == parent
+            && &self.name[..self.name_len] == name.as_bytes()
+    }
+}
+
+struct DentryCache {
+    entries: [Option<Dentry>; DENTRY_CACHE_SIZE],
+    /// The slot replaced next, entries are evicted round robin.
+    next: usize,
+}
+
+impl DentryCache {
+    fn get(&self, mount: usize, parent: u64, name: &str) -> Option<u64> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|dentry| dentry.is(mount, parent, name))
+            .map(|dentry| dentry.inode)
+    }
+
+    fn insert(&mut self, mount: usize, parent: u64, name: &str, inode: u64) {
+        if name.len() > DENTRY_NAME_MAX {
+            return;
+        }
+        let mut dentry = Dentry {
+            mount,
+            parent,
+            name: [0; DENTRY_NAME_MAX],
+            name_len: name.len(),
+            inode,
+        };
+        dentry.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.entries[self.next] = Some(dentry);
+        self.next = (self.next + 1) % DENTRY_CACHE_SIZE;
+    }
+
+    fn forget(&mut self, mount: usize) {
+        for entry in &mut self.entries {
+            if entry.is_some_and(|dentry| dentry.mount == mount) {
+                *entry = None;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct File {
+    mount: usize,
+    fs: &'static dyn FileSystem,
+    inode: u64,
+    kind: FileKind,
+    offset: u64,
+}
+
+/// An open file, see [`open`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fd(pub usize);
+
+static MOUNTS: Mutex<[Option<Mount>; MAX_MOUNTS]> = Mutex::new([const { None }; MAX_MOUNTS]);
+static DENTRIES: Mutex<DentryCache> = Mutex::new(DentryCache {
+    entries: [None; DENTRY_CACHE_SIZE],
+    next: 0,
+});
+static FILES: Mutex<[Option<File>; MAX_FILES]> = Mutex::new([None; MAX_FILES]);
+
+/// Where `path` ends up: the mount it is on, its file system and its inode.
+fn resolve(path: &str) -> Result<(usize, &'static dyn FileSystem, u64), OperateError> {
+    let path = Path::new(path)?;
+    let (mount, fs, rest) = {
+        let mounts = MOUNTS.lock();
+        let (mount, fs, rest) = mounts
+            .iter()
+            .enumerate()
+            .filter_map(|(index, mount)| {
+                let mount = mount.as_ref()?;
+                Some((index, mount.fs, path.strip(&mount.path)?))
+            })
+            // the deepest mount point has the least left over
+            .min_by_key(|(_, _, rest)| rest.len())
+            .ok_or(OperateError::NotFound)?;
+        (mount, fs, rest)
+    };
+
+    let mut inode = fs.root();
+    for name in rest.split('/').filter(|name| !name.is_empty()) {
+        let cached = DENTRIES.lock().get(mount, inode, name);
+        inode = match cached {
+            Some(child) => child,
+            None => {
+                let child = fs.lookup(inode, name)?.ok_or(OperateError::NotFound)?;
+                DENTRIES.lock().insert(mount, inode, name, child);
+                child
+            }
+        };
+    }
+    Ok((mount, fs, inode))
+}
+
+/// Mount `fs` at `path`, which has to be a directory unless it is `/`.
+pub fn mount(path: &str, fs: &'static dyn FileSystem) -> Result<(), OperateError> {
+    let normal = Path::new(path)?;
+    if !normal.as_str().is_empty() {
+        let (_, parent, inode) = resolve(path)?;
+        if parent.stat(inode)?.kind != FileKind::Directory {
+            return Err(OperateError::NotDirectory);
+        }
+    }
+
+    let mut mounts = MOUNTS.lock();
+    if mounts
+        .iter()
+        .flatten()
+        .any(|mount| mount.path.as_str() == normal.as_str())
+    {
+        return Err(OperateError::Busy);
+    }
+    let slot = mounts
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(OperateError::TableFull)?;
+    *slot = Some(Mount { path: normal, fs });
+    Ok(())
+}
+
+/// Take the file system off `path`, it must not have open files.
+// mounts made at boot stay until the machine goes down
+#[allow(dead_code)]
+pub fn unmount(path: &str) -> Result<(), OperateError> {
+    let normal = Path::new(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| {
+            mount
+                .as_ref()
+                .is_some_and(|mount| mount.path.as_str() == normal.as_str())
+        })
+        .ok_or(OperateError::NotFound)?;
+    if FILES
+        .lock()
+        .iter()
+        .flatten()
+        .any(|file| file.mount == index)
+    {
+        return Err(OperateError::Busy);
+    }
+    mounts[index] = None;
+    DENTRIES.lock().forget(index);
+    Ok(())
+}
+
+/// Visit each mount point and the name of its file system.
+// the ext4 test checks its mount with it
+#[cfg_attr(not(feature = "ext4-test"), allow(dead_code))]
+pub fn mounts(mut visit: impl FnMut(&str, &'static str)) {
+    for mount in MOUNTS.lock().iter().flatten() {
+        let path = mount.path.as_str();
+        visit(if path.is_empty() { "/" } else { path }, mount.fs.name());
+    }
+}
+
+pub fn stat(path: &str) -> Result<Stat, OperateError> {
+    let (_, fs, inode) = resolve(path)?;
+    fs.stat(inode)
+}
+
+/// Call `visit` with the inode and name of each entry of the directory at
+/// `path` until it returns `false`.
+pub fn read_dir(path: &str, mut visit: impl FnMut(u64, &[u8]) -> bool) -> Result<(), OperateError> {
+    let (_, fs, inode) = resolve(path)?;
+    if fs.stat(inode)?.kind != FileKind::Directory {
+        return Err(OperateError::NotDirectory);
+    }
+    fs.read_dir(inode, &mut visit)
+}
+
+/// Open the file or directory at `path`, reading and writing start at its beginning.
+pub fn open(path: &str) -> Result<Fd, OperateError> {
+    let (mount, fs, inode) = resolve(path)?;
+    let kind = fs.stat(inode)?.kind;
+    let mut files = FILES.lock();
+    let index = files
+        .iter()
+        .position(|file| file.is_none())
+        .ok_or(OperateError::TableFull)?;
+    files[index] = Some(File {
+        mount,
+        fs,
+        inode,
+        kind,
+        offset: 0,
+    });
+    Ok(Fd(index))
+}
+
+pub fn close(fd: Fd) -> Result<(), OperateError> {
+    FILES
+        .lock()
+        .get_mut(fd.0)
+        .and_then(Option::take)
+        .map(|_| ())
+        .ok_or(OperateError::InvalidFileDescriptor)
+}
+
+fn file(fd: Fd) -> Result<File, OperateError> {
+    FILES
+        .lock()
+        .get(fd.0)
+        .copied()
+        .flatten()
+        .ok_or(OperateError::InvalidFileDescriptor)
+}
+
+fn advance(fd: Fd, offset: u64) {
+    if let Some(Some(file)) = FILES.lock().get_mut(fd.0) {
+        file.offset = offset;
+    }
+}
+
+/// Read at the offset of `fd` and move it past what was read.
+pub fn read(fd: Fd, buffer: &mut [u8]) -> Result<usize, OperateError> {
+    let file = file(fd)?;
+    if file.kind == FileKind::Directory {
+        return Err(OperateError::IsDirectory);
+    }
+    let read = file.fs.read(file.inode, file.offset, buffer)?;
+    advance(fd, file.offset + read as u64);
+    Ok(read)
+}
+
+/// Write at the offset of `fd` and move it past what was written.
+// every file system mounted so far is read-only
+#[allow(dead_code)]
+pub fn write(fd: Fd, buffer: &[u8]) -> Result<usize, OperateError> {
+    let file = file(fd)?;
+    if file.kind == FileKind::Directory {
+        return Err(OperateError::IsDirectory);
+    }
+    let written = file.fs.write(file.inode, file.offset, buffer)?;
+    advance(fd, file.offset + written as u64);
+    Ok(written)
+}
+
+#[cfg_attr(not(feature = "ext4-test"), allow(dead_code))]
+pub fn seek(fd: Fd, offset: u64) -> Result<(), OperateError> {
+    file(fd)?;
+    advance(fd, offset);
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "ext4-test"), allow(dead_code))]
+pub fn fstat(fd: Fd) -> Result<Stat, OperateError> {
+    let file = file(fd)?;
+    file.fs.stat(file.inode)
+}
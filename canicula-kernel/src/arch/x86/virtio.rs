@@ -0,0 +1,61 @@
+//! canicula-virtio wired to the kernel: PCI configuration access, device memory
+//! from the vmap area and DMA memory from the frame allocator.
+
+use canicula_virtio::transport::pci::{self as virtio_pci, PciConfig, PciTransport};
+use canicula_virtio::{DeviceType, DmaRegion};
+
+use super::frames::Dma;
+use super::pci::{Bar, PciAddress, PciDevice, COMMAND_BUS_MASTER, COMMAND_MEMORY_SPACE};
+use super::vm;
+
+const BAR0: u8 = 0x10;
+const BAR_COUNT: u8 = 6;
+
+impl PciConfig for PciAddress {
+    fn read32(&self, offset: u8) -> u32 {
+        PciAddress::read32(self, offset)
+    }
+}
+
+/// Whether `device` is a virtio device of type `kind`.
+pub fn is_type(device: &PciDevice, kind: DeviceType) -> bool {
+    virtio_pci::device_type(&device.address) == Some(kind)
+}
+
+pub fn transport(device: &PciDevice) -> Result<PciTransport, &'static str> {
+    // physical start, size and where it is mapped
+    let mut windows = [(0u64, 0u64, 0u64); BAR_COUNT as usize];
+    let mut index = 0;
+    while index < BAR_COUNT {
+        let wide = device.address.read32(BAR0 + index * 4) & 0x7 == 0x4;
+        if let Some(Bar::Memory { address, size }) = device.bar(index) {
+            if address != 0 {
+                let base = vm::map_mmio(address, size).map_err(|_| "cannot map a BAR")?;
+                windows[index as usize] = (address, size, base);
+            }
+        }
+        // the upper half of a 64-bit BAR is not a BAR of its own
+        index += if wide { 2 } else { 1 };
+    }
+    device.enable(COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+
+    let map = |physical: u64| {
+        windows
+            .iter()
+            .find(|(start, size, _)| *size != 0 && (*start..*start + *size).contains(&physical))
+            .map_or(core::ptr::null_mut(), |(start, _, base)| {
+                (base + (physical - start)) as *mut u8
+            })
+    };
+    // every capability window lies in a BAR mapped above
+    unsafe { PciTransport::new(&device.address, map) }.map_err(|_| "not a modern virtio device")
+}
+
+/// The same memory as canicula-virtio describes it.
+pub fn region(dma: &Dma) -> DmaRegion {
+    DmaRegion {
+        virtual_address: dma.virtual_address,
+        physical_address: dma.physical,
+        size: dma.size,
+    }
+}
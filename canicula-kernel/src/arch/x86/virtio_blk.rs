@@ -0,0 +1,278 @@
+//! virtio-blk disks over the modern PCI transport.
+//!
+//! Each disk has one request queue with a single request in flight at a time:
+//! a header, the data in a bounce buffer and a status byte, polled for like AHCI.
+
+use canicula_common::fs::OperateError;
+use canicula_virtio::transport::pci::PciTransport;
+use canicula_virtio::{Buffer, DeviceType, Transport, VirtQueue};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use super::block::{self, BlockDevice, SECTOR_SIZE};
+use super::device::Device;
+use super::driver::{register_driver, Driver};
+use super::frames::{self, Dma, Owner, FRAME_SIZE};
+use super::pci::PciDevice;
+use super::virtio;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+const REQUEST_QUEUE: u16 = 0;
+/// A request takes three descriptors.
+const QUEUE_SIZE: u16 = 8;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// `capacity` in the device configuration, in 512 byte sectors.
+const CONFIG_CAPACITY: usize = 0;
+
+// where the header and status sit within the request frame
+const HEADER: usize = 0;
+const HEADER_SIZE: u32 = 16;
+const STATUS: usize = 16;
+
+/// Sectors moved per request, the size of each disk's bounce buffer.
+const TRANSFER_SECTORS: usize = 128;
+/// Polls of the used ring before a request counts as timed out.
+const SPIN_LIMIT: usize = 10_000_000;
+const MAX_DISKS: usize = 4;
+
+struct Queue {
+    transport: PciTransport,
+    queue: VirtQueue,
+    /// Backs `queue`, kept for as long as the device may use it.
+    #[allow(dead_code)]
+    ring: Dma,
+    request: Dma,
+    buffer: Dma,
+}
+
+// the transport's registers and the DMA memory belong to the disk alone
+unsafe impl Send for Queue {}
+
+impl Queue {
+    /// Move `sectors` sectors between the disk and the bounce buffer.
+    fn request(&mut self, kind: u32, sector: u64, sectors: usize) -> Result<(), OperateError> {
+        unsafe {
+            let header = self.request.virtual_address.add(HEADER);
+            core::ptr::copy_nonoverlapping(kind.to_le_bytes().as_ptr(), header, 4);
+            core::ptr::write_bytes(header.add(4), 0, 4);
+            core::ptr::copy_nonoverlapping(sector.to_le_bytes().as_ptr(), header.add(8), 8);
+            // anything but OK, so a request the device never finished is not one
+            self.request
+                .virtual_address
+                .add(STATUS)
+                .write_volatile(u8::MAX);
+        }
+
+        let header = Buffer {
+            address: self.request.physical + HEADER as u64,
+            len: HEADER_SIZE,
+        };
+        let data = Buffer {
+            address: self.buffer.physical,
+            len: (sectors * SECTOR_SIZE) as u32,
+        };
+        let status = Buffer {
+            address: self.request.physical + STATUS as u64,
+            len: 1,
+        };
+        let added = if kind == VIRTIO_BLK_T_OUT {
+            self.queue.add(&[header, data], &[status])
+        } else {
+            self.queue.add(&[header], &[data, status])
+        };
+        let token = added.map_err(|_| OperateError::Busy)?;
+        self.queue.notify(&mut self.transport);
+
+        for _ in 0..SPIN_LIMIT {
+            if let Some((used, _)) = self.queue.pop_used() {
+                if used != token {
+                    continue;
+                }
+                let status = unsafe { self.request.virtual_address.add(STATUS).read_volatile() };
+                return match status {
+                    VIRTIO_BLK_S_OK => Ok(()),
+                    _ => Err(OperateError::IO),
+                };
+            }
+            core::hint::spin_loop();
+        }
+        Err(OperateError::TimeOut)
+    }
+
+    fn buffer(&mut self, len: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.buffer.virtual_address, len) }
+    }
+}
+
+struct Disk {
+    name: &'static str,
+    queue: Mutex<Queue>,
+    sectors: u64,
+    read_only: bool,
+}
+
+impl Disk {
+    /// Check that `len` bytes at `sector` are whole sectors on the disk.
+    fn check(&self, sector: u64, len: usize) -> Result<(), OperateError> {
+        let count = (len / SECTOR_SIZE) as u64;
+        if len & (SECTOR_SIZE - 1) != 0
+            || sector
+                .checked_add(count)
+                .is_none_or(|end| end > self.sectors)
+        {
+            return Err(OperateError::Fault);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Disk {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), OperateError> {
+        self.check(sector, buffer.len())?;
+        let mut queue = self.queue.lock();
+        for (index, chunk) in buffer
+            .chunks_mut(TRANSFER_SECTORS * SECTOR_SIZE)
+            .enumerate()
+        {
+            let sector = sector + (index * TRANSFER_SECTORS) as u64;
+            queue.request(VIRTIO_BLK_T_IN, sector, chunk.len() / SECTOR_SIZE)?;
+            chunk.copy_from_slice(queue.buffer(chunk.len()));
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), OperateError> {
+        if self.read_only {
+            return Err(OperateError::ReadOnly);
+        }
+        self.check(sector, buffer.len())?;
+        let mut queue = self.queue.lock();
+        for (index, chunk) in buffer.chunks(TRANSFER_SECTORS * SECTOR_SIZE).enumerate() {
+            let sector = sector + (index * TRANSFER_SECTORS) as u64;
+            queue.buffer(chunk.len()).copy_from_slice(chunk);
+            queue.request(VIRTIO_BLK_T_OUT, sector, chunk.len() / SECTOR_SIZE)?;
+        }
+        Ok(())
+    }
+}
+
+const NAMES: [&str; MAX_DISKS] = ["vda", "vdb", "vdc", "vdd"];
+
+static DISKS: [Once<Disk>; MAX_DISKS] = [const { Once::new() }; MAX_DISKS];
+
+/// DMA memory for the ring, the request and the bounce buffer, freed together on failure.
+fn allocate(ring_size: usize) -> Option<(Dma, Dma, Dma)> {
+    let ring = frames::allocate_dma(ring_size, Owner::Drivers)?;
+    let Some(request) = frames::allocate_dma(FRAME_SIZE as usize, Owner::Drivers) else {
+        frames::free_dma(ring);
+        return None;
+    };
+    let Some(buffer) = frames::allocate_dma(TRANSFER_SECTORS * SECTOR_SIZE, Owner::Drivers) else {
+        frames::free_dma(ring);
+        frames::free_dma(request);
+        return None;
+    };
+    Some((ring, request, buffer))
+}
+
+fn attach(index: usize, mut transport: PciTransport) -> Result<Disk, &'static str> {
+    let features = canicula_virtio::begin_init(&mut transport, VIRTIO_BLK_F_RO)
+        .map_err(|_| "feature negotiation failed")?;
+    let size = QUEUE_SIZE.min(queue_size(&mut transport)?);
+    let (ring, request, buffer) =
+        allocate(VirtQueue::memory_size(size, false)).ok_or("out of DMA memory")?;
+    let mut queue = match VirtQueue::new(
+        &mut transport,
+        REQUEST_QUEUE,
+        size,
+        virtio::region(&ring),
+        features,
+    ) {
+        Ok(queue) => queue,
+        Err(_) => {
+            frames::free_dma(ring);
+            frames::free_dma(request);
+            frames::free_dma(buffer);
+            return Err("cannot set up the request queue");
+        }
+    };
+    queue.set_interrupts(false);
+    canicula_virtio::finish_init(&mut transport);
+
+    let mut capacity = [0u8; 8];
+    transport.read_config(CONFIG_CAPACITY, &mut capacity);
+    let sectors = u64::from_le_bytes(capacity);
+    let read_only = features & VIRTIO_BLK_F_RO != 0;
+    info!(
+        "[virtio-blk] {}: {} MiB{}",
+        NAMES[index],
+        (sectors * SECTOR_SIZE as u64) >> 20,
+        if read_only { ", read-only" } else { "" }
+    );
+    Ok(Disk {
+        name: NAMES[index],
+        queue: Mutex::new(Queue {
+            transport,
+            queue,
+            ring,
+            request,
+            buffer,
+        }),
+        sectors,
+        read_only,
+    })
+}
+
+/// Largest power of two queue size the device takes.
+fn queue_size(transport: &mut PciTransport) -> Result<u16, &'static str> {
+    match transport.max_queue_size(REQUEST_QUEUE) {
+        0 => Err("no request queue"),
+        max => Ok(1 << max.ilog2()),
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<(), &'static str> {
+    let index = DISKS
+        .iter()
+        .position(|slot| !slot.is_completed())
+        .ok_or("no room for another disk")?;
+    let disk = attach(index, virtio::transport(device)?)?;
+    let disk = DISKS[index].call_once(|| disk);
+    if block::register(disk).is_none() {
+        warn!("[virtio-blk] no room to register {}", disk.name);
+    }
+    Ok(())
+}
+
+struct VirtioBlk;
+
+impl Driver for VirtioBlk {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        device
+            .pci()
+            .is_some_and(|device| virtio::is_type(device, DeviceType::Block))
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), &'static str> {
+        probe(device.pci().ok_or("not a PCI device")?)
+    }
+}
+
+register_driver!(VirtioBlk);
@@ -0,0 +1,311 @@
+//! virtio-net interfaces over the modern PCI transport.
+//!
+//! The receive queue is kept full of frame sized buffers, each handed back to
+//! the device as soon as its frame is taken. Frames are sent one at a time from
+//! a single buffer, waiting for the device to finish with it. Nothing uses the
+//! device interrupt, [`NetworkDevice::receive_frame`] polls.
+
+use canicula_common::fs::OperateError;
+use canicula_virtio::transport::pci::PciTransport;
+use canicula_virtio::{Buffer, DeviceType, Transport, VirtQueue};
+use log::{info, warn};
+use spin::{Mutex, Once};
+
+use super::device::Device;
+use super::driver::{register_driver, Driver};
+use super::frames::{self, Dma, Owner};
+use super::net::{self, MacAddress, NetworkDevice, MAX_FRAME_SIZE};
+use super::pci::PciDevice;
+use super::virtio;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 16;
+
+/// `virtio_net_hdr`, in front of every frame. With `VIRTIO_F_VERSION_1` it
+/// always has `num_buffers`.
+const HEADER_SIZE: usize = 12;
+/// Room for one frame and its header.
+const BUFFER_SIZE: usize = 2048;
+const _: () = assert!(HEADER_SIZE + MAX_FRAME_SIZE <= BUFFER_SIZE);
+
+/// `mac` in the device configuration.
+const CONFIG_MAC: usize = 0;
+
+/// Polls of the used ring before a sent frame counts as lost.
+const SPIN_LIMIT: usize = 10_000_000;
+const MAX_INTERFACES: usize = 4;
+
+struct Queues {
+    transport: PciTransport,
+    receive: VirtQueue,
+    transmit: VirtQueue,
+    /// Back the two queues, kept for as long as the device may use them.
+    #[allow(dead_code)]
+    receive_ring: Dma,
+    #[allow(dead_code)]
+    transmit_ring: Dma,
+    /// `QUEUE_SIZE` receive buffers of `BUFFER_SIZE` bytes.
+    receive_buffers: Dma,
+    transmit_buffer: Dma,
+    /// Which receive buffer each chain token stands for.
+    receiving: [usize; QUEUE_SIZE as usize],
+}
+
+// the transport's registers and the DMA memory belong to the interface alone
+unsafe impl Send for Queues {}
+
+impl Queues {
+    /// Hand receive buffer `index` to the device.
+    fn post(&mut self, index: usize) -> Result<(), OperateError> {
+        let buffer = Buffer {
+            address: self.receive_buffers.physical + (index * BUFFER_SIZE) as u64,
+            len: BUFFER_SIZE as u32,
+        };
+        let token = self
+            .receive
+            .add(&[], &[buffer])
+            .map_err(|_| OperateError::Busy)?;
+        self.receiving[token as usize] = index;
+        Ok(())
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), OperateError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(OperateError::Fault);
+        }
+        let len = HEADER_SIZE + frame.len();
+        let buffer =
+            unsafe { core::slice::from_raw_parts_mut(self.transmit_buffer.virtual_address, len) };
+        // no checksum offload or segmentation, the header stays zero
+        buffer[..HEADER_SIZE].fill(0);
+        buffer[HEADER_SIZE..].copy_from_slice(frame);
+
+        let token = self
+            .transmit
+            .add(
+                &[Buffer {
+                    address: self.transmit_buffer.physical,
+                    len: len as u32,
+                }],
+                &[],
+            )
+            .map_err(|_| OperateError::Busy)?;
+        self.transmit.notify(&mut self.transport);
+
+        for _ in 0..SPIN_LIMIT {
+            if let Some((used, _)) = self.transmit.pop_used() {
+                if used == token {
+                    return Ok(());
+                }
+                continue;
+            }
+            core::hint::spin_loop();
+        }
+        Err(OperateError::TimeOut)
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<usize>, OperateError> {
+        let Some((token, written)) = self.receive.pop_used() else {
+            return Ok(None);
+        };
+        let index = self.receiving[token as usize];
+        let frame = (written as usize).saturating_sub(HEADER_SIZE);
+        let len = frame.min(buffer.len());
+        unsafe {
+            let source = self
+                .receive_buffers
+                .virtual_address
+                .add(index * BUFFER_SIZE + HEADER_SIZE);
+            core::ptr::copy_nonoverlapping(source, buffer.as_mut_ptr(), len);
+        }
+        self.post(index)?;
+        self.receive.notify(&mut self.transport);
+        Ok(Some(len))
+    }
+}
+
+struct Interface {
+    name: &'static str,
+    mac: MacAddress,
+    queues: Mutex<Queues>,
+}
+
+impl NetworkDevice for Interface {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send_frame(&self, frame: &[u8]) -> Result<(), OperateError> {
+        self.queues.lock().send(frame)
+    }
+
+    fn receive_frame(&self, buffer: &mut [u8]) -> Result<Option<usize>, OperateError> {
+        self.queues.lock().receive(buffer)
+    }
+}
+
+const NAMES: [&str; MAX_INTERFACES] = ["eth0", "eth1", "eth2", "eth3"];
+
+static INTERFACES: [Once<Interface>; MAX_INTERFACES] = [const { Once::new() }; MAX_INTERFACES];
+
+/// DMA memory for an interface, freed again if any of it is missing.
+struct Memory {
+    receive_ring: Dma,
+    transmit_ring: Dma,
+    receive_buffers: Dma,
+    transmit_buffer: Dma,
+}
+
+impl Memory {
+    fn allocate(ring_size: usize) -> Option<Self> {
+        let sizes = [
+            ring_size,
+            ring_size,
+            QUEUE_SIZE as usize * BUFFER_SIZE,
+            BUFFER_SIZE,
+        ];
+        let mut allocated: [Option<Dma>; 4] = [None, None, None, None];
+        for (slot, size) in allocated.iter_mut().zip(sizes) {
+            *slot = frames::allocate_dma(size, Owner::Network);
+        }
+        match allocated {
+            [Some(receive_ring), Some(transmit_ring), Some(receive_buffers), Some(transmit_buffer)] => {
+                Some(Memory {
+                    receive_ring,
+                    transmit_ring,
+                    receive_buffers,
+                    transmit_buffer,
+                })
+            }
+            allocated => {
+                allocated.into_iter().flatten().for_each(frames::free_dma);
+                None
+            }
+        }
+    }
+
+    fn free(self) {
+        frames::free_dma(self.receive_ring);
+        frames::free_dma(self.transmit_ring);
+        frames::free_dma(self.receive_buffers);
+        frames::free_dma(self.transmit_buffer);
+    }
+}
+
+fn attach(index: usize, mut transport: PciTransport) -> Result<Interface, &'static str> {
+    let features = canicula_virtio::begin_init(&mut transport, VIRTIO_NET_F_MAC)
+        .map_err(|_| "feature negotiation failed")?;
+    if features & VIRTIO_NET_F_MAC == 0 {
+        return Err("no MAC address");
+    }
+    let size = QUEUE_SIZE
+        .min(queue_size(&mut transport, RECEIVE_QUEUE)?)
+        .min(queue_size(&mut transport, TRANSMIT_QUEUE)?);
+    let memory =
+        Memory::allocate(VirtQueue::memory_size(size, false)).ok_or("out of DMA memory")?;
+
+    let queues = VirtQueue::new(
+        &mut transport,
+        RECEIVE_QUEUE,
+        size,
+        virtio::region(&memory.receive_ring),
+        features,
+    )
+    .and_then(|receive| {
+        let transmit = VirtQueue::new(
+            &mut transport,
+            TRANSMIT_QUEUE,
+            size,
+            virtio::region(&memory.transmit_ring),
+            features,
+        )?;
+        Ok((receive, transmit))
+    });
+    let (mut receive, mut transmit) = match queues {
+        Ok(queues) => queues,
+        Err(_) => {
+            memory.free();
+            return Err("cannot set up the queues");
+        }
+    };
+    receive.set_interrupts(false);
+    transmit.set_interrupts(false);
+
+    let mut queues = Queues {
+        transport,
+        receive,
+        transmit,
+        receive_ring: memory.receive_ring,
+        transmit_ring: memory.transmit_ring,
+        receive_buffers: memory.receive_buffers,
+        transmit_buffer: memory.transmit_buffer,
+        receiving: [0; QUEUE_SIZE as usize],
+    };
+    for buffer in 0..size as usize {
+        queues
+            .post(buffer)
+            .map_err(|_| "cannot fill the receive queue")?;
+    }
+    canicula_virtio::finish_init(&mut queues.transport);
+    queues.receive.notify(&mut queues.transport);
+
+    let mut mac = [0u8; 6];
+    queues.transport.read_config(CONFIG_MAC, &mut mac);
+    info!(
+        "[virtio-net] {}: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        NAMES[index], mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    );
+    Ok(Interface {
+        name: NAMES[index],
+        mac,
+        queues: Mutex::new(queues),
+    })
+}
+
+/// Largest power of two size queue `queue` takes.
+fn queue_size(transport: &mut PciTransport, queue: u16) -> Result<u16, &'static str> {
+    match transport.max_queue_size(queue) {
+        0 => Err("missing a queue"),
+        max => Ok(1 << max.ilog2()),
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<(), &'static str> {
+    let index = INTERFACES
+        .iter()
+        .position(|slot| !slot.is_completed())
+        .ok_or("no room for another interface")?;
+    let interface = attach(index, virtio::transport(device)?)?;
+    let interface = INTERFACES[index].call_once(|| interface);
+    if net::register(interface).is_none() {
+        warn!("[virtio-net] no room to register {}", interface.name);
+    }
+    Ok(())
+}
+
+struct VirtioNet;
+
+impl Driver for VirtioNet {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn matches(&self, device: &Device) -> bool {
+        device
+            .pci()
+            .is_some_and(|device| virtio::is_type(device, DeviceType::Network))
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), &'static str> {
+        probe(device.pci().ok_or("not a PCI device")?)
+    }
+}
+
+register_driver!(VirtioNet);
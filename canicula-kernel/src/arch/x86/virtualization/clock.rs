@@ -0,0 +1,174 @@
+//! Guest time: which TSC the guest reads and a kvmclock style page telling it
+//! what that TSC means in nanoseconds.
+//!
+//! The guest TSC is the host TSC scaled by `TSC_RATIO` plus the VMCB TSC offset.
+//! Pausing the clock remembers the guest TSC, resuming picks a new offset so the
+//! guest continues from there and never sees the time the VM was suspended.
+
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{fence, Ordering};
+
+use super::svm::{VCpu, TSC_RATIO_DEFAULT};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// The guest TSC does not go backwards between processors.
+pub const PVCLOCK_TSC_STABLE: u8 = 1 << 0;
+
+/// `pvclock_vcpu_time_info`, the page kvmclock guests read.
+///
+/// Guest nanoseconds are `system_time` plus `tsc - tsc_timestamp` scaled by
+/// `tsc_to_system_mul` and `tsc_shift`. An odd `version` means the host is
+/// writing, the guest retries until it reads the same even version twice.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct PvClock {
+    pub version: u32,
+    pad0: u32,
+    pub tsc_timestamp: u64,
+    pub system_time: u64,
+    pub tsc_to_system_mul: u32,
+    pub tsc_shift: i8,
+    pub flags: u8,
+    pad: [u8; 2],
+}
+
+const _: () = assert!(core::mem::size_of::<PvClock>() == 32);
+
+/// Multiplier and shift turning ticks at `tsc_hz` into nanoseconds, as kvmclock
+/// expects them: `((ticks << shift) * mul) >> 32`, a negative shift shifts right.
+pub fn time_scale(tsc_hz: u64) -> (u32, i8) {
+    let mut scaled = NANOS_PER_SECOND;
+    let mut ticks = tsc_hz.max(1);
+    let mut shift = 0i8;
+    while ticks > scaled * 2 || ticks >> 32 != 0 {
+        ticks >>= 1;
+        shift -= 1;
+    }
+    let mut ticks = ticks as u32;
+    while ticks as u64 <= scaled || scaled >> 32 != 0 {
+        if scaled >> 32 != 0 || ticks & 0x8000_0000 != 0 {
+            scaled >>= 1;
+        } else {
+            ticks <<= 1;
+        }
+        shift += 1;
+    }
+    (((scaled << 32) / ticks as u64) as u32, shift)
+}
+
+/// Apply a [`time_scale`] result to `ticks`.
+pub fn scale_ticks(ticks: u64, mul: u32, shift: i8) -> u64 {
+    let ticks = if shift < 0 {
+        ticks >> -shift
+    } else {
+        ticks << shift
+    };
+    ((ticks as u128 * mul as u128) >> 32) as u64
+}
+
+/// Time as one guest sees it, kept in its VCpu's TSC offset and ratio.
+pub struct GuestClock {
+    host_tsc_hz: u64,
+    guest_tsc_hz: u64,
+    /// Guest TSC at the moment the clock was paused.
+    paused: Option<u64>,
+    page: Option<&'static mut PvClock>,
+}
+
+impl GuestClock {
+    /// A clock for a host TSC ticking at `host_tsc_hz`, which the guest reads unscaled.
+    pub fn new(host_tsc_hz: u64) -> Self {
+        GuestClock {
+            host_tsc_hz,
+            guest_tsc_hz: host_tsc_hz,
+            paused: None,
+            page: None,
+        }
+    }
+
+    /// Give the guest a TSC ticking at `guest_tsc_hz`, needs TSC scaling.
+    // guests run at the host's rate until machines can be created with a frequency
+    #[allow(dead_code)]
+    pub fn with_frequency(mut self, guest_tsc_hz: u64) -> Self {
+        self.guest_tsc_hz = guest_tsc_hz;
+        self
+    }
+
+    /// Keep `page` up to date, the VMM maps it into the guest and tells it where.
+    // no guest is told about a clock page yet
+    #[allow(dead_code)]
+    pub fn with_page(mut self, page: &'static mut PvClock) -> Self {
+        *page = PvClock::default();
+        self.page = Some(page);
+        self
+    }
+
+    /// Program `vcpu` so its TSC starts at zero now, after `VCpu::configure`.
+    pub fn start(&mut self, vcpu: &mut VCpu) -> Result<(), &'static str> {
+        let ratio = if self.guest_tsc_hz == self.host_tsc_hz {
+            TSC_RATIO_DEFAULT
+        } else if self.host_tsc_hz == 0 {
+            return Err("host TSC frequency is unknown");
+        } else {
+            ((self.guest_tsc_hz as u128) << 32).div_ceil(self.host_tsc_hz as u128) as u64
+        };
+        vcpu.set_tsc_ratio(ratio)?;
+        self.paused = None;
+        self.continue_from(vcpu, 0);
+        Ok(())
+    }
+
+    /// The TSC `vcpu` reads right now, or where it stopped while paused.
+    pub fn guest_tsc(&self, vcpu: &VCpu) -> u64 {
+        match self.paused {
+            Some(tsc) => tsc,
+            None => vcpu.guest_tsc(unsafe { _rdtsc() }),
+        }
+    }
+
+    /// Stop guest time, before the VM is suspended. The guest must not run
+    /// until [`GuestClock::resume`], its TSC keeps going otherwise.
+    pub fn pause(&mut self, vcpu: &VCpu) {
+        if self.paused.is_none() {
+            self.paused = Some(self.guest_tsc(vcpu));
+        }
+    }
+
+    /// Let guest time go on from where [`GuestClock::pause`] stopped it.
+    pub fn resume(&mut self, vcpu: &mut VCpu) {
+        if let Some(tsc) = self.paused.take() {
+            self.continue_from(vcpu, tsc);
+        }
+    }
+
+    fn continue_from(&mut self, vcpu: &mut VCpu, guest_tsc: u64) {
+        let now = vcpu.guest_tsc(unsafe { _rdtsc() });
+        let offset = vcpu.vmcb().tsc_offset();
+        vcpu.vmcb_mut()
+            .set_tsc_offset(offset.wrapping_add(guest_tsc.wrapping_sub(now) as i64));
+        self.update(vcpu);
+    }
+
+    /// Publish the current guest TSC and time in the shared page, if there is one.
+    pub fn update(&mut self, vcpu: &VCpu) {
+        let tsc = self.guest_tsc(vcpu);
+        let (mul, shift) = time_scale(self.guest_tsc_hz);
+        let Some(page) = self.page.as_deref_mut() else {
+            return;
+        };
+
+        let version = page.version.wrapping_add(1) | 1;
+        unsafe { core::ptr::write_volatile(&mut page.version, version) };
+        fence(Ordering::Release);
+        unsafe {
+            core::ptr::write_volatile(&mut page.tsc_timestamp, tsc);
+            core::ptr::write_volatile(&mut page.system_time, scale_ticks(tsc, mul, shift));
+            core::ptr::write_volatile(&mut page.tsc_to_system_mul, mul);
+            core::ptr::write_volatile(&mut page.tsc_shift, shift);
+            core::ptr::write_volatile(&mut page.flags, PVCLOCK_TSC_STABLE);
+        }
+        fence(Ordering::Release);
+        unsafe { core::ptr::write_volatile(&mut page.version, version.wrapping_add(1)) };
+    }
+}
@@ -0,0 +1,194 @@
+//! One virtual machine: guest memory, the nested page table over it and a vCPU.
+//!
+//! Guest physical memory starts at zero and is one block of host frames, so a
+//! guest has at most 4 MiB. The guest starts in real mode and every I/O port
+//! and MSR it touches exits to the host, nothing reaches the hardware.
+
+use crate::arch::x86::frames::{self, Owner, FRAME_SIZE, ORDERS};
+use crate::arch::x86::time;
+
+use super::clock::GuestClock;
+use super::svm::{GuestConfig, VCpu, Vmcb};
+use super::{ExitAction, ExitHandlers, VirtError, VmExit};
+
+/// The largest block of frames there is.
+pub const MAX_MEMORY: u64 = FRAME_SIZE << (ORDERS - 1);
+
+/// Entries in a table of the nested page table.
+const ENTRIES: u64 = 512;
+/// Present, writable and user, nested paging treats every guest access as a user access.
+const NPT_FLAGS: u64 = 0b111;
+/// PML4, PDPT and page directory, the page tables come after them.
+const UPPER_TABLES: u64 = 3;
+
+/// The VMCB, then the I/O permission map, then the MSR permission map.
+const CONTROL_PAGES: u64 = 6;
+const IOPM_PAGE: u64 = 1;
+const IOPM_PAGES: u64 = 3;
+const MSRPM_PAGE: u64 = 4;
+const MSRPM_PAGES: u64 = 2;
+
+/// Where a real mode guest's stack starts, the top of its first segment.
+const REAL_MODE_STACK: u64 = 0xfffe;
+
+/// Frames zeroed and counted for guests.
+fn allocate(pages: u64) -> Result<u64, VirtError> {
+    let physical =
+        frames::allocate_contiguous(pages, Owner::Guest).ok_or(VirtError::OutOfFrames)?;
+    unsafe {
+        core::ptr::write_bytes(
+            frames::to_virtual(physical),
+            0,
+            (pages * FRAME_SIZE) as usize,
+        )
+    };
+    Ok(physical)
+}
+
+pub struct VirtualMachine {
+    /// Host physical address of guest physical zero.
+    memory: u64,
+    pages: u64,
+    tables: u64,
+    table_pages: u64,
+    control: u64,
+    config: GuestConfig,
+    vcpu: VCpu,
+    clock: GuestClock,
+    pub handlers: ExitHandlers,
+}
+
+impl VirtualMachine {
+    /// A guest with `size` bytes of zeroed memory that starts at `entry` once reset.
+    pub fn new(size: u64, entry: u64) -> Result<Self, VirtError> {
+        if size == 0 {
+            return Err(VirtError::Empty);
+        }
+        if size > MAX_MEMORY {
+            return Err(VirtError::TooLarge);
+        }
+        let pages = size.div_ceil(FRAME_SIZE);
+        let table_pages = UPPER_TABLES + pages.div_ceil(ENTRIES);
+
+        let memory = allocate(pages)?;
+        let tables = allocate(table_pages).inspect_err(|_| {
+            frames::free_contiguous(memory, pages);
+        })?;
+        let control = allocate(CONTROL_PAGES).inspect_err(|_| {
+            frames::free_contiguous(memory, pages);
+            frames::free_contiguous(tables, table_pages);
+        })?;
+        map_memory(tables, memory, pages);
+
+        // every bit set, so every port and MSR access exits
+        let maps = [(IOPM_PAGE, IOPM_PAGES), (MSRPM_PAGE, MSRPM_PAGES)];
+        for (page, count) in maps {
+            unsafe {
+                core::ptr::write_bytes(
+                    frames::to_virtual(control + page * FRAME_SIZE),
+                    0xff,
+                    (count * FRAME_SIZE) as usize,
+                )
+            };
+        }
+        let vmcb = unsafe { &mut *(frames::to_virtual(control) as *mut Vmcb) };
+
+        let mut config = GuestConfig::real_mode(entry, REAL_MODE_STACK.min(pages * FRAME_SIZE - 2));
+        config.nested_cr3 = Some(tables);
+        config.iopm = Some(control + IOPM_PAGE * FRAME_SIZE);
+        config.msrpm = Some(control + MSRPM_PAGE * FRAME_SIZE);
+
+        Ok(VirtualMachine {
+            memory,
+            pages,
+            tables,
+            table_pages,
+            control,
+            config,
+            vcpu: VCpu::new(vmcb, control),
+            clock: GuestClock::new(time::tsc_frequency().unwrap_or(0)),
+            handlers: ExitHandlers::with_defaults(),
+        })
+    }
+
+    pub fn memory_size(&self) -> u64 {
+        self.pages * FRAME_SIZE
+    }
+
+    /// The address space id the guest's TLB entries are tagged with, unique to it.
+    pub fn set_asid(&mut self, asid: u32) {
+        self.config.asid = asid;
+    }
+
+    /// Where guest physical `address` is for the host, if `len` bytes from it are guest memory.
+    fn host_address(&self, address: u64, len: usize) -> Result<*mut u8, VirtError> {
+        address
+            .checked_add(len as u64)
+            .filter(|end| *end <= self.memory_size())
+            .ok_or(VirtError::OutOfRange)?;
+        Ok(unsafe { frames::to_virtual(self.memory).add(address as usize) })
+    }
+
+    /// Copy `bytes` into guest memory at guest physical `address`.
+    pub fn write_memory(&mut self, address: u64, bytes: &[u8]) -> Result<(), VirtError> {
+        let destination = self.host_address(address, bytes.len())?;
+        unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), destination, bytes.len()) };
+        Ok(())
+    }
+
+    /// Put the vCPU back in its initial state and start guest time from zero.
+    /// Memory is left as it is.
+    pub fn reset(&mut self) {
+        self.vcpu.configure(&self.config);
+        // the guest TSC ticks at the host's rate, so there is no ratio that could be refused
+        let _ = self.clock.start(&mut self.vcpu);
+    }
+
+    /// Stop guest time while the guest does not run.
+    pub fn pause(&mut self) {
+        self.clock.pause(&self.vcpu);
+    }
+
+    /// Let guest time go on from where [`VirtualMachine::pause`] stopped it.
+    pub fn resume(&mut self) {
+        self.clock.resume(&mut self.vcpu);
+    }
+
+    /// Enter the guest once and hand the exit to its handler.
+    pub fn step(&mut self) -> (VmExit, ExitAction) {
+        let exit = self.vcpu.run();
+        let action = self.handlers.dispatch(&mut self.vcpu, &exit);
+        (exit, action)
+    }
+}
+
+impl Drop for VirtualMachine {
+    fn drop(&mut self) {
+        frames::free_contiguous(self.memory, self.pages);
+        frames::free_contiguous(self.tables, self.table_pages);
+        frames::free_contiguous(self.control, CONTROL_PAGES);
+    }
+}
+
+/// Fill the nested page table at `tables` so guest physical zero on is `memory`.
+fn map_memory(tables: u64, memory: u64, pages: u64) {
+    let base = frames::to_virtual(tables) as *mut u64;
+    let table = |index: u64| tables + index * FRAME_SIZE;
+    let set = |table: u64, index: u64, entry: u64| unsafe {
+        base.add((table * ENTRIES + index) as usize).write(entry)
+    };
+    // PML4 to PDPT to page directory, one entry each covers the first 1 GiB
+    set(0, 0, table(1) | NPT_FLAGS);
+    set(1, 0, table(2) | NPT_FLAGS);
+    for page in 0..pages {
+        let page_table = UPPER_TABLES + page / ENTRIES;
+        if page % ENTRIES == 0 {
+            set(2, page / ENTRIES, table(page_table) | NPT_FLAGS);
+        }
+        set(
+            page_table,
+            page % ENTRIES,
+            (memory + page * FRAME_SIZE) | NPT_FLAGS,
+        );
+    }
+}
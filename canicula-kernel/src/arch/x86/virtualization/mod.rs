@@ -0,0 +1,472 @@
+//! Virtual machines, run with AMD SVM.
+//!
+//! A machine is created with its memory and the image it boots, then started,
+//! suspended, resumed and stopped through the functions here. There are no
+//! threads yet, so starting or resuming a machine runs it on the caller until
+//! it stops or is suspended. [`stop_virtual_machine`] and
+//! [`suspend_virtual_machine`] work from interrupt handlers and exit handlers,
+//! the machine acts on them at its next exit. Intel CPUs are turned away
+//! until there is a VMX backend.
+
+pub mod clock;
+pub mod machine;
+pub mod svm;
+
+use log::{info, warn};
+use spin::Once;
+
+use super::cpu;
+use super::frames::{self, Owner, FRAME_SIZE};
+use super::interrupts::in_interrupt;
+use super::sync::IrqMutex;
+use machine::VirtualMachine;
+
+const MAX_MACHINES: usize = 4;
+const MAX_NAME: usize = 16;
+
+/// Why the guest stopped running, decoded from the backend specific exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExit {
+    /// `in`/`out` instruction on `port`.
+    Io {
+        port: u16,
+        size: u8,
+        write: bool,
+        string: bool,
+        repeat: bool,
+    },
+    Cpuid,
+    Hlt,
+    Vmmcall,
+    /// Nested page fault at guest physical address `address`.
+    NestedPageFault {
+        address: u64,
+        error_code: u64,
+    },
+    Msr {
+        write: bool,
+    },
+    /// A physical interrupt arrived while the guest was running.
+    Interrupt,
+    Shutdown,
+    Invalid,
+    Unknown(u64),
+}
+
+/// Coarse exit categories used to index the handler registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitKind {
+    Io,
+    Cpuid,
+    Hlt,
+    Vmmcall,
+    NestedPageFault,
+    Msr,
+    Interrupt,
+    Shutdown,
+    Invalid,
+    Unknown,
+}
+
+const EXIT_KINDS: usize = 10;
+
+impl VmExit {
+    pub fn kind(&self) -> ExitKind {
+        match self {
+            VmExit::Io { .. } => ExitKind::Io,
+            VmExit::Cpuid => ExitKind::Cpuid,
+            VmExit::Hlt => ExitKind::Hlt,
+            VmExit::Vmmcall => ExitKind::Vmmcall,
+            VmExit::NestedPageFault { .. } => ExitKind::NestedPageFault,
+            VmExit::Msr { .. } => ExitKind::Msr,
+            VmExit::Interrupt => ExitKind::Interrupt,
+            VmExit::Shutdown => ExitKind::Shutdown,
+            VmExit::Invalid => ExitKind::Invalid,
+            VmExit::Unknown(_) => ExitKind::Unknown,
+        }
+    }
+}
+
+/// What the run loop should do after an exit has been handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitAction {
+    /// Re-enter the guest.
+    Resume,
+    /// Leave the run loop, the guest is stopped.
+    Stop,
+}
+
+pub type ExitHandler = fn(&mut svm::VCpu, &VmExit) -> ExitAction;
+
+/// Per-VM table of exit handlers, unhandled exits stop the guest.
+pub struct ExitHandlers {
+    handlers: [Option<ExitHandler>; EXIT_KINDS],
+}
+
+impl ExitHandlers {
+    pub const fn new() -> Self {
+        ExitHandlers {
+            handlers: [None; EXIT_KINDS],
+        }
+    }
+
+    /// The default set: cpuid passthrough, hlt and shutdown stop the guest.
+    pub fn with_defaults() -> Self {
+        let mut handlers = ExitHandlers::new();
+        handlers.register(ExitKind::Cpuid, svm::handle_cpuid);
+        handlers.register(ExitKind::Hlt, |_, _| ExitAction::Stop);
+        handlers.register(ExitKind::Interrupt, |_, _| ExitAction::Resume);
+        handlers
+    }
+
+    pub fn register(&mut self, kind: ExitKind, handler: ExitHandler) {
+        self.handlers[kind as usize] = Some(handler);
+    }
+
+    pub fn dispatch(&self, vcpu: &mut svm::VCpu, exit: &VmExit) -> ExitAction {
+        match self.handlers[exit.kind() as usize] {
+            Some(handler) => handler(vcpu, exit),
+            None => {
+                log::warn!("[virtualization] unhandled exit {:?}, stopping guest", exit);
+                ExitAction::Stop
+            }
+        }
+    }
+}
+
+impl Default for ExitHandlers {
+    fn default() -> Self {
+        ExitHandlers::with_defaults()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtError {
+    /// No SVM, or SVM turned off by the firmware. VMX has no backend yet.
+    Unsupported,
+    OutOfFrames,
+    /// A memory size of zero.
+    Empty,
+    /// More memory than [`machine::MAX_MEMORY`].
+    TooLarge,
+    /// The guest physical range is not all guest memory.
+    OutOfRange,
+    NameTaken,
+    NameTooLong,
+    /// Every machine slot is in use.
+    NoSlot,
+    /// No machine by that id, it was destroyed.
+    NoMachine,
+    /// The machine is in a state that does not allow it.
+    BadState(State),
+    /// Running a machine was asked for in interrupt context, which must not wait.
+    WouldBlock,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Never started.
+    Created,
+    Running,
+    /// Left with its vCPU and clock as they were, to be resumed.
+    Suspended,
+    /// A handler stopped it or it was asked to, starting it again resets it.
+    Stopped,
+}
+
+/// What a running machine was asked to do, acted on at its next exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Request {
+    Stop,
+    Suspend,
+}
+
+/// A machine, which stops working once it is destroyed even if its slot is reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmId {
+    index: usize,
+    generation: u32,
+}
+
+// for whoever lists the machines, see `list_virtual_machines`
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct MachineInfo {
+    pub id: VmId,
+    pub state: State,
+    pub memory: u64,
+    pub exits: u64,
+    pub last_exit: Option<VmExit>,
+}
+
+struct Slot {
+    name: [u8; MAX_NAME],
+    name_len: u8,
+    generation: u32,
+    state: State,
+    request: Option<Request>,
+    memory: u64,
+    exits: u64,
+    last_exit: Option<VmExit>,
+    /// `None` while it runs, the run loop has it then.
+    machine: Option<VirtualMachine>,
+}
+
+impl Slot {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("")
+    }
+}
+
+struct Machines {
+    slots: [Option<Slot>; MAX_MACHINES],
+    /// Handed to the next machine created, so stale ids do not reach it.
+    generation: u32,
+}
+
+impl Machines {
+    fn get(&mut self, id: VmId) -> Result<&mut Slot, VirtError> {
+        self.slots
+            .get_mut(id.index)
+            .and_then(Option::as_mut)
+            .filter(|slot| slot.generation == id.generation)
+            .ok_or(VirtError::NoMachine)
+    }
+}
+
+static MACHINES: IrqMutex<Machines> = IrqMutex::new(Machines {
+    slots: [const { None }; MAX_MACHINES],
+    generation: 0,
+});
+
+/// Whether SVM could be turned on, which happens the first time a machine is created.
+static BACKEND: Once<Result<(), VirtError>> = Once::new();
+
+fn backend() -> Result<(), VirtError> {
+    *BACKEND.call_once(|| {
+        let features = cpu::features();
+        if features.svm {
+            if svm::is_disabled() {
+                warn!("[virtualization] SVM is disabled by the firmware");
+                return Err(VirtError::Unsupported);
+            }
+            let host_save_area = frames::allocate(0, Owner::Guest).ok_or(VirtError::OutOfFrames)?;
+            unsafe {
+                core::ptr::write_bytes(frames::to_virtual(host_save_area), 0, FRAME_SIZE as usize)
+            };
+            svm::enable(host_save_area);
+            info!("[virtualization] SVM enabled");
+            Ok(())
+        } else {
+            if features.vmx {
+                warn!("[virtualization] VMX is not supported yet");
+            }
+            Err(VirtError::Unsupported)
+        }
+    })
+}
+
+/// Call `visit` with each machine's name and state.
+// the lifecycle calls have no caller until the shell or a hypervisor service drives them
+#[allow(dead_code)]
+pub fn list_virtual_machines(mut visit: impl FnMut(&str, MachineInfo)) {
+    let machines = MACHINES.lock();
+    for (index, slot) in machines.slots.iter().enumerate() {
+        if let Some(slot) = slot {
+            let id = VmId {
+                index,
+                generation: slot.generation,
+            };
+            visit(
+                slot.name(),
+                MachineInfo {
+                    id,
+                    state: slot.state,
+                    memory: slot.memory,
+                    exits: slot.exits,
+                    last_exit: slot.last_exit,
+                },
+            );
+        }
+    }
+}
+
+/// A machine called `name` with `memory` bytes, `image` loaded at guest
+/// physical `entry` and a real mode vCPU that starts there.
+// like the rest of the lifecycle, no caller yet
+#[allow(dead_code)]
+pub fn create_virtual_machine(
+    name: &str,
+    memory: u64,
+    image: &[u8],
+    entry: u64,
+) -> Result<VmId, VirtError> {
+    if name.len() > MAX_NAME {
+        return Err(VirtError::NameTooLong);
+    }
+    backend()?;
+    // built before the lock is taken, allocating and zeroing megabytes takes a while
+    let mut machine = VirtualMachine::new(memory, entry)?;
+    machine.write_memory(entry, image)?;
+
+    let mut machines = MACHINES.lock();
+    if machines
+        .slots
+        .iter()
+        .flatten()
+        .any(|slot| slot.name() == name)
+    {
+        return Err(VirtError::NameTaken);
+    }
+    let index = machines
+        .slots
+        .iter()
+        .position(Option::is_none)
+        .ok_or(VirtError::NoSlot)?;
+    // ASID 0 is the host's
+    machine.set_asid(index as u32 + 1);
+    machines.generation = machines.generation.wrapping_add(1);
+    let generation = machines.generation;
+    let mut slot = Slot {
+        name: [0; MAX_NAME],
+        name_len: name.len() as u8,
+        generation,
+        state: State::Created,
+        request: None,
+        memory: machine.memory_size(),
+        exits: 0,
+        last_exit: None,
+        machine: Some(machine),
+    };
+    slot.name[..name.len()].copy_from_slice(name.as_bytes());
+    machines.slots[index] = Some(slot);
+    info!("[virtualization] created {}, {} KiB", name, memory / 1024);
+    Ok(VmId { index, generation })
+}
+
+/// Free a machine that is not running.
+// nothing creates machines yet
+#[allow(dead_code)]
+pub fn destroy_virtual_machine(id: VmId) -> Result<(), VirtError> {
+    let slot = {
+        let mut machines = MACHINES.lock();
+        let slot = machines.get(id)?;
+        if slot.state == State::Running {
+            return Err(VirtError::BadState(State::Running));
+        }
+        machines.slots[id.index].take()
+    };
+    // the machine's frames go back outside the lock
+    drop(slot);
+    Ok(())
+}
+
+/// Register `handler` for exits of `kind`, on a machine that is not running.
+// the default handlers are all a machine gets so far
+#[allow(dead_code)]
+pub fn set_exit_handler(id: VmId, kind: ExitKind, handler: ExitHandler) -> Result<(), VirtError> {
+    let mut machines = MACHINES.lock();
+    let slot = machines.get(id)?;
+    let machine = slot
+        .machine
+        .as_mut()
+        .ok_or(VirtError::BadState(State::Running))?;
+    machine.handlers.register(kind, handler);
+    Ok(())
+}
+
+/// Run a created or stopped machine from its entry point, memory as it is.
+///
+/// Gives the state the machine ended up in, stopped or suspended.
+// nothing creates machines to start yet
+#[allow(dead_code)]
+pub fn start_virtual_machine(id: VmId) -> Result<State, VirtError> {
+    let mut machine = take(id, &[State::Created, State::Stopped])?;
+    machine.reset();
+    Ok(run(id, machine))
+}
+
+/// Run a suspended machine from where it was, like [`start_virtual_machine`].
+// nothing suspends machines yet
+#[allow(dead_code)]
+pub fn resume_virtual_machine(id: VmId) -> Result<State, VirtError> {
+    let mut machine = take(id, &[State::Suspended])?;
+    machine.resume();
+    Ok(run(id, machine))
+}
+
+/// Suspend a running machine at its next exit.
+// asked for by handlers like suspending, none does yet
+#[allow(dead_code)]
+pub fn suspend_virtual_machine(id: VmId) -> Result<(), VirtError> {
+    let mut machines = MACHINES.lock();
+    let slot = machines.get(id)?;
+    if slot.state != State::Running {
+        return Err(VirtError::BadState(slot.state));
+    }
+    slot.request = Some(Request::Suspend);
+    Ok(())
+}
+
+/// Stop a machine, a running one at its next exit.
+// for interrupt and exit handlers, none asks yet
+#[allow(dead_code)]
+pub fn stop_virtual_machine(id: VmId) -> Result<(), VirtError> {
+    let mut machines = MACHINES.lock();
+    let slot = machines.get(id)?;
+    match slot.state {
+        State::Running => slot.request = Some(Request::Stop),
+        State::Created | State::Suspended => slot.state = State::Stopped,
+        State::Stopped => {}
+    }
+    Ok(())
+}
+
+/// Take the machine out of its slot to run it, if it is in one of `from`.
+fn take(id: VmId, from: &[State]) -> Result<VirtualMachine, VirtError> {
+    if in_interrupt() {
+        return Err(VirtError::WouldBlock);
+    }
+    let mut machines = MACHINES.lock();
+    let slot = machines.get(id)?;
+    if !from.contains(&slot.state) {
+        return Err(VirtError::BadState(slot.state));
+    }
+    let machine = slot
+        .machine
+        .take()
+        .ok_or(VirtError::BadState(State::Running))?;
+    slot.state = State::Running;
+    slot.request = None;
+    Ok(machine)
+}
+
+/// Enter the guest and handle its exits until a handler stops it or it is
+/// asked to stop or suspend, then put it back in its slot.
+fn run(id: VmId, mut machine: VirtualMachine) -> State {
+    let state = loop {
+        // the lock is not held in the guest or its handlers, both may take a while
+        let (exit, action) = machine.step();
+        let request = {
+            let mut machines = MACHINES.lock();
+            // a running machine cannot be destroyed
+            let slot = machines.get(id).expect("running machine lost its slot");
+            slot.exits += 1;
+            slot.last_exit = Some(exit);
+            slot.request.take()
+        };
+        match (action, request) {
+            (ExitAction::Stop, _) | (_, Some(Request::Stop)) => break State::Stopped,
+            (_, Some(Request::Suspend)) => {
+                machine.pause();
+                break State::Suspended;
+            }
+            (ExitAction::Resume, None) => {}
+        }
+    };
+    let mut machines = MACHINES.lock();
+    let slot = machines.get(id).expect("running machine lost its slot");
+    slot.state = state;
+    slot.machine = Some(machine);
+    state
+}
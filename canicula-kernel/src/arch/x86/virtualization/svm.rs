@@ -0,0 +1,478 @@
+use core::arch::global_asm;
+
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+
+use super::{ExitAction, VmExit};
+
+const VM_CR: u32 = 0xc001_0114;
+const VM_HSAVE_PA: u32 = 0xc001_0117;
+/// Host MSR applied by VMRUN, the guest reads TSC * ratio + offset.
+const TSC_RATIO: u32 = 0xc000_0104;
+
+/// Ratio of 1.0 in the 8.32 fixed point format of `TSC_RATIO`.
+pub const TSC_RATIO_DEFAULT: u64 = 1 << 32;
+/// Integer part is 8 bits wide, the rest of the MSR is reserved.
+const TSC_RATIO_MAX: u64 = (1 << 40) - 1;
+
+// control area
+const INTERCEPT_MISC1: usize = 0x00c;
+const INTERCEPT_MISC2: usize = 0x010;
+const IOPM_BASE_PA: usize = 0x040;
+const MSRPM_BASE_PA: usize = 0x048;
+const TSC_OFFSET: usize = 0x050;
+const GUEST_ASID: usize = 0x058;
+const TLB_CONTROL: usize = 0x05c;
+const EXIT_CODE: usize = 0x070;
+const EXIT_INFO_1: usize = 0x078;
+const EXIT_INFO_2: usize = 0x080;
+const NP_ENABLE: usize = 0x090;
+const NESTED_CR3: usize = 0x0b0;
+const NEXT_RIP: usize = 0x0c8;
+
+// state save area
+const SAVE_ES: usize = 0x400;
+const SAVE_CS: usize = 0x410;
+const SAVE_SS: usize = 0x420;
+const SAVE_DS: usize = 0x430;
+const SAVE_FS: usize = 0x440;
+const SAVE_GS: usize = 0x450;
+const SAVE_GDTR: usize = 0x460;
+const SAVE_LDTR: usize = 0x470;
+const SAVE_IDTR: usize = 0x480;
+const SAVE_TR: usize = 0x490;
+const SAVE_EFER: usize = 0x4d0;
+const SAVE_CR4: usize = 0x548;
+const SAVE_CR3: usize = 0x550;
+const SAVE_CR0: usize = 0x558;
+const SAVE_DR7: usize = 0x560;
+const SAVE_DR6: usize = 0x568;
+const SAVE_RFLAGS: usize = 0x570;
+const SAVE_RIP: usize = 0x578;
+const SAVE_RSP: usize = 0x5d8;
+const SAVE_RAX: usize = 0x5f8;
+const SAVE_G_PAT: usize = 0x668;
+
+// intercept bits
+const INTERCEPT_INTR: u32 = 1 << 0;
+const INTERCEPT_CPUID: u32 = 1 << 18;
+const INTERCEPT_HLT: u32 = 1 << 24;
+const INTERCEPT_IOIO_PROT: u32 = 1 << 27;
+const INTERCEPT_MSR_PROT: u32 = 1 << 28;
+const INTERCEPT_SHUTDOWN: u32 = 1 << 31;
+const INTERCEPT_VMRUN: u32 = 1 << 0;
+const INTERCEPT_VMMCALL: u32 = 1 << 1;
+
+// exit codes
+const VMEXIT_INTR: u64 = 0x60;
+const VMEXIT_CPUID: u64 = 0x72;
+const VMEXIT_HLT: u64 = 0x78;
+const VMEXIT_IOIO: u64 = 0x7b;
+const VMEXIT_MSR: u64 = 0x7c;
+const VMEXIT_SHUTDOWN: u64 = 0x7f;
+const VMEXIT_VMMCALL: u64 = 0x81;
+const VMEXIT_NPF: u64 = 0x400;
+const VMEXIT_INVALID: u64 = u64::MAX;
+
+const TLB_FLUSH_ALL: u8 = 1;
+
+/// Virtual machine control block, one 4 KiB page shared with the processor.
+#[repr(C, align(4096))]
+pub struct Vmcb([u8; 4096]);
+
+impl Vmcb {
+    pub const fn new() -> Self {
+        Vmcb([0; 4096])
+    }
+
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { core::ptr::read_unaligned(self.0.as_ptr().add(offset) as *const T) }
+    }
+
+    fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        unsafe { core::ptr::write_unaligned(self.0.as_mut_ptr().add(offset) as *mut T, value) }
+    }
+
+    fn write_segment(&mut self, offset: usize, segment: Segment) {
+        self.write(offset, segment.selector);
+        self.write(offset + 2, segment.attributes);
+        self.write(offset + 4, segment.limit);
+        self.write(offset + 8, segment.base);
+    }
+
+    pub fn tsc_offset(&self) -> i64 {
+        self.read(TSC_OFFSET)
+    }
+
+    pub fn set_tsc_offset(&mut self, offset: i64) {
+        self.write(TSC_OFFSET, offset);
+    }
+}
+
+impl Default for Vmcb {
+    fn default() -> Self {
+        Vmcb::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Segment {
+    pub selector: u16,
+    pub attributes: u16,
+    pub limit: u32,
+    pub base: u64,
+}
+
+/// Guest general purpose registers that the VMCB does not hold.
+///
+/// The field order is relied upon by `svm_vmrun`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GuestRegisters {
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+/// Initial guest processor state.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestConfig {
+    pub rip: u64,
+    pub rsp: u64,
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub code: Segment,
+    pub data: Segment,
+    /// Host physical address of the nested page table root, `None` runs without nested paging.
+    pub nested_cr3: Option<u64>,
+    /// Host physical addresses of the I/O and MSR permission maps, `None` intercepts nothing.
+    pub iopm: Option<u64>,
+    pub msrpm: Option<u64>,
+    pub asid: u32,
+}
+
+impl GuestConfig {
+    /// A 16-bit real mode guest starting at `rip` with flat 64 KiB segments.
+    pub fn real_mode(rip: u64, rsp: u64) -> Self {
+        GuestConfig {
+            rip,
+            rsp,
+            // ET
+            cr0: 1 << 4,
+            cr3: 0,
+            cr4: 0,
+            efer: 0,
+            code: Segment {
+                selector: 0,
+                attributes: 0x9b,
+                limit: 0xffff,
+                base: 0,
+            },
+            data: Segment {
+                selector: 0,
+                attributes: 0x93,
+                limit: 0xffff,
+                base: 0,
+            },
+            nested_cr3: None,
+            iopm: None,
+            msrpm: None,
+            asid: 1,
+        }
+    }
+}
+
+extern "C" {
+    fn svm_vmrun(vmcb_physical: u64, registers: *mut GuestRegisters);
+}
+
+global_asm!(
+    r#"
+    .global svm_vmrun
+svm_vmrun:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    push rsi
+
+    mov rax, rdi
+    mov rbx, [rsi + 0x00]
+    mov rcx, [rsi + 0x08]
+    mov rdx, [rsi + 0x10]
+    mov rdi, [rsi + 0x20]
+    mov rbp, [rsi + 0x28]
+    mov r8,  [rsi + 0x30]
+    mov r9,  [rsi + 0x38]
+    mov r10, [rsi + 0x40]
+    mov r11, [rsi + 0x48]
+    mov r12, [rsi + 0x50]
+    mov r13, [rsi + 0x58]
+    mov r14, [rsi + 0x60]
+    mov r15, [rsi + 0x68]
+    mov rsi, [rsi + 0x18]
+
+    clgi
+    vmrun rax
+    stgi
+
+    push rsi
+    mov rsi, [rsp + 8]
+    mov [rsi + 0x00], rbx
+    mov [rsi + 0x08], rcx
+    mov [rsi + 0x10], rdx
+    mov [rsi + 0x20], rdi
+    mov [rsi + 0x28], rbp
+    mov [rsi + 0x30], r8
+    mov [rsi + 0x38], r9
+    mov [rsi + 0x40], r10
+    mov [rsi + 0x48], r11
+    mov [rsi + 0x50], r12
+    mov [rsi + 0x58], r13
+    mov [rsi + 0x60], r14
+    mov [rsi + 0x68], r15
+    pop rax
+    mov [rsi + 0x18], rax
+
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#
+);
+
+/// Whether the firmware turned SVM off, enabling it would fault.
+pub fn is_disabled() -> bool {
+    let vm_cr = unsafe { Msr::new(VM_CR).read() };
+    vm_cr & (1 << 4) != 0
+}
+
+/// Whether `TSC_RATIO` exists, so guests can run with a TSC frequency of their own.
+pub fn tsc_scaling_supported() -> bool {
+    let edx = unsafe { core::arch::x86_64::__cpuid(0x8000_000a).edx };
+    edx & (1 << 4) != 0
+}
+
+/// Turn on SVM for this processor, `host_save_area` is the physical address of a zeroed page.
+pub fn enable(host_save_area: u64) {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE));
+        Msr::new(VM_HSAVE_PA).write(host_save_area);
+    }
+}
+
+pub struct VCpu {
+    vmcb: &'static mut Vmcb,
+    vmcb_physical: u64,
+    tsc_ratio: u64,
+    pub registers: GuestRegisters,
+}
+
+impl VCpu {
+    /// `vmcb` must be mapped at `vmcb_physical` in host physical memory.
+    pub fn new(vmcb: &'static mut Vmcb, vmcb_physical: u64) -> Self {
+        VCpu {
+            vmcb,
+            vmcb_physical,
+            tsc_ratio: TSC_RATIO_DEFAULT,
+            registers: GuestRegisters::default(),
+        }
+    }
+
+    pub fn vmcb(&self) -> &Vmcb {
+        self.vmcb
+    }
+
+    pub fn vmcb_mut(&mut self) -> &mut Vmcb {
+        self.vmcb
+    }
+
+    /// Scale the guest TSC by `ratio`, 8.32 fixed point, from the next `run` on.
+    pub fn set_tsc_ratio(&mut self, ratio: u64) -> Result<(), &'static str> {
+        if ratio == TSC_RATIO_DEFAULT {
+            self.tsc_ratio = ratio;
+            return Ok(());
+        }
+        if !tsc_scaling_supported() {
+            return Err("TSC scaling is not supported");
+        }
+        if ratio == 0 || ratio > TSC_RATIO_MAX {
+            return Err("TSC ratio is out of range");
+        }
+        self.tsc_ratio = ratio;
+        Ok(())
+    }
+
+    /// The TSC this guest reads when the host TSC is `host_tsc`.
+    pub fn guest_tsc(&self, host_tsc: u64) -> u64 {
+        let scaled = (host_tsc as u128 * self.tsc_ratio as u128) >> 32;
+        (scaled as u64).wrapping_add(self.vmcb.tsc_offset() as u64)
+    }
+
+    pub fn configure(&mut self, config: &GuestConfig) {
+        *self.vmcb = Vmcb::new();
+        let vmcb = &mut *self.vmcb;
+
+        let mut misc1 = INTERCEPT_INTR | INTERCEPT_CPUID | INTERCEPT_HLT | INTERCEPT_SHUTDOWN;
+        if let Some(iopm) = config.iopm {
+            misc1 |= INTERCEPT_IOIO_PROT;
+            vmcb.write(IOPM_BASE_PA, iopm);
+        }
+        if let Some(msrpm) = config.msrpm {
+            misc1 |= INTERCEPT_MSR_PROT;
+            vmcb.write(MSRPM_BASE_PA, msrpm);
+        }
+        vmcb.write(INTERCEPT_MISC1, misc1);
+        vmcb.write(INTERCEPT_MISC2, INTERCEPT_VMRUN | INTERCEPT_VMMCALL);
+        vmcb.write(GUEST_ASID, config.asid);
+        vmcb.write(TLB_CONTROL, TLB_FLUSH_ALL);
+
+        if let Some(nested_cr3) = config.nested_cr3 {
+            vmcb.write(NP_ENABLE, 1u64);
+            vmcb.write(NESTED_CR3, nested_cr3);
+        }
+
+        vmcb.write_segment(SAVE_CS, config.code);
+        for segment in [SAVE_DS, SAVE_ES, SAVE_FS, SAVE_GS, SAVE_SS] {
+            vmcb.write_segment(segment, config.data);
+        }
+        let table = Segment {
+            limit: 0xffff,
+            ..Segment::default()
+        };
+        vmcb.write_segment(SAVE_GDTR, table);
+        vmcb.write_segment(SAVE_IDTR, table);
+        vmcb.write_segment(
+            SAVE_LDTR,
+            Segment {
+                attributes: 0x82,
+                ..table
+            },
+        );
+        vmcb.write_segment(
+            SAVE_TR,
+            Segment {
+                attributes: 0x8b,
+                ..table
+            },
+        );
+
+        // the guest must have EFER.SVME set, otherwise VMRUN fails its consistency checks
+        vmcb.write(
+            SAVE_EFER,
+            config.efer | EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE.bits(),
+        );
+        vmcb.write(SAVE_CR0, config.cr0);
+        vmcb.write(SAVE_CR3, config.cr3);
+        vmcb.write(SAVE_CR4, config.cr4);
+        vmcb.write(SAVE_DR6, 0xffff_0ff0u64);
+        vmcb.write(SAVE_DR7, 0x400u64);
+        // reserved bit 1 is always set
+        vmcb.write(SAVE_RFLAGS, 1u64 << 1);
+        vmcb.write(SAVE_RIP, config.rip);
+        vmcb.write(SAVE_RSP, config.rsp);
+        vmcb.write(SAVE_RAX, 0u64);
+        vmcb.write(SAVE_G_PAT, 0x0007_0406_0007_0406u64);
+
+        self.registers = GuestRegisters::default();
+    }
+
+    /// Enter the guest once and decode why it exited.
+    pub fn run(&mut self) -> VmExit {
+        // the ratio is per processor, put it back so other guests are not scaled
+        if self.tsc_ratio != TSC_RATIO_DEFAULT {
+            unsafe {
+                Msr::new(TSC_RATIO).write(self.tsc_ratio);
+                svm_vmrun(self.vmcb_physical, &mut self.registers);
+                Msr::new(TSC_RATIO).write(TSC_RATIO_DEFAULT);
+            }
+        } else {
+            unsafe { svm_vmrun(self.vmcb_physical, &mut self.registers) };
+        }
+        self.vmcb.write(TLB_CONTROL, 0u8);
+        self.exit()
+    }
+
+    /// Decode the exit recorded in the VMCB by the last `run`.
+    pub fn exit(&self) -> VmExit {
+        let info_1: u64 = self.vmcb.read(EXIT_INFO_1);
+        let info_2: u64 = self.vmcb.read(EXIT_INFO_2);
+
+        match self.vmcb.read::<u64>(EXIT_CODE) {
+            VMEXIT_IOIO => VmExit::Io {
+                port: (info_1 >> 16) as u16,
+                size: ((info_1 >> 4) & 0b111) as u8,
+                write: info_1 & 1 == 0,
+                string: info_1 & (1 << 2) != 0,
+                repeat: info_1 & (1 << 3) != 0,
+            },
+            VMEXIT_CPUID => VmExit::Cpuid,
+            VMEXIT_HLT => VmExit::Hlt,
+            VMEXIT_VMMCALL => VmExit::Vmmcall,
+            VMEXIT_NPF => VmExit::NestedPageFault {
+                address: info_2,
+                error_code: info_1,
+            },
+            VMEXIT_MSR => VmExit::Msr { write: info_1 == 1 },
+            VMEXIT_INTR => VmExit::Interrupt,
+            VMEXIT_SHUTDOWN => VmExit::Shutdown,
+            VMEXIT_INVALID => VmExit::Invalid,
+            code => VmExit::Unknown(code),
+        }
+    }
+
+    pub fn rax(&self) -> u64 {
+        self.vmcb.read(SAVE_RAX)
+    }
+
+    pub fn set_rax(&mut self, value: u64) {
+        self.vmcb.write(SAVE_RAX, value);
+    }
+
+    // for exit handlers from `set_exit_handler`, the default ones only skip
+    #[allow(dead_code)]
+    pub fn rip(&self) -> u64 {
+        self.vmcb.read(SAVE_RIP)
+    }
+
+    /// Move past the instruction that caused the exit.
+    pub fn skip_instruction(&mut self) {
+        let next_rip: u64 = self.vmcb.read(NEXT_RIP);
+        self.vmcb.write(SAVE_RIP, next_rip);
+    }
+}
+
+/// Execute cpuid on behalf of the guest, hiding SVM from it.
+pub fn handle_cpuid(vcpu: &mut VCpu, _exit: &VmExit) -> ExitAction {
+    let leaf = vcpu.rax() as u32;
+    let subleaf = vcpu.registers.rcx as u32;
+    let mut result = unsafe { core::arch::x86_64::__cpuid_count(leaf, subleaf) };
+    if leaf == 0x8000_0001 {
+        result.ecx &= !(1 << 2);
+    }
+
+    vcpu.set_rax(result.eax as u64);
+    vcpu.registers.rbx = result.ebx as u64;
+    vcpu.registers.rcx = result.ecx as u64;
+    vcpu.registers.rdx = result.edx as u64;
+    vcpu.skip_instruction();
+    ExitAction::Resume
+}
@@ -0,0 +1,528 @@
+//! Kernel virtual memory: mapping, unmapping and protecting ranges of pages.
+//!
+//! Everything goes through one `OffsetPageTable` over the loader's map of
+//! physical memory. Page tables and the frames behind allocated regions come
+//! from [`frames`]. A region can also be reserved and filled page by page when
+//! it is first touched, or kept unmapped as a guard, see [`page_fault`].
+
+use canicula_common::entry::BootInfo;
+use canicula_common::layout::{KERNEL_STACK_ADDRESS, KERNEL_VMAP_SIZE, KERNEL_VMAP_START};
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::aslr;
+use super::frames::{self, FaultFrameAllocator, KernelFrameAllocator};
+
+pub const PAGE_SIZE: u64 = 0x1000;
+/// Regions [`reserve`] and [`reserve_guard`] can keep track of at once.
+const MAX_REGIONS: usize = 16;
+/// The first vmap mapping starts somewhere in this much of the vmap area.
+const VMAP_BASE_WINDOW: u64 = KERNEL_VMAP_SIZE / 4;
+/// Most unmapped space left before each later vmap mapping.
+const VMAP_GAP_MAX: u64 = 64 * PAGE_SIZE;
+
+/// Flags of [`map_mmio`] mappings, device memory must not be cached.
+const MMIO: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::WRITE_THROUGH);
+
+static VM: Mutex<Option<Vm>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// [`init`] has not run.
+    Uninitialized,
+    /// An address or size is not a multiple of [`PAGE_SIZE`].
+    Unaligned,
+    OutOfFrames,
+    /// No room left in the vmap area or for another reserved region.
+    OutOfSpace,
+    AlreadyMapped,
+    NotMapped,
+    /// The range is inside a huge page, which is never split.
+    HugePage,
+}
+
+impl<S> From<MapToError<S>> for VmError
+where
+    S: x86_64::structures::paging::PageSize,
+{
+    fn from(error: MapToError<S>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => VmError::OutOfFrames,
+            MapToError::ParentEntryHugePage => VmError::HugePage,
+            MapToError::PageAlreadyMapped(_) => VmError::AlreadyMapped,
+        }
+    }
+}
+
+impl From<UnmapError> for VmError {
+    fn from(error: UnmapError) -> Self {
+        match error {
+            UnmapError::ParentEntryHugePage => VmError::HugePage,
+            UnmapError::PageNotMapped | UnmapError::InvalidFrameAddress(_) => VmError::NotMapped,
+        }
+    }
+}
+
+impl From<FlagUpdateError> for VmError {
+    fn from(error: FlagUpdateError) -> Self {
+        match error {
+            FlagUpdateError::ParentEntryHugePage => VmError::HugePage,
+            FlagUpdateError::PageNotMapped => VmError::NotMapped,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Pages get a zeroed frame the first time they are touched.
+    Lazy,
+    /// Pages are never mapped, touching one is a bug, e.g. a stack overflow.
+    Guard,
+}
+
+/// A range of pages set aside for [`page_fault`] to deal with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub flags: PageTableFlags,
+    pub kind: RegionKind,
+}
+
+/// What [`page_fault`] made of a fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFault {
+    /// The page is mapped now, the access can be retried.
+    Resolved,
+    /// The address is in a guard region.
+    Guard(Region),
+    /// The address is in a lazy region but its page could not be mapped.
+    Failed(Region, VmError),
+    /// The page tables were locked, by the faulting code or by code a fault
+    /// handler interrupted.
+    Busy,
+    /// The kernel did not set the address aside, or the page is there and the
+    /// access was not allowed.
+    Invalid,
+}
+
+/// A kernel stack from [`allocate_stack`], a guard region of `guard` bytes sits right below `bottom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stack {
+    pub bottom: u64,
+    pub top: u64,
+    pub guard: u64,
+}
+
+struct Vm {
+    page_table: OffsetPageTable<'static>,
+    physical_memory_offset: u64,
+    regions: [Option<Region>; MAX_REGIONS],
+    /// Next free address in the vmap area, it only grows.
+    vmap_next: u64,
+}
+
+/// The pages of `start..start + size`, both have to be page aligned.
+fn pages(start: u64, size: u64) -> Result<impl Iterator<Item = Page<Size4KiB>>, VmError> {
+    if (start | size) & (PAGE_SIZE - 1) != 0 {
+        return Err(VmError::Unaligned);
+    }
+    let first = Page::containing_address(VirtAddr::new(start));
+    Ok((0..size / PAGE_SIZE).map(move |index| first + index))
+}
+
+impl Vm {
+    fn map<A: FrameAllocator<Size4KiB>>(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        frames: &mut A,
+    ) -> Result<(), VmError> {
+        unsafe {
+            self.page_table
+                .map_to(page, frame, flags | PageTableFlags::PRESENT, frames)?
+                .flush();
+        }
+        Ok(())
+    }
+
+    /// Unmap the pages of a region, giving their frames back if `free` is set.
+    /// Pages that are not mapped are skipped when `sparse` is set.
+    fn unmap(&mut self, start: u64, size: u64, free: bool, sparse: bool) -> Result<(), VmError> {
+        for page in pages(start, size)? {
+            match self.page_table.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    if free {
+                        frames::free(frame.start_address().as_u64(), 0);
+                    }
+                }
+                Err(UnmapError::PageNotMapped) if sparse => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Map `page` to a new zeroed frame.
+    fn map_zeroed<A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>>(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+        frames: &mut A,
+    ) -> Result<(), VmError> {
+        let frame = frames.allocate_frame().ok_or(VmError::OutOfFrames)?;
+        unsafe {
+            core::ptr::write_bytes(
+                (self.physical_memory_offset + frame.start_address().as_u64()) as *mut u8,
+                0,
+                PAGE_SIZE as usize,
+            )
+        };
+        self.map(page, frame, flags, frames)
+            .inspect_err(|_| unsafe { frames.deallocate_frame(frame) })
+    }
+
+    fn region(&self, address: u64) -> Option<Region> {
+        self.regions
+            .iter()
+            .flatten()
+            .find(|region| (region.start..region.end).contains(&address))
+            .copied()
+    }
+
+    fn add_region(&mut self, region: Region) -> Result<(), VmError> {
+        if (region.start | region.end) & (PAGE_SIZE - 1) != 0 {
+            return Err(VmError::Unaligned);
+        }
+        let overlaps = self
+            .regions
+            .iter()
+            .flatten()
+            .any(|other| other.start < region.end && region.start < other.end);
+        if overlaps {
+            return Err(VmError::AlreadyMapped);
+        }
+        let slot = self
+            .regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmError::OutOfSpace)?;
+        *slot = Some(region);
+        Ok(())
+    }
+
+    /// Take `size` bytes of the vmap area, a random distance past the last mapping.
+    fn take_vmap(&mut self, size: u64) -> Result<u64, VmError> {
+        let virt = aslr::place(
+            self.vmap_next,
+            self.vmap_next + VMAP_GAP_MAX + size,
+            size,
+            PAGE_SIZE,
+        );
+        // an unmapped page after each mapping catches overruns
+        let next = virt + size + PAGE_SIZE;
+        if next > KERNEL_VMAP_START + KERNEL_VMAP_SIZE {
+            return Err(VmError::OutOfSpace);
+        }
+        self.vmap_next = next;
+        Ok(virt)
+    }
+}
+
+fn with_vm<T>(f: impl FnOnce(&mut Vm) -> Result<T, VmError>) -> Result<T, VmError> {
+    f(VM.lock().as_mut().ok_or(VmError::Uninitialized)?)
+}
+
+/// Take over the page tables the loader built, after [`frames::init`].
+pub fn init(boot_info: &'static BootInfo) {
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let page_table = unsafe {
+        let level_4 = offset + Cr3::read().0.start_address().as_u64();
+        OffsetPageTable::new(&mut *level_4.as_mut_ptr::<PageTable>(), offset)
+    };
+    let mut vm = Vm {
+        page_table,
+        physical_memory_offset: boot_info.physical_memory_offset,
+        regions: [None; MAX_REGIONS],
+        vmap_next: aslr::place(
+            KERNEL_VMAP_START,
+            KERNEL_VMAP_START + VMAP_BASE_WINDOW,
+            0,
+            PAGE_SIZE,
+        ),
+    };
+    // the loader leaves the page below the boot stack unmapped
+    vm.add_region(Region {
+        start: KERNEL_STACK_ADDRESS - PAGE_SIZE,
+        end: KERNEL_STACK_ADDRESS,
+        flags: PageTableFlags::empty(),
+        kind: RegionKind::Guard,
+    })
+    .expect("[vm] no room for the boot stack guard");
+    *VM.lock() = Some(vm);
+}
+
+/// Map `size` bytes at `virt` to the physical memory at `physical`.
+///
+/// Nothing is mapped if any page fails.
+pub fn map_region(
+    virt: u64,
+    physical: u64,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), VmError> {
+    if physical & (PAGE_SIZE - 1) != 0 {
+        return Err(VmError::Unaligned);
+    }
+    with_vm(|vm| {
+        for (index, page) in pages(virt, size)?.enumerate() {
+            let frame =
+                PhysFrame::containing_address(PhysAddr::new(physical + index as u64 * PAGE_SIZE));
+            if let Err(error) = vm.map(page, frame, flags, &mut KernelFrameAllocator) {
+                vm.unmap(virt, index as u64 * PAGE_SIZE, false, false)?;
+                return Err(error);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Map `size` bytes at `virt` to new zeroed frames, see [`free_region`].
+pub fn allocate_region(virt: u64, size: u64, flags: PageTableFlags) -> Result<(), VmError> {
+    with_vm(|vm| {
+        for (index, page) in pages(virt, size)?.enumerate() {
+            if let Err(error) = vm.map_zeroed(page, flags, &mut KernelFrameAllocator) {
+                vm.unmap(virt, index as u64 * PAGE_SIZE, true, false)?;
+                return Err(error);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Unmap a region from [`map_region`], the memory behind it is left alone.
+// drivers keep their MMIO mapped for as long as the kernel runs
+#[allow(dead_code)]
+pub fn unmap_region(virt: u64, size: u64) -> Result<(), VmError> {
+    with_vm(|vm| vm.unmap(virt, size, false, false))
+}
+
+/// Unmap a region from [`allocate_region`] and free its frames.
+pub fn free_region(virt: u64, size: u64) -> Result<(), VmError> {
+    with_vm(|vm| vm.unmap(virt, size, true, false))
+}
+
+/// Change the flags of mapped pages, e.g. to make them read-only.
+// the kernel image is protected with `update_flags`, which keeps huge pages
+#[allow(dead_code)]
+pub fn protect(virt: u64, size: u64, flags: PageTableFlags) -> Result<(), VmError> {
+    with_vm(|vm| {
+        for page in pages(virt, size)? {
+            unsafe {
+                vm.page_table
+                    .update_flags(page, flags | PageTableFlags::PRESENT)?
+                    .flush();
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Change the flags of the pages mapping `virt..virt + size` with `update`,
+/// which gets each page's own flags. Huge pages are updated whole, so they
+/// have to lie inside the range.
+pub fn update_flags(
+    virt: u64,
+    size: u64,
+    update: impl Fn(PageTableFlags) -> PageTableFlags,
+) -> Result<(), VmError> {
+    if (virt | size) & (PAGE_SIZE - 1) != 0 {
+        return Err(VmError::Unaligned);
+    }
+    let end = virt + size;
+    with_vm(|vm| {
+        let mut address = virt;
+        while address < end {
+            let TranslateResult::Mapped { frame, flags, .. } =
+                vm.page_table.translate(VirtAddr::new(address))
+            else {
+                return Err(VmError::NotMapped);
+            };
+            let flags = update(flags) - PageTableFlags::HUGE_PAGE;
+            let page_size = frame.size();
+            if address & (page_size - 1) != 0 || address + page_size > end {
+                return Err(VmError::HugePage);
+            }
+            let page = VirtAddr::new(address);
+            unsafe {
+                match frame {
+                    MappedFrame::Size4KiB(_) => vm
+                        .page_table
+                        .update_flags(Page::<Size4KiB>::containing_address(page), flags)?
+                        .flush(),
+                    MappedFrame::Size2MiB(_) => vm
+                        .page_table
+                        .update_flags(Page::<Size2MiB>::containing_address(page), flags)?
+                        .flush(),
+                    MappedFrame::Size1GiB(_) => vm
+                        .page_table
+                        .update_flags(Page::<Size1GiB>::containing_address(page), flags)?
+                        .flush(),
+                }
+            }
+            address += page_size;
+        }
+        Ok(())
+    })
+}
+
+/// Map physical memory somewhere in the vmap area, returns where `physical` ended up.
+pub fn map_anywhere(physical: u64, size: u64, flags: PageTableFlags) -> Result<u64, VmError> {
+    let start = physical & !(PAGE_SIZE - 1);
+    let end = (physical + size).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    let virt = with_vm(|vm| vm.take_vmap(end - start))?;
+    map_region(virt, start, end - start, flags)?;
+    Ok(virt + (physical - start))
+}
+
+/// Map device memory somewhere in the vmap area, returns where `physical` ended up.
+pub fn map_mmio(physical: u64, size: u64) -> Result<u64, VmError> {
+    map_anywhere(physical, size, MMIO)
+}
+
+/// A kernel stack of `size` bytes in the vmap area with guard pages below it,
+/// as many as [`aslr::stack_guard_pages`] picks.
+///
+/// Overflowing it faults on the guard, which the double fault handler reports.
+// for kernel threads, there is no scheduler to run them yet
+#[allow(dead_code)]
+pub fn allocate_stack(size: u64) -> Result<Stack, VmError> {
+    let gap = aslr::stack_gap();
+    let guard = with_vm(|vm| vm.take_vmap(gap + size))?;
+    let bottom = guard + gap;
+    reserve_guard(guard, gap)?;
+    if let Err(error) = allocate_region(
+        bottom,
+        size,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    ) {
+        release(guard)?;
+        return Err(error);
+    }
+    Ok(Stack {
+        bottom,
+        top: bottom + size,
+        guard: gap,
+    })
+}
+
+/// Give back a stack from [`allocate_stack`], its address range is not reused.
+// goes with `allocate_stack`
+#[allow(dead_code)]
+pub fn free_stack(stack: Stack) -> Result<(), VmError> {
+    free_region(stack.bottom, stack.top - stack.bottom)?;
+    release(stack.bottom - stack.guard)
+}
+
+/// Set aside `size` bytes at `virt` to be backed by zeroed frames on first touch.
+// nothing needs memory on demand until there are user address spaces
+#[allow(dead_code)]
+pub fn reserve(virt: u64, size: u64, flags: PageTableFlags) -> Result<(), VmError> {
+    with_vm(|vm| {
+        vm.add_region(Region {
+            start: virt,
+            end: virt + size,
+            flags,
+            kind: RegionKind::Lazy,
+        })
+    })
+}
+
+/// Set aside `size` bytes at `virt` that must never be touched.
+pub fn reserve_guard(virt: u64, size: u64) -> Result<(), VmError> {
+    with_vm(|vm| {
+        vm.add_region(Region {
+            start: virt,
+            end: virt + size,
+            flags: PageTableFlags::empty(),
+            kind: RegionKind::Guard,
+        })
+    })
+}
+
+/// Drop a region starting at `virt` and free the pages it got so far.
+pub fn release(virt: u64) -> Result<(), VmError> {
+    with_vm(|vm| {
+        let slot = vm
+            .regions
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|region| region.start == virt))
+            .ok_or(VmError::NotMapped)?;
+        let region = slot.take().unwrap();
+        vm.unmap(region.start, region.end - region.start, true, true)
+    })
+}
+
+/// The region `address` is in, if any.
+// the fault handlers use `try_region`
+#[allow(dead_code)]
+pub fn region(address: u64) -> Option<Region> {
+    with_vm(|vm| vm.region(address).ok_or(VmError::NotMapped)).ok()
+}
+
+/// Like [`region`] but gives up if the page tables are locked, for exception handlers.
+pub fn try_region(address: u64) -> Option<Region> {
+    VM.try_lock()?.as_ref()?.region(address)
+}
+
+/// Back the page holding `address` now instead of on first touch.
+// goes with `reserve`
+#[allow(dead_code)]
+pub fn populate(address: u64) -> Result<(), VmError> {
+    with_vm(|vm| match vm.region(address) {
+        Some(region) if region.kind == RegionKind::Lazy => {
+            let page = Page::containing_address(VirtAddr::new(address));
+            vm.map_zeroed(page, region.flags, &mut KernelFrameAllocator)
+        }
+        _ => Err(VmError::NotMapped),
+    })
+}
+
+/// Deal with a fault at `address` for the page fault handler, `present` is
+/// whether the page was mapped.
+///
+/// The fault interrupted code that may be holding the locks of the page tables
+/// and the frame allocator, so this gives up instead of waiting for them.
+pub fn page_fault(address: u64, present: bool) -> PageFault {
+    let Some(mut vm) = VM.try_lock() else {
+        return PageFault::Busy;
+    };
+    let Some(vm) = vm.as_mut() else {
+        return PageFault::Invalid;
+    };
+    match vm.region(address) {
+        Some(region) if region.kind == RegionKind::Guard => PageFault::Guard(region),
+        Some(region) if !present => {
+            let page = Page::containing_address(VirtAddr::new(address));
+            match vm.map_zeroed(page, region.flags, &mut FaultFrameAllocator) {
+                Ok(()) => PageFault::Resolved,
+                Err(error) => PageFault::Failed(region, error),
+            }
+        }
+        _ => PageFault::Invalid,
+    }
+}
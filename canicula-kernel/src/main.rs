@@ -1,5 +1,6 @@
 #![no_std]
 #![no_main]
+#![feature(abi_x86_interrupt)]
 
 mod arch;
 
@@ -16,6 +17,6 @@ pub fn kernel() -> ! {
 }
 
 #[no_mangle]
-pub extern "C" fn kernel() -> ! {
-    arch::x86::entry();
+pub extern "C" fn kernel(boot_info: &'static canicula_common::entry::BootInfo) -> ! {
+    arch::x86::entry(boot_info);
 }
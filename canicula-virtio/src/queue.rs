@@ -0,0 +1,354 @@
+use core::sync::atomic::{fence, Ordering};
+
+use crate::transport::{QueueAddresses, Transport};
+use crate::{VirtioError, VIRTIO_F_RING_EVENT_IDX};
+
+/// Legacy devices take queues as one page aligned block, addressed by frame number.
+pub const LEGACY_QUEUE_ALIGN: usize = 4096;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+const USED_F_NO_NOTIFY: u16 = 1;
+
+const DESCRIPTOR_SIZE: usize = 16;
+/// flags and idx in front of both rings.
+const RING_HEADER: usize = 4;
+const USED_ELEMENT_SIZE: usize = 8;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Memory for DMA: where the CPU sees it and the address the device uses.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaRegion {
+    pub virtual_address: *mut u8,
+    pub physical_address: u64,
+    pub size: usize,
+}
+
+/// A buffer handed to the device, by physical address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub address: u64,
+    pub len: u32,
+}
+
+/// Byte offsets of the rings within the queue memory.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    avail: usize,
+    used: usize,
+    size: usize,
+}
+
+impl Layout {
+    fn new(queue_size: u16, legacy: bool) -> Self {
+        let queue_size = queue_size as usize;
+        let avail = DESCRIPTOR_SIZE * queue_size;
+        // flags, idx, ring and used_event
+        let avail_end = avail + RING_HEADER + 2 * queue_size + 2;
+        let used = if legacy {
+            avail_end.next_multiple_of(LEGACY_QUEUE_ALIGN)
+        } else {
+            avail_end.next_multiple_of(4)
+        };
+        // flags, idx, ring and avail_event
+        let size = used + RING_HEADER + USED_ELEMENT_SIZE * queue_size + 2;
+        Layout { avail, used, size }
+    }
+}
+
+/// Whether the other side asked to be told about index `event` when the index
+/// moved from `old` to `new`, the `vring_need_event` of the specification.
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+/// A split virtqueue in caller provided DMA memory.
+///
+/// Descriptor chains are identified by a token, the index of their first
+/// descriptor, which [`VirtQueue::pop_used`] returns once the device is done.
+#[derive(Debug)]
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    memory: *mut u8,
+    layout: Layout,
+    free_head: u16,
+    free_count: u16,
+    /// Our copy of the available index, the device only ever reads it.
+    avail_index: u16,
+    /// Available index at the last notification.
+    notified_index: u16,
+    last_used: u16,
+    event_index: bool,
+}
+
+impl VirtQueue {
+    /// Bytes of DMA memory a queue of `size` entries needs.
+    pub fn memory_size(size: u16, legacy: bool) -> usize {
+        Layout::new(size, legacy).size
+    }
+
+    /// Set up queue `index` with `size` entries in `memory`, which must stay valid
+    /// and mapped for as long as the device uses the queue.
+    ///
+    /// `features` are the negotiated ones, for `VIRTIO_F_RING_EVENT_IDX`.
+    pub fn new(
+        transport: &mut impl Transport,
+        index: u16,
+        size: u16,
+        memory: DmaRegion,
+        features: u64,
+    ) -> Result<Self, VirtioError> {
+        if transport.queue_used(index) {
+            return Err(VirtioError::QueueInUse);
+        }
+        let max = transport.max_queue_size(index);
+        if max == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        if size == 0 || size > max || !size.is_power_of_two() {
+            return Err(VirtioError::InvalidParameter);
+        }
+
+        let legacy = transport.is_legacy();
+        let layout = Layout::new(size, legacy);
+        let align = if legacy { LEGACY_QUEUE_ALIGN } else { 16 };
+        if memory.size < layout.size || memory.physical_address & (align as u64 - 1) != 0 {
+            return Err(VirtioError::InvalidMemory);
+        }
+
+        let mut queue = VirtQueue {
+            index,
+            size,
+            memory: memory.virtual_address,
+            layout,
+            free_head: 0,
+            free_count: size,
+            avail_index: 0,
+            notified_index: 0,
+            last_used: 0,
+            event_index: features & VIRTIO_F_RING_EVENT_IDX != 0,
+        };
+        unsafe { core::ptr::write_bytes(queue.memory, 0, layout.size) };
+        for descriptor in 0..size {
+            queue.set_descriptor(
+                descriptor,
+                Descriptor {
+                    address: 0,
+                    len: 0,
+                    flags: 0,
+                    next: descriptor.wrapping_add(1),
+                },
+            );
+        }
+
+        let physical = memory.physical_address;
+        transport.queue_set(
+            index,
+            size,
+            QueueAddresses {
+                descriptors: physical,
+                driver: physical + layout.avail as u64,
+                device: physical + layout.used as u64,
+            },
+        )?;
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Descriptors not part of any chain the device holds.
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    fn descriptor(&self, index: u16) -> Descriptor {
+        unsafe {
+            (self.memory as *const Descriptor)
+                .add(index as usize)
+                .read_volatile()
+        }
+    }
+
+    fn set_descriptor(&mut self, index: u16, descriptor: Descriptor) {
+        unsafe {
+            (self.memory as *mut Descriptor)
+                .add(index as usize)
+                .write_volatile(descriptor)
+        }
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        unsafe { (self.memory.add(offset) as *const u16).read_volatile() }
+    }
+
+    fn write16(&mut self, offset: usize, value: u16) {
+        unsafe { (self.memory.add(offset) as *mut u16).write_volatile(value) }
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { (self.memory.add(offset) as *const u32).read_volatile() }
+    }
+
+    /// `used_event`, trailing the available ring.
+    fn used_event_offset(&self) -> usize {
+        self.layout.avail + RING_HEADER + 2 * self.size as usize
+    }
+
+    /// `avail_event`, trailing the used ring.
+    fn avail_event_offset(&self) -> usize {
+        self.layout.used + RING_HEADER + USED_ELEMENT_SIZE * self.size as usize
+    }
+
+    /// Chain `readable` then `writable` buffers and make the chain available.
+    ///
+    /// The device is not notified, see [`VirtQueue::notify`], so several chains
+    /// can be queued for one notification.
+    pub fn add(&mut self, readable: &[Buffer], writable: &[Buffer]) -> Result<u16, VirtioError> {
+        let count = readable.len() + writable.len();
+        if count == 0 {
+            return Err(VirtioError::InvalidParameter);
+        }
+        if count > self.free_count as usize {
+            return Err(VirtioError::QueueFull);
+        }
+
+        let head = self.free_head;
+        let mut last = head;
+        let mut next = head;
+        let buffers = readable
+            .iter()
+            .map(|buffer| (buffer, 0))
+            .chain(writable.iter().map(|buffer| (buffer, DESC_F_WRITE)));
+        for (buffer, flags) in buffers {
+            let descriptor = self.descriptor(next);
+            self.set_descriptor(
+                next,
+                Descriptor {
+                    address: buffer.address,
+                    len: buffer.len,
+                    flags: flags | DESC_F_NEXT,
+                    next: descriptor.next,
+                },
+            );
+            last = next;
+            next = descriptor.next;
+        }
+        let mut tail = self.descriptor(last);
+        tail.flags &= !DESC_F_NEXT;
+        self.set_descriptor(last, tail);
+        self.free_head = next;
+        self.free_count -= count as u16;
+
+        let slot = (self.avail_index % self.size) as usize;
+        self.write16(self.layout.avail + RING_HEADER + 2 * slot, head);
+        // the device must see the descriptors and ring entry before the new index
+        fence(Ordering::SeqCst);
+        self.avail_index = self.avail_index.wrapping_add(1);
+        self.write16(self.layout.avail + 2, self.avail_index);
+        Ok(head)
+    }
+
+    /// Whether the device wants to hear about the chains added since the last notification.
+    pub fn should_notify(&self) -> bool {
+        // the index update must be visible before we look at what the device asked for
+        fence(Ordering::SeqCst);
+        if self.event_index {
+            let avail_event = self.read16(self.avail_event_offset());
+            need_event(avail_event, self.avail_index, self.notified_index)
+        } else {
+            self.read16(self.layout.used) & USED_F_NO_NOTIFY == 0
+        }
+    }
+
+    /// Notify the device of new chains unless it suppressed notifications, returns
+    /// whether it was notified.
+    pub fn notify(&mut self, transport: &mut impl Transport) -> bool {
+        let notify = self.should_notify();
+        if notify {
+            transport.notify(self.index);
+        }
+        self.notified_index = self.avail_index;
+        notify
+    }
+
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+        self.read16(self.layout.used + 2) != self.last_used
+    }
+
+    /// The next chain the device is done with, as its token and the bytes written.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        while self.has_used() {
+            let slot = (self.last_used % self.size) as usize;
+            let element = self.layout.used + RING_HEADER + USED_ELEMENT_SIZE * slot;
+            let id = self.read32(element);
+            let len = self.read32(element + 4);
+            self.last_used = self.last_used.wrapping_add(1);
+            if self.event_index && self.interrupts_enabled() {
+                self.write16(self.used_event_offset(), self.last_used);
+            }
+
+            // ignore ids that cannot be ours rather than walking foreign memory
+            if id >= self.size as u32 {
+                continue;
+            }
+            self.free_chain(id as u16);
+            return Some((id as u16, len));
+        }
+        None
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        let mut index = head;
+        loop {
+            let mut descriptor = self.descriptor(index);
+            self.free_count += 1;
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                descriptor.next = self.free_head;
+                descriptor.flags = 0;
+                self.set_descriptor(index, descriptor);
+                break;
+            }
+            index = descriptor.next;
+        }
+        self.free_head = head;
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.read16(self.layout.avail) & AVAIL_F_NO_INTERRUPT == 0
+    }
+
+    /// Ask the device to interrupt, or not, when it adds used chains.
+    ///
+    /// Only a hint, the device may interrupt anyway.
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        let flags = if enabled { 0 } else { AVAIL_F_NO_INTERRUPT };
+        self.write16(self.layout.avail, flags);
+        if self.event_index {
+            // half the index space behind, so the device never reaches it
+            let event = if enabled {
+                self.last_used
+            } else {
+                self.last_used.wrapping_sub(0x8000)
+            };
+            self.write16(self.used_event_offset(), event);
+        }
+    }
+}
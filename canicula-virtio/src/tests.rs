@@ -0,0 +1,348 @@
+mod test {
+    use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+    use crate::transport::{QueueAddresses, Transport};
+    use crate::*;
+
+    /// Device side of a transport, queues live in host memory at their "physical" address.
+    struct FakeTransport {
+        legacy: bool,
+        features: u64,
+        driver_features: u64,
+        status: u8,
+        /// Clear FEATURES_OK when the driver sets it.
+        reject_features: bool,
+        max_queue_size: u16,
+        queue: Option<(u16, QueueAddresses)>,
+        notifications: usize,
+    }
+
+    impl FakeTransport {
+        fn new(features: u64) -> Self {
+            FakeTransport {
+                legacy: false,
+                features,
+                driver_features: 0,
+                status: 0,
+                reject_features: false,
+                max_queue_size: 16,
+                queue: None,
+                notifications: 0,
+            }
+        }
+    }
+
+    impl Transport for FakeTransport {
+        fn device_type(&self) -> DeviceType {
+            DeviceType::Block
+        }
+
+        fn is_legacy(&self) -> bool {
+            self.legacy
+        }
+
+        fn device_features(&mut self) -> u64 {
+            self.features
+        }
+
+        fn set_driver_features(&mut self, features: u64) {
+            self.driver_features = features;
+        }
+
+        fn status(&mut self) -> u8 {
+            self.status
+        }
+
+        fn set_status(&mut self, status: u8) {
+            self.status = if self.reject_features {
+                status & !STATUS_FEATURES_OK
+            } else {
+                status
+            };
+        }
+
+        fn max_queue_size(&mut self, queue: u16) -> u16 {
+            if queue == 0 {
+                self.max_queue_size
+            } else {
+                0
+            }
+        }
+
+        fn queue_used(&mut self, _queue: u16) -> bool {
+            self.queue.is_some()
+        }
+
+        fn queue_set(
+            &mut self,
+            _queue: u16,
+            size: u16,
+            addresses: QueueAddresses,
+        ) -> Result<(), VirtioError> {
+            self.queue = Some((size, addresses));
+            Ok(())
+        }
+
+        fn notify(&mut self, _queue: u16) {
+            self.notifications += 1;
+        }
+
+        fn ack_interrupt(&mut self) -> u8 {
+            0
+        }
+
+        fn read_config(&mut self, _offset: usize, buffer: &mut [u8]) {
+            buffer.fill(0);
+        }
+    }
+
+    struct Memory {
+        pointer: *mut u8,
+        layout: Layout,
+    }
+
+    impl Memory {
+        fn new(size: usize) -> Self {
+            let layout = Layout::from_size_align(size, 4096).unwrap();
+            Memory {
+                pointer: unsafe { alloc_zeroed(layout) },
+                layout,
+            }
+        }
+
+        fn region(&self) -> DmaRegion {
+            DmaRegion {
+                virtual_address: self.pointer,
+                physical_address: self.pointer as u64,
+                size: self.layout.size(),
+            }
+        }
+    }
+
+    impl Drop for Memory {
+        fn drop(&mut self) {
+            unsafe { dealloc(self.pointer, self.layout) };
+        }
+    }
+
+    unsafe fn read16(address: u64) -> u16 {
+        (address as *const u16).read_volatile()
+    }
+
+    unsafe fn write16(address: u64, value: u16) {
+        (address as *mut u16).write_volatile(value)
+    }
+
+    /// Complete every available chain, returns the chains as (address, len, flags) lists.
+    fn process(transport: &FakeTransport, used_index: &mut u16) -> Vec<Vec<(u64, u32, u16)>> {
+        let (size, addresses) = transport.queue.unwrap();
+        let mut chains = Vec::new();
+        unsafe {
+            let avail_index = read16(addresses.driver + 2);
+            while *used_index != avail_index {
+                let slot = (*used_index % size) as u64;
+                let head = read16(addresses.driver + 4 + 2 * slot);
+
+                let mut chain = Vec::new();
+                let mut written = 0;
+                let mut index = head;
+                loop {
+                    let descriptor = addresses.descriptors + 16 * index as u64;
+                    let address = (descriptor as *const u64).read_volatile();
+                    let len = ((descriptor + 8) as *const u32).read_volatile();
+                    let flags = read16(descriptor + 12);
+                    chain.push((address, len, flags));
+                    if flags & 2 != 0 {
+                        written += len;
+                    }
+                    if flags & 1 == 0 {
+                        break;
+                    }
+                    index = read16(descriptor + 14);
+                }
+                chains.push(chain);
+
+                let element = addresses.device + 4 + 8 * slot;
+                (element as *mut u32).write_volatile(head as u32);
+                ((element + 4) as *mut u32).write_volatile(written);
+                *used_index = used_index.wrapping_add(1);
+                write16(addresses.device + 2, *used_index);
+            }
+        }
+        chains
+    }
+
+    #[test]
+    fn negotiate() {
+        let mut transport = FakeTransport::new(VIRTIO_F_VERSION_1 | VIRTIO_F_RING_EVENT_IDX | 0b11);
+        let features = begin_init(&mut transport, 0b1).unwrap();
+        assert_eq!(features, VIRTIO_F_VERSION_1 | VIRTIO_F_RING_EVENT_IDX | 0b1);
+        assert_eq!(transport.driver_features, features);
+        assert_eq!(
+            transport.status,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK
+        );
+        finish_init(&mut transport);
+        assert_ne!(transport.status & STATUS_DRIVER_OK, 0);
+
+        let mut transport = FakeTransport::new(VIRTIO_F_VERSION_1);
+        transport.reject_features = true;
+        assert_eq!(
+            begin_init(&mut transport, 0),
+            Err(VirtioError::FeaturesRejected)
+        );
+        assert_eq!(transport.status, STATUS_FAILED);
+
+        // modern transports need VERSION_1, legacy ones only get 32 bits
+        let mut transport = FakeTransport::new(0b1);
+        assert_eq!(
+            begin_init(&mut transport, 0b1),
+            Err(VirtioError::Unsupported)
+        );
+        let mut transport = FakeTransport::new(VIRTIO_F_VERSION_1 | 0b1);
+        transport.legacy = true;
+        assert_eq!(begin_init(&mut transport, 0b1), Ok(0b1));
+    }
+
+    #[test]
+    fn queue() {
+        let mut transport = FakeTransport::new(VIRTIO_F_VERSION_1);
+        let memory = Memory::new(VirtQueue::memory_size(4, false));
+        let mut queue = VirtQueue::new(&mut transport, 0, 4, memory.region(), 0).unwrap();
+        assert_eq!(
+            VirtQueue::new(&mut transport, 0, 4, memory.region(), 0).unwrap_err(),
+            VirtioError::QueueInUse
+        );
+
+        let header = Buffer {
+            address: 0x1000,
+            len: 16,
+        };
+        let data = Buffer {
+            address: 0x2000,
+            len: 512,
+        };
+        let status = Buffer {
+            address: 0x3000,
+            len: 1,
+        };
+        let token = queue.add(&[header], &[data, status]).unwrap();
+        assert_eq!(queue.free_descriptors(), 1);
+        assert_eq!(
+            queue.add(&[header, header], &[]),
+            Err(VirtioError::QueueFull)
+        );
+        assert!(queue.notify(&mut transport));
+        assert_eq!(transport.notifications, 1);
+
+        assert_eq!(queue.pop_used(), None);
+        let mut used_index = 0;
+        let chains = process(&transport, &mut used_index);
+        assert_eq!(
+            chains,
+            [vec![(0x1000, 16, 1), (0x2000, 512, 3), (0x3000, 1, 2)]]
+        );
+        assert_eq!(queue.pop_used(), Some((token, 513)));
+        assert_eq!(queue.free_descriptors(), 4);
+
+        // freed descriptors are reused, the ring wraps around
+        for round in 0..10u64 {
+            let first = queue
+                .add(
+                    &[Buffer {
+                        address: round,
+                        len: 1,
+                    }],
+                    &[],
+                )
+                .unwrap();
+            let second = queue
+                .add(
+                    &[],
+                    &[Buffer {
+                        address: round,
+                        len: 2,
+                    }],
+                )
+                .unwrap();
+            queue.notify(&mut transport);
+            assert_eq!(process(&transport, &mut used_index).len(), 2);
+            assert_eq!(queue.pop_used(), Some((first, 0)));
+            assert_eq!(queue.pop_used(), Some((second, 2)));
+        }
+        assert_eq!(queue.free_descriptors(), 4);
+    }
+
+    #[test]
+    fn notification_suppression() {
+        let buffer = [Buffer {
+            address: 0x1000,
+            len: 8,
+        }];
+
+        // without event index the device sets a flag in the used ring
+        let mut transport = FakeTransport::new(VIRTIO_F_VERSION_1);
+        let memory = Memory::new(VirtQueue::memory_size(8, false));
+        let mut queue = VirtQueue::new(&mut transport, 0, 8, memory.region(), 0).unwrap();
+        let used = transport.queue.unwrap().1.device;
+        unsafe { write16(used, 1) };
+        queue.add(&buffer, &[]).unwrap();
+        assert!(!queue.notify(&mut transport));
+        unsafe { write16(used, 0) };
+        queue.add(&buffer, &[]).unwrap();
+        assert!(queue.notify(&mut transport));
+
+        // with it the device names the available index it wants to hear about
+        let mut transport = FakeTransport::new(VIRTIO_F_VERSION_1);
+        let memory = Memory::new(VirtQueue::memory_size(8, false));
+        let mut queue = VirtQueue::new(
+            &mut transport,
+            0,
+            8,
+            memory.region(),
+            VIRTIO_F_RING_EVENT_IDX,
+        )
+        .unwrap();
+        let avail_event = transport.queue.unwrap().1.device + 4 + 8 * 8;
+        unsafe { write16(avail_event, 2) };
+        queue.add(&buffer, &[]).unwrap();
+        assert!(!queue.notify(&mut transport));
+        queue.add(&buffer, &[]).unwrap();
+        queue.add(&buffer, &[]).unwrap();
+        assert!(queue.notify(&mut transport));
+        assert_eq!(transport.notifications, 1);
+
+        // the driver publishes the used index it wants an interrupt for
+        let used_event = transport.queue.unwrap().1.driver + 4 + 2 * 8;
+        let mut used_index = 0;
+        process(&transport, &mut used_index);
+        while queue.pop_used().is_some() {}
+        assert_eq!(unsafe { read16(used_event) }, 3);
+        queue.set_interrupts(false);
+        assert_eq!(unsafe { read16(used_event) }, 3u16.wrapping_sub(0x8000));
+    }
+
+    #[test]
+    fn legacy_layout() {
+        // the used ring starts on the next page after descriptors and available ring
+        assert_eq!(VirtQueue::memory_size(256, true), 8192 + 4 + 8 * 256 + 2);
+        assert_eq!(
+            VirtQueue::memory_size(256, false),
+            4096 + 520 + 4 + 8 * 256 + 2
+        );
+
+        let mut transport = FakeTransport::new(0);
+        transport.legacy = true;
+        let memory = Memory::new(8192);
+        let mut region = memory.region();
+        region.physical_address += 16;
+        assert_eq!(
+            VirtQueue::new(&mut transport, 0, 16, region, 0).unwrap_err(),
+            VirtioError::InvalidMemory
+        );
+        assert_eq!(
+            VirtQueue::new(&mut transport, 1, 16, memory.region(), 0).unwrap_err(),
+            VirtioError::QueueUnavailable
+        );
+    }
+}
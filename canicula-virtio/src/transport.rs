@@ -0,0 +1,112 @@
+use crate::{DeviceType, VirtioError};
+
+pub mod mmio;
+pub mod pci;
+
+/// Physical addresses of the three parts of a split virtqueue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueAddresses {
+    pub descriptors: u64,
+    /// The available ring.
+    pub driver: u64,
+    /// The used ring.
+    pub device: u64,
+}
+
+/// How a driver reaches its device, independent of what kind of device it is.
+pub trait Transport {
+    fn device_type(&self) -> DeviceType;
+
+    /// Pre 1.0 interface: 32 feature bits and page aligned, contiguous queues.
+    fn is_legacy(&self) -> bool;
+
+    fn device_features(&mut self) -> u64;
+
+    fn set_driver_features(&mut self, features: u64);
+
+    fn status(&mut self) -> u8;
+
+    fn set_status(&mut self, status: u8);
+
+    /// Largest size of queue `queue`, `0` if it does not exist.
+    fn max_queue_size(&mut self, queue: u16) -> u16;
+
+    /// Whether queue `queue` has been handed to the device already.
+    fn queue_used(&mut self, queue: u16) -> bool;
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u16,
+        addresses: QueueAddresses,
+    ) -> Result<(), VirtioError>;
+
+    fn notify(&mut self, queue: u16);
+
+    /// Read and acknowledge the interrupt status, see [`INTERRUPT_QUEUE`] and [`INTERRUPT_CONFIG`].
+    fn ack_interrupt(&mut self) -> u8;
+
+    /// Read `buffer.len()` bytes of device specific configuration from `offset`.
+    fn read_config(&mut self, offset: usize, buffer: &mut [u8]);
+}
+
+/// A used buffer was added to one of the queues.
+pub const INTERRUPT_QUEUE: u8 = 1;
+/// The device configuration changed.
+pub const INTERRUPT_CONFIG: u8 = 2;
+
+/// A transport's register block, memory mapped or in I/O space.
+pub trait Registers {
+    fn read8(&self, offset: usize) -> u8;
+    fn read16(&self, offset: usize) -> u16;
+    fn read32(&self, offset: usize) -> u32;
+    fn write8(&mut self, offset: usize, value: u8);
+    fn write16(&mut self, offset: usize, value: u16);
+    fn write32(&mut self, offset: usize, value: u32);
+
+    fn write64(&mut self, offset: usize, value: u64) {
+        self.write32(offset, value as u32);
+        self.write32(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// Registers mapped into memory, accessed with volatile loads and stores.
+#[derive(Debug)]
+pub struct Mmio {
+    base: *mut u8,
+}
+
+impl Mmio {
+    /// # Safety
+    ///
+    /// `base` must map the whole register block for as long as this lives.
+    pub unsafe fn new(base: *mut u8) -> Self {
+        Mmio { base }
+    }
+}
+
+impl Registers for Mmio {
+    fn read8(&self, offset: usize) -> u8 {
+        unsafe { self.base.add(offset).read_volatile() }
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        unsafe { (self.base.add(offset) as *const u16).read_volatile() }
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { (self.base.add(offset) as *const u32).read_volatile() }
+    }
+
+    fn write8(&mut self, offset: usize, value: u8) {
+        unsafe { self.base.add(offset).write_volatile(value) }
+    }
+
+    fn write16(&mut self, offset: usize, value: u16) {
+        unsafe { (self.base.add(offset) as *mut u16).write_volatile(value) }
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        unsafe { (self.base.add(offset) as *mut u32).write_volatile(value) }
+    }
+}
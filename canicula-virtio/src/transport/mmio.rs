@@ -0,0 +1,162 @@
+use super::{QueueAddresses, Registers, Transport};
+use crate::queue::LEGACY_QUEUE_ALIGN;
+use crate::{DeviceType, VirtioError};
+
+/// "virt" in little endian.
+const MAGIC: u32 = 0x7472_6976;
+
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c;
+const QUEUE_PFN: usize = 0x040;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC: usize = 0x080;
+const QUEUE_DRIVER: usize = 0x090;
+const QUEUE_DEVICE: usize = 0x0a0;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+/// The virtio-mmio register layout, version 1 (legacy) or 2.
+#[derive(Debug)]
+pub struct MmioTransport<R: Registers> {
+    registers: R,
+    version: u32,
+    device_type: DeviceType,
+}
+
+impl<R: Registers> MmioTransport<R> {
+    pub fn new(mut registers: R) -> Result<Self, VirtioError> {
+        if registers.read32(MAGIC_VALUE) != MAGIC {
+            return Err(VirtioError::InvalidDevice);
+        }
+        let version = registers.read32(VERSION);
+        if version != 1 && version != 2 {
+            return Err(VirtioError::InvalidDevice);
+        }
+        // device id 0 is a placeholder slot without a device behind it
+        let device_id = registers.read32(DEVICE_ID);
+        if device_id == 0 {
+            return Err(VirtioError::InvalidDevice);
+        }
+
+        if version == 1 {
+            registers.write32(GUEST_PAGE_SIZE, LEGACY_QUEUE_ALIGN as u32);
+        }
+        Ok(MmioTransport {
+            registers,
+            version,
+            device_type: DeviceType::from_id(device_id),
+        })
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+impl<R: Registers> Transport for MmioTransport<R> {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    fn device_features(&mut self) -> u64 {
+        self.registers.write32(DEVICE_FEATURES_SEL, 0);
+        let low = self.registers.read32(DEVICE_FEATURES);
+        self.registers.write32(DEVICE_FEATURES_SEL, 1);
+        let high = self.registers.read32(DEVICE_FEATURES);
+        (high as u64) << 32 | low as u64
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.registers.write32(DRIVER_FEATURES_SEL, 0);
+        self.registers.write32(DRIVER_FEATURES, features as u32);
+        self.registers.write32(DRIVER_FEATURES_SEL, 1);
+        self.registers
+            .write32(DRIVER_FEATURES, (features >> 32) as u32);
+    }
+
+    fn status(&mut self) -> u8 {
+        self.registers.read32(STATUS) as u8
+    }
+
+    fn set_status(&mut self, status: u8) {
+        self.registers.write32(STATUS, status as u32);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        self.registers.write32(QUEUE_SEL, queue as u32);
+        self.registers.read32(QUEUE_NUM_MAX) as u16
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.registers.write32(QUEUE_SEL, queue as u32);
+        if self.is_legacy() {
+            self.registers.read32(QUEUE_PFN) != 0
+        } else {
+            self.registers.read32(QUEUE_READY) != 0
+        }
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u16,
+        addresses: QueueAddresses,
+    ) -> Result<(), VirtioError> {
+        self.registers.write32(QUEUE_SEL, queue as u32);
+        self.registers.write32(QUEUE_NUM, size as u32);
+
+        if self.is_legacy() {
+            let pfn = addresses.descriptors / LEGACY_QUEUE_ALIGN as u64;
+            self.registers
+                .write32(QUEUE_ALIGN, LEGACY_QUEUE_ALIGN as u32);
+            self.registers.write32(QUEUE_PFN, pfn as u32);
+        } else {
+            self.registers.write64(QUEUE_DESC, addresses.descriptors);
+            self.registers.write64(QUEUE_DRIVER, addresses.driver);
+            self.registers.write64(QUEUE_DEVICE, addresses.device);
+            self.registers.write32(QUEUE_READY, 1);
+        }
+        Ok(())
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.registers.write32(QUEUE_NOTIFY, queue as u32);
+    }
+
+    fn ack_interrupt(&mut self) -> u8 {
+        let status = self.registers.read32(INTERRUPT_STATUS);
+        self.registers.write32(INTERRUPT_ACK, status);
+        status as u8
+    }
+
+    fn read_config(&mut self, offset: usize, buffer: &mut [u8]) {
+        loop {
+            let generation = self.registers.read32(CONFIG_GENERATION);
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = self.registers.read8(CONFIG + offset + i);
+            }
+            // legacy devices have no generation counter, it always reads 0
+            if self.registers.read32(CONFIG_GENERATION) == generation {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use super::{Mmio, QueueAddresses, Registers, Transport};
+use crate::queue::LEGACY_QUEUE_ALIGN;
+use crate::{DeviceType, VirtioError};
+
+pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+const PCI_VENDOR_ID: u8 = 0x00;
+const PCI_STATUS: u8 = 0x06;
+const PCI_BAR0: u8 = 0x10;
+const PCI_SUBSYSTEM_ID: u8 = 0x2e;
+const PCI_CAPABILITIES: u8 = 0x34;
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+const CAPABILITY_VENDOR: u8 = 0x09;
+
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// virtio_pci_common_cfg
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0c;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_ENABLE: usize = 0x1c;
+const QUEUE_NOTIFY_OFF: usize = 0x1e;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+// the legacy I/O register block, without MSI-X
+const LEGACY_HOST_FEATURES: usize = 0x00;
+const LEGACY_GUEST_FEATURES: usize = 0x04;
+const LEGACY_QUEUE_ADDRESS: usize = 0x08;
+const LEGACY_QUEUE_SIZE: usize = 0x0c;
+const LEGACY_QUEUE_SELECT: usize = 0x0e;
+const LEGACY_QUEUE_NOTIFY: usize = 0x10;
+const LEGACY_DEVICE_STATUS: usize = 0x12;
+const LEGACY_ISR: usize = 0x13;
+const LEGACY_CONFIG: usize = 0x14;
+
+/// Configuration space of one PCI function.
+pub trait PciConfig {
+    fn read32(&self, offset: u8) -> u32;
+
+    fn read16(&self, offset: u8) -> u16 {
+        (self.read32(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    fn read8(&self, offset: u8) -> u8 {
+        (self.read32(offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory(u64),
+    Io(u16),
+}
+
+/// Decode base address register `index`, a 64-bit BAR also uses the next one.
+pub fn read_bar(config: &impl PciConfig, index: u8) -> Option<Bar> {
+    if index >= 6 {
+        return None;
+    }
+    let low = config.read32(PCI_BAR0 + index * 4);
+    if low & 1 == 1 {
+        return Some(Bar::Io((low & !3) as u16));
+    }
+
+    let address = (low & !0xf) as u64;
+    if low >> 1 & 3 == 2 {
+        if index == 5 {
+            return None;
+        }
+        let high = config.read32(PCI_BAR0 + (index + 1) * 4) as u64;
+        return Some(Bar::Memory(high << 32 | address));
+    }
+    Some(Bar::Memory(address))
+}
+
+/// The virtio device behind a PCI function, `None` if it is not one.
+///
+/// Transitional devices (0x1000..0x103f) carry the type in the subsystem id,
+/// modern ones (0x1040..) in the device id.
+pub fn device_type(config: &impl PciConfig) -> Option<DeviceType> {
+    let id = config.read32(PCI_VENDOR_ID);
+    if id as u16 != VIRTIO_VENDOR_ID {
+        return None;
+    }
+    match (id >> 16) as u16 {
+        0x1000..=0x103f => Some(DeviceType::from_id(config.read16(PCI_SUBSYSTEM_ID) as u32)),
+        device @ 0x1040..=0x107f => Some(DeviceType::from_id(device as u32 - 0x1040)),
+        _ => None,
+    }
+}
+
+/// `virtio_pci_cap`, a window into one of the BARs.
+#[derive(Debug, Clone, Copy)]
+struct Capability {
+    bar: u8,
+    offset: u32,
+    /// Where the capability itself sits in configuration space.
+    position: u8,
+}
+
+/// First capability of each virtio structure type, indexed by type.
+fn capabilities(config: &impl PciConfig) -> [Option<Capability>; 5] {
+    let mut found = [None; 5];
+    if config.read16(PCI_STATUS) & STATUS_CAPABILITIES_LIST == 0 {
+        return found;
+    }
+
+    let mut position = config.read8(PCI_CAPABILITIES) & !3;
+    // a corrupt list could loop, configuration space only fits 48 capabilities
+    for _ in 0..48 {
+        if position == 0 {
+            break;
+        }
+        if config.read8(position) == CAPABILITY_VENDOR {
+            let kind = config.read8(position + 3);
+            if (1..=4).contains(&kind) && found[kind as usize].is_none() {
+                found[kind as usize] = Some(Capability {
+                    bar: config.read8(position + 4),
+                    offset: config.read32(position + 8),
+                    position,
+                });
+            }
+        }
+        position = config.read8(position + 1) & !3;
+    }
+    found
+}
+
+/// The modern (virtio 1.0) PCI transport, with its structures in memory BARs.
+#[derive(Debug)]
+pub struct PciTransport {
+    device_type: DeviceType,
+    common: Mmio,
+    notify: Mmio,
+    notify_multiplier: u32,
+    isr: Mmio,
+    device: Option<Mmio>,
+}
+
+impl PciTransport {
+    /// # Safety
+    ///
+    /// `map` must return a pointer through which the physical address it is given,
+    /// and the register block behind it, can be accessed as device memory.
+    pub unsafe fn new(
+        config: &impl PciConfig,
+        map: impl Fn(u64) -> *mut u8,
+    ) -> Result<Self, VirtioError> {
+        let device_type = device_type(config).ok_or(VirtioError::InvalidDevice)?;
+        let capabilities = capabilities(config);
+        let window = |kind: u8| -> Result<Mmio, VirtioError> {
+            let capability = capabilities[kind as usize].ok_or(VirtioError::InvalidDevice)?;
+            match read_bar(config, capability.bar) {
+                Some(Bar::Memory(address)) if address != 0 => {
+                    Ok(unsafe { Mmio::new(map(address + capability.offset as u64)) })
+                }
+                _ => Err(VirtioError::InvalidDevice),
+            }
+        };
+
+        let notify_capability =
+            capabilities[CAP_NOTIFY_CFG as usize].ok_or(VirtioError::InvalidDevice)?;
+        Ok(PciTransport {
+            device_type,
+            common: window(CAP_COMMON_CFG)?,
+            notify: window(CAP_NOTIFY_CFG)?,
+            notify_multiplier: config.read32(notify_capability.position + 16),
+            isr: window(CAP_ISR_CFG)?,
+            device: window(CAP_DEVICE_CFG).ok(),
+        })
+    }
+}
+
+impl Transport for PciTransport {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn is_legacy(&self) -> bool {
+        false
+    }
+
+    fn device_features(&mut self) -> u64 {
+        self.common.write32(DEVICE_FEATURE_SELECT, 0);
+        let low = self.common.read32(DEVICE_FEATURE);
+        self.common.write32(DEVICE_FEATURE_SELECT, 1);
+        let high = self.common.read32(DEVICE_FEATURE);
+        (high as u64) << 32 | low as u64
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.common.write32(DRIVER_FEATURE_SELECT, 0);
+        self.common.write32(DRIVER_FEATURE, features as u32);
+        self.common.write32(DRIVER_FEATURE_SELECT, 1);
+        self.common.write32(DRIVER_FEATURE, (features >> 32) as u32);
+    }
+
+    fn status(&mut self) -> u8 {
+        self.common.read8(DEVICE_STATUS)
+    }
+
+    fn set_status(&mut self, status: u8) {
+        self.common.write8(DEVICE_STATUS, status);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        self.common.write16(QUEUE_SELECT, queue);
+        self.common.read16(QUEUE_SIZE)
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.common.write16(QUEUE_SELECT, queue);
+        self.common.read16(QUEUE_ENABLE) != 0
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u16,
+        addresses: QueueAddresses,
+    ) -> Result<(), VirtioError> {
+        self.common.write16(QUEUE_SELECT, queue);
+        self.common.write16(QUEUE_SIZE, size);
+        self.common.write64(QUEUE_DESC, addresses.descriptors);
+        self.common.write64(QUEUE_DRIVER, addresses.driver);
+        self.common.write64(QUEUE_DEVICE, addresses.device);
+        self.common.write16(QUEUE_ENABLE, 1);
+        Ok(())
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.common.write16(QUEUE_SELECT, queue);
+        let offset = self.common.read16(QUEUE_NOTIFY_OFF) as usize;
+        self.notify
+            .write16(offset * self.notify_multiplier as usize, queue);
+    }
+
+    fn ack_interrupt(&mut self) -> u8 {
+        // reading the ISR status clears it
+        self.isr.read8(0)
+    }
+
+    fn read_config(&mut self, offset: usize, buffer: &mut [u8]) {
+        let Some(device) = self.device.as_ref() else {
+            buffer.fill(0);
+            return;
+        };
+        loop {
+            let generation = self.common.read8(CONFIG_GENERATION);
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = device.read8(offset + i);
+            }
+            if self.common.read8(CONFIG_GENERATION) == generation {
+                return;
+            }
+        }
+    }
+}
+
+/// The legacy PCI transport of transitional devices, registers in I/O BAR 0.
+#[derive(Debug)]
+pub struct LegacyPciTransport<R: Registers> {
+    device_type: DeviceType,
+    registers: R,
+}
+
+impl<R: Registers> LegacyPciTransport<R> {
+    /// `registers` accesses the I/O space at [`Bar::Io`] of BAR 0.
+    pub fn new(config: &impl PciConfig, registers: R) -> Result<Self, VirtioError> {
+        let device_type = device_type(config).ok_or(VirtioError::InvalidDevice)?;
+        if !matches!(read_bar(config, 0), Some(Bar::Io(_))) {
+            return Err(VirtioError::InvalidDevice);
+        }
+        Ok(LegacyPciTransport {
+            device_type,
+            registers,
+        })
+    }
+}
+
+impl<R: Registers> Transport for LegacyPciTransport<R> {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn is_legacy(&self) -> bool {
+        true
+    }
+
+    fn device_features(&mut self) -> u64 {
+        self.registers.read32(LEGACY_HOST_FEATURES) as u64
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.registers
+            .write32(LEGACY_GUEST_FEATURES, features as u32);
+    }
+
+    fn status(&mut self) -> u8 {
+        self.registers.read8(LEGACY_DEVICE_STATUS)
+    }
+
+    fn set_status(&mut self, status: u8) {
+        self.registers.write8(LEGACY_DEVICE_STATUS, status);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        self.registers.write16(LEGACY_QUEUE_SELECT, queue);
+        self.registers.read16(LEGACY_QUEUE_SIZE)
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.registers.write16(LEGACY_QUEUE_SELECT, queue);
+        self.registers.read32(LEGACY_QUEUE_ADDRESS) != 0
+    }
+
+    /// The queue size is fixed by the device, `size` has to be the maximum.
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u16,
+        addresses: QueueAddresses,
+    ) -> Result<(), VirtioError> {
+        if size != self.max_queue_size(queue) {
+            return Err(VirtioError::InvalidParameter);
+        }
+        let pfn = addresses.descriptors / LEGACY_QUEUE_ALIGN as u64;
+        self.registers.write32(LEGACY_QUEUE_ADDRESS, pfn as u32);
+        Ok(())
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.registers.write16(LEGACY_QUEUE_NOTIFY, queue);
+    }
+
+    fn ack_interrupt(&mut self) -> u8 {
+        self.registers.read8(LEGACY_ISR)
+    }
+
+    fn read_config(&mut self, offset: usize, buffer: &mut [u8]) {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.registers.read8(LEGACY_CONFIG + offset + i);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+#![cfg_attr(not(test), no_std)]
+
+pub mod queue;
+#[cfg(test)]
+mod tests;
+pub mod transport;
+
+pub use queue::{Buffer, DmaRegion, VirtQueue};
+pub use transport::Transport;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_DEVICE_NEEDS_RESET: u8 = 64;
+pub const STATUS_FAILED: u8 = 128;
+
+pub const VIRTIO_F_RING_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_F_RING_EVENT_IDX: u64 = 1 << 29;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Transport and ring features implemented here, offered on top of the device's own.
+const CORE_FEATURES: u64 = VIRTIO_F_RING_EVENT_IDX | VIRTIO_F_VERSION_1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// No virtio device, or a register layout version we do not know.
+    InvalidDevice,
+    /// The device has no queue with that index.
+    QueueUnavailable,
+    QueueInUse,
+    QueueFull,
+    /// Memory handed to a queue is too small or misaligned.
+    InvalidMemory,
+    InvalidParameter,
+    /// A modern device that does not offer `VIRTIO_F_VERSION_1`.
+    Unsupported,
+    /// The device cleared `FEATURES_OK` after negotiation.
+    FeaturesRejected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Balloon,
+    ScsiHost,
+    Gpu,
+    Input,
+    Socket,
+    Other(u32),
+}
+
+impl DeviceType {
+    pub fn from_id(id: u32) -> Self {
+        match id {
+            1 => DeviceType::Network,
+            2 => DeviceType::Block,
+            3 => DeviceType::Console,
+            4 => DeviceType::Entropy,
+            5 => DeviceType::Balloon,
+            8 => DeviceType::ScsiHost,
+            16 => DeviceType::Gpu,
+            18 => DeviceType::Input,
+            19 => DeviceType::Socket,
+            id => DeviceType::Other(id),
+        }
+    }
+}
+
+/// Reset the device and negotiate features, the first half of device initialisation.
+///
+/// `supported` holds the device specific features the driver understands. The
+/// result is what both sides agreed on; the driver sets up its queues with it and
+/// then calls [`finish_init`].
+pub fn begin_init(transport: &mut impl Transport, supported: u64) -> Result<u64, VirtioError> {
+    transport.set_status(0);
+    while transport.status() != 0 {
+        core::hint::spin_loop();
+    }
+    transport.set_status(STATUS_ACKNOWLEDGE);
+    transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+    let mut features = transport.device_features() & (supported | CORE_FEATURES);
+    if transport.is_legacy() {
+        // legacy devices have 32 feature bits and no FEATURES_OK handshake
+        features &= 0xffff_ffff;
+        transport.set_driver_features(features);
+        return Ok(features);
+    }
+    if features & VIRTIO_F_VERSION_1 == 0 {
+        transport.set_status(STATUS_FAILED);
+        return Err(VirtioError::Unsupported);
+    }
+
+    transport.set_driver_features(features);
+    transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+    if transport.status() & STATUS_FEATURES_OK == 0 {
+        transport.set_status(STATUS_FAILED);
+        return Err(VirtioError::FeaturesRejected);
+    }
+    Ok(features)
+}
+
+/// Tell the device the driver is ready, after its queues are set up.
+pub fn finish_init(transport: &mut impl Transport) {
+    let status = transport.status();
+    transport.set_status(status | STATUS_DRIVER_OK);
+}